@@ -0,0 +1,74 @@
+// Times a round of matching on pools of increasing size with criterion, run with `cargo bench`. A
+// name given after `--` only runs the cases matching it, e.g. `cargo bench -- optimal`.
+//
+// The matching code is built in directly as the crate has no library to link against.
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/domain.rs"]
+mod domain;
+#[allow(dead_code)]
+#[path = "../src/matching.rs"]
+mod matching;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use domain::PersonId;
+use matching::{Graph, OddPersonPolicy, Strategy};
+
+// A pool like a long running deployment's: everyone has met a handful of others, some more than
+// once, a few pairs are blocked and last round's odd one out has priority.
+fn pool(size: usize) -> Graph {
+    let mut g = Graph::default();
+    for i in 0..size {
+        g.add_node(PersonId(i as u32 + 1));
+    }
+    let mut state = size as u64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for i in 0..size {
+        for _ in 0..10 {
+            let j = (next() % size as u64) as usize;
+            if i != j {
+                g.add_edge(i, j, g.weight(i, j) + 1 + (next() % 3) as u32);
+            }
+        }
+        if next() % 20 == 0 {
+            let j = (next() % size as u64) as usize;
+            if i != j {
+                g.forbid(i, j);
+            }
+        }
+    }
+    g.set_priority(0);
+    g
+}
+
+fn matching(c: &mut Criterion) {
+    let strategies = [
+        ("optimal", Strategy::Optimal),
+        ("greedy", Strategy::Greedy),
+        ("random", Strategy::Random),
+        ("round_robin", Strategy::RoundRobin),
+    ];
+    let graphs: Vec<_> = [100, 500, 1001, 2000, 5000]
+        .into_iter()
+        .map(|size| (size, pool(size)))
+        .collect();
+    for (name, strategy) in strategies {
+        let mut group = c.benchmark_group(name);
+        // The largest optimal runs take seconds each.
+        group.sample_size(10);
+        for (size, graph) in &graphs {
+            group.bench_with_input(BenchmarkId::from_parameter(size), graph, |b, graph| {
+                b.iter(|| graph.matching(OddPersonPolicy::Unmatched, strategy.implementation()))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, matching);
+criterion_main!(benches);
@@ -0,0 +1,111 @@
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use tokio::sync::Notify;
+
+// How far a test clock can be moved ahead, a hundred years, which keeps every date it gives well
+// within what can be represented.
+pub const MAX_OFFSET_SECONDS: i64 = 100 * 366 * 24 * 60 * 60;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AdvanceError {
+    // Only a test clock can be moved.
+    RealClock,
+    // Backwards, or further than `MAX_OFFSET_SECONDS` ahead.
+    OutOfRange,
+}
+
+// The time the app works to, for sessions, pauses, the schedule and anything else that depends on
+// the date. It follows the system clock, but a test clock can also be moved forward so end-to-end
+// tests can check what happens days later without waiting for them.
+#[derive(Clone)]
+pub struct Clock {
+    test: bool,
+    offset_seconds: Arc<AtomicI64>,
+    advanced: Arc<Notify>,
+}
+
+impl Clock {
+    pub fn new(test: bool) -> Self {
+        Self {
+            test,
+            offset_seconds: Arc::default(),
+            advanced: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        Utc::now() + TimeDelta::seconds(self.offset_seconds.load(Ordering::Relaxed))
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        self.now().timestamp()
+    }
+
+    // How far ahead of the system clock it is.
+    pub fn offset_seconds(&self) -> i64 {
+        self.offset_seconds.load(Ordering::Relaxed)
+    }
+
+    // Move a test clock forward, waking anything waiting for a time, and return the new time. It can
+    // only go forward, and no further than `MAX_OFFSET_SECONDS` ahead of the system clock in total.
+    pub fn advance(&self, seconds: i64) -> Result<DateTime<Utc>, AdvanceError> {
+        if !self.test {
+            return Err(AdvanceError::RealClock);
+        }
+        if seconds < 0 {
+            return Err(AdvanceError::OutOfRange);
+        }
+        self.offset_seconds
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |offset| {
+                offset
+                    .checked_add(seconds)
+                    .filter(|offset| *offset <= MAX_OFFSET_SECONDS)
+            })
+            .map_err(|_| AdvanceError::OutOfRange)?;
+        self.advanced.notify_waiters();
+        Ok(self.now())
+    }
+
+    // Resolves when a test clock is moved forward, never for the real clock.
+    pub async fn advanced(&self) {
+        self.advanced.notified().await;
+    }
+
+    // Sleep until the clock reaches `at`, however it gets there.
+    pub async fn sleep_until(&self, at: DateTime<Utc>) {
+        loop {
+            // Listen before reading the time so an advance in between isn't missed.
+            let advanced = self.advanced.notified();
+            tokio::pin!(advanced);
+            advanced.as_mut().enable();
+            let Ok(remaining) = (at - self.now()).to_std() else {
+                return;
+            };
+            tokio::select! {
+                () = tokio::time::sleep(remaining) => {}
+                () = advanced => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advance_is_bounded() {
+        let clock = Clock::new(true);
+        assert_eq!(clock.advance(-1), Err(AdvanceError::OutOfRange));
+        assert_eq!(clock.advance(i64::MAX), Err(AdvanceError::OutOfRange));
+        assert!(clock.advance(MAX_OFFSET_SECONDS - 10).is_ok());
+        assert_eq!(clock.advance(11), Err(AdvanceError::OutOfRange));
+        assert!(clock.advance(10).is_ok());
+        assert_eq!(clock.offset_seconds(), MAX_OFFSET_SECONDS);
+        assert_eq!(Clock::new(false).advance(1), Err(AdvanceError::RealClock));
+    }
+}
@@ -0,0 +1,515 @@
+use std::{collections::HashMap, fmt::Debug, str::FromStr};
+
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::DEFAULT_ROUND_TYPE,
+    logging::LogFormat,
+    mail::MailConfig,
+    matching::{OddPersonPolicy, SameTeamPolicy, Strategy},
+    slack::SlackConfig,
+    validation,
+};
+
+// Everything that can differ between deployments. Values are read from the JSON file named by
+// `MATCHER_CONFIG` (if set) and then overridden by individual `MATCHER_*` environment variables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub public_address: String,
+    pub private_address: String,
+    // Where people reach the public site, used for links in emails.
+    pub public_url: String,
+    pub database_path: String,
+    // Where snapshots of the database are taken before destructive admin actions.
+    pub snapshot_dir: String,
+    // How many snapshots to keep, the oldest are removed first. 0 keeps them all.
+    pub max_snapshots: usize,
+    pub template_dir: String,
+    // The language the templates are written in, as a BCP 47 tag such as `en` or `de-CH`. Given to
+    // every template as `locale`.
+    pub locale: String,
+    pub session_lifetime_seconds: i64,
+    // Signs session cookies when set, at least 32 bytes. Changing it signs everyone out.
+    pub cookie_key: Option<String>,
+    // Encrypts emails, contact details and round notes in the database when set, at least 32 bytes.
+    // Once set it can't be removed or changed, as the data can only be read with it.
+    pub encryption_key: Option<String>,
+    // A file holding the encryption key instead, e.g. written by a KMS or secrets manager agent.
+    pub encryption_key_file: Option<String>,
+    // How many times a minute each address can try to sign in or up, 0 for no limit.
+    pub auth_requests_per_minute: u32,
+    // Use the address the last proxy in `X-Forwarded-For` saw for the limit, only set behind one.
+    pub trust_forwarded_for: bool,
+    // Accounts are locked for `lockout_minutes` after this many wrong passwords within that time,
+    // 0 never locks them.
+    pub lockout_attempts: u32,
+    pub lockout_minutes: i64,
+    pub stale_account_days: i64,
+    // Pools that haven't run for this many months (of 30 days), with nobody waiting, are archived.
+    // Unset never archives them.
+    pub archive_pools_after_months: Option<u32>,
+    // Email everyone who isn't waiting this many days before each scheduled run, inviting them to
+    // opt in. Unset sends no reminders.
+    pub reminder_days_before: Option<u32>,
+    // Show totals of participants, rounds and meetings to anyone at `/stats`, e.g. to link to when
+    // promoting the program.
+    pub public_stats: bool,
+    // Let the clock be moved forward from the private port, for end-to-end tests. Never set this
+    // in production.
+    pub test_clock: bool,
+    pub odd_person_policy: OddPersonPolicy,
+    // How pairs are chosen, `optimal` minimises how often people have met.
+    pub matching_strategy: Strategy,
+    // Pools with more waiters than this are split into buckets by timezone and matched per bucket.
+    pub max_exact_pool_size: usize,
+    // Past matches count for half as much after this many generations. Unset means no decay.
+    pub edge_half_life_generations: Option<u32>,
+    // People matched in the last this many generations are never matched again, 0 allows repeats.
+    pub repeat_window_generations: u32,
+    // How many people nobody in a match has met yet to suggest in their email, 0 for none.
+    pub introduction_suggestions: u32,
+    // Taken off the weight between two people for each interest they share, in past matches.
+    pub interest_bonus: u32,
+    pub same_team_policy: SameTeamPolicy,
+    // Added to the weight between people in the same team when avoiding them, in past matches.
+    pub same_team_penalty: u32,
+    // Matching is refused within this many seconds of the last generation.
+    pub min_run_interval_seconds: i64,
+    // The kinds of round that can run side by side, e.g. coffee and mentoring. Scheduled runs use
+    // the first.
+    pub round_types: Vec<String>,
+    // Round types (or pools) that only pair mentors with mentees.
+    pub mentoring_round_types: Vec<String>,
+    // Round types (or pools) matched in groups of more than two, e.g. lunch groups of 4.
+    pub group_sizes: HashMap<String, usize>,
+    // The most detailed level to log: off, error, warn, info, debug or trace.
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub smtp: Option<MailConfig>,
+    pub slack: Option<SlackConfig>,
+    pub schedule: Option<ScheduleConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            public_address: "0.0.0.0:3000".to_owned(),
+            private_address: "0.0.0.0:3001".to_owned(),
+            public_url: "http://localhost:3000".to_owned(),
+            database_path: "matcher.sqlite".to_owned(),
+            snapshot_dir: "snapshots".to_owned(),
+            max_snapshots: 20,
+            template_dir: "templates".to_owned(),
+            locale: "en".to_owned(),
+            session_lifetime_seconds: 60 * 60 * 24 * 7,
+            cookie_key: None,
+            encryption_key: None,
+            encryption_key_file: None,
+            auth_requests_per_minute: 10,
+            trust_forwarded_for: false,
+            lockout_attempts: 5,
+            lockout_minutes: 15,
+            stale_account_days: 30,
+            archive_pools_after_months: None,
+            reminder_days_before: None,
+            public_stats: false,
+            test_clock: false,
+            odd_person_policy: OddPersonPolicy::default(),
+            matching_strategy: Strategy::default(),
+            max_exact_pool_size: 500,
+            edge_half_life_generations: None,
+            repeat_window_generations: 0,
+            introduction_suggestions: 0,
+            interest_bonus: 0,
+            same_team_policy: SameTeamPolicy::default(),
+            same_team_penalty: 1,
+            min_run_interval_seconds: 60,
+            round_types: vec![DEFAULT_ROUND_TYPE.to_owned()],
+            mentoring_round_types: Vec::new(),
+            group_sizes: HashMap::new(),
+            log_level: "info".to_owned(),
+            log_format: LogFormat::default(),
+            smtp: None,
+            slack: None,
+            schedule: None,
+        }
+    }
+}
+
+// Run matching automatically at `start` and then every `every_days` after it, or whenever `cron`
+// matches from `start` on. Admins can replace this at runtime, the saved schedule then takes
+// priority.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub start: DateTime<Utc>,
+    #[serde(default = "default_every_days")]
+    pub every_days: i64,
+    // Five cron fields (minute, hour, day of month, month, day of week) in UTC, or a nickname such as
+    // `@weekly`, to run on instead of every `every_days`.
+    #[serde(default)]
+    pub cron: Option<String>,
+    // Runs with fewer people waiting than this are skipped, and admins are told.
+    #[serde(default)]
+    pub min_waiters: usize,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_every_days() -> i64 {
+    7
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl ScheduleConfig {
+    // The first run of the schedule after `now`.
+    pub fn next_run(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        if let Some(cron) = &self.cron {
+            // Expressions are checked to have a next run when they are saved or loaded.
+            return next_cron_run(cron, self.start, now).unwrap_or(DateTime::<Utc>::MAX_UTC);
+        }
+        if now < self.start {
+            return self.start;
+        }
+        let every = chrono::Duration::days(self.every_days);
+        let elapsed_runs = (now - self.start).num_seconds() / every.num_seconds();
+        self.start + every * (elapsed_runs as i32 + 1)
+    }
+
+    // The next `count` runs after `now`.
+    pub fn next_runs(&self, now: DateTime<Utc>, count: usize) -> Vec<DateTime<Utc>> {
+        let mut runs = Vec::with_capacity(count);
+        let mut after = now;
+        for _ in 0..count {
+            after = self.next_run(after);
+            runs.push(after);
+        }
+        runs
+    }
+}
+
+// The first time after `now`, and no earlier than `start`, that the cron expression matches. `None`
+// if it isn't valid or never matches.
+pub fn next_cron_run(
+    cron: &str,
+    start: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let cron = croner::Cron::new(cron).parse().ok()?;
+    if now < start {
+        cron.find_next_occurrence(&start.trunc_subsecs(0), true)
+    } else {
+        cron.find_next_occurrence(&now.trunc_subsecs(0), false)
+    }
+    .ok()
+}
+
+// Parse an environment variable if it is set, panicking with the variable's name if it is invalid.
+fn env<T>(name: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(error) => panic!("Invalid value for {name}: {error:?}"),
+    }
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let mut config = match std::env::var("MATCHER_CONFIG") {
+            Ok(path) => {
+                let file = std::fs::read_to_string(&path)
+                    .unwrap_or_else(|error| panic!("Failed to read config {path}: {error}"));
+                serde_json::from_str(&file)
+                    .unwrap_or_else(|error| panic!("Invalid config {path}: {error}"))
+            }
+            Err(_) => Config::default(),
+        };
+
+        if let Some(address) = env("MATCHER_PUBLIC_ADDRESS") {
+            config.public_address = address;
+        }
+        if let Some(address) = env("MATCHER_PRIVATE_ADDRESS") {
+            config.private_address = address;
+        }
+        if let Some(url) = env("MATCHER_PUBLIC_URL") {
+            config.public_url = url;
+        }
+        if let Some(path) = env("MATCHER_DATABASE_PATH") {
+            config.database_path = path;
+        }
+        if let Some(dir) = env("MATCHER_SNAPSHOT_DIR") {
+            config.snapshot_dir = dir;
+        }
+        if let Some(count) = env("MATCHER_MAX_SNAPSHOTS") {
+            config.max_snapshots = count;
+        }
+        if let Some(dir) = env("MATCHER_TEMPLATE_DIR") {
+            config.template_dir = dir;
+        }
+        if let Some(locale) = env("MATCHER_LOCALE") {
+            config.locale = locale;
+        }
+        if let Some(seconds) = env("MATCHER_SESSION_LIFETIME_SECONDS") {
+            config.session_lifetime_seconds = seconds;
+        }
+        if let Some(key) = env("MATCHER_COOKIE_KEY") {
+            config.cookie_key = Some(key);
+        }
+        if let Some(key) = env("MATCHER_ENCRYPTION_KEY") {
+            config.encryption_key = Some(key);
+        }
+        if let Some(path) = env("MATCHER_ENCRYPTION_KEY_FILE") {
+            config.encryption_key_file = Some(path);
+        }
+        if let Some(limit) = env("MATCHER_AUTH_REQUESTS_PER_MINUTE") {
+            config.auth_requests_per_minute = limit;
+        }
+        if let Some(trust) = env("MATCHER_TRUST_FORWARDED_FOR") {
+            config.trust_forwarded_for = trust;
+        }
+        if let Some(attempts) = env("MATCHER_LOCKOUT_ATTEMPTS") {
+            config.lockout_attempts = attempts;
+        }
+        if let Some(minutes) = env("MATCHER_LOCKOUT_MINUTES") {
+            config.lockout_minutes = minutes;
+        }
+        if let Some(days) = env("MATCHER_STALE_ACCOUNT_DAYS") {
+            config.stale_account_days = days;
+        }
+        if let Some(months) = env("MATCHER_ARCHIVE_POOLS_AFTER_MONTHS") {
+            config.archive_pools_after_months = Some(months);
+        }
+        if let Some(days) = env("MATCHER_REMINDER_DAYS_BEFORE") {
+            config.reminder_days_before = Some(days);
+        }
+        if let Some(public) = env("MATCHER_PUBLIC_STATS") {
+            config.public_stats = public;
+        }
+        if let Some(test) = env("MATCHER_TEST_CLOCK") {
+            config.test_clock = test;
+        }
+        if let Some(policy) = env("MATCHER_ODD_PERSON_POLICY") {
+            config.odd_person_policy = policy;
+        }
+        if let Some(strategy) = env("MATCHER_MATCHING_STRATEGY") {
+            config.matching_strategy = strategy;
+        }
+        if let Some(size) = env("MATCHER_MAX_EXACT_POOL_SIZE") {
+            config.max_exact_pool_size = size;
+        }
+        if let Some(generations) = env("MATCHER_EDGE_HALF_LIFE_GENERATIONS") {
+            config.edge_half_life_generations = Some(generations);
+        }
+        if let Some(generations) = env("MATCHER_REPEAT_WINDOW_GENERATIONS") {
+            config.repeat_window_generations = generations;
+        }
+        if let Some(count) = env("MATCHER_INTRODUCTION_SUGGESTIONS") {
+            config.introduction_suggestions = count;
+        }
+        if let Some(bonus) = env("MATCHER_INTEREST_BONUS") {
+            config.interest_bonus = bonus;
+        }
+        if let Some(policy) = env("MATCHER_SAME_TEAM_POLICY") {
+            config.same_team_policy = policy;
+        }
+        if let Some(penalty) = env("MATCHER_SAME_TEAM_PENALTY") {
+            config.same_team_penalty = penalty;
+        }
+        if let Some(seconds) = env("MATCHER_MIN_RUN_INTERVAL_SECONDS") {
+            config.min_run_interval_seconds = seconds;
+        }
+        if let Some(round_types) = env::<String>("MATCHER_ROUND_TYPES") {
+            config.round_types = round_types
+                .split(',')
+                .map(|round_type| round_type.trim().to_owned())
+                .filter(|round_type| !round_type.is_empty())
+                .collect();
+        }
+        if let Some(round_types) = env::<String>("MATCHER_MENTORING_ROUND_TYPES") {
+            config.mentoring_round_types = round_types
+                .split(',')
+                .map(|round_type| round_type.trim().to_owned())
+                .filter(|round_type| !round_type.is_empty())
+                .collect();
+        }
+        if let Some(group_sizes) = env::<String>("MATCHER_GROUP_SIZES") {
+            config.group_sizes = group_sizes
+                .split(',')
+                .filter(|entry| !entry.trim().is_empty())
+                .map(|entry| {
+                    entry
+                        .split_once('=')
+                        .and_then(|(round_type, size)| {
+                            Some((round_type.trim().to_owned(), size.trim().parse().ok()?))
+                        })
+                        .unwrap_or_else(|| {
+                            panic!("Invalid value for MATCHER_GROUP_SIZES: expected round_type=size, got {entry:?}")
+                        })
+                })
+                .collect();
+        }
+        if let Some(level) = env("MATCHER_LOG_LEVEL") {
+            config.log_level = level;
+        }
+        if let Some(format) = env("MATCHER_LOG_FORMAT") {
+            config.log_format = format;
+        }
+
+        if let Some(host) = env("MATCHER_SMTP_HOST") {
+            config.smtp = Some(MailConfig {
+                host,
+                ..config.smtp.unwrap_or_default()
+            });
+        }
+        if let Some(smtp) = &mut config.smtp {
+            if let Some(port) = env("MATCHER_SMTP_PORT") {
+                smtp.port = port;
+            }
+            if let Some(username) = env("MATCHER_SMTP_USERNAME") {
+                smtp.username = Some(username);
+            }
+            if let Some(password) = env("MATCHER_SMTP_PASSWORD") {
+                smtp.password = Some(password);
+            }
+            if let Some(tls) = env("MATCHER_SMTP_TLS") {
+                smtp.tls = tls;
+            }
+            if let Some(from) = env("MATCHER_SMTP_FROM") {
+                smtp.from = Some(from);
+            }
+            if let Some(max_concurrent) = env("MATCHER_SMTP_MAX_CONCURRENT") {
+                smtp.max_concurrent = max_concurrent;
+            }
+            if let Some(per_minute) = env("MATCHER_SMTP_PER_MINUTE") {
+                smtp.per_minute = Some(per_minute);
+            }
+        }
+
+        let webhook_url = env::<String>("MATCHER_SLACK_WEBHOOK_URL");
+        let bot_token = env::<String>("MATCHER_SLACK_BOT_TOKEN");
+        if webhook_url.is_some() || bot_token.is_some() {
+            let slack = config.slack.get_or_insert(SlackConfig {
+                webhook_url: None,
+                bot_token: None,
+            });
+            if webhook_url.is_some() {
+                slack.webhook_url = webhook_url;
+            }
+            if bot_token.is_some() {
+                slack.bot_token = bot_token;
+            }
+        }
+
+        if let Some(start) = env::<DateTime<Utc>>("MATCHER_SCHEDULE_START") {
+            let (every_days, cron, min_waiters) = config
+                .schedule
+                .map_or((default_every_days(), None, 0), |s| {
+                    (s.every_days, s.cron, s.min_waiters)
+                });
+            config.schedule = Some(ScheduleConfig {
+                start,
+                every_days,
+                cron,
+                min_waiters,
+                enabled: true,
+            });
+        }
+        if let Some(schedule) = &mut config.schedule {
+            if let Some(days) = env("MATCHER_SCHEDULE_EVERY_DAYS") {
+                schedule.every_days = days;
+            }
+            if let Some(cron) = env::<String>("MATCHER_SCHEDULE_CRON") {
+                schedule.cron = Some(cron);
+            }
+            if let Some(waiters) = env("MATCHER_SCHEDULE_MIN_WAITERS") {
+                schedule.min_waiters = waiters;
+            }
+            // Held to the same checks as schedules saved by admins, `next_run` divides by the days.
+            if let Err(error) = validation::clean_every_days(schedule.every_days) {
+                panic!("Invalid schedule: {error}");
+            }
+            match validation::clean_cron(schedule.cron.as_deref().unwrap_or_default()) {
+                Ok(cron) => schedule.cron = cron,
+                Err(error) => panic!("Invalid schedule: {error}"),
+            }
+        }
+
+        config
+    }
+
+    // The encryption key, read from `encryption_key_file` if it isn't given directly.
+    pub fn encryption_key(&self) -> Option<String> {
+        if let Some(key) = &self.encryption_key {
+            return Some(key.clone());
+        }
+        let path = self.encryption_key_file.as_ref()?;
+        let key = std::fs::read_to_string(path)
+            .unwrap_or_else(|error| panic!("Failed to read encryption key {path}: {error}"));
+        Some(key.trim_end().to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(timestamp: &str) -> DateTime<Utc> {
+        timestamp.parse().unwrap()
+    }
+
+    #[test]
+    fn cron_schedules_run_when_the_expression_matches() {
+        let schedule = ScheduleConfig {
+            start: at("2024-01-03T00:00:00Z"),
+            every_days: 7,
+            cron: Some("0 9 * * MON,THU".to_owned()),
+            min_waiters: 0,
+            enabled: true,
+        };
+        // Nothing before the start, then each Monday and Thursday, ignoring `every_days`.
+        assert_eq!(
+            schedule.next_runs(at("2024-01-01T00:00:00Z"), 3),
+            vec![
+                at("2024-01-04T09:00:00Z"),
+                at("2024-01-08T09:00:00Z"),
+                at("2024-01-11T09:00:00Z"),
+            ]
+        );
+        assert_eq!(
+            schedule.next_run(at("2024-01-08T09:00:00.5Z")),
+            at("2024-01-11T09:00:00Z")
+        );
+    }
+
+    #[test]
+    fn day_schedules_run_every_few_days() {
+        let schedule = ScheduleConfig {
+            start: at("2024-01-01T09:00:00Z"),
+            every_days: 14,
+            cron: None,
+            min_waiters: 0,
+            enabled: true,
+        };
+        assert_eq!(
+            schedule.next_run(at("2024-01-01T09:00:00Z")),
+            at("2024-01-15T09:00:00Z")
+        );
+    }
+
+    #[test]
+    fn cron_expressions_that_never_match_are_refused() {
+        let now = at("2024-01-01T00:00:00Z");
+        assert!(next_cron_run("0 0 30 2 *", now, now).is_none());
+        assert!(next_cron_run("not cron", now, now).is_none());
+        assert!(next_cron_run("@weekly", now, now).is_some());
+    }
+}
@@ -0,0 +1,192 @@
+use std::{fmt::Debug, sync::Arc};
+
+use aes_gcm_siv::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256GcmSiv, Nonce,
+};
+use base64ct::{Base64, Encoding};
+use sha2::{Digest, Sha256};
+
+// Marks a column value as sealed by `Cipher`, followed by the nonce and ciphertext in base64.
+// Anything without it is plaintext, from before a key was set.
+const SEALED_PREFIX: &str = "enc1:";
+const NONCE_LENGTH: usize = 12;
+
+// Encrypts sensitive columns (emails, contact details and round notes) before they are written and
+// decrypts them when they are read, when the deployment has a key. Without one values are stored as
+// they are.
+#[derive(Clone, Default)]
+pub struct Cipher {
+    keys: Option<Arc<Keys>>,
+}
+
+struct Keys {
+    cipher: Aes256GcmSiv,
+    // Derives the nonce for `seal_lookup` from the value, kept apart from the encryption key.
+    lookup: [u8; 32],
+}
+
+// Never print the keys.
+impl Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cipher")
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum CipherError {
+    // The value is sealed but no key is configured.
+    NoKey,
+    // The value isn't valid base64, or was sealed with another key or has been changed.
+    Invalid,
+}
+
+impl std::fmt::Display for CipherError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CipherError::NoKey => write!(f, "value is encrypted but no encryption key is set"),
+            CipherError::Invalid => write!(f, "value could not be decrypted with the key"),
+        }
+    }
+}
+
+impl std::error::Error for CipherError {}
+
+impl Cipher {
+    // Both keys are derived from `key` with SHA-256, so any string of at least 32 bytes will do.
+    pub fn new(key: Option<&str>) -> Self {
+        let keys = key.map(|key| {
+            let derive = |purpose: &[u8]| -> [u8; 32] {
+                Sha256::new()
+                    .chain_update(purpose)
+                    .chain_update(key.as_bytes())
+                    .finalize()
+                    .into()
+            };
+            Arc::new(Keys {
+                cipher: Aes256GcmSiv::new(&derive(b"matcher column encryption").into()),
+                lookup: derive(b"matcher column lookup"),
+            })
+        });
+        Self { keys }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    // Encrypt a value with a random nonce, so equal values look different.
+    pub fn seal(&self, value: &str) -> String {
+        let Some(keys) = &self.keys else {
+            return value.to_owned();
+        };
+        keys.seal(value, Aes256GcmSiv::generate_nonce(&mut OsRng).into())
+    }
+
+    // Encrypt a value so the same value always gives the same result, for columns that are looked
+    // up or must be unique, such as emails. Gives away which rows are equal but nothing more.
+    pub fn seal_lookup(&self, value: &str) -> String {
+        let Some(keys) = &self.keys else {
+            return value.to_owned();
+        };
+        let digest = Sha256::new()
+            .chain_update(keys.lookup)
+            .chain_update(value.as_bytes())
+            .finalize();
+        keys.seal(value, digest[..NONCE_LENGTH].try_into().unwrap())
+    }
+
+    // Decrypt a value read from the database, plaintext is returned as it is.
+    pub fn open(&self, value: String) -> Result<String, CipherError> {
+        let Some(sealed) = value.strip_prefix(SEALED_PREFIX) else {
+            return Ok(value);
+        };
+        let keys = self.keys.as_ref().ok_or(CipherError::NoKey)?;
+        let bytes = Base64::decode_vec(sealed).map_err(|_| CipherError::Invalid)?;
+        if bytes.len() < NONCE_LENGTH {
+            return Err(CipherError::Invalid);
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+        let plaintext = keys
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| CipherError::Invalid)?;
+        String::from_utf8(plaintext).map_err(|_| CipherError::Invalid)
+    }
+
+    pub fn open_optional(&self, value: Option<String>) -> Result<Option<String>, CipherError> {
+        value.map(|value| self.open(value)).transpose()
+    }
+}
+
+impl Keys {
+    fn seal(&self, value: &str, nonce: [u8; NONCE_LENGTH]) -> String {
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), value.as_bytes())
+            .expect("Encrypting a column value can't fail");
+        let mut bytes = nonce.to_vec();
+        bytes.extend(ciphertext);
+        format!("{SEALED_PREFIX}{}", Base64::encode_string(&bytes))
+    }
+}
+
+pub fn is_sealed(value: &str) -> bool {
+    value.starts_with(SEALED_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "a test key that is at least 32 bytes long";
+
+    #[test]
+    fn sealed_values_open_again() {
+        let cipher = Cipher::new(Some(KEY));
+        let sealed = cipher.seal("alice@example.com");
+        assert!(is_sealed(&sealed));
+        assert_ne!(sealed, cipher.seal("alice@example.com"));
+        assert_eq!(cipher.open(sealed).unwrap(), "alice@example.com");
+    }
+
+    #[test]
+    fn lookups_seal_equal_values_equally() {
+        let cipher = Cipher::new(Some(KEY));
+        let sealed = cipher.seal_lookup("alice@example.com");
+        assert_eq!(sealed, cipher.seal_lookup("alice@example.com"));
+        assert_ne!(sealed, cipher.seal_lookup("bob@example.com"));
+        assert_eq!(cipher.open(sealed).unwrap(), "alice@example.com");
+    }
+
+    #[test]
+    fn other_keys_cannot_open_values() {
+        let sealed = Cipher::new(Some(KEY)).seal("alice@example.com");
+        let other = Cipher::new(Some("another test key that is at least 32 bytes"));
+        assert!(matches!(
+            other.open(sealed.clone()),
+            Err(CipherError::Invalid)
+        ));
+        assert!(matches!(
+            Cipher::new(None).open(sealed),
+            Err(CipherError::NoKey)
+        ));
+    }
+
+    #[test]
+    fn plaintext_passes_through() {
+        let cipher = Cipher::new(Some(KEY));
+        assert_eq!(
+            cipher.open("from before a key".to_owned()).unwrap(),
+            "from before a key"
+        );
+        let disabled = Cipher::new(None);
+        assert_eq!(disabled.seal("alice@example.com"), "alice@example.com");
+        assert_eq!(
+            disabled.seal_lookup("alice@example.com"),
+            "alice@example.com"
+        );
+    }
+}
@@ -1,59 +1,736 @@
 use std::{
-    collections::HashSet,
-    sync::{Arc, Mutex},
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
     Argon2, PasswordHash, PasswordVerifier,
 };
-use rusqlite::{params, Connection};
+use rusqlite::{
+    backup::Progress, params, types::Type, Connection, DatabaseName, ErrorCode, OptionalExtension,
+    Row,
+};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache::TtlCache,
+    clock::Clock,
+    config::ScheduleConfig,
+    crypto::{is_sealed, Cipher},
+    domain::{
+        ConflictGroupId, GenerationDetails, GenerationId, IcebreakerId, MailingId, Match,
+        MatchMeta, MatchQuality, OrganisationId, PastMatch, Person, PersonId, DEFAULT_ORGANISATION,
+    },
+    mail::MailConfig,
+    matching::{MatchingAudit, MentoringRole},
+};
+
+// How long cached people and sessions are served before going back to the database.
+const CACHE_TTL: Duration = Duration::from_secs(30);
+
+// How long a statement waits for another connection, e.g. a backup, to release its lock. SQLite
+// retries with backoff until then before reporting the database busy.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// `call_retrying` runs work this many times in all when the database stays busy, doubling the delay
+// between attempts.
+const BUSY_ATTEMPTS: u32 = 3;
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+// Why database work failed.
+#[derive(Debug)]
+pub enum DatabaseError {
+    // Still busy or locked after waiting and retrying, trying again in a moment should work.
+    Busy,
+    // Anything else SQLite reported, e.g. a constraint being violated.
+    Sqlite(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for DatabaseError {
+    fn from(error: rusqlite::Error) -> Self {
+        match error.sqlite_error_code() {
+            Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => DatabaseError::Busy,
+            _ => DatabaseError::Sqlite(error),
+        }
+    }
+}
+
+impl std::fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DatabaseError::Busy => write!(f, "The database is busy, please try again in a moment."),
+            DatabaseError::Sqlite(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+pub type Result<T, E = DatabaseError> = std::result::Result<T, E>;
+
+// Someone with each of their partners, newest first.
+pub type PersonMatches = (Person, Vec<PastMatch>);
+
+// How often the database has been found busy since startup.
+#[derive(Debug, Default)]
+pub struct BusyCounts {
+    // Work that was run again after finding the database busy.
+    pub retries: AtomicU64,
+    // Work given up on because the database stayed busy.
+    pub failures: AtomicU64,
+}
 
 pub enum SignInError {
     UnknownUser,
     InvalidPassword,
+    Deactivated,
+    // Too many wrong passwords recently, the password isn't checked until the lockout ends.
+    LockedOut,
+}
+
+pub enum SignUpError {
+    EmailTaken,
+    LockedOut,
+    // The address belongs to an imported person without a password. The account is only theirs
+    // once they follow a link sent to it with this token.
+    Imported { token: String },
+}
+
+// Lock an account for `seconds` once `attempts` wrong passwords have been given for it within that
+// time, to stop guessing its password.
+#[derive(Debug, Clone, Copy)]
+pub struct LoginLockout {
+    pub attempts: u32,
+    pub seconds: i64,
+}
+
+// Read a column written with `Cipher::seal` or `Cipher::seal_lookup`.
+fn get_sealed(cipher: &Cipher, row: &Row, index: usize) -> rusqlite::Result<String> {
+    cipher.open(row.get(index)?).map_err(|error| {
+        rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(error))
+    })
+}
+
+fn get_sealed_optional(
+    cipher: &Cipher,
+    row: &Row,
+    index: usize,
+) -> rusqlite::Result<Option<String>> {
+    cipher.open_optional(row.get(index)?).map_err(|error| {
+        rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(error))
+    })
+}
+
+impl Person {
+    // Read a person from the columns id, email, name, waiting, status and team starting at `start`.
+    fn from_row(row: &Row, start: usize, cipher: &Cipher) -> rusqlite::Result<Person> {
+        Ok(Person {
+            id: row.get(start)?,
+            email: get_sealed(cipher, row, start + 1)?,
+            name: row.get(start + 2)?,
+            waiting: row.get(start + 3)?,
+            status: row.get(start + 4)?,
+            team: row.get(start + 5)?,
+        })
+    }
+}
+
+// How far an interrupted generation got before the process stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterruptedStage {
+    // Some groups may not have been saved, anyone not in a saved group is still waiting.
+    Matching,
+    // Every group was saved but not everyone may have been told.
+    Notifying,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InterruptedGeneration {
+    pub generation: GenerationId,
+    pub time: u64,
+    pub stage: InterruptedStage,
+    // How many groups were saved.
+    pub groups: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StaleAccount {
+    pub person: Person,
+    pub created_at: i64,
+    pub flagged_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Organisation {
+    pub id: OrganisationId,
+    pub name: String,
+    // Signing up with it joins the organisation.
+    pub sign_up_token: String,
+    pub members: u32,
+}
+
+// A rotation run separately from the main one, with its own members.
+#[derive(Debug, Serialize)]
+pub struct Pool {
+    pub name: String,
+    pub description: String,
+    pub members: u32,
+    pub waiting: u32,
+    // Whether managers can be matched with their direct reports in it.
+    pub allow_manager_pairs: bool,
+    // When it was archived for being idle, a unix timestamp. Archived pools can't be joined or run.
+    pub archived_at: Option<i64>,
+    // People appointed to manage its members and run it, besides the deployment's admins.
+    pub admins: Vec<Person>,
+}
+
+// Someone in a pool, as its admins see them.
+#[derive(Debug, Serialize)]
+pub struct PoolParticipant {
+    #[serde(flatten)]
+    pub person: Person,
+    // Whether they're waiting for the pool's next round, rather than the main one.
+    pub pool_waiting: bool,
+}
+
+// People who must never be matched with each other, e.g. an interview panel and their candidates.
+#[derive(Debug, Serialize)]
+pub struct ConflictGroup {
+    pub id: ConflictGroupId,
+    pub name: String,
+    pub members: Vec<Person>,
+}
+
+// A conversation starter admins have written for matches.
+#[derive(Debug, Serialize)]
+pub struct Icebreaker {
+    pub id: IcebreakerId,
+    pub prompt: String,
+}
+
+// People admins can email to bring them back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Segment {
+    // Signed up but never in a match.
+    NeverMatched,
+    // Not signed in for `INACTIVE_DAYS`, or never signed in and signed up that long ago.
+    Inactive,
+    // Waiting through more than `LONG_WAITING_ROUNDS` rounds since they last started waiting.
+    LongWaiting,
+}
+
+pub const INACTIVE_DAYS: i64 = 60;
+pub const LONG_WAITING_ROUNDS: u32 = 3;
+
+impl Segment {
+    pub const ALL: [Segment; 3] = [
+        Segment::NeverMatched,
+        Segment::Inactive,
+        Segment::LongWaiting,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Segment::NeverMatched => "never_matched",
+            Segment::Inactive => "inactive",
+            Segment::LongWaiting => "long_waiting",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|segment| segment.as_str() == s)
+    }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
-pub struct Person {
+// An email sent to a segment, with how sending it to everyone went.
+#[derive(Debug, Serialize)]
+pub struct Mailing {
+    pub id: MailingId,
+    pub segment: Option<Segment>,
+    pub subject: String,
+    pub sent_by: Option<String>,
+    pub created_at: i64,
+    pub recipients: u32,
+    pub sent: u32,
+    pub failed: u32,
+    pub suppressed: u32,
+}
+
+// A round robin planned ahead for a round type, run a round at a time by matching.
+#[derive(Debug, Serialize)]
+pub struct SeasonPlan {
     pub id: u32,
+    pub round_type: String,
+    pub created_at: i64,
+    pub rounds: u32,
+    // How many rounds have been run.
+    pub done: u32,
+    // The first round not yet run, none when they all have. Rounds are run again after their
+    // generation is rolled back, so this can be before the last one run.
+    pub next_round: Option<u32>,
+}
+
+// How an org chart import went.
+#[derive(Debug, Serialize)]
+pub struct ManagersImport {
+    // People whose manager was set or cleared.
+    pub updated: usize,
+    // Emails of people, or their managers, without an account.
+    pub unknown: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoolMembership {
+    pub pool: String,
+    pub description: String,
+    pub member: bool,
+    // Waiting for the pool's next round, only members can be.
+    pub waiting: bool,
+    // Archived pools can't be joined or left until they're reactivated.
+    pub archived: bool,
+}
+
+// Who was in the waiting pool when a generation ran, captured as they were at the time.
+#[derive(Debug, Serialize)]
+pub struct WaiterSnapshot {
+    pub person_id: PersonId,
+    pub name: String,
+    pub email: String,
+    pub excluded_reason: Option<String>,
+    // Brought in as a floater rather than waiting themselves.
+    pub floater: bool,
+}
+
+// Everything stored about a person, for them to download.
+#[derive(Debug, Serialize)]
+pub struct PersonExport {
+    pub id: PersonId,
+    pub name: String,
     pub email: String,
+    pub status: Option<String>,
+    pub team: Option<String>,
+    pub timezone: Option<String>,
+    pub waiting: bool,
+    pub paused_until: Option<i64>,
+    pub max_matches_per_season: Option<u32>,
+    pub match_every_rounds: Option<u32>,
+    pub mentoring_role: MentoringRole,
+    pub mentee_capacity: u32,
+    pub manager: Option<String>,
+    pub email_notifications: bool,
+    pub created_at: Option<i64>,
+    pub last_sign_in: Option<i64>,
+    pub admin: bool,
+    pub floater: bool,
+    pub slack_id: Option<String>,
+    pub has_api_token: bool,
+    pub matches: Vec<ExportedMatch>,
+    pub blocked: Vec<String>,
+    pub interests: Vec<String>,
+    pub pools: Vec<String>,
+    pub conflict_groups: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedMatch {
+    pub generation: GenerationId,
+    pub time: u64,
+    pub partner: String,
+}
+
+// Ways of getting in touch beyond email.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContactKind {
+    Phone,
+    Slack,
+    Teams,
+}
+
+impl ContactKind {
+    pub const ALL: [ContactKind; 3] = [ContactKind::Phone, ContactKind::Slack, ContactKind::Teams];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ContactKind::Phone => "phone",
+            ContactKind::Slack => "slack",
+            ContactKind::Teams => "teams",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == s)
+    }
+}
+
+// Where someone's match email for a generation has got to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationStatus {
+    // Queued but not yet handed to the mail server.
+    Pending,
+    // Accepted by the mail server.
+    Sent,
+    // Reported as delivered by the provider.
+    Delivered,
+    // Refused by the mail server or reported as bounced by the provider.
+    Bounced,
+    // Couldn't be sent, e.g. the mail server was unreachable, trying again may work.
+    Failed,
+    // Not sent because they have turned off email notifications.
+    Suppressed,
+}
+
+impl NotificationStatus {
+    const ALL: [NotificationStatus; 6] = [
+        NotificationStatus::Pending,
+        NotificationStatus::Sent,
+        NotificationStatus::Delivered,
+        NotificationStatus::Bounced,
+        NotificationStatus::Failed,
+        NotificationStatus::Suppressed,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            NotificationStatus::Pending => "pending",
+            NotificationStatus::Sent => "sent",
+            NotificationStatus::Delivered => "delivered",
+            NotificationStatus::Bounced => "bounced",
+            NotificationStatus::Failed => "failed",
+            NotificationStatus::Suppressed => "suppressed",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|status| status.as_str() == s)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub status: NotificationStatus,
+    pub error: Option<String>,
+    pub updated_at: i64,
+}
+
+// Who can see a contact field, besides its owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContactVisibility {
+    // People they have been matched with, and admins.
+    Matches,
+    Everyone,
+    Admins,
+}
+
+impl ContactVisibility {
+    fn as_str(self) -> &'static str {
+        match self {
+            ContactVisibility::Matches => "matches",
+            ContactVisibility::Everyone => "everyone",
+            ContactVisibility::Admins => "admins",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        [Self::Matches, Self::Everyone, Self::Admins]
+            .into_iter()
+            .find(|visibility| visibility.as_str() == s)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContactField {
+    pub kind: ContactKind,
+    pub value: String,
+    pub visibility: ContactVisibility,
+}
+
+// Everything needed to move a pool of people to another deployment. Members are referred to by
+// their id in the exporting deployment, importing gives them new ones.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PoolExport {
+    pub branding: Branding,
+    pub schedule: Option<ScheduleConfig>,
+    pub members: Vec<PoolMember>,
+    pub generations: Vec<PoolGeneration>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PoolMember {
+    pub id: PersonId,
     pub name: String,
+    pub email: String,
+    pub team: Option<String>,
+    pub status: Option<String>,
+    pub timezone: Option<String>,
     pub waiting: bool,
+    pub interests: Vec<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PoolGeneration {
+    pub time: u64,
+    // Exports from before round types are all the default type.
+    #[serde(default = "default_round_type")]
+    pub round_type: String,
+    pub quality: Option<MatchQuality>,
+    pub groups: Vec<Vec<PersonId>>,
+}
+
+// What rebuilding `edges` from the matches found.
+#[derive(Debug, Serialize)]
+pub struct EdgesRebuild {
+    pub matches: u32,
+    pub edges: u32,
+    // Pairs whose meeting count was wrong (or missing) before the rebuild.
+    pub changed: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoolImport {
+    pub created: usize,
+    pub existing: usize,
+    pub generations: usize,
+}
+
+pub enum PoolImportError {
+    // History can only be imported into a deployment without any, so generations stay in order.
+    HasGenerations,
+    UnknownMember(PersonId),
+    UnknownOrganisation(OrganisationId),
+    // Missing or archived.
+    UnknownPool(String),
+}
+
+// Where an exported pool's members go, and whether to also take its branding and schedule over this
+// deployment's own.
+#[derive(Debug)]
+pub struct PoolImportOptions {
+    pub organisation: OrganisationId,
+    // Members join this pool, waiting for it if they were waiting in the export, and its history
+    // becomes the pool's. Otherwise they join the main pool.
+    pub pool: Option<String>,
+    pub branding: bool,
+    pub schedule: bool,
+}
+
+// A generation and how many groups it made, for listing the history of matching.
 #[derive(Debug, Serialize)]
-pub struct Match {
-    pub person1: Person,
-    pub person2: Option<Person>,
+pub struct GenerationSummary {
+    pub generation: GenerationId,
+    pub time: u64,
+    pub round_type: String,
+    pub title: Option<String>,
+    // Groups of two or more.
+    pub pairs: u32,
+    // People left on their own.
+    pub unmatched: u32,
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
-pub struct MatchMeta {
-    pub generation: u32,
+// A generation and who was grouped together in it, for bulk export.
+#[derive(Debug, Serialize)]
+pub struct GenerationExport {
+    pub generation: GenerationId,
     pub time: u64,
+    pub round_type: String,
+    pub updated_at: i64,
+    pub quality: Option<MatchQuality>,
+    pub groups: Vec<Vec<PersonId>>,
+}
+
+// A pair who have been matched together and how much that counts against matching them again.
+#[derive(Debug, Serialize)]
+pub struct EdgeHistory {
+    pub person1: PersonId,
+    pub person2: PersonId,
+    // How many times they have been matched.
+    pub count: u32,
+    pub last_generation: GenerationId,
+    // The count with older meetings decayed, what matching actually uses.
+    pub effective_weight: f64,
+}
+
+// Decayed weights are scaled up by this before being given to the matcher, which works in integers.
+pub const EDGE_WEIGHT_SCALE: f64 = 1000.0;
+
+// The round type of generations from before there were several, and of scheduled runs.
+pub const DEFAULT_ROUND_TYPE: &str = "coffee";
+
+fn default_round_type() -> String {
+    DEFAULT_ROUND_TYPE.to_owned()
+}
+
+// How the deployment is presented on every page, so different organisations can white-label it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Branding {
+    pub product_name: String,
+    pub logo_url: Option<String>,
+    pub accent_colour: String,
+    pub footer_links: Vec<FooterLink>,
+}
+
+impl Default for Branding {
+    fn default() -> Self {
+        Self {
+            product_name: "Matcher".to_owned(),
+            logo_url: None,
+            accent_colour: "#0085f2".to_owned(),
+            footer_links: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FooterLink {
+    pub label: String,
+    pub url: String,
+}
+
+// Aggregate statistics that don't identify anyone.
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub people: u32,
+    pub active_last_7_days: u32,
+    pub average_matches_per_person: f64,
+    // Times a floater was brought in to even out a round.
+    pub floater_matches: u32,
+    pub months: Vec<MonthStats>,
+}
+
+// Totals that are fine to show anyone, for promoting the program. Only generations that were
+// finished count.
+#[derive(Debug, Serialize)]
+pub struct PublicStats {
+    // People who have met someone through matching.
+    pub participants: u32,
+    pub rounds: u32,
+    // Groups that met, leaving out anyone who was left on their own.
+    pub meetings: u32,
+}
+
+// How many people joined and left the pool on a day, and how many were waiting at the end of it.
+#[derive(Debug, Serialize)]
+pub struct WaitingDay {
+    pub day: String,
+    pub joined: u32,
+    pub left: u32,
+    pub waiting: i64,
+}
+
+// Something that happened in someone's participation, for their timeline.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TimelineEvent {
+    StartedWaiting {
+        at: i64,
+    },
+    StoppedWaiting {
+        at: i64,
+    },
+    // `partners` is empty when they sat the round out.
+    Matched {
+        at: i64,
+        generation: GenerationId,
+        round_type: String,
+        partners: Vec<String>,
+    },
+    Paused {
+        at: i64,
+        until: i64,
+    },
+}
+
+impl TimelineEvent {
+    pub fn at(&self) -> i64 {
+        match self {
+            Self::StartedWaiting { at }
+            | Self::StoppedWaiting { at }
+            | Self::Matched { at, .. }
+            | Self::Paused { at, .. } => *at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MonthStats {
+    pub month: String,
+    pub generations: u32,
+    pub participants: u32,
+    pub participation_rate: f64,
+}
+
+impl Organisation {
+    // Read an organisation from `id, name, sign_up_token, members` columns.
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            sign_up_token: row.get(2)?,
+            members: row.get(3)?,
+        })
+    }
+}
+
+impl MatchMeta {
+    // Read a generation from `id, time, strategy, buckets, repeat_pairs, total_weight, round_type,
+    // title, notes` columns.
+    fn from_row(row: &Row, cipher: &Cipher) -> rusqlite::Result<Self> {
+        let strategy: Option<String> = row.get(2)?;
+        Ok(Self {
+            generation: row.get(0)?,
+            time: row.get(1)?,
+            round_type: row.get(6)?,
+            title: row.get(7)?,
+            notes: get_sealed_optional(cipher, row, 8)?,
+            quality: match strategy {
+                Some(strategy) => Some(MatchQuality {
+                    strategy,
+                    buckets: row.get(3)?,
+                    repeat_pairs: row.get(4)?,
+                    total_weight: row.get(5)?,
+                }),
+                None => None,
+            },
+        })
+    }
 }
 
 const CREATE_TABLE_PEOPLE: &str = "CREATE TABLE IF NOT EXISTS people (
     id integer primary key,
     email text not null unique,
     name text not null,
-    waiting boolean not null
+    waiting boolean not null,
+    status text,
+    max_matches_per_season integer,
+    timezone text,
+    email_notifications boolean not null default true,
+    unsubscribe_token text
 )";
 
 const CREATE_TABLE_MATCHES: &str = "CREATE TABLE IF NOT EXISTS matches (
     generation integer not null,
     person1 text not null,
     person2 text,
+    person3 integer,
     foreign key(generation) references generations(id),
     foreign key(person1) references people(id),
-    foreign key(person2) references people(id)
+    foreign key(person2) references people(id),
+    foreign key(person3) references people(id)
 )";
 
 const CREATE_TABLE_GENERATIONS: &str = "CREATE TABLE IF NOT EXISTS generations (
     id integer primary key,
-    time integer
+    time integer,
+    updated_at integer
 )";
 
 const CREATE_TABLE_EDGES: &str = "CREATE TABLE IF NOT EXISTS edges (
@@ -78,367 +755,5385 @@ const CREATE_TABLE_SESSIONS: &str = "CREATE TABLE IF NOT EXISTS sessions (
     FOREIGN KEY(person) REFERENCES people(id)
 )";
 
-#[derive(Clone)]
-pub struct Database {
-    connection: Arc<Mutex<rusqlite::Connection>>,
+// Tokens handed out to users are only ever stored hashed, so a copy of the database can't be
+// used to impersonate anyone.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
 }
 
-impl Database {
-    pub fn init() -> Database {
-        let conn = Connection::open("matcher.sqlite").unwrap();
-        let s = Database {
-            connection: Arc::new(Mutex::new(conn)),
-        };
-        s.migrate();
-        s
-    }
+// Tracks when accounts were created and last signed in to, so accounts that were never used
+// after signing up can be flagged for cleanup.
+const CREATE_TABLE_ACCOUNT_ACTIVITY: &str = "CREATE TABLE IF NOT EXISTS account_activity (
+    person INTEGER PRIMARY KEY,
+    created_at INTEGER NOT NULL,
+    last_sign_in INTEGER,
+    flagged_at INTEGER,
+    FOREIGN KEY(person) REFERENCES people(id)
+)";
 
-    fn migrate(&self) {
-        let conn = self.connection.lock().unwrap();
-        let creations = [
-            "BEGIN",
-            CREATE_TABLE_PEOPLE,
-            CREATE_TABLE_GENERATIONS,
-            CREATE_TABLE_MATCHES,
-            CREATE_TABLE_EDGES,
-            CREATE_TABLE_AUTH,
-            CREATE_TABLE_SESSIONS,
-            "COMMIT",
-        ];
-        conn.execute_batch(&creations.join(";")).unwrap();
+const CREATE_TABLE_ADMINS: &str = "CREATE TABLE IF NOT EXISTS admins (
+    person INTEGER PRIMARY KEY,
+    FOREIGN KEY(person) REFERENCES people(id)
+)";
+
+const CREATE_TABLE_GENERATION_WAITERS: &str = "CREATE TABLE IF NOT EXISTS generation_waiters (
+    generation INTEGER NOT NULL,
+    person INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    email TEXT NOT NULL,
+    excluded_reason TEXT,
+    PRIMARY KEY(generation, person),
+    FOREIGN KEY(generation) REFERENCES generations(id)
+)";
+
+// Columns encrypted when the deployment has an encryption key, and whether each is looked up by
+// value so has to be encrypted the same way every time.
+const SEALED_COLUMNS: &[(&str, &str, bool)] = &[
+    ("people", "email", true),
+    ("email_changes", "email", true),
+    ("generation_waiters", "email", true),
+    ("contact_fields", "value", false),
+    ("generations", "notes", false),
+    ("mail_settings", "password", false),
+];
+
+// Add a column to a table created before the column existed.
+fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) {
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{table}') WHERE name = ?1"
+        ))
+        .unwrap()
+        .exists([column])
+        .unwrap();
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            [],
+        )
+        .unwrap();
     }
+}
 
-    pub fn get_person(&self, id: u32) -> Option<Person> {
-        self.connection
-            .lock()
-            .unwrap()
-            .query_row(
-                "select p.id, p.email, p.name, p.waiting from people p
-                 where p.id = ?1",
-                [id],
-                |row| {
-                    Ok(Person {
-                        id: row.get(0).unwrap(),
-                        email: row.get(1).unwrap(),
-                        name: row.get(2).unwrap(),
-                        waiting: row.get(3).unwrap(),
-                    })
-                },
-            )
-            .ok()
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    let password_hash = match PasswordHash::new(password_hash) {
+        Ok(password_hash) => password_hash,
+        Err(error) => {
+            tracing::error!(%error, "Stored password hash is invalid");
+            return false;
+        }
+    };
+    Argon2::default()
+        .verify_password(password.as_bytes(), &password_hash)
+        .is_ok()
+}
+
+fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("hashing with the default parameters works for any password")
+        .to_string()
+}
+
+// The account with a (sealed) email, with its password hash if it has one and whether it's been
+// deactivated.
+fn account_with_email(
+    conn: &Connection,
+    email: &str,
+) -> rusqlite::Result<Option<(PersonId, Option<String>, bool)>> {
+    conn.query_row(
+        "SELECT p.id, a.password_hash, p.deactivated_at IS NOT NULL FROM people p LEFT JOIN auth a ON a.person = p.id WHERE p.email = ?1",
+        [email],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+    )
+    .optional()
+}
+
+// Whether the account has had too many wrong passwords to be signed in to yet.
+fn locked_out(
+    conn: &Connection,
+    person_id: PersonId,
+    lockout: LoginLockout,
+    now: i64,
+) -> rusqlite::Result<bool> {
+    let failures: u32 = conn.query_row(
+        "SELECT count(*) FROM failed_logins WHERE person = ?1 AND at > ?2",
+        params![person_id, now - lockout.seconds],
+        |row| row.get(0),
+    )?;
+    Ok(lockout.attempts > 0 && failures >= lockout.attempts)
+}
+
+fn record_failed_login(conn: &Connection, person_id: PersonId, now: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO failed_logins (person, at) VALUES (?1, ?2)",
+        params![person_id, now],
+    )?;
+    Ok(())
+}
+
+fn clear_failed_logins(conn: &Connection, person_id: PersonId) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM failed_logins WHERE person = ?1", [person_id])?;
+    Ok(())
+}
+
+// Start a new session for the person, alongside any they already have in other browsers.
+fn insert_session(conn: &Connection, person_id: PersonId, now: i64) -> rusqlite::Result<String> {
+    let session_id = uuid::Uuid::new_v4().to_string();
+    conn.execute(
+        "INSERT INTO sessions (id, person, last_seen) VALUES (?1, ?2, ?3)",
+        params![hash_token(&session_id), person_id, now],
+    )?;
+    Ok(session_id)
+}
+
+const CREATE_TABLE_SLACK_IDENTITIES: &str = "CREATE TABLE IF NOT EXISTS slack_identities (
+    person INTEGER PRIMARY KEY,
+    slack_id TEXT NOT NULL UNIQUE,
+    FOREIGN KEY(person) REFERENCES people(id)
+)";
+
+// People who have asked never to be matched with someone.
+const CREATE_TABLE_BLOCKED_PAIRS: &str = "CREATE TABLE IF NOT EXISTS blocked_pairs (
+    person INTEGER NOT NULL,
+    blocked INTEGER NOT NULL,
+    PRIMARY KEY(person, blocked),
+    FOREIGN KEY(person) REFERENCES people(id),
+    FOREIGN KEY(blocked) REFERENCES people(id)
+)";
+
+// Each person can have one token for the JSON API, stored hashed like session ids.
+const CREATE_TABLE_API_TOKENS: &str = "CREATE TABLE IF NOT EXISTS api_tokens (
+    person INTEGER PRIMARY KEY,
+    token TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY(person) REFERENCES people(id)
+)";
+
+// A single row holding how this deployment presents itself.
+const CREATE_TABLE_BRANDING: &str = "CREATE TABLE IF NOT EXISTS branding (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    product_name TEXT NOT NULL,
+    logo_url TEXT,
+    accent_colour TEXT NOT NULL,
+    footer_links TEXT NOT NULL
+)";
+
+const CREATE_TABLE_SCHEMA_VERSION: &str =
+    "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)";
+
+// The steps to build the schema, version `n` of the schema is the result of applying the first `n`.
+// Steps must never be changed or reordered once released, schema changes (such as new columns) are
+// made by appending a new step.
+const MIGRATIONS: &[fn(&Connection)] = &[
+    baseline_schema,
+    generation_quality,
+    pause_until,
+    floaters,
+    autoincrement_generations,
+    deactivation,
+    generation_audits,
+    email_changes,
+    waiting_changes,
+    interests,
+    schedule,
+    teams,
+    contact_fields,
+    generation_progress,
+    notifications,
+    round_types,
+    failed_logins,
+    lowercase_emails,
+    match_cadence,
+    pools,
+    organisations,
+    mentoring,
+    match_members,
+    managers,
+    pauses,
+    conflict_groups,
+    season_plans,
+    icebreakers,
+    pool_archives,
+    generation_details,
+    mailings,
+    round_reminders,
+    mail_settings,
+    mail_tls,
+    unsubscribe_tokens,
+    schedule_cron,
+    sessions_per_person,
+    backfill_account_activity,
+    account_activations,
+    pool_admins,
+];
+
+// The schema as it was before versioning. Databases from then may be at any point in its history so
+// this only creates what is missing.
+fn baseline_schema(conn: &Connection) {
+    let creations = [
+        CREATE_TABLE_PEOPLE,
+        CREATE_TABLE_GENERATIONS,
+        CREATE_TABLE_MATCHES,
+        CREATE_TABLE_EDGES,
+        CREATE_TABLE_AUTH,
+        CREATE_TABLE_SESSIONS,
+        CREATE_TABLE_ACCOUNT_ACTIVITY,
+        CREATE_TABLE_ADMINS,
+        CREATE_TABLE_GENERATION_WAITERS,
+        CREATE_TABLE_SLACK_IDENTITIES,
+        CREATE_TABLE_BLOCKED_PAIRS,
+        CREATE_TABLE_BRANDING,
+        CREATE_TABLE_API_TOKENS,
+    ];
+    conn.execute_batch(&creations.join(";")).unwrap();
+    add_column(conn, "people", "status", "text");
+    add_column(conn, "matches", "person3", "integer references people(id)");
+    add_column(conn, "people", "max_matches_per_season", "integer");
+    add_column(conn, "generations", "updated_at", "integer");
+    add_column(conn, "people", "timezone", "text");
+    add_column(
+        conn,
+        "people",
+        "email_notifications",
+        "boolean not null default true",
+    );
+    add_column(conn, "people", "unsubscribe_token", "text");
+    conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS people_unsubscribe_token ON people (unsubscribe_token)",
+        [],
+    )
+    .unwrap();
+    conn.execute(
+        "UPDATE generations SET updated_at = time WHERE updated_at IS NULL",
+        [],
+    )
+    .unwrap();
+}
+
+fn generation_quality(conn: &Connection) {
+    conn.execute_batch(
+        "ALTER TABLE generations ADD COLUMN strategy TEXT;
+         ALTER TABLE generations ADD COLUMN buckets INTEGER;
+         ALTER TABLE generations ADD COLUMN repeat_pairs INTEGER;
+         ALTER TABLE generations ADD COLUMN total_weight INTEGER;",
+    )
+    .unwrap();
+}
+
+fn pause_until(conn: &Connection) {
+    conn.execute_batch("ALTER TABLE people ADD COLUMN paused_until INTEGER;")
+        .unwrap();
+}
+
+// Volunteers who can be brought into a round to make the numbers even.
+fn floaters(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE floaters (
+             person INTEGER PRIMARY KEY,
+             FOREIGN KEY(person) REFERENCES people(id)
+         );
+         ALTER TABLE generation_waiters ADD COLUMN floater BOOLEAN NOT NULL DEFAULT FALSE;",
+    )
+    .unwrap();
+}
+
+// `AUTOINCREMENT` means ids are assigned by SQLite within the insert and are never reused, even
+// if the latest generation is deleted. SQLite can't add it to an existing table so the table is
+// rebuilt.
+fn autoincrement_generations(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE generations_new (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             time INTEGER,
+             updated_at INTEGER,
+             strategy TEXT,
+             buckets INTEGER,
+             repeat_pairs INTEGER,
+             total_weight INTEGER
+         );
+         INSERT INTO generations_new (id, time, updated_at, strategy, buckets, repeat_pairs, total_weight)
+         SELECT id, time, updated_at, strategy, buckets, repeat_pairs, total_weight FROM generations;
+         DROP TABLE generations;
+         ALTER TABLE generations_new RENAME TO generations;",
+    )
+    .unwrap();
+}
+
+fn deactivation(conn: &Connection) {
+    conn.execute_batch("ALTER TABLE people ADD COLUMN deactivated_at INTEGER;")
+        .unwrap();
+}
+
+fn generation_audits(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE generation_audits (
+             generation INTEGER PRIMARY KEY,
+             audit TEXT NOT NULL,
+             FOREIGN KEY(generation) REFERENCES generations(id)
+         );",
+    )
+    .unwrap();
+}
+
+// New email addresses waiting for their owner to follow the link sent to them.
+fn email_changes(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE email_changes (
+             person INTEGER PRIMARY KEY,
+             email TEXT NOT NULL,
+             token TEXT NOT NULL UNIQUE,
+             created_at INTEGER NOT NULL,
+             FOREIGN KEY(person) REFERENCES people(id)
+         );",
+    )
+    .unwrap();
+}
+
+// Free form tags people choose, matching prefers pairs that share some.
+fn interests(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE interests (
+             person INTEGER NOT NULL,
+             tag TEXT NOT NULL,
+             PRIMARY KEY(person, tag),
+             FOREIGN KEY(person) REFERENCES people(id)
+         );
+         CREATE INDEX interests_tag ON interests (tag);",
+    )
+    .unwrap();
+}
+
+// The matching schedule as last saved by an admin.
+fn schedule(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE schedule (
+             id INTEGER PRIMARY KEY CHECK (id = 1),
+             start INTEGER NOT NULL,
+             every_days INTEGER NOT NULL,
+             min_waiters INTEGER NOT NULL,
+             enabled BOOLEAN NOT NULL
+         );",
+    )
+    .unwrap();
+}
+
+fn teams(conn: &Connection) {
+    conn.execute_batch("ALTER TABLE people ADD COLUMN team TEXT;")
+        .unwrap();
+}
+
+fn contact_fields(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE contact_fields (
+             person INTEGER NOT NULL,
+             kind TEXT NOT NULL,
+             value TEXT NOT NULL,
+             visibility TEXT NOT NULL,
+             PRIMARY KEY(person, kind),
+             FOREIGN KEY(person) REFERENCES people(id)
+         );",
+    )
+    .unwrap();
+}
+
+// How long the link to confirm a new email address works for.
+const EMAIL_CHANGE_LIFETIME_SECONDS: i64 = 60 * 60 * 24;
+
+// How long the link to activate an imported account works for.
+const ACCOUNT_ACTIVATION_LIFETIME_SECONDS: i64 = 60 * 60 * 24;
+
+// Remove everything about a person except their row in `people` and the matches they were in.
+fn delete_personal_data(conn: &Connection, person_id: PersonId) -> rusqlite::Result<()> {
+    for statement in [
+        "DELETE FROM sessions WHERE person = ?1",
+        "DELETE FROM auth WHERE person = ?1",
+        "DELETE FROM api_tokens WHERE person = ?1",
+        "DELETE FROM admins WHERE person = ?1",
+        "DELETE FROM floaters WHERE person = ?1",
+        "DELETE FROM email_changes WHERE person = ?1",
+        "DELETE FROM account_activations WHERE person = ?1",
+        "DELETE FROM interests WHERE person = ?1",
+        "DELETE FROM contact_fields WHERE person = ?1",
+        "DELETE FROM slack_identities WHERE person = ?1",
+        "DELETE FROM blocked_pairs WHERE person = ?1 OR blocked = ?1",
+        "DELETE FROM edges WHERE person1 = ?1 OR person2 = ?1",
+        "DELETE FROM account_activity WHERE person = ?1",
+        "DELETE FROM pool_members WHERE person = ?1",
+        "DELETE FROM pool_admins WHERE person = ?1",
+        "DELETE FROM pauses WHERE person = ?1",
+        "DELETE FROM conflict_group_members WHERE person = ?1",
+        "DELETE FROM season_plan_pairs WHERE person1 = ?1 OR person2 = ?1",
+        "DELETE FROM round_reminders WHERE person = ?1",
+        "DELETE FROM failed_logins WHERE person = ?1",
+        "DELETE FROM waiting_changes WHERE person = ?1",
+        "DELETE FROM notifications WHERE person = ?1",
+        "DELETE FROM mailing_recipients WHERE person = ?1",
+        "DELETE FROM unsubscribe_tokens WHERE person = ?1",
+    ] {
+        conn.execute(statement, [person_id])?;
+    }
+    Ok(())
+}
+
+// Remove everything personal about someone, keeping their row so everyone else's matches still make
+// sense.
+fn anonymise_person(conn: &Connection, person_id: PersonId, now: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE people SET name = 'Deleted account', email = 'deleted-' || id || '@invalid',
+         status = NULL, team = NULL, timezone = NULL, unsubscribe_token = NULL, email_notifications = FALSE,
+         max_matches_per_season = NULL, match_every_rounds = NULL,
+         mentoring_role = 'either', mentee_capacity = 1, manager = NULL, waiting = FALSE,
+         paused_until = NULL, deactivated_at = coalesce(deactivated_at, ?2)
+         WHERE id = ?1",
+        params![person_id, now],
+    )?;
+    // After the update, so the waiting change it records is deleted too.
+    delete_personal_data(conn, person_id)?;
+    conn.execute(
+        "UPDATE generation_waiters SET name = 'Deleted account', email = '' WHERE person = ?1",
+        [person_id],
+    )?;
+    Ok(())
+}
+
+// Erase someone from the database copy at `path`, e.g. a snapshot taken before their account was
+// deleted.
+pub fn erase_person_at(path: &Path, person_id: PersonId, now: i64) -> Result<()> {
+    let mut conn = Connection::open(path)?;
+    // Restoring migrates a snapshot anyway, this just does it sooner so every table is there.
+    migrate(&mut conn);
+    let tx = conn.transaction()?;
+    anonymise_person(&tx, person_id, now)?;
+    tx.commit()?;
+    Ok(())
+}
+
+// Apply any steps in `MIGRATIONS` the database hasn't had yet.
+fn migrate(conn: &mut Connection) {
+    conn.execute(CREATE_TABLE_SCHEMA_VERSION, []).unwrap();
+    let version: usize = conn
+        .query_row(
+            "SELECT coalesce(max(version), 0) FROM schema_version",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert!(
+        version <= MIGRATIONS.len(),
+        "Database schema version {version} is newer than this version of matcher supports ({})",
+        MIGRATIONS.len()
+    );
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction().unwrap();
+        migration(&tx);
+        tx.execute("DELETE FROM schema_version", []).unwrap();
+        tx.execute(
+            "INSERT INTO schema_version (version) VALUES (?1)",
+            [index + 1],
+        )
+        .unwrap();
+        tx.commit().unwrap();
+        tracing::info!(version = index + 1, "Migrated database");
+    }
+}
+
+// Every time someone starts or stops waiting. Triggers record the changes so that nothing which
+// updates `people.waiting` can forget to. Those already waiting are recorded as joining now.
+fn waiting_changes(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE waiting_changes (
+             person INTEGER NOT NULL,
+             waiting BOOLEAN NOT NULL,
+             at INTEGER NOT NULL
+         );
+         CREATE INDEX waiting_changes_at ON waiting_changes (at);
+         INSERT INTO waiting_changes (person, waiting, at)
+         SELECT id, TRUE, CAST(strftime('%s', 'now') AS INTEGER) FROM people WHERE waiting;
+         CREATE TRIGGER people_waiting_inserted AFTER INSERT ON people WHEN new.waiting
+         BEGIN
+             INSERT INTO waiting_changes (person, waiting, at)
+             VALUES (new.id, TRUE, CAST(strftime('%s', 'now') AS INTEGER));
+         END;
+         CREATE TRIGGER people_waiting_updated AFTER UPDATE OF waiting ON people
+         WHEN old.waiting IS NOT new.waiting
+         BEGIN
+             INSERT INTO waiting_changes (person, waiting, at)
+             VALUES (new.id, new.waiting, CAST(strftime('%s', 'now') AS INTEGER));
+         END;",
+    )
+    .unwrap();
+}
+
+// When each generation finished being matched and notified, so a run cut short by the process
+// stopping can be found at startup. Earlier generations are assumed to have finished.
+fn generation_progress(conn: &Connection) {
+    conn.execute_batch(
+        "ALTER TABLE generations ADD COLUMN matched_at INTEGER;
+         ALTER TABLE generations ADD COLUMN notified_at INTEGER;
+         UPDATE generations SET matched_at = updated_at, notified_at = updated_at;",
+    )
+    .unwrap();
+}
+
+// How each person's match email went, the generation's outbox.
+fn notifications(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE notifications (
+             generation INTEGER NOT NULL,
+             person INTEGER NOT NULL,
+             status TEXT NOT NULL,
+             error TEXT,
+             updated_at INTEGER NOT NULL,
+             PRIMARY KEY(generation, person),
+             FOREIGN KEY(generation) REFERENCES generations(id),
+             FOREIGN KEY(person) REFERENCES people(id)
+         );",
+    )
+    .unwrap();
+}
+
+// Generations belong to a round type so different kinds of round can run side by side. Those from
+// before are all the default type.
+fn round_types(conn: &Connection) {
+    conn.execute_batch(&format!(
+        "ALTER TABLE generations ADD COLUMN round_type TEXT NOT NULL DEFAULT '{DEFAULT_ROUND_TYPE}';
+         CREATE INDEX generations_round_type ON generations (round_type, id);"
+    ))
+    .unwrap();
+}
+
+// Wrong passwords given for each account, for locking it after too many.
+fn failed_logins(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE failed_logins (
+             person INTEGER NOT NULL,
+             at INTEGER NOT NULL,
+             FOREIGN KEY(person) REFERENCES people(id)
+         );
+         CREATE INDEX failed_logins_person ON failed_logins (person, at);",
+    )
+    .unwrap();
+}
+
+// Emails are now compared ignoring case. Addresses that would then belong to two accounts are left
+// alone, only the one already in lower case can be signed in to.
+fn lowercase_emails(conn: &Connection) {
+    conn.execute(
+        "UPDATE people SET email = lower(email) WHERE email != lower(email)
+         AND NOT EXISTS (SELECT 1 FROM people other WHERE other.id != people.id AND lower(other.email) = lower(people.email))",
+        [],
+    )
+    .unwrap();
+    let clashing: usize = conn
+        .query_row(
+            "SELECT count(*) FROM people WHERE email != lower(email)",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    if clashing > 0 {
+        tracing::warn!(
+            clashing,
+            "Some accounts' emails only differ by case from another's, merge them by hand"
+        );
+    }
+}
+
+// How often someone wants to be matched, they sit out until `match_every_rounds` rounds of a type
+// have run since their last match in it.
+fn match_cadence(conn: &Connection) {
+    conn.execute_batch("ALTER TABLE people ADD COLUMN match_every_rounds INTEGER;")
+        .unwrap();
+}
+
+// Rotations with their own members and waiting flags, e.g. lunch roulette alongside coffee chats.
+// A pool's generations have its name as their round type.
+fn pools(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE pools (
+             name TEXT PRIMARY KEY,
+             description TEXT NOT NULL DEFAULT '',
+             created_at INTEGER NOT NULL
+         );
+         CREATE TABLE pool_members (
+             pool TEXT NOT NULL,
+             person INTEGER NOT NULL,
+             waiting BOOLEAN NOT NULL DEFAULT FALSE,
+             PRIMARY KEY(pool, person),
+             FOREIGN KEY(pool) REFERENCES pools(name),
+             FOREIGN KEY(person) REFERENCES people(id)
+         );",
+    )
+    .unwrap();
+}
+
+// Companies or teams sharing the deployment, each person belongs to one. Everyone so far is in the
+// default organisation.
+fn organisations(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE organisations (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             name TEXT NOT NULL,
+             sign_up_token TEXT NOT NULL UNIQUE,
+             created_at INTEGER NOT NULL
+         );
+         ALTER TABLE people ADD COLUMN organisation INTEGER NOT NULL DEFAULT 1;
+         CREATE INDEX people_organisation ON people (organisation);",
+    )
+    .unwrap();
+    conn.execute(
+        "INSERT INTO organisations (id, name, sign_up_token, created_at) VALUES (?1, 'Default', ?2, ?3)",
+        params![
+            DEFAULT_ORGANISATION,
+            uuid::Uuid::new_v4().to_string(),
+            chrono::offset::Utc::now().timestamp()
+        ],
+    )
+    .unwrap();
+}
+
+// Which side of mentoring rounds someone is on, and how many mentees they can take at once as a
+// mentor.
+fn mentoring(conn: &Connection) {
+    conn.execute_batch(
+        "ALTER TABLE people ADD COLUMN mentoring_role TEXT NOT NULL DEFAULT 'either';
+         ALTER TABLE people ADD COLUMN mentee_capacity INTEGER NOT NULL DEFAULT 1;",
+    )
+    .unwrap();
+}
+
+// Groups can be any size, so their members move to a table of their own. Each group keeps its
+// rowid as its id.
+fn match_members(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE matches_new (
+             id INTEGER PRIMARY KEY,
+             generation INTEGER NOT NULL,
+             FOREIGN KEY(generation) REFERENCES generations(id)
+         );
+         INSERT INTO matches_new (id, generation) SELECT rowid, generation FROM matches;
+         CREATE TABLE match_members (
+             match INTEGER NOT NULL,
+             person INTEGER NOT NULL,
+             PRIMARY KEY(match, person),
+             FOREIGN KEY(match) REFERENCES matches(id),
+             FOREIGN KEY(person) REFERENCES people(id)
+         );
+         INSERT INTO match_members (match, person)
+             SELECT rowid, CAST(person1 AS INTEGER) FROM matches
+             UNION SELECT rowid, CAST(person2 AS INTEGER) FROM matches WHERE person2 IS NOT NULL
+             UNION SELECT rowid, person3 FROM matches WHERE person3 IS NOT NULL;
+         DROP TABLE matches;
+         ALTER TABLE matches_new RENAME TO matches;
+         CREATE INDEX matches_generation ON matches (generation);
+         CREATE INDEX match_members_person ON match_members (person);",
+    )
+    .unwrap();
+}
+
+// Who each person reports to, and the pools where managers can still be matched with their reports.
+fn managers(conn: &Connection) {
+    conn.execute_batch(
+        "ALTER TABLE people ADD COLUMN manager INTEGER REFERENCES people(id);
+         ALTER TABLE pools ADD COLUMN allow_manager_pairs BOOLEAN NOT NULL DEFAULT FALSE;",
+    )
+    .unwrap();
+}
+
+// Every pause someone takes, for their timeline. Only the current one is on `people`.
+fn pauses(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE pauses (
+             person INTEGER NOT NULL,
+             at INTEGER NOT NULL,
+             until INTEGER NOT NULL,
+             FOREIGN KEY(person) REFERENCES people(id)
+         );
+         CREATE INDEX pauses_person ON pauses (person);",
+    )
+    .unwrap();
+}
+
+// A list of people to bind as a JSON array, so queries can filter to them in SQL with
+// `IN (SELECT value FROM json_each(?))`.
+fn id_array(people: &[PersonId]) -> String {
+    serde_json::to_string(people).expect("ids serialize")
+}
+
+// Groups of people admins keep apart, every pair within a group is never matched.
+fn conflict_groups(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE conflict_groups (
+             id INTEGER PRIMARY KEY,
+             name TEXT NOT NULL,
+             created_at INTEGER NOT NULL
+         );
+         CREATE TABLE conflict_group_members (
+             conflict_group INTEGER NOT NULL,
+             person INTEGER NOT NULL,
+             PRIMARY KEY(conflict_group, person),
+             FOREIGN KEY(conflict_group) REFERENCES conflict_groups(id),
+             FOREIGN KEY(person) REFERENCES people(id)
+         );
+         CREATE INDEX conflict_group_members_person ON conflict_group_members (person);",
+    )
+    .unwrap();
+}
+
+// Round robins planned ahead, with the pairs for each round and the generation each was run in.
+fn season_plans(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE season_plans (
+             id INTEGER PRIMARY KEY,
+             round_type TEXT NOT NULL,
+             created_at INTEGER NOT NULL,
+             rounds INTEGER NOT NULL,
+             cancelled_at INTEGER
+         );
+         CREATE TABLE season_plan_pairs (
+             plan INTEGER NOT NULL,
+             round INTEGER NOT NULL,
+             person1 INTEGER NOT NULL,
+             person2 INTEGER NOT NULL,
+             FOREIGN KEY(plan) REFERENCES season_plans(id),
+             FOREIGN KEY(person1) REFERENCES people(id),
+             FOREIGN KEY(person2) REFERENCES people(id)
+         );
+         CREATE INDEX season_plan_pairs_round ON season_plan_pairs (plan, round);
+         CREATE TABLE season_plan_rounds (
+             plan INTEGER NOT NULL,
+             round INTEGER NOT NULL,
+             generation INTEGER NOT NULL,
+             PRIMARY KEY(plan, round),
+             FOREIGN KEY(plan) REFERENCES season_plans(id),
+             FOREIGN KEY(generation) REFERENCES generations(id)
+         );",
+    )
+    .unwrap();
+}
+
+// Conversation starters for matches. Each match keeps its own copy of the one it was given, so
+// editing or deleting a prompt leaves past matches as they were.
+fn icebreakers(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE icebreakers (
+             id INTEGER PRIMARY KEY,
+             prompt TEXT NOT NULL,
+             created_at INTEGER NOT NULL
+         );
+         ALTER TABLE matches ADD COLUMN icebreaker TEXT;",
+    )
+    .unwrap();
+}
+
+// Idle pools are archived: their matches move out of `matches` and `match_members` into a row per
+// group here, until the pool is reactivated. The meetings still count in `edges`.
+fn pool_archives(conn: &Connection) {
+    conn.execute_batch(
+        "ALTER TABLE pools ADD COLUMN archived_at INTEGER;
+         CREATE TABLE archived_matches (
+             generation INTEGER NOT NULL,
+             members TEXT NOT NULL,
+             icebreaker TEXT,
+             FOREIGN KEY(generation) REFERENCES generations(id)
+         );
+         CREATE INDEX archived_matches_generation ON archived_matches (generation);",
+    )
+    .unwrap();
+}
+
+// Admins can name a round and leave notes on it when they run it.
+fn generation_details(conn: &Connection) {
+    conn.execute_batch(
+        "ALTER TABLE generations ADD COLUMN title TEXT;
+         ALTER TABLE generations ADD COLUMN notes TEXT;",
+    )
+    .unwrap();
+}
+
+// Emails admins send to a segment of people, and how sending each one went, like `notifications`
+// for match emails.
+fn mailings(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE mailings (
+             id INTEGER PRIMARY KEY,
+             organisation INTEGER NOT NULL,
+             segment TEXT NOT NULL,
+             subject TEXT NOT NULL,
+             body TEXT NOT NULL,
+             sent_by INTEGER,
+             created_at INTEGER NOT NULL,
+             FOREIGN KEY(organisation) REFERENCES organisations(id),
+             FOREIGN KEY(sent_by) REFERENCES people(id)
+         );
+         CREATE TABLE mailing_recipients (
+             mailing INTEGER NOT NULL,
+             person INTEGER NOT NULL,
+             status TEXT NOT NULL,
+             error TEXT,
+             updated_at INTEGER NOT NULL,
+             PRIMARY KEY(mailing, person),
+             FOREIGN KEY(mailing) REFERENCES mailings(id),
+             FOREIGN KEY(person) REFERENCES people(id)
+         );",
+    )
+    .unwrap();
+}
+
+// Who has been reminded about each scheduled run, so nobody is reminded twice, with the hashed token
+// in their link to opt in.
+fn round_reminders(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE round_reminders (
+             round_at INTEGER NOT NULL,
+             person INTEGER NOT NULL,
+             token TEXT NOT NULL UNIQUE,
+             sent_at INTEGER NOT NULL,
+             opted_in_at INTEGER,
+             PRIMARY KEY(round_at, person),
+             FOREIGN KEY(person) REFERENCES people(id)
+         );",
+    )
+    .unwrap();
+}
+
+// The SMTP server as last saved by an admin, e.g. from the setup wizard.
+fn mail_settings(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE mail_settings (
+             id INTEGER PRIMARY KEY CHECK (id = 1),
+             host TEXT NOT NULL,
+             port INTEGER NOT NULL,
+             username TEXT,
+             password TEXT,
+             sender TEXT
+         );",
+    )
+    .unwrap();
+}
+
+fn mail_tls(conn: &Connection) {
+    conn.execute_batch(
+        "ALTER TABLE mail_settings ADD COLUMN tls TEXT NOT NULL DEFAULT 'starttls';",
+    )
+    .unwrap();
+}
+
+// Unsubscribe tokens are kept hashed like every other token, so each email gets a new one and they
+// are no longer kept on `people`. Links in emails sent before now keep working.
+fn unsubscribe_tokens(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE unsubscribe_tokens (
+             token TEXT PRIMARY KEY,
+             person INTEGER NOT NULL,
+             created_at INTEGER NOT NULL,
+             FOREIGN KEY(person) REFERENCES people(id)
+         );",
+    )
+    .unwrap();
+    let existing: Vec<(PersonId, String)> = conn
+        .prepare("SELECT id, unsubscribe_token FROM people WHERE unsubscribe_token IS NOT NULL")
+        .unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap();
+    for (person_id, token) in existing {
+        conn.execute(
+            "INSERT INTO unsubscribe_tokens (token, person, created_at)
+             VALUES (?1, ?2, CAST(strftime('%s', 'now') AS INTEGER))",
+            params![hash_token(&token), person_id],
+        )
+        .unwrap();
+    }
+    conn.execute_batch(
+        "DROP INDEX IF EXISTS people_unsubscribe_token;
+         UPDATE people SET unsubscribe_token = NULL;",
+    )
+    .unwrap();
+}
+
+fn schedule_cron(conn: &Connection) {
+    conn.execute_batch("ALTER TABLE schedule ADD COLUMN cron TEXT;")
+        .unwrap();
+}
+
+// People can be signed in from several browsers at once, so a person can have any number of
+// sessions. SQLite can't drop the unique constraint on `person` so the table is rebuilt.
+fn sessions_per_person(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE sessions_new (
+             id TEXT PRIMARY KEY,
+             person INTEGER NOT NULL,
+             last_seen INTEGER NOT NULL,
+             FOREIGN KEY(person) REFERENCES people(id)
+         );
+         INSERT INTO sessions_new (id, person, last_seen) SELECT id, person, last_seen FROM sessions;
+         DROP TABLE sessions;
+         ALTER TABLE sessions_new RENAME TO sessions;
+         CREATE INDEX sessions_person ON sessions (person);",
+    )
+    .unwrap();
+}
+
+// Accounts created before `account_activity` have no row in it, so they could never be flagged as
+// stale. When they were created isn't known, so they count from now and are only flagged once
+// they have gone unused for the whole period since.
+fn backfill_account_activity(conn: &Connection) {
+    conn.execute_batch(
+        "INSERT INTO account_activity (person, created_at)
+         SELECT person, CAST(strftime('%s', 'now') AS INTEGER) FROM auth
+         WHERE person NOT IN (SELECT person FROM account_activity);",
+    )
+    .unwrap();
+}
+
+// Imported people signing up for the first time, waiting for them to follow the link emailed to
+// them. What they signed up with only replaces the imported details once they have.
+fn account_activations(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE account_activations (
+             person INTEGER PRIMARY KEY,
+             name TEXT NOT NULL,
+             password_hash TEXT NOT NULL,
+             token TEXT NOT NULL UNIQUE,
+             created_at INTEGER NOT NULL,
+             FOREIGN KEY(person) REFERENCES people(id)
+         );",
+    )
+    .unwrap();
+}
+
+// People appointed by the deployment's admins to manage a single pool's members and run its rounds.
+fn pool_admins(conn: &Connection) {
+    conn.execute_batch(
+        "CREATE TABLE pool_admins (
+             pool TEXT NOT NULL,
+             person INTEGER NOT NULL,
+             PRIMARY KEY(pool, person),
+             FOREIGN KEY(pool) REFERENCES pools(name),
+             FOREIGN KEY(person) REFERENCES people(id)
+         );",
+    )
+    .unwrap();
+}
+
+// Record a group in a generation, counting every pair in it as having met once more. Returns the
+// group's row in `matches`.
+fn insert_group(
+    conn: &Connection,
+    generation: GenerationId,
+    members: &[PersonId],
+) -> rusqlite::Result<i64> {
+    conn.execute("INSERT INTO matches (generation) VALUES (?1)", [generation])?;
+    let group = conn.last_insert_rowid();
+    for (i, p1) in members.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO match_members (match, person) VALUES (?1, ?2)",
+            params![group, p1],
+        )?;
+        for p2 in &members[i + 1..] {
+            conn.execute(
+                "INSERT INTO edges (person1, person2, weight) VALUES (?1, ?2, 1)
+                 ON CONFLICT (person1, person2) DO UPDATE SET weight = weight + 1",
+                params![p1, p2],
+            )?;
+        }
+    }
+    Ok(group)
+}
+
+// Emails are compared ignoring case, so `Foo@x.com` and `foo@x.com` are the same account.
+pub fn normalise_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+#[derive(Clone)]
+pub struct Database {
+    connection: Arc<Mutex<rusqlite::Connection>>,
+    people_cache: TtlCache<PersonId, Person>,
+    // Each session's person and when it was last seen.
+    sessions_cache: TtlCache<String, (PersonId, i64)>,
+    // Sessions that haven't been seen for this long are expired.
+    session_lifetime_seconds: i64,
+    lockout: LoginLockout,
+    clock: Clock,
+    cipher: Cipher,
+    pub busy: Arc<BusyCounts>,
+}
+
+impl Database {
+    pub fn init(
+        path: &str,
+        session_lifetime_seconds: i64,
+        lockout: LoginLockout,
+        clock: Clock,
+        cipher: Cipher,
+    ) -> Database {
+        let conn = Connection::open(path).unwrap();
+        conn.busy_timeout(BUSY_TIMEOUT).unwrap();
+        let s = Database {
+            connection: Arc::new(Mutex::new(conn)),
+            people_cache: TtlCache::new(CACHE_TTL),
+            sessions_cache: TtlCache::new(CACHE_TTL),
+            session_lifetime_seconds,
+            lockout,
+            clock,
+            cipher,
+            busy: Arc::default(),
+        };
+        s.migrate();
+        s.seal_columns();
+        s
+    }
+
+    // A fresh database that only lives as long as the handle, for tests.
+    #[cfg(test)]
+    pub fn in_memory() -> Database {
+        Database::init(
+            ":memory:",
+            3600,
+            LoginLockout {
+                attempts: 5,
+                seconds: 900,
+            },
+            Clock::new(false),
+            Cipher::default(),
+        )
+    }
+
+    // The current time on the app's clock.
+    pub fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        self.clock.now()
+    }
+
+    // Close the connection, which only happens once every other handle to the database is dropped.
+    pub fn close(self) {
+        match Arc::try_unwrap(self.connection) {
+            Ok(connection) => {
+                if let Err((_, error)) = connection.into_inner().unwrap().close() {
+                    tracing::error!(%error, "Failed to close the database");
+                }
+            }
+            Err(_) => tracing::warn!("Database still in use, leaving it to close on exit"),
+        }
+    }
+
+    // Copy the whole database to a new file at `path`.
+    pub fn backup_to(&self, path: &Path) -> Result<()> {
+        let conn = self.connection.lock().unwrap();
+        conn.backup(DatabaseName::Main, path, None)?;
+        Ok(())
+    }
+
+    // Replace the whole database with the copy at `path`, bringing it up to date if it was taken
+    // before a migration. Cached people and sessions are dropped as they may no longer be right.
+    pub fn restore_from(&self, path: &Path) -> Result<()> {
+        let mut conn = self.connection.lock().unwrap();
+        conn.restore(DatabaseName::Main, path, None::<fn(Progress)>)?;
+        drop(conn);
+        self.people_cache.retain(|_, _| false);
+        self.sessions_cache.retain(|_, _| false);
+        self.migrate();
+        self.seal_columns();
+        Ok(())
+    }
+
+    // Run database work on tokio's blocking thread pool, so waiting on SQLite (or the connection
+    // lock) doesn't hold up the async executor.
+    pub async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Database) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.clone();
+        let span = tracing::Span::current();
+        let result = tokio::task::spawn_blocking(move || span.in_scope(|| f(&db))).await;
+        match result {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(error)) => {
+                self.log_failure(&error);
+                Err(error)
+            }
+            Err(error) => {
+                tracing::error!(%error, "Database call panicked");
+                // The connection was held when it panicked, but SQLite leaves it usable.
+                self.connection.clear_poison();
+                std::panic::resume_unwind(error.into_panic())
+            }
+        }
+    }
+
+    // Like `call` but running `f` again, after a growing delay, while the database is busy. Only
+    // for work that is safe to repeat after being cut off part way through.
+    pub async fn call_retrying<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&Database) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.call(move |db| {
+            let mut delay = BUSY_RETRY_DELAY;
+            for attempt in 1..BUSY_ATTEMPTS {
+                match f(db) {
+                    Err(DatabaseError::Busy) => {}
+                    result => return result,
+                }
+                db.busy.retries.fetch_add(1, Ordering::Relaxed);
+                tracing::info!(attempt, "Database busy, retrying");
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            f(db)
+        })
+        .await
+    }
+
+    fn log_failure(&self, error: &DatabaseError) {
+        match error {
+            DatabaseError::Busy => {
+                self.busy.failures.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!("Database call failed as the database is busy");
+            }
+            DatabaseError::Sqlite(error) => tracing::error!(%error, "Database call failed"),
+        }
+    }
+
+    // Whether the database can still be queried.
+    pub fn ping(&self) -> bool {
+        self.connection.lock().is_ok_and(|conn| {
+            conn.query_row("SELECT 1", [], |row| row.get::<_, i64>(0))
+                .is_ok()
+        })
+    }
+
+    // Bring the schema up to date by applying, in order, each migration the database hasn't seen
+    // yet. Every step runs in its own transaction along with recording the new version.
+    fn migrate(&self) {
+        migrate(&mut self.connection.lock().unwrap());
+    }
+
+    // Encrypt sensitive values still stored as plaintext, e.g. from before a key was set. Refuses to
+    // start when values are encrypted but there's no key, or a different one.
+    fn seal_columns(&self) {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction().unwrap();
+        for (table, column, lookup) in SEALED_COLUMNS {
+            let values: Vec<(i64, String)> = tx
+                .prepare(&format!(
+                    "SELECT rowid, {column} FROM {table}
+                     WHERE {column} IS NOT NULL AND {column} != ''
+                         AND {column} NOT LIKE 'deleted-%@invalid'"
+                ))
+                .unwrap()
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap();
+            let mut checked = false;
+            let mut sealed = 0;
+            for (rowid, value) in values {
+                if is_sealed(&value) {
+                    // One value is enough to tell whether the key is right.
+                    if !checked {
+                        if let Err(error) = self.cipher.open(value) {
+                            panic!("Failed to decrypt {table}.{column}, {error}");
+                        }
+                        checked = true;
+                    }
+                } else if self.cipher.is_enabled() {
+                    let value = if *lookup {
+                        self.cipher.seal_lookup(&value)
+                    } else {
+                        self.cipher.seal(&value)
+                    };
+                    tx.execute(
+                        &format!("UPDATE {table} SET {column} = ?2 WHERE rowid = ?1"),
+                        params![rowid, value],
+                    )
+                    .unwrap();
+                    sealed += 1;
+                }
+            }
+            if sealed > 0 {
+                tracing::info!(table, column, rows = sealed, "Encrypted column");
+            }
+        }
+        tx.commit().unwrap();
+    }
+
+    // How an email is stored, to look it up by or write it.
+    fn stored_email(&self, email: &str) -> String {
+        self.cipher.seal_lookup(&normalise_email(email))
+    }
+
+    pub fn get_person(&self, id: PersonId) -> Result<Option<Person>> {
+        self.people_cache
+            .get_or_try_insert_with(id, || self.load_person(id))
+    }
+
+    fn load_person(&self, id: PersonId) -> Result<Option<Person>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "select p.id, p.email, p.name, p.waiting, p.status, p.team from people p
+                 where p.id = ?1",
+                [id],
+                |row| Person::from_row(row, 0, &self.cipher),
+            )
+            .optional()?)
+    }
+
+    pub fn get_person_and_matches(&self, id: PersonId) -> Result<Option<PersonMatches>> {
+        let Some(person) = self.get_person(id)? else {
+            return Ok(None);
+        };
+        let matches = self.matches_for(id)?;
+        Ok(Some((person, matches)))
+    }
+
+    // Signing up again with the same email and password, e.g. from submitting the form twice, signs
+    // in to the account created the first time rather than failing.
+    // New people join `organisation`, anyone who already has an account stays in theirs.
+    pub fn sign_up_session(
+        &self,
+        name: &str,
+        email: &str,
+        password: &str,
+        organisation: OrganisationId,
+    ) -> Result<Result<(PersonId, String), SignUpError>> {
+        let email = self.stored_email(email);
+        let existing = account_with_email(&self.connection.lock().unwrap(), &email)?;
+        let imported = match existing {
+            // Signing in is where deactivated people are told why they can't.
+            Some((_, _, true)) => return Ok(Err(SignUpError::EmailTaken)),
+            // Signing up again checks the password like signing in, so is locked out the same way.
+            Some((id, Some(existing_password_hash), false)) => {
+                let time = self.clock.timestamp();
+                return Ok(
+                    match self.check_password(id, password, &existing_password_hash, time)? {
+                        None => Err(SignUpError::LockedOut),
+                        Some(false) => Err(SignUpError::EmailTaken),
+                        Some(true) => Ok((
+                            id,
+                            insert_session(&self.connection.lock().unwrap(), id, time)?,
+                        )),
+                    },
+                );
+            }
+            Some((id, None, false)) => Some(id),
+            None => None,
+        };
+        // Hashing is deliberately slow, so isn't done while holding the connection.
+        let password_hash = hash_password(password);
+        let mut conn = self.connection.lock().unwrap();
+        // Someone else may have signed up with the address in the meantime.
+        if account_with_email(&conn, &email)? != imported.map(|id| (id, None, false)) {
+            return Ok(Err(SignUpError::EmailTaken));
+        }
+        // Imported people don't have a password until they first sign up themselves, and only
+        // someone who can read the address's email can do that.
+        if let Some(id) = imported {
+            let token = uuid::Uuid::new_v4().simple().to_string();
+            conn.execute(
+                "INSERT INTO account_activations (person, name, password_hash, token, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (person) DO UPDATE SET name = excluded.name,
+                     password_hash = excluded.password_hash, token = excluded.token,
+                     created_at = excluded.created_at",
+                params![
+                    id,
+                    name,
+                    password_hash,
+                    hash_token(&token),
+                    self.clock.timestamp()
+                ],
+            )?;
+            return Ok(Err(SignUpError::Imported { token }));
+        }
+        // A failure part way through mustn't leave a person who can never sign in.
+        let tx = conn.transaction()?;
+        let id = tx.query_row(
+            "insert into people (email, name, waiting, organisation) values (?1, ?2, FALSE, ?3) RETURNING id",
+            params![&email, name, organisation],
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            "INSERT INTO auth (person, password_hash) values (?1, ?2)",
+            params![id, password_hash],
+        )?;
+        let time = self.clock.timestamp();
+        let session_id = insert_session(&tx, id, time)?;
+        tx.execute(
+            "INSERT INTO account_activity (person, created_at) VALUES (?1, ?2)",
+            params![id, time],
+        )?;
+        tx.commit()?;
+        Ok(Ok((id, session_id)))
+    }
+
+    // Give an imported person the name and password they signed up with, from the link emailed to
+    // them, and sign them in. Returns None if the link has expired or was already used.
+    pub fn activate_account(&self, token: &str, now: i64) -> Result<Option<(PersonId, String)>> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let Some((id, name, password_hash)): Option<(PersonId, String, String)> = tx
+            .query_row(
+                "DELETE FROM account_activations WHERE token = ?1 AND created_at > ?2
+                 RETURNING person, name, password_hash",
+                params![hash_token(token), now - ACCOUNT_ACTIVATION_LIFETIME_SECONDS],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let claimed = tx
+            .query_row("SELECT 1 FROM auth WHERE person = ?1", [id], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if claimed {
+            return Ok(None);
+        }
+        tx.execute(
+            "UPDATE people SET name = ?2 WHERE id = ?1",
+            params![id, name],
+        )?;
+        tx.execute(
+            "INSERT INTO auth (person, password_hash) VALUES (?1, ?2)",
+            params![id, password_hash],
+        )?;
+        let session_id = insert_session(&tx, id, now)?;
+        tx.execute(
+            "INSERT INTO account_activity (person, created_at, last_sign_in) VALUES (?1, ?2, ?2)
+             ON CONFLICT (person) DO UPDATE SET last_sign_in = excluded.last_sign_in, flagged_at = NULL",
+            params![id, now],
+        )?;
+        tx.commit()?;
+        self.people_cache.remove(&id);
+        Ok(Some((id, session_id)))
+    }
+
+    // Whether nobody has an account yet, so the first-run setup should be shown.
+    pub fn needs_setup(&self) -> Result<bool> {
+        Ok(self.connection.lock().unwrap().query_row(
+            "SELECT NOT EXISTS (SELECT 1 FROM people)",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    // Create the first account as an admin in the default organisation, renamed to
+    // `organisation_name`, and sign them in. Returns None if someone has made an account since the
+    // setup page was shown.
+    pub fn set_up(
+        &self,
+        name: &str,
+        email: &str,
+        password: &str,
+        organisation_name: &str,
+    ) -> Result<Option<(PersonId, String)>> {
+        let email = self.stored_email(email);
+        let password_hash = hash_password(password);
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let empty: bool = tx.query_row("SELECT NOT EXISTS (SELECT 1 FROM people)", [], |row| {
+            row.get(0)
+        })?;
+        if !empty {
+            return Ok(None);
+        }
+        let id: PersonId = tx.query_row(
+            "INSERT INTO people (email, name, waiting, organisation) VALUES (?1, ?2, FALSE, ?3) RETURNING id",
+            params![&email, name, DEFAULT_ORGANISATION],
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            "INSERT INTO auth (person, password_hash) VALUES (?1, ?2)",
+            params![id, password_hash],
+        )?;
+        tx.execute("INSERT INTO admins (person) VALUES (?1)", [id])?;
+        tx.execute(
+            "UPDATE organisations SET name = ?2 WHERE id = ?1",
+            params![DEFAULT_ORGANISATION, organisation_name],
+        )?;
+        let time = self.clock.timestamp();
+        let session_id = insert_session(&tx, id, time)?;
+        tx.execute(
+            "INSERT INTO account_activity (person, created_at) VALUES (?1, ?2)",
+            params![id, time],
+        )?;
+        tx.commit()?;
+        Ok(Some((id, session_id)))
+    }
+
+    pub fn toggle_waiter(&self, person_id: PersonId) -> Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE people SET waiting = (CASE WHEN waiting = FALSE THEN TRUE ELSE FALSE END), paused_until = NULL
+                 WHERE id = ?1 AND deactivated_at IS NULL",
+                [person_id],
+            )?;
+        self.people_cache.remove(&person_id);
+        Ok(())
+    }
+
+    // Everyone someone has been matched with, newest generation first.
+    pub fn matches_for(&self, person_id: PersonId) -> Result<Vec<PastMatch>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "select m.generation, g.time, g.round_type,
+                 p.id, p.email, p.name, p.waiting, p.status, p.team, m.icebreaker from match_members mine
+                 join matches m on m.id = mine.match
+                 join generations g on g.id = m.generation
+                 join match_members other on other.match = mine.match AND other.person != ?1
+                 join people p on p.id = other.person
+                 WHERE mine.person = ?1
+                 ORDER BY m.generation DESC, p.name",
+        )?;
+        let mut rows = stmnt.query([person_id])?;
+
+        let mut matches = Vec::new();
+        while let Some(row) = rows.next()? {
+            matches.push(PastMatch {
+                generation: row.get(0)?,
+                time: row.get(1)?,
+                round_type: row.get(2)?,
+                partner: Person::from_row(row, 3, &self.cipher)?,
+                icebreaker: row.get(9)?,
+            });
+        }
+
+        Ok(matches)
+    }
+
+    pub fn all_people(&self) -> Result<Vec<Person>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn
+            .prepare("select p.id, p.email, p.name, p.waiting, p.status, p.team from people p")?;
+        let mut rows = stmnt.query([])?;
+
+        let mut people = Vec::new();
+        while let Some(row) = rows.next()? {
+            people.push(Person::from_row(row, 0, &self.cipher)?);
+        }
+        Ok(people)
+    }
+
+    pub fn match_meta_at(&self, generation: GenerationId) -> Result<Option<MatchMeta>> {
+        Ok(self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "select id, time, strategy, buckets, repeat_pairs, total_weight, round_type, title, notes from generations where id = ?1",
+                [generation],
+                |row| MatchMeta::from_row(row, &self.cipher),
+            )
+            .optional()?)
+    }
+
+    // How many generations of the round type there have been.
+    pub fn rounds_run(&self, round_type: &str) -> Result<u64> {
+        Ok(self.connection.lock().unwrap().query_row(
+            "SELECT count(*) FROM generations WHERE round_type = ?1",
+            [round_type],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn latest_match_meta(&self, round_type: &str) -> Result<Option<MatchMeta>> {
+        Ok(self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "select id, time, strategy, buckets, repeat_pairs, total_weight, round_type, title, notes from generations
+                 where round_type = ?1 order by id desc limit 1",
+                [round_type],
+                |row| MatchMeta::from_row(row, &self.cipher),
+            )
+            .optional()?)
+    }
+
+    // The latest generation of each round type, by round type.
+    pub fn latest_match_metas(&self) -> Result<Vec<MatchMeta>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn
+            .prepare(
+                "select id, time, strategy, buckets, repeat_pairs, total_weight, round_type, title, notes from generations
+                 where id in (select max(id) from generations group by round_type)
+                     and round_type not in (select name from pools where archived_at is not null)
+                 order by round_type",
+            )?;
+        let metas = stmnt
+            .query_map([], |row| MatchMeta::from_row(row, &self.cipher))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(metas)
+    }
+
+    // Every generation, oldest first, counting only the groups from the organisation.
+    pub fn list_generations(&self, organisation: OrganisationId) -> Result<Vec<GenerationSummary>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT g.id, g.time, g.round_type, g.title,
+                     count(m.id) FILTER (WHERE m.size > 1),
+                     count(m.id) FILTER (WHERE m.size = 1)
+                 FROM generations g
+                 LEFT JOIN (SELECT mt.id, mt.generation, count(*) AS size
+                            FROM matches mt
+                            JOIN match_members mm ON mm.match = mt.id
+                            JOIN people p ON p.id = mm.person
+                            WHERE p.organisation = ?1
+                            GROUP BY mt.id) m ON m.generation = g.id
+                 GROUP BY g.id
+                 ORDER BY g.id",
+        )?;
+        let generations = stmnt
+            .query_map([organisation], |row| {
+                Ok(GenerationSummary {
+                    generation: row.get(0)?,
+                    time: row.get(1)?,
+                    round_type: row.get(2)?,
+                    title: row.get(3)?,
+                    pairs: row.get(4)?,
+                    unmatched: row.get(5)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(generations)
+    }
+
+    pub fn matches_at(&self, generation: GenerationId) -> Result<Option<(MatchMeta, Vec<Match>)>> {
+        let Some(match_meta) = self.match_meta_at(generation)? else {
+            return Ok(None);
+        };
+        Ok(Some((match_meta, self.matches_in(generation)?)))
+    }
+
+    // The groups in a generation, people left unmatched last.
+    pub fn matches_in(&self, generation: GenerationId) -> Result<Vec<Match>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "select p.id, p.email, p.name, p.waiting, p.status, p.team, m.id, m.icebreaker
+                 from matches m join match_members mm on mm.match = m.id
+                 join people p on p.id = mm.person
+                 where m.generation = ?1
+                 order by (select count(*) from match_members s where s.match = m.id) = 1, m.id, p.id",
+        )?;
+        let mut rows = stmt.query([generation])?;
+        let mut matches: Vec<Match> = Vec::new();
+        let mut last_group = None;
+        while let Some(row) = rows.next()? {
+            let person = Person::from_row(row, 0, &self.cipher)?;
+            let group: i64 = row.get(6)?;
+            match matches.last_mut() {
+                Some(last) if last_group == Some(group) => last.members.push(person),
+                _ => matches.push(Match {
+                    members: vec![person],
+                    icebreaker: row.get(7)?,
+                }),
+            }
+            last_group = Some(group);
+        }
+        Ok(matches)
+    }
+
+    // The current matches of every round type.
+    pub fn latest_matches(&self) -> Result<Vec<(MatchMeta, Vec<Match>)>> {
+        self.latest_match_metas()?
+            .into_iter()
+            .map(|meta| {
+                let matches = self.matches_in(meta.generation)?;
+                Ok((meta, matches))
+            })
+            .collect()
+    }
+
+    // Record a group of people as matched in the generation, every pair in the group has now met
+    // and they stop waiting for the generation's round type.
+    pub fn add_matching(&self, members: &[PersonId], generation: GenerationId) -> Result<()> {
+        let mut members = members.to_vec();
+        members.sort();
+        let conn = self.connection.lock().unwrap();
+        let group = insert_group(&conn, generation, &members)?;
+        // Nobody needs a conversation starter on their own.
+        if members.len() > 1 {
+            conn.execute(
+                "UPDATE matches SET icebreaker =
+                     (SELECT prompt FROM icebreakers ORDER BY random() LIMIT 1)
+                 WHERE id = ?1",
+                [group],
+            )?;
+        }
+        conn.execute(
+            "UPDATE generations SET updated_at = ?2 WHERE id = ?1",
+            params![generation, self.clock.timestamp()],
+        )?;
+        for p1id in &members {
+            conn.execute(
+                "UPDATE pool_members SET waiting = FALSE
+                 WHERE person = ?1 AND pool = (SELECT round_type FROM generations WHERE id = ?2)",
+                params![p1id, generation],
+            )?;
+            conn.execute(
+                "UPDATE people SET waiting = FALSE WHERE id = ?1 AND NOT EXISTS
+                     (SELECT 1 FROM pools WHERE name = (SELECT round_type FROM generations WHERE id = ?2))",
+                params![p1id, generation],
+            )?;
+            self.people_cache.remove(p1id);
+        }
+        Ok(())
+    }
+
+    pub fn add_matching_generation(
+        &self,
+        quality: &MatchQuality,
+        round_type: &str,
+        details: &GenerationDetails,
+    ) -> Result<MatchMeta> {
+        let time = self.clock.timestamp();
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let meta = tx
+            .query_row(
+                "insert into generations (time, updated_at, strategy, buckets, repeat_pairs, total_weight, round_type, title, notes)
+                 values (?1, ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 returning id, time, strategy, buckets, repeat_pairs, total_weight, round_type, title, notes",
+                params![
+                    time,
+                    quality.strategy,
+                    quality.buckets,
+                    quality.repeat_pairs,
+                    quality.total_weight,
+                    round_type,
+                    details.title,
+                    details.notes.as_deref().map(|notes| self.cipher.seal(notes))
+                ],
+                |row| MatchMeta::from_row(row, &self.cipher),
+            )?;
+        tx.commit()?;
+        Ok(meta)
+    }
+
+    // Everyone's timezone, for those who have one.
+    pub fn timezones(&self, people: &[PersonId]) -> Result<HashMap<PersonId, String>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt =
+            conn.prepare("SELECT timezone FROM people WHERE id = ?1 AND timezone IS NOT NULL")?;
+        let mut timezones = HashMap::new();
+        for person in people {
+            if let Some(timezone) = stmnt.query_row([person], |row| row.get(0)).optional()? {
+                timezones.insert(*person, timezone);
+            }
+        }
+        Ok(timezones)
+    }
+
+    pub fn waiters(&self) -> Result<Vec<PersonId>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn
+            .prepare(
+                "select id from people WHERE waiting = TRUE AND paused_until IS NULL AND deactivated_at IS NULL",
+            )?;
+        let mut rows = stmnt.query([])?;
+        let mut people = Vec::new();
+        while let Some(row) = rows.next()? {
+            people.push(row.get(0)?);
+        }
+        Ok(people)
+    }
+
+    // Weights between waiters for matching. Without a half life these are the raw counts, otherwise
+    // they are the decayed weights scaled by `EDGE_WEIGHT_SCALE`.
+    pub fn edges_for(
+        &self,
+        waiters: Vec<PersonId>,
+        half_life: Option<u32>,
+    ) -> Result<Vec<(PersonId, PersonId, u32)>> {
+        if half_life.is_some() {
+            return Ok(self
+                .edge_history_among(Some(&waiters), half_life)?
+                .into_iter()
+                .map(|e| {
+                    let weight = (e.effective_weight * EDGE_WEIGHT_SCALE).round() as u32;
+                    (e.person1, e.person2, weight)
+                })
+                .collect());
+        }
+        let conn = self.connection.lock().unwrap();
+        // Without the `+` SQLite looks up every pair of waiters in the primary key, rather than
+        // scanning each waiter's edges and checking the other end.
+        let mut stmnt = conn.prepare(
+            "SELECT person1, person2, weight FROM edges
+                 WHERE person1 IN (SELECT value FROM json_each(?1))
+                     AND +person2 IN (SELECT value FROM json_each(?1))",
+        )?;
+        let mut rows = stmnt.query([id_array(&waiters)])?;
+        let mut edges = Vec::new();
+        while let Some(row) = rows.next()? {
+            edges.push((row.get(0)?, row.get(1)?, row.get(2)?));
+        }
+        Ok(edges)
+    }
+
+    // Cached sessions remember when they were last seen, so they still expire, and `last_seen` is
+    // written again whenever one is loaded, at most `CACHE_TTL` behind.
+    pub fn get_session(&self, session_id: &str, now: i64) -> Result<Option<PersonId>> {
+        let key = session_id.to_owned();
+        if let Some((person, last_seen)) = self.sessions_cache.get(&key) {
+            if now - last_seen <= self.session_lifetime_seconds {
+                return Ok(Some(person));
+            }
+            self.sessions_cache.remove(&key);
+        }
+        let session = self.load_session(session_id, now)?;
+        if let Some(session) = session {
+            self.sessions_cache.insert(key, session);
+        }
+        Ok(session.map(|(person, _)| person))
+    }
+
+    // The person a session is for and when it was last seen, now, unless it has expired.
+    fn load_session(&self, session_id: &str, now: i64) -> Result<Option<(PersonId, i64)>> {
+        let conn = self.connection.lock().unwrap();
+        let Some((person, last_seen)) = conn
+            .query_row(
+                "SELECT person, last_seen FROM sessions WHERE id = ?1",
+                [hash_token(session_id)],
+                |row| Ok((row.get(0)?, row.get::<_, i64>(1)?)),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        if now - last_seen > self.session_lifetime_seconds {
+            conn.execute(
+                "DELETE FROM sessions WHERE id = ?1",
+                [hash_token(session_id)],
+            )?;
+            Ok(None)
+        } else {
+            let time = self.clock.timestamp();
+            conn.execute(
+                "UPDATE sessions SET last_seen = ?2 WHERE id = ?1",
+                params![hash_token(session_id), time],
+            )?;
+            Ok(Some((person, time)))
+        }
+    }
+
+    pub fn sign_in_session(
+        &self,
+        email: &str,
+        password: &str,
+    ) -> Result<Result<String, SignInError>> {
+        let expected: Option<(PersonId, String, bool)> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT id, password_hash, deactivated_at IS NOT NULL FROM auth JOIN people ON id = person WHERE email = ?1",
+                [self.stored_email(email)],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .optional()?;
+        let Some((person_id, expected_password_hash, deactivated)) = expected else {
+            return Ok(Err(SignInError::UnknownUser));
+        };
+        let time = self.clock.timestamp();
+        match self.check_password(person_id, password, &expected_password_hash, time)? {
+            None => return Ok(Err(SignInError::LockedOut)),
+            Some(false) => return Ok(Err(SignInError::InvalidPassword)),
+            Some(true) if deactivated => return Ok(Err(SignInError::Deactivated)),
+            Some(true) => {}
+        }
+        let conn = self.connection.lock().unwrap();
+        let session_id = insert_session(&conn, person_id, time)?;
+        conn.execute(
+            "UPDATE account_activity SET last_sign_in = ?2, flagged_at = NULL WHERE person = ?1",
+            params![person_id, time],
+        )?;
+        Ok(Ok(session_id))
+    }
+
+    // Check a password against the person's stored hash, recording a wrong one towards locking the
+    // account and clearing them on a right one. Argon2 is deliberately slow, so the connection isn't
+    // held while it runs. Returns None when the account is locked out.
+    fn check_password(
+        &self,
+        person_id: PersonId,
+        password: &str,
+        password_hash: &str,
+        now: i64,
+    ) -> Result<Option<bool>> {
+        if locked_out(
+            &self.connection.lock().unwrap(),
+            person_id,
+            self.lockout,
+            now,
+        )? {
+            return Ok(None);
+        }
+        let verified = verify_password(password, password_hash);
+        let conn = self.connection.lock().unwrap();
+        // Guesses made while this one was checked count too.
+        if locked_out(&conn, person_id, self.lockout, now)? {
+            return Ok(None);
+        }
+        if verified {
+            clear_failed_logins(&conn, person_id)?;
+        } else {
+            record_failed_login(&conn, person_id, now)?;
+        }
+        Ok(Some(verified))
+    }
+
+    pub fn sign_out_session(&self, session_id: &str) -> Result<()> {
+        let conn = self.connection.lock().unwrap();
+        conn.execute(
+            "DELETE FROM sessions WHERE id = ?1",
+            [hash_token(session_id)],
+        )?;
+        self.sessions_cache.remove(&session_id.to_owned());
+        Ok(())
+    }
+
+    // Flag accounts that were created before `created_before` and have never been signed in to
+    // or matched since, returning how many were newly flagged.
+    pub fn flag_stale_accounts(&self, created_before: i64, now: i64) -> Result<usize> {
+        Ok(self.connection.lock().unwrap().execute(
+            "UPDATE account_activity SET flagged_at = ?2
+                 WHERE flagged_at IS NULL AND last_sign_in IS NULL AND created_at < ?1
+                 AND person NOT IN (SELECT person FROM match_members)",
+            params![created_before, now],
+        )?)
+    }
+
+    pub fn stale_accounts(&self) -> Result<Vec<StaleAccount>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn
+            .prepare(
+                "select p.id, p.email, p.name, p.waiting, p.status, p.team, a.created_at, a.flagged_at from people p
+                 join account_activity a on a.person = p.id WHERE a.flagged_at IS NOT NULL",
+            )?;
+        let mut rows = stmnt.query([])?;
+
+        let mut accounts = Vec::new();
+        while let Some(row) = rows.next()? {
+            accounts.push(StaleAccount {
+                person: Person::from_row(row, 0, &self.cipher)?,
+                created_at: row.get(6)?,
+                flagged_at: row.get(7)?,
+            });
+        }
+        Ok(accounts)
+    }
+
+    // Clear the flag on an account the admin has decided to keep, it won't be flagged again
+    // as the last sign in is set to when it was reviewed.
+    pub fn keep_stale_account(&self, person_id: PersonId, now: i64) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE account_activity SET flagged_at = NULL, last_sign_in = ?2 WHERE person = ?1",
+            params![person_id, now],
+        )?;
+        Ok(())
+    }
+
+    // Delete an account that has been flagged as stale, returning whether anything was deleted.
+    pub fn delete_stale_account(&self, person_id: PersonId) -> Result<bool> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let flagged = tx
+            .query_row(
+                "SELECT 1 FROM account_activity WHERE person = ?1 AND flagged_at IS NOT NULL",
+                [person_id],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if !flagged {
+            return Ok(false);
+        }
+        delete_personal_data(&tx, person_id)?;
+        tx.execute("DELETE FROM people WHERE id = ?1", [person_id])?;
+        tx.commit()?;
+        self.people_cache.remove(&person_id);
+        self.sessions_cache
+            .retain(|_, (person, _)| *person != person_id);
+        Ok(true)
+    }
+
+    pub fn is_admin(&self, person_id: PersonId) -> Result<bool> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT 1 FROM admins WHERE person = ?1",
+                [person_id],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    // Admins of the default organisation run the whole deployment.
+    pub fn is_deployment_admin(&self, person_id: PersonId) -> Result<bool> {
+        Ok(self.is_admin(person_id)? && self.organisation_of(person_id)? == DEFAULT_ORGANISATION)
+    }
+
+    // Admins only manage people in their own organisation.
+    pub fn is_admin_of(&self, viewer: PersonId, person_id: PersonId) -> Result<bool> {
+        Ok(self.is_admin(viewer)? && self.same_organisation(viewer, person_id)?)
+    }
+
+    // Whether `viewer` can manage `person_id`, being them or an admin of their organisation.
+    pub fn can_manage(&self, viewer: PersonId, person_id: PersonId) -> Result<bool> {
+        Ok(viewer == person_id || self.is_admin_of(viewer, person_id)?)
+    }
+
+    pub fn same_organisation(&self, person_id: PersonId, other: PersonId) -> Result<bool> {
+        Ok(self.organisation_of(person_id)? == self.organisation_of(other)?)
+    }
+
+    pub fn organisation_of(&self, person_id: PersonId) -> Result<OrganisationId> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT organisation FROM people WHERE id = ?1",
+                [person_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(DEFAULT_ORGANISATION))
+    }
+
+    pub fn organisations_of(
+        &self,
+        people: &[PersonId],
+    ) -> Result<HashMap<PersonId, OrganisationId>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare("SELECT organisation FROM people WHERE id = ?1")?;
+        let mut organisations = HashMap::new();
+        for person in people {
+            if let Some(organisation) = stmnt.query_row([person], |row| row.get(0)).optional()? {
+                organisations.insert(*person, organisation);
+            }
+        }
+        Ok(organisations)
+    }
+
+    // Everyone in the organisation, for keeping what people see to their own.
+    pub fn organisation_members(&self, organisation: OrganisationId) -> Result<HashSet<PersonId>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare("SELECT id FROM people WHERE organisation = ?1")?;
+        let members = stmnt
+            .query_map([organisation], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(members)
+    }
+
+    pub fn people_in(&self, organisation: OrganisationId) -> Result<Vec<Person>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "select p.id, p.email, p.name, p.waiting, p.status, p.team from people p
+             where p.organisation = ?1",
+        )?;
+        let people = stmnt
+            .query_map([organisation], |row| Person::from_row(row, 0, &self.cipher))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(people)
+    }
+
+    pub fn organisations(&self) -> Result<Vec<Organisation>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT o.id, o.name, o.sign_up_token,
+                 (SELECT count(*) FROM people p WHERE p.organisation = o.id)
+             FROM organisations o ORDER BY o.id",
+        )?;
+        let organisations = stmnt
+            .query_map([], Organisation::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(organisations)
+    }
+
+    pub fn create_organisation(&self, name: &str) -> Result<Organisation> {
+        Ok(self.connection.lock().unwrap().query_row(
+            "INSERT INTO organisations (name, sign_up_token, created_at) VALUES (?1, ?2, ?3)
+             RETURNING id, name, sign_up_token, 0",
+            params![
+                name,
+                uuid::Uuid::new_v4().to_string(),
+                self.clock.timestamp()
+            ],
+            Organisation::from_row,
+        )?)
+    }
+
+    pub fn organisation_by_token(&self, token: &str) -> Result<Option<Organisation>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT o.id, o.name, o.sign_up_token,
+                     (SELECT count(*) FROM people p WHERE p.organisation = o.id)
+                 FROM organisations o WHERE o.sign_up_token = ?1",
+                [token],
+                Organisation::from_row,
+            )
+            .optional()?)
+    }
+
+    // Move someone to another organisation, returning false if either doesn't exist. Their matches
+    // and sessions go with them.
+    pub fn set_organisation(
+        &self,
+        person_id: PersonId,
+        organisation: OrganisationId,
+    ) -> Result<bool> {
+        let updated = self.connection.lock().unwrap().execute(
+            "UPDATE people SET organisation = ?2
+             WHERE id = ?1 AND EXISTS (SELECT 1 FROM organisations WHERE id = ?2)",
+            params![person_id, organisation],
+        )?;
+        Ok(updated > 0)
+    }
+
+    pub fn admins(&self) -> Result<Vec<Person>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn
+            .prepare("SELECT p.id, p.email, p.name, p.waiting, p.status, p.team FROM admins a JOIN people p ON p.id = a.person")?;
+        let mut rows = stmnt.query([])?;
+
+        let mut people = Vec::new();
+        while let Some(row) = rows.next()? {
+            people.push(Person::from_row(row, 0, &self.cipher)?);
+        }
+        Ok(people)
+    }
+
+    pub fn add_admin(&self, person_id: PersonId) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO admins (person) VALUES (?1) ON CONFLICT DO NOTHING",
+            [person_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_admin(&self, person_id: PersonId) -> Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM admins WHERE person = ?1", [person_id])?;
+        Ok(())
+    }
+
+    // Record the people considered for a generation, those that were left out of the matching
+    // carry the reason why.
+    pub fn snapshot_waiters(
+        &self,
+        generation: GenerationId,
+        included: &[PersonId],
+        excluded: &[(PersonId, String)],
+        floaters: &[PersonId],
+    ) -> Result<()> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let people = included
+            .iter()
+            .map(|id| (id, None))
+            .chain(excluded.iter().map(|(id, reason)| (id, Some(reason))));
+        for (person_id, reason) in people {
+            tx.execute(
+                "INSERT INTO generation_waiters (generation, person, name, email, excluded_reason, floater)
+                 SELECT ?1, id, name, email, ?3, ?4 FROM people WHERE id = ?2",
+                params![generation, person_id, reason, floaters.contains(person_id)],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn waiters_snapshot(&self, generation: GenerationId) -> Result<Vec<WaiterSnapshot>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn
+            .prepare("SELECT person, name, email, excluded_reason, floater FROM generation_waiters WHERE generation = ?1")?;
+        let mut rows = stmnt.query([generation])?;
+        let mut waiters = Vec::new();
+        while let Some(row) = rows.next()? {
+            waiters.push(WaiterSnapshot {
+                person_id: row.get(0)?,
+                name: row.get(1)?,
+                email: get_sealed(&self.cipher, row, 2)?,
+                excluded_reason: row.get(3)?,
+                floater: row.get(4)?,
+            });
+        }
+        Ok(waiters)
+    }
+
+    pub fn set_status(&self, person_id: PersonId, status: Option<&str>) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE people SET status = ?2 WHERE id = ?1",
+            params![person_id, status],
+        )?;
+        self.people_cache.remove(&person_id);
+        Ok(())
+    }
+
+    // Totals for one organisation, so nothing is given away about any other.
+    pub fn public_stats(&self, organisation: OrganisationId) -> Result<PublicStats> {
+        let conn = self.connection.lock().unwrap();
+        Ok(conn.query_row(
+            "WITH met AS (
+                 SELECT m.id, m.generation FROM matches m JOIN generations g ON m.generation = g.id
+                 WHERE g.notified_at IS NOT NULL
+                 AND (SELECT count(*) FROM match_members mm WHERE mm.match = m.id) > 1
+                 AND EXISTS (SELECT 1 FROM match_members mm JOIN people p ON p.id = mm.person
+                     WHERE mm.match = m.id AND p.organisation = ?1)
+             )
+             SELECT
+                 (SELECT count(DISTINCT mm.person) FROM match_members mm JOIN people p ON p.id = mm.person
+                     WHERE mm.match IN (SELECT id FROM met) AND p.organisation = ?1),
+                 (SELECT count(DISTINCT generation) FROM met),
+                 (SELECT count(*) FROM met)",
+            [organisation],
+            |row| {
+                Ok(PublicStats {
+                    participants: row.get(0)?,
+                    rounds: row.get(1)?,
+                    meetings: row.get(2)?,
+                })
+            },
+        )?)
+    }
+
+    pub fn stats(&self, organisation: OrganisationId) -> Result<Stats> {
+        let conn = self.connection.lock().unwrap();
+        let people: u32 = conn.query_row(
+            "SELECT count(*) FROM people WHERE organisation = ?1",
+            [organisation],
+            |r| r.get(0),
+        )?;
+        let memberships: u32 = conn.query_row(
+            "SELECT count(*) FROM match_members mm JOIN people p ON p.id = mm.person
+             WHERE p.organisation = ?1",
+            [organisation],
+            |r| r.get(0),
+        )?;
+        let average_matches_per_person = if people == 0 {
+            0.
+        } else {
+            f64::from(memberships) / f64::from(people)
+        };
+
+        let mut stmnt = conn
+            .prepare(
+                "WITH participants AS (
+                     SELECT DISTINCT strftime('%Y-%m', g.time, 'unixepoch') AS month, mm.person AS person,
+                         g.id AS generation
+                     FROM match_members mm JOIN matches m ON mm.match = m.id
+                     JOIN generations g ON m.generation = g.id
+                     JOIN people p ON p.id = mm.person
+                     WHERE p.organisation = ?1
+                 ), months AS (
+                     SELECT month, count(DISTINCT generation) AS generations
+                     FROM participants GROUP BY month
+                 )
+                 SELECT months.month, months.generations,
+                 (SELECT count(DISTINCT person) FROM participants p WHERE p.month = months.month)
+                 FROM months ORDER BY months.month",
+            )?;
+        let mut rows = stmnt.query([organisation])?;
+        let mut months = Vec::new();
+        while let Some(row) = rows.next()? {
+            let participants: u32 = row.get(2)?;
+            months.push(MonthStats {
+                month: row.get(0)?,
+                generations: row.get(1)?,
+                participants,
+                participation_rate: if people == 0 {
+                    0.
+                } else {
+                    f64::from(participants) / f64::from(people)
+                },
+            });
+        }
+
+        let floater_matches = conn.query_row(
+            "SELECT count(*) FROM generation_waiters w JOIN people p ON p.id = w.person
+             WHERE w.floater AND p.organisation = ?1",
+            [organisation],
+            |r| r.get(0),
+        )?;
+
+        let active_last_7_days = conn.query_row(
+            "SELECT count(DISTINCT s.person) FROM sessions s JOIN people p ON p.id = s.person
+             WHERE s.last_seen > ?1 AND p.organisation = ?2",
+            params![self.clock.timestamp() - 60 * 60 * 24 * 7, organisation],
+            |r| r.get(0),
+        )?;
+
+        Ok(Stats {
+            people,
+            active_last_7_days,
+            average_matches_per_person,
+            floater_matches,
+            months,
+        })
+    }
+
+    // Recount how many times each pair has met from the matches, replacing `edges`. Pairs with
+    // someone whose account was deleted are left out, as deleting it removes their edges.
+    pub fn rebuild_edges(&self) -> Result<EdgesRebuild> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "CREATE TEMP TABLE edges_before AS SELECT person1, person2, weight FROM edges",
+            [],
+        )?;
+        tx.execute("DELETE FROM edges", [])?;
+        tx.execute(
+            "WITH pairs AS (
+                 SELECT a.person AS a, b.person AS b
+                 FROM match_members a JOIN match_members b ON a.match = b.match AND a.person < b.person
+                 UNION ALL
+                 SELECT a.value, b.value
+                 FROM archived_matches m, json_each(m.members) a, json_each(m.members) b
+                 WHERE a.value < b.value
+             ), live AS (
+                 SELECT id FROM people WHERE email NOT LIKE 'deleted-%@invalid'
+             )
+             INSERT INTO edges (person1, person2, weight)
+             SELECT min(a, b), max(a, b), count(*) FROM pairs
+             WHERE a IN live AND b IN live
+             GROUP BY min(a, b), max(a, b)",
+            [],
+        )?;
+        let changed = tx.query_row(
+            "SELECT (SELECT count(*) FROM edges e WHERE NOT EXISTS (SELECT 1 FROM edges_before b
+                         WHERE b.person1 = e.person1 AND b.person2 = e.person2 AND b.weight = e.weight))
+                 + (SELECT count(*) FROM edges_before b WHERE NOT EXISTS (SELECT 1 FROM edges e
+                         WHERE e.person1 = b.person1 AND e.person2 = b.person2))",
+            [],
+            |row| row.get(0),
+        )?;
+        tx.execute("DROP TABLE edges_before", [])?;
+        let matches = tx.query_row(
+            "SELECT (SELECT count(*) FROM matches) + (SELECT count(*) FROM archived_matches)",
+            [],
+            |row| row.get(0),
+        )?;
+        let edges = tx.query_row("SELECT count(*) FROM edges", [], |row| row.get(0))?;
+        tx.commit()?;
+        Ok(EdgesRebuild {
+            matches,
+            edges,
+            changed,
+        })
+    }
+
+    // Forget wrong passwords too old to count towards a lockout.
+    pub fn purge_failed_logins(&self, now: i64) -> Result<usize> {
+        Ok(self.connection.lock().unwrap().execute(
+            "DELETE FROM failed_logins WHERE at <= ?1",
+            [now - self.lockout.seconds],
+        )?)
+    }
+
+    pub fn purge_expired_sessions(&self, now: i64) -> Result<usize> {
+        let expired_before = now - self.session_lifetime_seconds;
+        let purged = self.connection.lock().unwrap().execute(
+            "DELETE FROM sessions WHERE last_seen < ?1",
+            [expired_before],
+        )?;
+        self.sessions_cache
+            .retain(|_, (_, last_seen)| *last_seen >= expired_before);
+        Ok(purged)
+    }
+
+    // Create inactive accounts for Slack members, linking existing accounts by email. Returns
+    // the number of new accounts created.
+    pub fn import_slack_members(&self, members: &[(String, String, String)]) -> Result<usize> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut created = 0;
+        for (slack_id, email, name) in members {
+            let email = &self.stored_email(email);
+            created += tx
+                .execute(
+                    "INSERT INTO people (email, name, waiting) VALUES (?1, ?2, FALSE) ON CONFLICT DO NOTHING",
+                    [email, name],
+                )?;
+            tx.execute(
+                "INSERT INTO slack_identities (person, slack_id) SELECT id, ?2 FROM people WHERE email = ?1
+                 ON CONFLICT DO NOTHING",
+                [email, slack_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(created)
+    }
+
+    // The people who were left without a partner in the latest generation of the round type.
+    pub fn unmatched_in_latest_generation(&self, round_type: &str) -> Result<Vec<PersonId>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT mm.person FROM match_members mm JOIN matches m ON mm.match = m.id
+                 WHERE m.generation = (SELECT max(id) FROM generations WHERE round_type = ?1)
+                 AND NOT EXISTS (SELECT 1 FROM match_members o WHERE o.match = m.id AND o.person != mm.person)",
+        )?;
+        let mut rows = stmnt.query([round_type])?;
+        let mut people = Vec::new();
+        while let Some(row) = rows.next()? {
+            people.push(row.get(0)?);
+        }
+        Ok(people)
+    }
+
+    pub fn block(&self, person_id: PersonId, blocked_id: PersonId) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO blocked_pairs (person, blocked) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+            [person_id, blocked_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn unblock(&self, person_id: PersonId, blocked_id: PersonId) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM blocked_pairs WHERE person = ?1 AND blocked = ?2",
+            [person_id, blocked_id],
+        )?;
+        Ok(())
+    }
+
+    // The people that this person has blocked.
+    pub fn blocked_by(&self, person_id: PersonId) -> Result<Vec<Person>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT p.id, p.email, p.name, p.waiting, p.status, p.team FROM blocked_pairs b
+                 JOIN people p ON p.id = b.blocked WHERE b.person = ?1",
+        )?;
+        let mut rows = stmnt.query([person_id])?;
+        let mut people = Vec::new();
+        while let Some(row) = rows.next()? {
+            people.push(Person::from_row(row, 0, &self.cipher)?);
+        }
+        Ok(people)
+    }
+
+    // Pairs of waiters that must not be matched, whichever of them asked for it.
+    pub fn forbidden_pairs(&self, waiters: &[PersonId]) -> Result<Vec<(PersonId, PersonId)>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare("SELECT person, blocked FROM blocked_pairs")?;
+        let mut rows = stmnt.query([])?;
+        let waiters = HashSet::<&PersonId>::from_iter(waiters);
+        let mut pairs = Vec::new();
+        while let Some(row) = rows.next()? {
+            let p1 = row.get(0)?;
+            let p2 = row.get(1)?;
+            if waiters.contains(&p1) && waiters.contains(&p2) {
+                pairs.push((p1, p2));
+            }
+        }
+        Ok(pairs)
+    }
+
+    // Pairs of waiters in the same conflict group.
+    pub fn conflict_pairs(&self, waiters: &[PersonId]) -> Result<Vec<(PersonId, PersonId)>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT DISTINCT a.person, b.person FROM conflict_group_members a
+                 JOIN conflict_group_members b
+                     ON b.conflict_group = a.conflict_group AND a.person < b.person
+                 WHERE a.person IN (SELECT value FROM json_each(?1))
+                     AND +b.person IN (SELECT value FROM json_each(?1))",
+        )?;
+        let pairs = stmnt
+            .query_map([id_array(waiters)], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(pairs)
+    }
+
+    // Every conflict group with its members, by name.
+    pub fn conflict_groups(&self) -> Result<Vec<ConflictGroup>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare("SELECT id, name FROM conflict_groups ORDER BY name, id")?;
+        let mut groups: Vec<ConflictGroup> = stmnt
+            .query_map([], |row| {
+                Ok(ConflictGroup {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    members: Vec::new(),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut stmnt = conn.prepare(
+            "SELECT p.id, p.email, p.name, p.waiting, p.status, p.team FROM conflict_group_members m
+                 JOIN people p ON p.id = m.person
+                 WHERE m.conflict_group = ?1 ORDER BY p.name",
+        )?;
+        for group in &mut groups {
+            let mut rows = stmnt.query([group.id])?;
+            while let Some(row) = rows.next()? {
+                group.members.push(Person::from_row(row, 0, &self.cipher)?);
+            }
+        }
+        Ok(groups)
+    }
+
+    pub fn create_conflict_group(&self, name: &str) -> Result<ConflictGroupId> {
+        let conn = self.connection.lock().unwrap();
+        conn.execute(
+            "INSERT INTO conflict_groups (name, created_at) VALUES (?1, ?2)",
+            params![name, self.clock.timestamp()],
+        )?;
+        Ok(ConflictGroupId(conn.last_insert_rowid() as u32))
+    }
+
+    // Returns false when there's no such group.
+    pub fn delete_conflict_group(&self, group: ConflictGroupId) -> Result<bool> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM conflict_group_members WHERE conflict_group = ?1",
+            [group],
+        )?;
+        let deleted = tx.execute("DELETE FROM conflict_groups WHERE id = ?1", [group])?;
+        tx.commit()?;
+        Ok(deleted > 0)
+    }
+
+    // Add everyone with one of the emails to the group, returning the emails without an account, or
+    // None when there's no such group.
+    pub fn add_conflict_group_members(
+        &self,
+        group: ConflictGroupId,
+        emails: &[String],
+    ) -> Result<Option<Vec<String>>> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM conflict_groups WHERE id = ?1)",
+            [group],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(None);
+        }
+        let mut unknown = Vec::new();
+        for email in emails {
+            let person: Option<PersonId> = tx
+                .query_row(
+                    "SELECT id FROM people WHERE email = ?1",
+                    [self.stored_email(email)],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let Some(person) = person else {
+                unknown.push(email.clone());
+                continue;
+            };
+            tx.execute(
+                "INSERT INTO conflict_group_members (conflict_group, person) VALUES (?1, ?2)
+                 ON CONFLICT DO NOTHING",
+                params![group, person],
+            )?;
+        }
+        tx.commit()?;
+        Ok(Some(unknown))
+    }
+
+    pub fn remove_conflict_group_member(
+        &self,
+        group: ConflictGroupId,
+        person_id: PersonId,
+    ) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM conflict_group_members WHERE conflict_group = ?1 AND person = ?2",
+            params![group, person_id],
+        )?;
+        Ok(())
+    }
+
+    // The names of the conflict groups someone is in.
+    pub fn conflict_groups_of(&self, person_id: PersonId) -> Result<Vec<String>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT g.name FROM conflict_group_members m
+                 JOIN conflict_groups g ON g.id = m.conflict_group
+                 WHERE m.person = ?1 ORDER BY g.name",
+        )?;
+        let names = stmnt
+            .query_map([person_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(names)
+    }
+
+    // Save a season plan for the round type, each round's pairs in order, replacing any plan still
+    // in progress.
+    pub fn create_season_plan(
+        &self,
+        round_type: &str,
+        rounds: &[Vec<(PersonId, PersonId)>],
+    ) -> Result<SeasonPlan> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let now = self.clock.timestamp();
+        tx.execute(
+            "UPDATE season_plans SET cancelled_at = ?2 WHERE round_type = ?1 AND cancelled_at IS NULL",
+            params![round_type, now],
+        )?;
+        tx.execute(
+            "INSERT INTO season_plans (round_type, created_at, rounds) VALUES (?1, ?2, ?3)",
+            params![round_type, now, rounds.len()],
+        )?;
+        let id = tx.last_insert_rowid() as u32;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO season_plan_pairs (plan, round, person1, person2) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (round, pairs) in rounds.iter().enumerate() {
+                for (a, b) in pairs {
+                    insert.execute(params![id, round, a, b])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(SeasonPlan {
+            id,
+            round_type: round_type.to_owned(),
+            created_at: now,
+            rounds: rounds.len() as u32,
+            done: 0,
+            next_round: (!rounds.is_empty()).then_some(0),
+        })
+    }
+
+    // The round type's season plan, unless it was cancelled or replaced.
+    pub fn season_plan(&self, round_type: &str) -> Result<Option<SeasonPlan>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT p.id, p.round_type, p.created_at, p.rounds,
+                     (SELECT count(*) FROM season_plan_rounds r WHERE r.plan = p.id),
+                     CASE
+                         WHEN p.rounds > 0 AND NOT EXISTS
+                             (SELECT 1 FROM season_plan_rounds r WHERE r.plan = p.id AND r.round = 0)
+                         THEN 0
+                         ELSE (SELECT min(r.round + 1) FROM season_plan_rounds r
+                             WHERE r.plan = p.id AND r.round + 1 < p.rounds AND NOT EXISTS
+                                 (SELECT 1 FROM season_plan_rounds s
+                                  WHERE s.plan = p.id AND s.round = r.round + 1))
+                     END
+                 FROM season_plans p
+                 WHERE p.round_type = ?1 AND p.cancelled_at IS NULL
+                 ORDER BY p.id DESC LIMIT 1",
+                [round_type],
+                |row| {
+                    Ok(SeasonPlan {
+                        id: row.get(0)?,
+                        round_type: row.get(1)?,
+                        created_at: row.get(2)?,
+                        rounds: row.get(3)?,
+                        done: row.get(4)?,
+                        next_round: row.get(5)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    // Returns false when the round type had no plan to cancel.
+    pub fn cancel_season_plan(&self, round_type: &str) -> Result<bool> {
+        let cancelled = self.connection.lock().unwrap().execute(
+            "UPDATE season_plans SET cancelled_at = ?2 WHERE round_type = ?1 AND cancelled_at IS NULL",
+            params![round_type, self.clock.timestamp()],
+        )?;
+        Ok(cancelled > 0)
+    }
+
+    pub fn season_plan_pairs(&self, plan: u32, round: u32) -> Result<Vec<(PersonId, PersonId)>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT person1, person2 FROM season_plan_pairs WHERE plan = ?1 AND round = ?2",
+        )?;
+        let pairs = stmnt
+            .query_map(params![plan, round], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(pairs)
+    }
+
+    pub fn record_season_round(
+        &self,
+        plan: u32,
+        round: u32,
+        generation: GenerationId,
+    ) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO season_plan_rounds (plan, round, generation) VALUES (?1, ?2, ?3)",
+            params![plan, round, generation],
+        )?;
+        Ok(())
+    }
+
+    pub fn icebreakers(&self) -> Result<Vec<Icebreaker>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare("SELECT id, prompt FROM icebreakers ORDER BY id")?;
+        let icebreakers = stmnt
+            .query_map([], |row| {
+                Ok(Icebreaker {
+                    id: row.get(0)?,
+                    prompt: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(icebreakers)
+    }
+
+    pub fn create_icebreaker(&self, prompt: &str) -> Result<IcebreakerId> {
+        let conn = self.connection.lock().unwrap();
+        conn.execute(
+            "INSERT INTO icebreakers (prompt, created_at) VALUES (?1, ?2)",
+            params![prompt, self.clock.timestamp()],
+        )?;
+        Ok(IcebreakerId(conn.last_insert_rowid() as u32))
+    }
+
+    // Returns false when there's no such prompt.
+    pub fn update_icebreaker(&self, icebreaker: IcebreakerId, prompt: &str) -> Result<bool> {
+        let updated = self.connection.lock().unwrap().execute(
+            "UPDATE icebreakers SET prompt = ?2 WHERE id = ?1",
+            params![icebreaker, prompt],
+        )?;
+        Ok(updated > 0)
+    }
+
+    // Returns false when there's no such prompt.
+    pub fn delete_icebreaker(&self, icebreaker: IcebreakerId) -> Result<bool> {
+        let deleted = self
+            .connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM icebreakers WHERE id = ?1", [icebreaker])?;
+        Ok(deleted > 0)
+    }
+
+    // Everyone active in the organisation who is in the segment, by name.
+    pub fn segment_members(
+        &self,
+        organisation: OrganisationId,
+        segment: Segment,
+    ) -> Result<Vec<Person>> {
+        let now = self.clock.timestamp();
+        let since = now - INACTIVE_DAYS * 24 * 60 * 60;
+        let (condition, param) = match segment {
+            Segment::NeverMatched => (
+                "p.id NOT IN (SELECT person FROM match_members)
+                 AND NOT EXISTS (SELECT 1 FROM archived_matches m, json_each(m.members) a
+                                 WHERE a.value = p.id)",
+                None,
+            ),
+            Segment::Inactive => (
+                "coalesce((SELECT coalesce(a.last_sign_in, a.created_at) FROM account_activity a
+                           WHERE a.person = p.id), 0) < ?2",
+                Some(since),
+            ),
+            // Only rounds people wait for on their own page count, not pools.
+            Segment::LongWaiting => (
+                "p.waiting AND (SELECT count(*) FROM generations g
+                     WHERE g.round_type NOT IN (SELECT name FROM pools)
+                         AND g.time > (SELECT max(at) FROM waiting_changes w
+                                       WHERE w.person = p.id AND w.waiting)) > ?2",
+                Some(i64::from(LONG_WAITING_ROUNDS)),
+            ),
+        };
+        let params: Vec<i64> = std::iter::once(i64::from(organisation.0))
+            .chain(param)
+            .collect();
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(&format!(
+            "SELECT p.id, p.email, p.name, p.waiting, p.status, p.team FROM people p
+             WHERE p.organisation = ?1 AND p.deactivated_at IS NULL AND {condition}
+             ORDER BY p.name"
+        ))?;
+        let people = stmnt
+            .query_map(rusqlite::params_from_iter(params), |row| {
+                Person::from_row(row, 0, &self.cipher)
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(people)
+    }
+
+    // Record an email from `sent_by` to everyone in `recipients`, kept with the sender's
+    // organisation. It is pending for those with an unsubscribe token in `tokens` and suppressed
+    // for anyone who turned emails off.
+    pub fn create_mailing(
+        &self,
+        segment: Segment,
+        subject: &str,
+        body: &str,
+        sent_by: PersonId,
+        recipients: &[PersonId],
+        tokens: &HashMap<PersonId, String>,
+    ) -> Result<MailingId> {
+        let now = self.clock.timestamp();
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO mailings (organisation, segment, subject, body, sent_by, created_at)
+             VALUES ((SELECT organisation FROM people WHERE id = ?4), ?1, ?2, ?3, ?4, ?5)",
+            params![segment.as_str(), subject, body, sent_by, now],
+        )?;
+        let mailing = MailingId(tx.last_insert_rowid() as u32);
+        for person in recipients {
+            let status = if tokens.contains_key(person) {
+                NotificationStatus::Pending
+            } else {
+                NotificationStatus::Suppressed
+            };
+            tx.execute(
+                "INSERT INTO mailing_recipients (mailing, person, status, updated_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![mailing, person, status.as_str(), now],
+            )?;
+        }
+        tx.commit()?;
+        Ok(mailing)
+    }
+
+    // Record how sending the mailing to someone went.
+    pub fn set_mailing_recipient(
+        &self,
+        mailing: MailingId,
+        person_id: PersonId,
+        status: NotificationStatus,
+        error: Option<&str>,
+    ) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE mailing_recipients SET status = ?3, error = ?4, updated_at = ?5
+                 WHERE mailing = ?1 AND person = ?2",
+            params![
+                mailing,
+                person_id,
+                status.as_str(),
+                error,
+                self.clock.timestamp()
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Every mailing sent in the organisation, newest first.
+    pub fn mailings(&self, organisation: OrganisationId) -> Result<Vec<Mailing>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT m.id, m.segment, m.subject, p.name, m.created_at, count(r.person),
+                     count(*) FILTER (WHERE r.status IN ('sent', 'delivered')),
+                     count(*) FILTER (WHERE r.status IN ('failed', 'bounced')),
+                     count(*) FILTER (WHERE r.status = 'suppressed')
+                 FROM mailings m
+                 LEFT JOIN people p ON p.id = m.sent_by
+                 LEFT JOIN mailing_recipients r ON r.mailing = m.id
+                 WHERE m.organisation = ?1
+                 GROUP BY m.id
+                 ORDER BY m.id DESC",
+        )?;
+        let mailings = stmnt
+            .query_map([organisation], |row| {
+                let segment: String = row.get(1)?;
+                Ok(Mailing {
+                    id: row.get(0)?,
+                    segment: Segment::parse(&segment),
+                    subject: row.get(2)?,
+                    sent_by: row.get(3)?,
+                    created_at: row.get(4)?,
+                    recipients: row.get(5)?,
+                    sent: row.get(6)?,
+                    failed: row.get(7)?,
+                    suppressed: row.get(8)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(mailings)
+    }
+
+    // Everyone active who isn't waiting or paused and hasn't been reminded about the run at
+    // `round_at` yet.
+    pub fn reminder_recipients(&self, round_at: i64) -> Result<Vec<Person>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT p.id, p.email, p.name, p.waiting, p.status, p.team FROM people p
+             WHERE NOT p.waiting AND p.paused_until IS NULL AND p.deactivated_at IS NULL
+                 AND p.id NOT IN (SELECT person FROM round_reminders WHERE round_at = ?1)
+             ORDER BY p.id",
+        )?;
+        let people = stmnt
+            .query_map([round_at], |row| Person::from_row(row, 0, &self.cipher))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(people)
+    }
+
+    // Record that someone is being reminded about the run at `round_at`, returning the token for
+    // their link to opt in. It's recorded before sending so the link works as soon as it arrives, and
+    // forgotten with `forget_reminders` if the email couldn't be sent.
+    pub fn create_reminder(&self, round_at: i64, person_id: PersonId) -> Result<String> {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO round_reminders (round_at, person, token, sent_at) VALUES (?1, ?2, ?3, ?4)",
+            params![round_at, person_id, hash_token(&token), self.clock.timestamp()],
+        )?;
+        Ok(token)
+    }
+
+    // Forget the reminders about the run at `round_at` that couldn't be sent, so they are tried again.
+    pub fn forget_reminders(&self, round_at: i64, people: &[PersonId]) -> Result<()> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        for person_id in people {
+            tx.execute(
+                "DELETE FROM round_reminders
+                 WHERE round_at = ?1 AND person = ?2 AND opted_in_at IS NULL",
+                params![round_at, person_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Start whoever a reminder's token was for waiting, returning who and the run they joined, or
+    // `None` if the token is unknown or that run has happened.
+    pub fn opt_in(&self, token: &str) -> Result<Option<(PersonId, i64)>> {
+        let now = self.clock.timestamp();
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let Some((person_id, round_at)): Option<(PersonId, i64)> = tx
+            .query_row(
+                "UPDATE round_reminders SET opted_in_at = coalesce(opted_in_at, ?2)
+                 WHERE token = ?1 AND round_at > ?2
+                 RETURNING person, round_at",
+                params![hash_token(token), now],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let updated = tx.execute(
+            "UPDATE people SET waiting = TRUE, paused_until = NULL
+             WHERE id = ?1 AND deactivated_at IS NULL",
+            [person_id],
+        )?;
+        if updated == 0 {
+            return Ok(None);
+        }
+        tx.commit()?;
+        self.people_cache.remove(&person_id);
+        Ok(Some((person_id, round_at)))
+    }
+
+    // Waiters where one is the other's direct manager.
+    pub fn manager_pairs(&self, waiters: &[PersonId]) -> Result<Vec<(PersonId, PersonId)>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare("SELECT id, manager FROM people WHERE manager IS NOT NULL")?;
+        let mut rows = stmnt.query([])?;
+        let waiters = HashSet::<&PersonId>::from_iter(waiters);
+        let mut pairs = Vec::new();
+        while let Some(row) = rows.next()? {
+            let report = row.get(0)?;
+            let manager = row.get(1)?;
+            if waiters.contains(&report) && waiters.contains(&manager) {
+                pairs.push((report, manager));
+            }
+        }
+        Ok(pairs)
+    }
+
+    pub fn manager(&self, person_id: PersonId) -> Result<Option<Person>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT m.id, m.email, m.name, m.waiting, m.status, m.team
+                 FROM people p JOIN people m ON m.id = p.manager WHERE p.id = ?1",
+                [person_id],
+                |row| Person::from_row(row, 0, &self.cipher),
+            )
+            .optional()?)
+    }
+
+    // Set each person's manager from an org chart keyed by email, clearing it when there's none.
+    // Everyone is updated together so a chart can't be left half imported.
+    pub fn import_managers(&self, people: &[(String, Option<String>)]) -> Result<ManagersImport> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut import = ManagersImport {
+            updated: 0,
+            unknown: Vec::new(),
+        };
+        let id_of = |email: &str| -> rusqlite::Result<Option<PersonId>> {
+            tx.query_row(
+                "SELECT id FROM people WHERE email = ?1",
+                [self.stored_email(email)],
+                |row| row.get(0),
+            )
+            .optional()
+        };
+        for (email, manager) in people {
+            let Some(person) = id_of(email)? else {
+                import.unknown.push(email.clone());
+                continue;
+            };
+            let manager = match manager {
+                Some(manager) => match id_of(manager)? {
+                    // Nobody manages themselves, treated as having no manager.
+                    Some(id) => Some(id).filter(|id| *id != person),
+                    None => {
+                        import.unknown.push(manager.clone());
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            import.updated += tx.execute(
+                "UPDATE people SET manager = ?2 WHERE id = ?1",
+                params![person, manager],
+            )?;
+        }
+        tx.commit()?;
+        import.unknown.sort();
+        import.unknown.dedup();
+        Ok(import)
+    }
+
+    pub fn set_max_matches_per_season(
+        &self,
+        person_id: PersonId,
+        max_matches: Option<u32>,
+    ) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE people SET max_matches_per_season = ?2 WHERE id = ?1",
+            params![person_id, max_matches],
+        )?;
+        Ok(())
+    }
+
+    // How many times the person has been matched since the season started and their limit.
+    pub fn season_matches(
+        &self,
+        person_id: PersonId,
+        season_start: i64,
+    ) -> Result<(u32, Option<u32>)> {
+        Ok(self.connection.lock().unwrap().query_row(
+            "SELECT (SELECT count(*) FROM match_members mm JOIN matches m ON mm.match = m.id
+                     JOIN generations g ON m.generation = g.id
+                     WHERE g.time >= ?2 AND mm.person = ?1
+                     AND EXISTS (SELECT 1 FROM match_members o WHERE o.match = m.id AND o.person != ?1)),
+                 max_matches_per_season FROM people WHERE id = ?1",
+            params![person_id, season_start],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?)
+    }
+
+    // People who have already been matched as many times this season as they want to be.
+    pub fn waiters_at_season_limit(&self, season_start: i64) -> Result<Vec<PersonId>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn
+            .prepare(
+                "SELECT p.id FROM people p WHERE p.max_matches_per_season IS NOT NULL
+                 AND p.max_matches_per_season <= (SELECT count(*) FROM match_members mm
+                     JOIN matches m ON mm.match = m.id JOIN generations g ON m.generation = g.id
+                     WHERE g.time >= ?1 AND mm.person = p.id
+                     AND EXISTS (SELECT 1 FROM match_members o WHERE o.match = m.id AND o.person != p.id))",
+            )?;
+        let mut rows = stmnt.query([season_start])?;
+        let mut people = Vec::new();
+        while let Some(row) = rows.next()? {
+            people.push(row.get(0)?);
+        }
+        Ok(people)
+    }
+
+    // `None` (or 1) is every round.
+    pub fn set_match_every_rounds(
+        &self,
+        person_id: PersonId,
+        every_rounds: Option<u32>,
+    ) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE people SET match_every_rounds = ?2 WHERE id = ?1",
+            params![person_id, every_rounds],
+        )?;
+        Ok(())
+    }
+
+    pub fn match_every_rounds(&self, person_id: PersonId) -> Result<Option<u32>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT match_every_rounds FROM people WHERE id = ?1",
+                [person_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    // People who were matched too recently in the round type for how often they want to be
+    // matched, with that cadence. Someone matching every 3 rounds sits out the 2 rounds after
+    // being matched.
+    pub fn waiters_resting(&self, round_type: &str) -> Result<Vec<(PersonId, u32)>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT p.id, p.match_every_rounds FROM people p WHERE p.match_every_rounds > 1
+                 AND EXISTS (SELECT 1 FROM match_members mm JOIN matches m ON mm.match = m.id
+                     JOIN generations g ON m.generation = g.id
+                     WHERE g.round_type = ?1 AND mm.person = p.id
+                     AND EXISTS (SELECT 1 FROM match_members o WHERE o.match = m.id AND o.person != p.id)
+                     AND (SELECT count(*) FROM generations later
+                          WHERE later.round_type = ?1 AND later.id >= g.id) < p.match_every_rounds)",
+        )?;
+        let mut rows = stmnt.query([round_type])?;
+        let mut people = Vec::new();
+        while let Some(row) = rows.next()? {
+            people.push((row.get(0)?, row.get(1)?));
+        }
+        Ok(people)
+    }
+
+    // Stop them waiting for the round type, in its pool if it's one.
+    pub fn stop_waiting(&self, person_ids: &[PersonId], round_type: &str) -> Result<()> {
+        let conn = self.connection.lock().unwrap();
+        for person_id in person_ids {
+            conn.execute(
+                "UPDATE pool_members SET waiting = FALSE WHERE pool = ?2 AND person = ?1",
+                params![person_id, round_type],
+            )?;
+            conn.execute(
+                "UPDATE people SET waiting = FALSE
+                 WHERE id = ?1 AND NOT EXISTS (SELECT 1 FROM pools WHERE name = ?2)",
+                params![person_id, round_type],
+            )?;
+            self.people_cache.remove(person_id);
+        }
+        Ok(())
+    }
+
+    pub fn pools(&self) -> Result<Vec<Pool>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT p.name, p.description,
+                 (SELECT count(*) FROM pool_members m WHERE m.pool = p.name),
+                 (SELECT count(*) FROM pool_members m WHERE m.pool = p.name AND m.waiting),
+                 p.allow_manager_pairs, p.archived_at
+             FROM pools p ORDER BY p.name",
+        )?;
+        let mut pools = stmnt
+            .query_map([], |row| {
+                Ok(Pool {
+                    name: row.get(0)?,
+                    description: row.get(1)?,
+                    members: row.get(2)?,
+                    waiting: row.get(3)?,
+                    allow_manager_pairs: row.get(4)?,
+                    archived_at: row.get(5)?,
+                    admins: Vec::new(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<Pool>>>()?;
+        let mut stmnt = conn.prepare(
+            "SELECT p.id, p.email, p.name, p.waiting, p.status, p.team FROM pool_admins a
+                 JOIN people p ON p.id = a.person
+                 WHERE a.pool = ?1 ORDER BY p.name",
+        )?;
+        for pool in &mut pools {
+            let mut rows = stmnt.query([&pool.name])?;
+            while let Some(row) = rows.next()? {
+                pool.admins.push(Person::from_row(row, 0, &self.cipher)?);
+            }
+        }
+        Ok(pools)
+    }
+
+    // The pools someone has been appointed to run, deployment admins run every pool without being
+    // appointed.
+    pub fn administered_pools(&self, person_id: PersonId) -> Result<Vec<String>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt =
+            conn.prepare("SELECT pool FROM pool_admins WHERE person = ?1 ORDER BY pool")?;
+        let pools = stmnt
+            .query_map([person_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(pools)
+    }
+
+    // Whether someone can manage a pool's members and run it.
+    pub fn is_pool_admin(&self, person_id: PersonId, pool: &str) -> Result<bool> {
+        if self.is_deployment_admin(person_id)? {
+            return Ok(true);
+        }
+        Ok(self.connection.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM pool_admins WHERE pool = ?1 AND person = ?2)",
+            params![pool, person_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    // Returns false when there's no such pool or person.
+    pub fn add_pool_admin(&self, pool: &str, person_id: PersonId) -> Result<bool> {
+        let conn = self.connection.lock().unwrap();
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM pools WHERE name = ?1)
+                 AND EXISTS (SELECT 1 FROM people WHERE id = ?2)",
+            params![pool, person_id],
+            |row| row.get(0),
+        )?;
+        if exists {
+            conn.execute(
+                "INSERT INTO pool_admins (pool, person) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+                params![pool, person_id],
+            )?;
+        }
+        Ok(exists)
+    }
+
+    pub fn remove_pool_admin(&self, pool: &str, person_id: PersonId) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM pool_admins WHERE pool = ?1 AND person = ?2",
+            params![pool, person_id],
+        )?;
+        Ok(())
+    }
+
+    // Everyone in a pool, for its admins.
+    pub fn pool_members(&self, pool: &str) -> Result<Vec<PoolParticipant>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT p.id, p.email, p.name, p.waiting, p.status, p.team, m.waiting
+                 FROM pool_members m JOIN people p ON p.id = m.person
+                 WHERE m.pool = ?1 ORDER BY p.name",
+        )?;
+        let mut rows = stmnt.query([pool])?;
+        let mut members = Vec::new();
+        while let Some(row) = rows.next()? {
+            members.push(PoolParticipant {
+                person: Person::from_row(row, 0, &self.cipher)?,
+                pool_waiting: row.get(6)?,
+            });
+        }
+        Ok(members)
+    }
+
+    pub fn person_with_email(&self, email: &str) -> Result<Option<PersonId>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT id FROM people WHERE email = ?1",
+                [self.stored_email(email)],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn is_pool(&self, name: &str) -> Result<bool> {
+        Ok(self.connection.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM pools WHERE name = ?1)",
+            [name],
+            |row| row.get(0),
+        )?)
+    }
+
+    // A pool that hasn't been archived, so can be joined and run.
+    pub fn is_active_pool(&self, name: &str) -> Result<bool> {
+        Ok(self.connection.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM pools WHERE name = ?1 AND archived_at IS NULL)",
+            [name],
+            |row| row.get(0),
+        )?)
+    }
+
+    // Pools created before `idle_since` that haven't run since, with nobody waiting for them.
+    pub fn idle_pools(&self, idle_since: i64) -> Result<Vec<String>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT p.name FROM pools p
+             WHERE p.archived_at IS NULL AND p.created_at < ?1
+                 AND NOT EXISTS (SELECT 1 FROM generations g WHERE g.round_type = p.name AND g.time >= ?1)
+                 AND NOT EXISTS (SELECT 1 FROM pool_members m WHERE m.pool = p.name AND m.waiting)
+             ORDER BY p.name",
+        )?;
+        let names = stmnt
+            .query_map([idle_since], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(names)
+    }
+
+    // Move the pool's matches into `archived_matches`, a row per group, and stop it being joined or
+    // run. Returns false when there's no such pool or it's already archived.
+    pub fn archive_pool(&self, pool: &str) -> Result<bool> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let archived = tx.execute(
+            "UPDATE pools SET archived_at = ?2 WHERE name = ?1 AND archived_at IS NULL",
+            params![pool, self.clock.timestamp()],
+        )?;
+        if archived == 0 {
+            return Ok(false);
+        }
+        for statement in [
+            "INSERT INTO archived_matches (generation, members, icebreaker)
+             SELECT m.generation,
+                 (SELECT json_group_array(person) FROM
+                     (SELECT person FROM match_members WHERE match = m.id ORDER BY person)),
+                 m.icebreaker
+             FROM matches m JOIN generations g ON g.id = m.generation
+             WHERE g.round_type = ?1 ORDER BY m.id",
+            "DELETE FROM match_members WHERE match IN (SELECT m.id FROM matches m
+                 JOIN generations g ON g.id = m.generation WHERE g.round_type = ?1)",
+            "DELETE FROM matches WHERE generation IN (SELECT id FROM generations WHERE round_type = ?1)",
+        ] {
+            tx.execute(statement, [pool])?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    // Put an archived pool's matches back, leaving the meeting counts as they are as they were
+    // never taken out. Returns false when there's no such archived pool.
+    pub fn reactivate_pool(&self, pool: &str) -> Result<bool> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let reactivated = tx.execute(
+            "UPDATE pools SET archived_at = NULL WHERE name = ?1 AND archived_at IS NOT NULL",
+            [pool],
+        )?;
+        if reactivated == 0 {
+            return Ok(false);
+        }
+        let groups: Vec<(GenerationId, String, Option<String>)> = {
+            let mut stmnt = tx.prepare(
+                "SELECT a.generation, a.members, a.icebreaker FROM archived_matches a
+                 JOIN generations g ON g.id = a.generation
+                 WHERE g.round_type = ?1 ORDER BY a.rowid",
+            )?;
+            let rows = stmnt
+                .query_map([pool], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            rows
+        };
+        for (generation, members, icebreaker) in groups {
+            tx.execute(
+                "INSERT INTO matches (generation, icebreaker) VALUES (?1, ?2)",
+                params![generation, icebreaker],
+            )?;
+            tx.execute(
+                "INSERT INTO match_members (match, person) SELECT ?1, value FROM json_each(?2)",
+                params![tx.last_insert_rowid(), members],
+            )?;
+        }
+        tx.execute(
+            "DELETE FROM archived_matches
+             WHERE generation IN (SELECT id FROM generations WHERE round_type = ?1)",
+            [pool],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    // Only pools can allow it, managers are never matched with their reports in round types.
+    pub fn allows_manager_pairs(&self, round_type: &str) -> Result<bool> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT allow_manager_pairs FROM pools WHERE name = ?1",
+                [round_type],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(false))
+    }
+
+    // Returns false when there's no such pool.
+    pub fn set_allow_manager_pairs(&self, pool: &str, allowed: bool) -> Result<bool> {
+        let updated = self.connection.lock().unwrap().execute(
+            "UPDATE pools SET allow_manager_pairs = ?2 WHERE name = ?1",
+            params![pool, allowed],
+        )?;
+        Ok(updated > 0)
+    }
+
+    // Returns false when there's already a pool with the name.
+    pub fn create_pool(&self, name: &str, description: &str) -> Result<bool> {
+        let created = self.connection.lock().unwrap().execute(
+            "INSERT INTO pools (name, description, created_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT DO NOTHING",
+            params![name, description, self.clock.timestamp()],
+        )?;
+        Ok(created > 0)
+    }
+
+    // Every pool, archived ones too, and whether the person is in it and waiting for its next round.
+    pub fn pool_memberships(&self, person_id: PersonId) -> Result<Vec<PoolMembership>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT p.name, p.description, m.person IS NOT NULL, coalesce(m.waiting, FALSE),
+                 p.archived_at IS NOT NULL
+             FROM pools p LEFT JOIN pool_members m ON m.pool = p.name AND m.person = ?1
+             ORDER BY p.name",
+        )?;
+        let memberships = stmnt
+            .query_map([person_id], |row| {
+                Ok(PoolMembership {
+                    pool: row.get(0)?,
+                    description: row.get(1)?,
+                    member: row.get(2)?,
+                    waiting: row.get(3)?,
+                    archived: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(memberships)
+    }
+
+    // Joining a pool starts waiting for its next round, leaving it forgets the person was in it.
+    pub fn set_pool_member(&self, pool: &str, person_id: PersonId, member: bool) -> Result<()> {
+        let conn = self.connection.lock().unwrap();
+        if member {
+            conn.execute(
+                "INSERT INTO pool_members (pool, person, waiting)
+                 SELECT name, ?2, TRUE FROM pools WHERE name = ?1 AND archived_at IS NULL
+                 ON CONFLICT DO NOTHING",
+                params![pool, person_id],
+            )?;
+        } else {
+            conn.execute(
+                "DELETE FROM pool_members WHERE pool = ?1 AND person = ?2",
+                params![pool, person_id],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn toggle_pool_waiter(&self, pool: &str, person_id: PersonId) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE pool_members SET waiting = NOT waiting WHERE pool = ?1 AND person = ?2
+                 AND pool IN (SELECT name FROM pools WHERE archived_at IS NULL)",
+            params![pool, person_id],
+        )?;
+        Ok(())
+    }
+
+    // Who is waiting for the next round of the round type, those waiting in its pool if it's one.
+    // Paused and deactivated people aren't waiting for any pool.
+    pub fn waiters_for(&self, round_type: &str) -> Result<Vec<PersonId>> {
+        if !self.is_pool(round_type)? {
+            return self.waiters();
+        }
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT p.id FROM pool_members m JOIN people p ON p.id = m.person
+             WHERE m.pool = ?1 AND m.waiting AND p.paused_until IS NULL AND p.deactivated_at IS NULL",
+        )?;
+        let people = stmnt
+            .query_map([round_type], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(people)
+    }
+
+    // The active members of the pool, or just those in `team`, along with the history of their
+    // matches with each other and the deployment's settings. Groups are cut down to the members
+    // being exported, dropping pairs that would otherwise look like someone sat out.
+    pub fn export_pool(&self, team: Option<&str>) -> Result<PoolExport> {
+        let mut members: Vec<PoolMember> = {
+            let conn = self.connection.lock().unwrap();
+            let mut stmnt = conn.prepare(
+                "SELECT id, name, email, team, status, timezone, waiting FROM people
+                     WHERE deactivated_at IS NULL AND email NOT LIKE 'deleted-%@invalid'
+                     AND (?1 IS NULL OR team = ?1)
+                     ORDER BY id",
+            )?;
+            let mut rows = stmnt.query([team])?;
+            let mut members = Vec::new();
+            while let Some(row) = rows.next()? {
+                members.push(PoolMember {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    email: get_sealed(&self.cipher, row, 2)?,
+                    team: row.get(3)?,
+                    status: row.get(4)?,
+                    timezone: row.get(5)?,
+                    waiting: row.get(6)?,
+                    interests: Vec::new(),
+                });
+            }
+            members
+        };
+        for member in &mut members {
+            member.interests = self.interests(member.id)?;
+        }
+        let ids: HashSet<PersonId> = members.iter().map(|m| m.id).collect();
+        let generations = self
+            .generations_since(0)?
+            .into_iter()
+            .map(|generation| PoolGeneration {
+                time: generation.time,
+                round_type: generation.round_type,
+                quality: generation.quality,
+                groups: generation
+                    .groups
+                    .into_iter()
+                    .filter_map(|group| {
+                        let kept: Vec<PersonId> =
+                            group.iter().copied().filter(|p| ids.contains(p)).collect();
+                        (kept.len() > 1 || (!kept.is_empty() && group.len() == 1)).then_some(kept)
+                    })
+                    .collect(),
+            })
+            .filter(|generation| !generation.groups.is_empty())
+            .collect();
+        Ok(PoolExport {
+            branding: self.branding()?,
+            schedule: self.schedule()?,
+            members,
+            generations,
+        })
+    }
+
+    // Add an exported pool to this deployment. Members already here (by email) are kept as they are,
+    // the rest are created in the organisation without a password and can claim their account by
+    // signing up. Only people and history are imported unless `options` asks for more.
+    pub fn import_pool(
+        &self,
+        pool: &PoolExport,
+        options: &PoolImportOptions,
+    ) -> Result<Result<PoolImport, PoolImportError>> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let organisation_exists: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM organisations WHERE id = ?1)",
+            [options.organisation],
+            |row| row.get(0),
+        )?;
+        if !organisation_exists {
+            return Ok(Err(PoolImportError::UnknownOrganisation(
+                options.organisation,
+            )));
+        }
+        if let Some(name) = &options.pool {
+            let pool_exists: bool = tx.query_row(
+                "SELECT EXISTS (SELECT 1 FROM pools WHERE name = ?1 AND archived_at IS NULL)",
+                [name],
+                |row| row.get(0),
+            )?;
+            if !pool_exists {
+                return Ok(Err(PoolImportError::UnknownPool(name.clone())));
+            }
+        }
+        let has_generations: bool =
+            tx.query_row("SELECT EXISTS (SELECT 1 FROM generations)", [], |row| {
+                row.get(0)
+            })?;
+        if has_generations && !pool.generations.is_empty() {
+            return Ok(Err(PoolImportError::HasGenerations));
+        }
+
+        let mut summary = PoolImport {
+            created: 0,
+            existing: 0,
+            generations: 0,
+        };
+        let mut ids = HashMap::new();
+        for member in &pool.members {
+            let email = self.stored_email(&member.email);
+            let created = tx.execute(
+                "INSERT INTO people (email, name, waiting, team, status, timezone, organisation)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                     ON CONFLICT DO NOTHING",
+                params![
+                    email,
+                    member.name,
+                    member.waiting && options.pool.is_none(),
+                    member.team,
+                    member.status,
+                    member.timezone,
+                    options.organisation
+                ],
+            )?;
+            let id: PersonId =
+                tx.query_row("SELECT id FROM people WHERE email = ?1", [&email], |row| {
+                    row.get(0)
+                })?;
+            if created > 0 {
+                summary.created += 1;
+                for tag in &member.interests {
+                    tx.execute(
+                        "INSERT INTO interests (person, tag) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+                        params![id, tag],
+                    )?;
+                }
+            } else {
+                summary.existing += 1;
+            }
+            if let Some(name) = &options.pool {
+                tx.execute(
+                    "INSERT INTO pool_members (pool, person, waiting) VALUES (?1, ?2, ?3)
+                     ON CONFLICT DO NOTHING",
+                    params![name, id, member.waiting],
+                )?;
+            }
+            ids.insert(member.id, id);
+        }
+
+        let now = self.clock.timestamp();
+        for generation in &pool.generations {
+            let quality = generation.quality.as_ref();
+            let id: GenerationId = tx
+                .query_row(
+                    "INSERT INTO generations (time, updated_at, strategy, buckets, repeat_pairs, total_weight, matched_at, notified_at, round_type)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?2, ?2, ?7) RETURNING id",
+                    params![
+                        generation.time,
+                        now,
+                        quality.map(|q| &q.strategy),
+                        quality.map(|q| q.buckets),
+                        quality.map(|q| q.repeat_pairs),
+                        quality.map(|q| q.total_weight),
+                        options.pool.as_ref().unwrap_or(&generation.round_type)
+                    ],
+                    |row| row.get(0),
+                )?;
+            for group in &generation.groups {
+                let members = group
+                    .iter()
+                    .map(|p| {
+                        ids.get(p)
+                            .copied()
+                            .ok_or(PoolImportError::UnknownMember(*p))
+                    })
+                    .collect::<Result<Vec<PersonId>, _>>();
+                let mut members = match members {
+                    Ok(members) => members,
+                    Err(error) => return Ok(Err(error)),
+                };
+                members.sort();
+                insert_group(&tx, id, &members)?;
+            }
+            summary.generations += 1;
+        }
+        tx.commit()?;
+        drop(conn);
+
+        if options.branding {
+            self.set_branding(&pool.branding)?;
+        }
+        if let (true, Some(schedule)) = (options.schedule, &pool.schedule) {
+            self.set_schedule(schedule)?;
+        }
+        Ok(Ok(summary))
+    }
+
+    // Generations updated at or after `since`, oldest update first. The cursor is inclusive so
+    // updates made within the same second as the previous sync aren't missed.
+    pub fn generations_since(&self, since: i64) -> Result<Vec<GenerationExport>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn
+            .prepare(
+                "SELECT g.id, g.time, g.strategy, g.buckets, g.repeat_pairs, g.total_weight, g.round_type,
+                        g.title, g.notes, g.updated_at, m.id, mm.person
+                 FROM generations g LEFT JOIN matches m ON m.generation = g.id
+                 LEFT JOIN match_members mm ON mm.match = m.id
+                 WHERE g.updated_at >= ?1
+                 ORDER BY g.updated_at, g.id, m.id, mm.person",
+            )?;
+        let mut rows = stmnt.query([since])?;
+        let mut generations: Vec<GenerationExport> = Vec::new();
+        let mut last_group = None;
+        while let Some(row) = rows.next()? {
+            let generation: GenerationId = row.get(0)?;
+            if generations.last().map(|g| g.generation) != Some(generation) {
+                let meta = MatchMeta::from_row(row, &self.cipher)?;
+                generations.push(GenerationExport {
+                    generation,
+                    time: meta.time,
+                    round_type: meta.round_type,
+                    updated_at: row.get(9)?,
+                    quality: meta.quality,
+                    groups: Vec::new(),
+                });
+            }
+            let (Some(group), Some(person)) = (
+                row.get::<_, Option<i64>>(10)?,
+                row.get::<_, Option<PersonId>>(11)?,
+            ) else {
+                continue;
+            };
+            let groups = &mut generations.last_mut().unwrap().groups;
+            match groups.last_mut() {
+                Some(members) if last_group == Some(group) => members.push(person),
+                _ => groups.push(vec![person]),
+            }
+            last_group = Some(group);
+        }
+        Ok(generations)
+    }
+
+    pub fn branding(&self) -> Result<Branding> {
+        Ok(self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT product_name, logo_url, accent_colour, footer_links FROM branding WHERE id = 1",
+                [],
+                |row| {
+                    let footer_links: String = row.get(3)?;
+                    Ok(Branding {
+                        product_name: row.get(0)?,
+                        logo_url: row.get(1)?,
+                        accent_colour: row.get(2)?,
+                        footer_links: serde_json::from_str(&footer_links).map_err(|error| {
+                            rusqlite::Error::FromSqlConversionFailure(3, Type::Text, Box::new(error))
+                        })?,
+                    })
+                },
+            )
+            .optional()?
+            .unwrap_or_default())
+    }
+
+    pub fn set_branding(&self, branding: &Branding) -> Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO branding (id, product_name, logo_url, accent_colour, footer_links) VALUES (1, ?1, ?2, ?3, ?4)
+                 ON CONFLICT (id) DO UPDATE SET product_name = ?1, logo_url = ?2, accent_colour = ?3, footer_links = ?4",
+                params![
+                    branding.product_name,
+                    branding.logo_url,
+                    branding.accent_colour,
+                    serde_json::to_string(&branding.footer_links)
+                        .map_err(|error| rusqlite::Error::ToSqlConversionFailure(Box::new(error)))?,
+                ],
+            )?;
+        Ok(())
+    }
+
+    pub fn schedule(&self) -> Result<Option<ScheduleConfig>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT start, every_days, min_waiters, enabled, cron FROM schedule WHERE id = 1",
+                [],
+                |row| {
+                    let start = row.get(0)?;
+                    Ok(ScheduleConfig {
+                        start: chrono::DateTime::from_timestamp(start, 0)
+                            .ok_or(rusqlite::Error::IntegralValueOutOfRange(0, start))?,
+                        every_days: row.get(1)?,
+                        cron: row.get(4)?,
+                        min_waiters: row.get(2)?,
+                        enabled: row.get(3)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn set_schedule(&self, schedule: &ScheduleConfig) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO schedule (id, start, every_days, min_waiters, enabled, cron)
+                 VALUES (1, ?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (id) DO UPDATE SET start = ?1, every_days = ?2, min_waiters = ?3,
+                     enabled = ?4, cron = ?5",
+            params![
+                schedule.start.timestamp(),
+                schedule.every_days,
+                schedule.min_waiters,
+                schedule.enabled,
+                schedule.cron,
+            ],
+        )?;
+        Ok(())
+    }
+
+    // The SMTP server saved by an admin, with the default sending limits.
+    pub fn mail_settings(&self) -> Result<Option<MailConfig>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT host, port, username, password, sender, tls FROM mail_settings WHERE id = 1",
+                [],
+                |row| {
+                    let tls: String = row.get(5)?;
+                    Ok(MailConfig {
+                        host: row.get(0)?,
+                        port: row.get(1)?,
+                        username: row.get(2)?,
+                        password: get_sealed_optional(&self.cipher, row, 3)?,
+                        tls: tls.parse().map_err(|error: String| {
+                            rusqlite::Error::FromSqlConversionFailure(5, Type::Text, error.into())
+                        })?,
+                        from: row.get(4)?,
+                        ..MailConfig::default()
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn set_mail_settings(&self, settings: &MailConfig) -> Result<()> {
+        let password = settings
+            .password
+            .as_deref()
+            .map(|password| self.cipher.seal(password));
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO mail_settings (id, host, port, username, password, sender, tls) VALUES (1, ?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (id) DO UPDATE SET host = ?1, port = ?2, username = ?3, password = ?4, sender = ?5, tls = ?6",
+            params![
+                settings.host,
+                settings.port,
+                settings.username,
+                password,
+                settings.from,
+                settings.tls.as_str(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Create a new API token for the person, replacing any they had before.
+    pub fn create_api_token(&self, person_id: PersonId) -> Result<String> {
+        let token = uuid::Uuid::new_v4().to_string();
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO api_tokens (person, token, created_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (person) DO UPDATE SET token = excluded.token, created_at = excluded.created_at",
+                params![
+                    person_id,
+                    hash_token(&token),
+                    self.clock.timestamp()
+                ],
+            )?;
+        Ok(token)
+    }
+
+    pub fn revoke_api_token(&self, person_id: PersonId) -> Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM api_tokens WHERE person = ?1", [person_id])?;
+        Ok(())
+    }
+
+    pub fn has_api_token(&self, person_id: PersonId) -> Result<bool> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .prepare("SELECT 1 FROM api_tokens WHERE person = ?1")?
+            .exists([person_id])?)
+    }
+
+    pub fn api_token_person(&self, token: &str) -> Result<Option<PersonId>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT person FROM api_tokens WHERE token = ?1",
+                [hash_token(token)],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn timezone(&self, person_id: PersonId) -> Result<Option<String>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT timezone FROM people WHERE id = ?1",
+                [person_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    pub fn set_timezone(&self, person_id: PersonId, timezone: Option<&str>) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE people SET timezone = ?2 WHERE id = ?1",
+            params![person_id, timezone],
+        )?;
+        Ok(())
+    }
+
+    // Record the timezone detected by the browser, unless the person already has one.
+    pub fn set_default_timezone(&self, person_id: PersonId, timezone: &str) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE people SET timezone = ?2 WHERE id = ?1 AND timezone IS NULL",
+            params![person_id, timezone],
+        )?;
+        Ok(())
+    }
+
+    // New unsubscribe tokens for those people who want notification emails, one for each email as
+    // only their hashes are kept.
+    pub fn email_recipients(&self, people: &[PersonId]) -> Result<HashMap<PersonId, String>> {
+        let now = self.clock.timestamp();
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut recipients = HashMap::new();
+        for person in people {
+            let wanted: bool = tx
+                .query_row(
+                    "SELECT email_notifications FROM people WHERE id = ?1",
+                    [person],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .unwrap_or(false);
+            if !wanted {
+                continue;
+            }
+            let token = uuid::Uuid::new_v4().simple().to_string();
+            tx.execute(
+                "INSERT INTO unsubscribe_tokens (token, person, created_at) VALUES (?1, ?2, ?3)",
+                params![hash_token(&token), person, now],
+            )?;
+            recipients.insert(*person, token);
+        }
+        tx.commit()?;
+        Ok(recipients)
+    }
+
+    pub fn email_notifications(&self, person_id: PersonId) -> Result<bool> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT email_notifications FROM people WHERE id = ?1",
+                [person_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(false))
+    }
+
+    pub fn set_email_notifications(&self, person_id: PersonId, enabled: bool) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE people SET email_notifications = ?2 WHERE id = ?1",
+            params![person_id, enabled],
+        )?;
+        Ok(())
+    }
+
+    // Turn off emails for whoever the token belongs to, returns whether the token was known.
+    pub fn unsubscribe(&self, token: &str) -> Result<bool> {
+        Ok(self.connection.lock().unwrap().execute(
+            "UPDATE people SET email_notifications = FALSE
+             WHERE id = (SELECT person FROM unsubscribe_tokens WHERE token = ?1)",
+            [hash_token(token)],
+        )? > 0)
+    }
+
+    // Every pair who have been matched together, built from the full match history. With a half
+    // life, a meeting that many generations before the latest one counts for half as much.
+    pub fn edge_history(&self, half_life: Option<u32>) -> Result<Vec<EdgeHistory>> {
+        self.edge_history_among(None, half_life)
+    }
+
+    // `edge_history` only for pairs where both are in `people`, when given.
+    fn edge_history_among(
+        &self,
+        people: Option<&[PersonId]>,
+        half_life: Option<u32>,
+    ) -> Result<Vec<EdgeHistory>> {
+        let conn = self.connection.lock().unwrap();
+        let latest: u32 = conn.query_row(
+            "SELECT COALESCE(MAX(generation), 0) FROM matches",
+            [],
+            |row| row.get(0),
+        )?;
+        // Filtering starts from each person's memberships, as `edges_for` does from their edges.
+        let filter = if people.is_some() {
+            "WHERE a.person IN (SELECT value FROM json_each(?1))
+                 AND +b.person IN (SELECT value FROM json_each(?1))"
+        } else {
+            ""
+        };
+        let mut stmnt = conn.prepare(&format!(
+            "SELECT m.generation, a.person, b.person FROM matches m
+                 JOIN match_members a ON a.match = m.id
+                 JOIN match_members b ON b.match = m.id AND a.person < b.person
+                 {filter}
+                 ORDER BY m.generation"
+        ))?;
+        let mut rows = stmnt.query(rusqlite::params_from_iter(people.map(id_array)))?;
+        let mut edges: BTreeMap<(PersonId, PersonId), EdgeHistory> = BTreeMap::new();
+        while let Some(row) = rows.next()? {
+            let generation: GenerationId = row.get(0)?;
+            let (person1, person2): (PersonId, PersonId) = (row.get(1)?, row.get(2)?);
+            let decay = match half_life {
+                Some(half_life) => {
+                    0.5f64.powf(f64::from(latest - generation.0) / f64::from(half_life.max(1)))
+                }
+                None => 1.0,
+            };
+            let edge = edges.entry((person1, person2)).or_insert(EdgeHistory {
+                person1,
+                person2,
+                count: 0,
+                last_generation: generation,
+                effective_weight: 0.0,
+            });
+            edge.count += 1;
+            edge.last_generation = generation;
+            edge.effective_weight += decay;
+        }
+        Ok(edges.into_values().collect())
+    }
+
+    // Stop waiting until `until`, when housekeeping puts them back in the pool.
+    pub fn pause(&self, person_id: PersonId, until: i64) -> Result<()> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let paused = tx.execute(
+            "UPDATE people SET waiting = FALSE, paused_until = ?2 WHERE id = ?1 AND deactivated_at IS NULL",
+            params![person_id, until],
+        )?;
+        if paused > 0 {
+            tx.execute(
+                "INSERT INTO pauses (person, at, until) VALUES (?1, ?2, ?3)",
+                params![person_id, self.clock.timestamp(), until],
+            )?;
+        }
+        tx.commit()?;
+        self.people_cache.remove(&person_id);
+        Ok(())
+    }
+
+    // Everything in someone's participation, oldest first: when they started and stopped waiting,
+    // each round they were in and each pause.
+    pub fn timeline(&self, person_id: PersonId) -> Result<Vec<TimelineEvent>> {
+        let conn = self.connection.lock().unwrap();
+        let mut events = Vec::new();
+        let mut stmnt = conn
+            .prepare("SELECT waiting, at FROM waiting_changes WHERE person = ?1 ORDER BY rowid")?;
+        let mut rows = stmnt.query([person_id])?;
+        while let Some(row) = rows.next()? {
+            let at = row.get(1)?;
+            events.push(if row.get(0)? {
+                TimelineEvent::StartedWaiting { at }
+            } else {
+                TimelineEvent::StoppedWaiting { at }
+            });
+        }
+        let mut stmnt = conn.prepare(
+            "SELECT g.time, g.id, g.round_type,
+                 (SELECT json_group_array(p.name) FROM match_members other
+                  JOIN people p ON p.id = other.person
+                  WHERE other.match = mine.match AND other.person != ?1)
+             FROM match_members mine JOIN matches m ON m.id = mine.match
+             JOIN generations g ON g.id = m.generation
+             WHERE mine.person = ?1",
+        )?;
+        let mut rows = stmnt.query([person_id])?;
+        while let Some(row) = rows.next()? {
+            let partners: String = row.get(3)?;
+            events.push(TimelineEvent::Matched {
+                at: row.get(0)?,
+                generation: row.get(1)?,
+                round_type: row.get(2)?,
+                partners: serde_json::from_str(&partners).unwrap_or_default(),
+            });
+        }
+        let mut stmnt = conn.prepare("SELECT at, until FROM pauses WHERE person = ?1")?;
+        let mut rows = stmnt.query([person_id])?;
+        while let Some(row) = rows.next()? {
+            events.push(TimelineEvent::Paused {
+                at: row.get(0)?,
+                until: row.get(1)?,
+            });
+        }
+        events.sort_by_key(TimelineEvent::at);
+        Ok(events)
+    }
+
+    pub fn paused_until(&self, person_id: PersonId) -> Result<Option<i64>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT paused_until FROM people WHERE id = ?1",
+                [person_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten())
+    }
+
+    // Start everyone whose pause has ended waiting again, returns how many were resumed.
+    pub fn resume_paused(&self, now: i64) -> Result<usize> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "UPDATE people SET waiting = TRUE, paused_until = NULL
+                 WHERE paused_until <= ?1 RETURNING id",
+        )?;
+        let resumed: Vec<PersonId> = stmnt
+            .query_map([now], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        for person_id in &resumed {
+            self.people_cache.remove(person_id);
+        }
+        Ok(resumed.len())
+    }
+
+    pub fn add_floater(&self, person_id: PersonId) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO floaters (person) VALUES (?1) ON CONFLICT DO NOTHING",
+            [person_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_floater(&self, person_id: PersonId) -> Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM floaters WHERE person = ?1", [person_id])?;
+        Ok(())
+    }
+
+    // The floater to bring in to even out a round, spreading the load by picking whoever has floated
+    // the least. Floaters who are paused or `unavailable`, e.g. already waiting or left out of the
+    // round, aren't picked.
+    pub fn pick_floater(
+        &self,
+        unavailable: &[PersonId],
+        organisation: OrganisationId,
+    ) -> Result<Option<PersonId>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn
+            .prepare(
+                "SELECT f.person FROM floaters f JOIN people p ON p.id = f.person
+                 WHERE p.paused_until IS NULL AND p.deactivated_at IS NULL AND p.organisation = ?1
+                 ORDER BY (SELECT count(*) FROM generation_waiters w WHERE w.person = f.person AND w.floater), f.person",
+            )?;
+        let mut rows = stmnt.query([organisation])?;
+        while let Some(row) = rows.next()? {
+            let person: PersonId = row.get(0)?;
+            if !unavailable.contains(&person) {
+                return Ok(Some(person));
+            }
+        }
+        Ok(None)
+    }
+
+    // Stop someone taking part without deleting their history: they are signed out everywhere,
+    // taken out of the pool and can't sign back in until reactivated. Returns false if there is no
+    // such person.
+    pub fn deactivate(&self, person_id: PersonId, now: i64) -> Result<bool> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let updated = tx
+            .execute(
+                "UPDATE people SET deactivated_at = coalesce(deactivated_at, ?2), waiting = FALSE, paused_until = NULL
+                 WHERE id = ?1",
+                params![person_id, now],
+            )?;
+        if updated == 0 {
+            return Ok(false);
+        }
+        tx.execute("DELETE FROM sessions WHERE person = ?1", [person_id])?;
+        tx.execute("DELETE FROM api_tokens WHERE person = ?1", [person_id])?;
+        tx.execute("DELETE FROM floaters WHERE person = ?1", [person_id])?;
+        tx.commit()?;
+        self.people_cache.remove(&person_id);
+        self.sessions_cache
+            .retain(|_, (person, _)| *person != person_id);
+        Ok(true)
+    }
+
+    pub fn reactivate(&self, person_id: PersonId) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE people SET deactivated_at = NULL WHERE id = ?1",
+            [person_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn save_audit(&self, audit: &MatchingAudit) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO generation_audits (generation, audit) VALUES (?1, ?2)",
+            params![
+                audit.generation,
+                serde_json::to_string(audit)
+                    .map_err(|error| rusqlite::Error::ToSqlConversionFailure(Box::new(error)))?
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Record that every group in the generation has been saved.
+    pub fn finish_matching(&self, generation: GenerationId) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE generations SET matched_at = coalesce(matched_at, ?2) WHERE id = ?1",
+            params![generation, self.clock.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    // Record that everyone in the generation has been sent their match.
+    pub fn finish_notifying(&self, generation: GenerationId) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE generations SET notified_at = coalesce(notified_at, ?2) WHERE id = ?1",
+            params![generation, self.clock.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    // Record how someone's match email for a generation went.
+    pub fn set_notification(
+        &self,
+        generation: GenerationId,
+        person_id: PersonId,
+        status: NotificationStatus,
+        error: Option<&str>,
+    ) -> Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO notifications (generation, person, status, error, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (generation, person) DO UPDATE
+                 SET status = excluded.status, error = excluded.error, updated_at = excluded.updated_at",
+                params![
+                    generation,
+                    person_id,
+                    status.as_str(),
+                    error,
+                    self.clock.timestamp()
+                ],
+            )?;
+        Ok(())
+    }
+
+    // Update the status of an email that has already been recorded, returns false if there's no
+    // such email.
+    pub fn update_notification(
+        &self,
+        generation: GenerationId,
+        person_id: PersonId,
+        status: NotificationStatus,
+        error: Option<&str>,
+    ) -> Result<bool> {
+        Ok(self.connection.lock().unwrap().execute(
+            "UPDATE notifications SET status = ?3, error = ?4, updated_at = ?5
+                 WHERE generation = ?1 AND person = ?2",
+            params![
+                generation,
+                person_id,
+                status.as_str(),
+                error,
+                self.clock.timestamp()
+            ],
+        )? > 0)
     }
 
-    pub fn get_person_and_matches(&self, id: u32) -> Option<(Person, Vec<(u32, Person)>)> {
-        let person = self.get_person(id)?;
-        let matches = self.matches_for(id);
-        Some((person, matches))
+    // How everyone's match email for a generation went, for those who were emailed.
+    pub fn notifications(
+        &self,
+        generation: GenerationId,
+    ) -> Result<HashMap<PersonId, Notification>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT person, status, error, updated_at FROM notifications WHERE generation = ?1",
+        )?;
+        let rows = stmnt
+            .query_map([generation], |row| {
+                let status: String = row.get(1)?;
+                Ok((
+                    row.get(0)?,
+                    Notification {
+                        status: NotificationStatus::parse(&status)
+                            .unwrap_or(NotificationStatus::Pending),
+                        error: row.get(2)?,
+                        updated_at: row.get(3)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rows)
     }
 
-    pub fn sign_up_session(&self, name: &str, email: &str, password: &str) -> (u32, String) {
-        let salt = SaltString::generate(&mut OsRng);
-        let password_hash = Argon2::default()
-            .hash_password(password.as_bytes(), &salt)
-            .unwrap()
-            .to_string();
+    // Generations that haven't finished being matched or notified, oldest first. Only meaningful
+    // while no round is running, e.g. at startup.
+    pub fn interrupted_generations(&self) -> Result<Vec<InterruptedGeneration>> {
         let conn = self.connection.lock().unwrap();
-        let id: u32 = conn
+        let mut stmnt = conn.prepare(
+            "SELECT g.id, g.time, g.matched_at IS NULL,
+                     (SELECT count(*) FROM matches m WHERE m.generation = g.id)
+                 FROM generations g
+                 WHERE g.matched_at IS NULL OR g.notified_at IS NULL
+                 ORDER BY g.id",
+        )?;
+        let rows = stmnt
+            .query_map([], |row| {
+                Ok(InterruptedGeneration {
+                    generation: row.get(0)?,
+                    time: row.get(1)?,
+                    stage: if row.get(2)? {
+                        InterruptedStage::Matching
+                    } else {
+                        InterruptedStage::Notifying
+                    },
+                    groups: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rows)
+    }
+
+    // Undo a generation as if it never ran: its groups no longer count as having met and everyone
+    // who was waiting for it is waiting again. Floaters and those left out at their season limit
+    // weren't waiting, so they stay as they are.
+    pub fn roll_back_generation(&self, generation: GenerationId) -> Result<()> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE edges SET weight = weight - (
+                 SELECT count(*) FROM matches m
+                 JOIN match_members a ON a.match = m.id AND a.person = edges.person1
+                 JOIN match_members b ON b.match = m.id AND b.person = edges.person2
+                 WHERE m.generation = ?1
+             )",
+            [generation],
+        )?;
+        tx.execute("DELETE FROM edges WHERE weight <= 0", [])?;
+        let pool: Option<String> = tx
             .query_row(
-                "insert into people (email, name, waiting) values (?1, ?2, FALSE) RETURNING id",
-                [email, name],
+                "SELECT name FROM pools WHERE name = (SELECT round_type FROM generations WHERE id = ?1)",
+                [generation],
                 |row| row.get(0),
             )
-            .unwrap();
-        conn.execute(
-            "INSERT INTO auth (person, password_hash) values (?1, ?2)",
-            params![id, password_hash],
-        )
-        .unwrap();
-        let session_id = uuid::Uuid::new_v4().to_string();
-        let time = chrono::offset::Utc::now().timestamp();
-        conn.execute(
-            "INSERT INTO sessions (id, person, last_seen) VALUES (?1, ?2, ?3)",
-            params![session_id, id, time],
-        )
-        .unwrap();
-        (id, session_id)
+            .optional()?;
+        let waiting_again = match pool {
+            Some(_) => {
+                "UPDATE pool_members SET waiting = TRUE
+                 WHERE pool = (SELECT round_type FROM generations WHERE id = ?1) AND person IN (
+                     SELECT person FROM generation_waiters
+                     WHERE generation = ?1 AND excluded_reason IS NULL AND NOT floater
+                 )"
+            }
+            None => {
+                "UPDATE people SET waiting = TRUE
+                 WHERE deactivated_at IS NULL AND id IN (
+                     SELECT person FROM generation_waiters
+                     WHERE generation = ?1 AND excluded_reason IS NULL AND NOT floater
+                 )"
+            }
+        };
+        tx.execute(waiting_again, [generation])?;
+        for statement in [
+            "DELETE FROM match_members WHERE match IN (SELECT id FROM matches WHERE generation = ?1)",
+            "DELETE FROM matches WHERE generation = ?1",
+            "DELETE FROM generation_waiters WHERE generation = ?1",
+            "DELETE FROM generation_audits WHERE generation = ?1",
+            // Its round of a season plan is run again next time.
+            "DELETE FROM season_plan_rounds WHERE generation = ?1",
+            "DELETE FROM notifications WHERE generation = ?1",
+            "DELETE FROM generations WHERE id = ?1",
+        ] {
+            tx.execute(statement, [generation])?;
+        }
+        tx.commit()?;
+        self.people_cache.retain(|_, _| false);
+        Ok(())
     }
 
-    pub fn toggle_waiter(&self, person_id: u32) {
-        self.connection
+    // The audit of a generation as JSON, generations matched before audits were kept have none.
+    pub fn audit(&self, generation: GenerationId) -> Result<Option<String>> {
+        Ok(self
+            .connection
             .lock()
             .unwrap()
-            .execute(
-                "UPDATE people SET waiting = (CASE WHEN waiting = FALSE THEN TRUE ELSE FALSE END) WHERE id = ?1",
-                [person_id],
+            .query_row(
+                "SELECT audit FROM generation_audits WHERE generation = ?1",
+                [generation],
+                |row| row.get(0),
             )
-            .unwrap();
+            .optional()?)
     }
 
-    pub fn matches_for(&self, person_id: u32) -> Vec<(u32, Person)> {
+    // The Slack user ids of those who have one, from importing the workspace.
+    pub fn slack_ids(&self, people: &[PersonId]) -> Result<HashMap<PersonId, String>> {
         let conn = self.connection.lock().unwrap();
-        let mut stmnt = conn
-            .prepare("select m.generation, p.id, p.name from matches m join people p on m.person2 = p.id WHERE m.person1 = ?1")
-            .unwrap();
-        let mut rows = stmnt.query([person_id]).unwrap();
+        let mut stmnt = conn.prepare("SELECT slack_id FROM slack_identities WHERE person = ?1")?;
+        let mut slack_ids = HashMap::new();
+        for person in people {
+            if let Some(slack_id) = stmnt.query_row([person], |row| row.get(0)).optional()? {
+                slack_ids.insert(*person, slack_id);
+            }
+        }
+        Ok(slack_ids)
+    }
 
-        let mut people = Vec::new();
-        while let Some(row) = rows.next().unwrap() {
-            people.push((
-                row.get(0).unwrap(),
-                Person {
-                    id: row.get(1).unwrap(),
-                    email: "".to_owned(),
-                    name: row.get(2).unwrap(),
-                    waiting: false,
-                },
-            ));
+    pub fn contact_fields(&self, person_id: PersonId) -> Result<Vec<ContactField>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt =
+            conn.prepare("SELECT kind, value, visibility FROM contact_fields WHERE person = ?1")?;
+        let mut rows = stmnt.query([person_id])?;
+        let mut fields = Vec::new();
+        while let Some(row) = rows.next()? {
+            let kind: String = row.get(0)?;
+            let visibility: String = row.get(2)?;
+            let unknown = |column, value: &str| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    column,
+                    Type::Text,
+                    format!("unknown contact field value {value:?}").into(),
+                )
+            };
+            fields.push(ContactField {
+                kind: ContactKind::parse(&kind).ok_or_else(|| unknown(0, &kind))?,
+                value: get_sealed(&self.cipher, row, 1)?,
+                visibility: ContactVisibility::parse(&visibility)
+                    .ok_or_else(|| unknown(2, &visibility))?,
+            });
         }
+        fields.sort_by_key(|field| ContactKind::ALL.iter().position(|k| *k == field.kind));
+        Ok(fields)
+    }
 
-        let mut stmnt = conn
-            .prepare("select m.generation, p.id, p.name from matches m join people p on m.person1 = p.id WHERE m.person2 = ?1")
-            .unwrap();
-        let mut rows = stmnt.query([person_id]).unwrap();
-
-        while let Some(row) = rows.next().unwrap() {
-            people.push((
-                row.get(0).unwrap(),
-                Person {
-                    id: row.get(1).unwrap(),
-                    email: "".to_owned(),
-                    name: row.get(2).unwrap(),
-                    waiting: false,
-                },
-            ));
+    // Replace all of someone's contact fields.
+    pub fn set_contact_fields(&self, person_id: PersonId, fields: &[ContactField]) -> Result<()> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM contact_fields WHERE person = ?1", [person_id])?;
+        for field in fields {
+            tx.execute(
+                "INSERT INTO contact_fields (person, kind, value, visibility) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    person_id,
+                    field.kind.as_str(),
+                    self.cipher.seal(&field.value),
+                    field.visibility.as_str()
+                ],
+            )?;
         }
+        tx.commit()?;
+        Ok(())
+    }
 
+    // The contact fields each of `people` shares with their matches.
+    pub fn contact_fields_for_matches(
+        &self,
+        people: &[PersonId],
+    ) -> Result<HashMap<PersonId, Vec<ContactField>>> {
         people
+            .iter()
+            .map(|person| {
+                let fields = self
+                    .contact_fields(*person)?
+                    .into_iter()
+                    .filter(|field| field.visibility != ContactVisibility::Admins)
+                    .collect();
+                Ok((*person, fields))
+            })
+            .collect()
     }
 
-    pub fn all_people(&self) -> Vec<Person> {
-        let conn = self.connection.lock().unwrap();
-        let mut stmnt = conn
-            .prepare("select p.id, p.email, p.name, p.waiting from people p")
-            .unwrap();
-        let mut rows = stmnt.query([]).unwrap();
+    pub fn set_team(&self, person_id: PersonId, team: Option<&str>) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE people SET team = ?2 WHERE id = ?1",
+            params![person_id, team],
+        )?;
+        self.people_cache.remove(&person_id);
+        Ok(())
+    }
 
-        let mut people = Vec::new();
-        while let Some(row) = rows.next().unwrap() {
-            people.push(Person {
-                id: row.get(0).unwrap(),
-                email: row.get(1).unwrap(),
-                name: row.get(2).unwrap(),
-                waiting: row.get(3).unwrap(),
-            });
+    // Every team anyone in the organisation is in, for suggesting when people pick theirs.
+    pub fn team_names(&self, organisation: OrganisationId) -> Result<Vec<String>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT DISTINCT team FROM people WHERE team IS NOT NULL AND organisation = ?1
+             ORDER BY team",
+        )?;
+        let mut rows = stmnt.query([organisation])?;
+        let mut teams = Vec::new();
+        while let Some(row) = rows.next()? {
+            teams.push(row.get(0)?);
         }
-        people
+        Ok(teams)
     }
 
-    pub fn match_meta_at(&self, generation: u32) -> Option<MatchMeta> {
-        self.connection
+    pub fn set_mentoring(
+        &self,
+        person_id: PersonId,
+        role: MentoringRole,
+        capacity: u32,
+    ) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE people SET mentoring_role = ?2, mentee_capacity = ?3 WHERE id = ?1",
+            params![person_id, role.as_str(), capacity],
+        )?;
+        Ok(())
+    }
+
+    // Their role in mentoring rounds and how many mentees they can take.
+    pub fn mentoring(&self, person_id: PersonId) -> Result<(MentoringRole, u32)> {
+        Ok(self
+            .connection
             .lock()
             .unwrap()
             .query_row(
-                "select id, time from generations where id = ?1",
-                [generation],
-                |r| {
-                    Ok(MatchMeta {
-                        generation: r.get(0)?,
-                        time: r.get(1)?,
-                    })
+                "SELECT mentoring_role, mentee_capacity FROM people WHERE id = ?1",
+                [person_id],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?.parse().unwrap_or_default(),
+                        row.get(1)?,
+                    ))
                 },
             )
-            .ok()
+            .optional()?
+            .unwrap_or((MentoringRole::Either, 1)))
     }
 
-    pub fn latest_match_meta(&self) -> Option<MatchMeta> {
-        self.connection
-            .lock()
-            .unwrap()
-            .query_row("select max(id), time from generations", [], |r| {
-                Ok(MatchMeta {
-                    generation: r.get(0)?,
-                    time: r.get(1)?,
-                })
-            })
-            .ok()
+    pub fn mentoring_roles(
+        &self,
+        people: &[PersonId],
+    ) -> Result<HashMap<PersonId, (MentoringRole, u32)>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt =
+            conn.prepare("SELECT mentoring_role, mentee_capacity FROM people WHERE id = ?1")?;
+        let mut roles = HashMap::new();
+        for person in people {
+            if let Some((role, capacity)) = stmnt
+                .query_row([person], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))
+                .optional()?
+            {
+                roles.insert(*person, (role.parse().unwrap_or_default(), capacity));
+            }
+        }
+        Ok(roles)
     }
 
-    pub fn matches_at(&self, generation: u32) -> Option<(MatchMeta, Vec<Match>)> {
-        let match_meta = self.match_meta_at(generation)?;
+    pub fn teams(&self, people: &[PersonId]) -> Result<HashMap<PersonId, String>> {
         let conn = self.connection.lock().unwrap();
-        let mut stmt = conn
-            .prepare("select p1.id, p1.email, p1.name, p1.waiting, p2.id, p2.email, p2.name, p2.waiting from matches m join people p1 on m.person1 = p1.id join people p2 on m.person2 = p2.id where m.generation = ?1")
-            .unwrap();
-        let mut rows = stmt.query([generation]).unwrap();
-        let mut matches = Vec::new();
-        while let Some(row) = rows.next().unwrap() {
-            matches.push(Match {
-                person1: Person {
-                    id: row.get(0).unwrap(),
-                    email: row.get(1).unwrap(),
-                    name: row.get(2).unwrap(),
-                    waiting: row.get(3).unwrap(),
-                },
-                person2: Some(Person {
-                    id: row.get(4).unwrap(),
-                    email: row.get(5).unwrap(),
-                    name: row.get(6).unwrap(),
-                    waiting: row.get(7).unwrap(),
-                }),
-            })
+        let mut stmnt =
+            conn.prepare("SELECT team FROM people WHERE id = ?1 AND team IS NOT NULL")?;
+        let mut teams = HashMap::new();
+        for person in people {
+            if let Ok(team) = stmnt.query_row([person], |row| row.get(0)) {
+                teams.insert(*person, team);
+            }
         }
-        let mut stmt = conn
-            .prepare("select p1.id, p1.email, p1.name, p1.waiting from matches m join people p1 on m.person1 = p1.id where m.generation = ?1 AND m.person2 IS NULL")
-            .unwrap();
-        let mut rows = stmt.query([generation]).unwrap();
-        while let Some(row) = rows.next().unwrap() {
-            matches.push(Match {
-                person1: Person {
-                    id: row.get(0).unwrap(),
-                    email: row.get(1).unwrap(),
-                    name: row.get(2).unwrap(),
-                    waiting: row.get(3).unwrap(),
-                },
-                person2: None,
-            })
+        Ok(teams)
+    }
+
+    pub fn set_name(&self, person_id: PersonId, name: &str) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE people SET name = ?2 WHERE id = ?1",
+            params![person_id, name],
+        )?;
+        self.people_cache.remove(&person_id);
+        Ok(())
+    }
+
+    pub fn interests(&self, person_id: PersonId) -> Result<Vec<String>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare("SELECT tag FROM interests WHERE person = ?1 ORDER BY tag")?;
+        let mut rows = stmnt.query([person_id])?;
+        let mut tags = Vec::new();
+        while let Some(row) = rows.next()? {
+            tags.push(row.get(0)?);
         }
-        Some((match_meta, matches))
+        Ok(tags)
     }
 
-    pub fn latest_matches(&self) -> Option<(MatchMeta, Vec<Match>)> {
-        let latest_match_meta = self.latest_match_meta()?;
-        self.matches_at(latest_match_meta.generation)
+    pub fn set_interests(&self, person_id: PersonId, tags: &[String]) -> Result<()> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM interests WHERE person = ?1", [person_id])?;
+        for tag in tags {
+            tx.execute(
+                "INSERT INTO interests (person, tag) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+                params![person_id, tag],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // How many interests each pair of `people` has in common, keyed with the lower id first. Pairs
+    // with nothing in common are left out.
+    pub fn shared_interests(
+        &self,
+        people: &[PersonId],
+    ) -> Result<HashMap<(PersonId, PersonId), u32>> {
+        let conn = self.connection.lock().unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT a.person, b.person, count(*) FROM interests a
+                 JOIN interests b ON a.tag = b.tag AND a.person < b.person
+                 GROUP BY a.person, b.person",
+        )?;
+        let mut rows = stmnt.query([])?;
+        let people = HashSet::<&PersonId>::from_iter(people);
+        let mut shared = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let p1 = row.get(0)?;
+            let p2 = row.get(1)?;
+            if people.contains(&p1) && people.contains(&p2) {
+                shared.insert((p1, p2), row.get(2)?);
+            }
+        }
+        Ok(shared)
+    }
+
+    // Replace the password if `current` is right, returns whether it was.
+    // Change someone's password and sign them out everywhere except `session_id`, where they
+    // changed it, as a changed password is often because someone else knew the old one.
+    pub fn change_password(
+        &self,
+        person_id: PersonId,
+        current: &str,
+        new: &str,
+        session_id: &str,
+    ) -> Result<bool> {
+        let expected = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT password_hash FROM auth WHERE person = ?1",
+                [person_id],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        // Argon2 is slow, so runs without holding the connection.
+        if !expected.is_some_and(|expected| verify_password(current, &expected)) {
+            return Ok(false);
+        }
+        let password_hash = hash_password(new);
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE auth SET password_hash = ?2 WHERE person = ?1",
+            params![person_id, password_hash],
+        )?;
+        tx.execute(
+            "DELETE FROM sessions WHERE person = ?1 AND id != ?2",
+            params![person_id, hash_token(session_id)],
+        )?;
+        tx.commit()?;
+        self.sessions_cache
+            .retain(|id, (person, _)| *person != person_id || id == session_id);
+        Ok(true)
     }
 
-    pub fn add_matching(&self, p1id: u32, p2id: Option<u32>, generation: u32) {
+    // Start changing someone's email, returning the token to confirm it with or `None` if the
+    // address is already in use. Any earlier unconfirmed change is replaced.
+    pub fn request_email_change(
+        &self,
+        person_id: PersonId,
+        email: &str,
+        now: i64,
+    ) -> Result<Option<String>> {
+        let email = self.stored_email(email);
         let conn = self.connection.lock().unwrap();
+        let taken = conn
+            .query_row(
+                "SELECT 1 FROM people WHERE email = ?1",
+                [&email],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if taken {
+            return Ok(None);
+        }
+        let token = uuid::Uuid::new_v4().simple().to_string();
         conn.execute(
-            "INSERT INTO matches (generation, person1, person2) VALUES (?1, ?2, ?3)",
-            params![generation, p1id, p2id],
-        )
-        .unwrap();
-        if let Some(p2id) = p2id {
-            conn.execute(
-            "INSERT INTO edges (person1, person2, weight) VALUES (?1, ?2, 1) ON CONFLICT (person1, person2) DO UPDATE SET weight = weight + 1",
-            params![p1id, p2id],
+            "INSERT INTO email_changes (person, email, token, created_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (person) DO UPDATE SET email = excluded.email, token = excluded.token, created_at = excluded.created_at",
+            params![person_id, email, hash_token(&token), now],
+        )?;
+        Ok(Some(token))
+    }
+
+    // The new email someone has asked for but not confirmed yet.
+    pub fn pending_email_change(&self, person_id: PersonId, now: i64) -> Result<Option<String>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT email FROM email_changes WHERE person = ?1 AND created_at > ?2",
+                params![person_id, now - EMAIL_CHANGE_LIFETIME_SECONDS],
+                |row| get_sealed(&self.cipher, row, 0),
             )
-            .unwrap();
-            conn.execute(
-                "UPDATE people SET waiting = FALSE WHERE id = ?1 OR id = ?2",
-                params![p1id, p2id],
+            .optional()?)
+    }
+
+    // Switch to the new email for a confirmation token, returns who it was for. Fails if the link
+    // has expired or someone else has taken the address since.
+    pub fn confirm_email_change(&self, token: &str, now: i64) -> Result<Option<PersonId>> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let Some((person_id, email)): Option<(PersonId, String)> = tx
+            .query_row(
+                "DELETE FROM email_changes WHERE token = ?1 AND created_at > ?2 RETURNING person, email",
+                params![hash_token(token), now - EMAIL_CHANGE_LIFETIME_SECONDS],
+                |row| Ok((row.get(0)?, row.get(1)?)),
             )
-            .unwrap();
-        } else {
-            conn.execute(
-                "UPDATE people SET waiting = FALSE WHERE id = ?1",
-                params![p1id],
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let updated = tx.execute(
+            "UPDATE people SET email = ?2 WHERE id = ?1",
+            params![person_id, email],
+        );
+        match updated {
+            Ok(_) => {}
+            // Someone else has the address now.
+            Err(error) if error.sqlite_error_code() == Some(ErrorCode::ConstraintViolation) => {
+                return Ok(None)
+            }
+            Err(error) => return Err(error.into()),
+        }
+        tx.commit()?;
+        self.people_cache.remove(&person_id);
+        Ok(Some(person_id))
+    }
+
+    // Delete someone's account at their request. Their matches are kept so everyone else's history
+    // still makes sense, but they are shown as a deleted account. Returns false if the password is
+    // wrong.
+    pub fn delete_account(&self, person_id: PersonId, password: &str) -> Result<bool> {
+        let mut conn = self.connection.lock().unwrap();
+        let tx = conn.transaction()?;
+        let Some(expected) = tx
+            .query_row(
+                "SELECT password_hash FROM auth WHERE person = ?1",
+                [person_id],
+                |row| row.get::<_, String>(0),
             )
-            .unwrap();
+            .optional()?
+        else {
+            return Ok(false);
+        };
+        if !verify_password(password, &expected) {
+            return Ok(false);
         }
+        anonymise_person(&tx, person_id, self.clock.timestamp())?;
+        tx.commit()?;
+        self.people_cache.remove(&person_id);
+        self.sessions_cache
+            .retain(|_, (person, _)| *person != person_id);
+        Ok(true)
     }
 
-    pub fn add_matching_generation(&self) -> u32 {
-        let time = chrono::offset::Utc::now().timestamp();
-        self.connection
+    pub fn export_person(&self, person_id: PersonId) -> Result<Option<PersonExport>> {
+        let export = self
+            .connection
             .lock()
             .unwrap()
             .query_row(
-                "insert into generations (id, time) values ((select max(id) + 1 from generations), ?1) returning id",
-                [time],
-                |row| row.get(0),
+                "SELECT p.id, p.name, p.email, p.status, p.timezone, p.waiting, p.paused_until,
+                 p.max_matches_per_season, p.email_notifications, a.created_at, a.last_sign_in,
+                 EXISTS (SELECT 1 FROM admins WHERE person = p.id),
+                 EXISTS (SELECT 1 FROM floaters WHERE person = p.id),
+                 (SELECT slack_id FROM slack_identities WHERE person = p.id),
+                 EXISTS (SELECT 1 FROM api_tokens WHERE person = p.id),
+                 p.team, p.match_every_rounds, p.mentoring_role, p.mentee_capacity,
+                 (SELECT name FROM people WHERE id = p.manager)
+                 FROM people p LEFT JOIN account_activity a ON a.person = p.id
+                 WHERE p.id = ?1",
+                [person_id],
+                |row| {
+                    Ok(PersonExport {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        email: get_sealed(&self.cipher, row, 2)?,
+                        status: row.get(3)?,
+                        team: row.get(15)?,
+                        timezone: row.get(4)?,
+                        waiting: row.get(5)?,
+                        paused_until: row.get(6)?,
+                        max_matches_per_season: row.get(7)?,
+                        match_every_rounds: row.get(16)?,
+                        mentoring_role: row.get::<_, String>(17)?.parse().unwrap_or_default(),
+                        mentee_capacity: row.get(18)?,
+                        manager: row.get(19)?,
+                        email_notifications: row.get(8)?,
+                        created_at: row.get(9)?,
+                        last_sign_in: row.get(10)?,
+                        admin: row.get(11)?,
+                        floater: row.get(12)?,
+                        slack_id: row.get(13)?,
+                        has_api_token: row.get(14)?,
+                        matches: Vec::new(),
+                        blocked: Vec::new(),
+                        interests: Vec::new(),
+                        pools: Vec::new(),
+                        conflict_groups: Vec::new(),
+                    })
+                },
             )
-            .unwrap()
+            .optional()?;
+        let Some(mut export) = export else {
+            return Ok(None);
+        };
+        export.matches = self
+            .matches_for(person_id)?
+            .into_iter()
+            .map(|m| ExportedMatch {
+                generation: m.generation,
+                time: m.time,
+                partner: m.partner.name,
+            })
+            .collect();
+        export.blocked = self
+            .blocked_by(person_id)?
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        export.interests = self.interests(person_id)?;
+        export.pools = self
+            .pool_memberships(person_id)?
+            .into_iter()
+            .filter(|membership| membership.member)
+            .map(|membership| membership.pool)
+            .collect();
+        export.conflict_groups = self.conflict_groups_of(person_id)?;
+        Ok(Some(export))
     }
 
-    pub fn waiters(&self) -> Vec<u32> {
+    // The size of the waiting pool over time, one entry per day that it changed.
+    pub fn waiting_history(&self) -> Result<Vec<WaitingDay>> {
         let conn = self.connection.lock().unwrap();
         let mut stmnt = conn
-            .prepare("select id from people WHERE waiting = TRUE")
-            .unwrap();
-        let mut rows = stmnt.query([]).unwrap();
-        let mut people = Vec::new();
-        while let Some(row) = rows.next().unwrap() {
-            people.push(row.get(0).unwrap());
+            .prepare(
+                "WITH days AS (
+                     SELECT date(at, 'unixepoch') AS day, sum(waiting) AS joined, sum(NOT waiting) AS left
+                     FROM waiting_changes GROUP BY day
+                 )
+                 SELECT day, joined, left, sum(joined - left) OVER (ORDER BY day) FROM days ORDER BY day",
+            )?;
+        let mut rows = stmnt.query([])?;
+        let mut history = Vec::new();
+        while let Some(row) = rows.next()? {
+            history.push(WaitingDay {
+                day: row.get(0)?,
+                joined: row.get(1)?,
+                left: row.get(2)?,
+                waiting: row.get(3)?,
+            });
         }
-        people
+        Ok(history)
     }
 
-    pub fn edges_for(&self, waiters: Vec<u32>) -> Vec<(u32, u32, u32)> {
+    // Pairs of waiters who were matched together in the latest `generations` generations of the
+    // round type.
+    pub fn recent_pairs(
+        &self,
+        waiters: &[PersonId],
+        generations: u32,
+        round_type: &str,
+    ) -> Result<Vec<(PersonId, PersonId)>> {
         let conn = self.connection.lock().unwrap();
-        let mut stmnt = conn.prepare("select * from edges e").unwrap();
-        // maybe use rarray module
-        let mut rows = stmnt.query([]).unwrap();
-        let mut edges = Vec::new();
-        let waiters = HashSet::<u32>::from_iter(waiters);
-        while let Some(row) = rows.next().unwrap() {
-            let p1 = row.get(0).unwrap();
-            let p2 = row.get(1).unwrap();
-            let weight = row.get(2).unwrap();
+        let mut stmnt = conn.prepare(
+            "SELECT a.person, b.person FROM matches m
+                 JOIN match_members a ON a.match = m.id
+                 JOIN match_members b ON b.match = m.id AND a.person < b.person
+                 WHERE m.generation IN
+                     (SELECT id FROM generations WHERE round_type = ?2 ORDER BY id DESC LIMIT ?1)",
+        )?;
+        let mut rows = stmnt.query(params![generations, round_type])?;
+        let waiters = HashSet::<&PersonId>::from_iter(waiters);
+        let mut pairs = Vec::new();
+        while let Some(row) = rows.next()? {
+            let (p1, p2) = (row.get(0)?, row.get(1)?);
             if waiters.contains(&p1) && waiters.contains(&p2) {
-                edges.push((p1, p2, weight));
+                pairs.push((p1, p2));
             }
         }
-        edges
+        Ok(pairs)
     }
 
-    pub fn get_session(&self, session_id: &str, now: i64) -> Option<u32> {
+    // Up to `count` people none of the group have met yet, for them to get to know outside of
+    // matching. Only people in the group's organisation are suggested, those who have met the fewest
+    // others first, and nobody who has been blocked by or has blocked someone in the group.
+    pub fn not_yet_met(&self, group: &[PersonId], count: u32) -> Result<Vec<Person>> {
+        // Only ids are formatted in so this can't inject anything.
+        let ids = group
+            .iter()
+            .map(PersonId::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
         let conn = self.connection.lock().unwrap();
-        let (person, last_seen) = conn
+        let mut stmnt = conn
+            .prepare(&format!(
+                "SELECT p.id, p.email, p.name, p.waiting, p.status, p.team FROM people p
+                 WHERE p.deactivated_at IS NULL AND p.id NOT IN ({ids})
+                 AND p.organisation IN (SELECT organisation FROM people WHERE id IN ({ids}))
+                 AND NOT EXISTS (SELECT 1 FROM edges e
+                     WHERE (e.person1 = p.id AND e.person2 IN ({ids})) OR (e.person2 = p.id AND e.person1 IN ({ids})))
+                 AND NOT EXISTS (SELECT 1 FROM blocked_pairs b
+                     WHERE (b.person = p.id AND b.blocked IN ({ids})) OR (b.blocked = p.id AND b.person IN ({ids})))
+                 ORDER BY (SELECT count(*) FROM edges e WHERE e.person1 = p.id OR e.person2 = p.id), p.id
+                 LIMIT ?1"
+            ))?;
+        let mut rows = stmnt.query([count])?;
+        let mut people = Vec::new();
+        while let Some(row) = rows.next()? {
+            people.push(Person::from_row(row, 0, &self.cipher)?);
+        }
+        Ok(people)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_map_to_domain_types() {
+        let db = Database::init(
+            ":memory:",
+            3600,
+            LoginLockout {
+                attempts: 5,
+                seconds: 900,
+            },
+            Clock::new(false),
+            Cipher::new(Some("a test key that is at least 32 bytes long")),
+        );
+        db.import_slack_members(&[
+            ("Ua".to_owned(), "a@example.com".to_owned(), "a".to_owned()),
+            ("Ub".to_owned(), "b@example.com".to_owned(), "b".to_owned()),
+        ])
+        .unwrap();
+        let people = db.all_people().unwrap();
+        let a = people.iter().find(|p| p.name == "a").unwrap();
+        let b = people.iter().find(|p| p.name == "b").unwrap();
+        assert_eq!(b.email, "b@example.com");
+
+        let quality = MatchQuality {
+            strategy: "exact".to_owned(),
+            buckets: 1,
+            repeat_pairs: 0,
+            total_weight: 3,
+        };
+        let details = GenerationDetails {
+            title: Some("First".to_owned()),
+            notes: Some("Sealed".to_owned()),
+        };
+        let meta = db
+            .add_matching_generation(&quality, DEFAULT_ROUND_TYPE, &details)
+            .unwrap();
+        assert_eq!(meta.quality, Some(quality));
+        assert_eq!(meta.notes.as_deref(), Some("Sealed"));
+        assert_eq!(
+            db.match_meta_at(meta.generation).unwrap(),
+            Some(meta.clone())
+        );
+        db.add_matching(&[a.id, b.id], meta.generation).unwrap();
+
+        // Read from an offset, after the generation's columns.
+        let past = db.matches_for(a.id).unwrap();
+        assert_eq!(past.len(), 1);
+        assert_eq!(past[0].generation, meta.generation);
+        assert_eq!(past[0].round_type, DEFAULT_ROUND_TYPE);
+        assert_eq!(
+            past[0].partner,
+            Person {
+                waiting: false,
+                ..b.clone()
+            }
+        );
+
+        // Generations from before quality tracking have none.
+        let old = db
+            .connection
+            .lock()
+            .unwrap()
             .query_row(
-                "SELECT person, last_seen FROM sessions WHERE id = ?1",
-                [session_id],
-                |row| Ok((row.get(0).unwrap(), row.get::<_, i64>(1).unwrap())),
+                "INSERT INTO generations (time, updated_at) VALUES (1, 1) RETURNING id",
+                [],
+                |row| row.get(0),
             )
-            .ok()?;
-        let week_seconds = 60 * 60 * 24 * 7;
-        if last_seen - now > week_seconds {
-            conn.execute("DELETE FROM sessions WHERE id = ?1", [session_id])
-                .unwrap();
-            None
-        } else {
-            let time = chrono::offset::Utc::now().timestamp();
-            conn.execute(
-                "UPDATE sessions SET last_seen = ?2 WHERE id = ?1",
-                params![session_id, time],
+            .unwrap();
+        let old = db.match_meta_at(old).unwrap().unwrap();
+        assert_eq!(old.quality, None);
+        assert_eq!(old.notes, None);
+        assert_eq!(old.round_type, DEFAULT_ROUND_TYPE);
+    }
+
+    // A database as the first release left it, before the schema was versioned.
+    #[test]
+    fn unversioned_databases_are_migrated() {
+        let path = std::env::temp_dir().join(format!(
+            "matcher-test-{}.sqlite",
+            uuid::Uuid::new_v4().simple()
+        ));
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE people (
+                 id integer primary key,
+                 email text not null unique,
+                 name text not null,
+                 waiting boolean not null
+             );
+             CREATE TABLE generations (
+                 id integer primary key,
+                 time integer
+             );
+             CREATE TABLE matches (
+                 generation integer not null,
+                 person1 text not null,
+                 person2 text,
+                 foreign key(generation) references generations(id),
+                 foreign key(person1) references people(id),
+                 foreign key(person2) references people(id)
+             );
+             CREATE TABLE edges (
+                 person1 integer not null,
+                 person2 integer not null,
+                 weight integer not null,
+                 primary key(person1, person2),
+                 foreign key(person1) references people(id),
+                 foreign key(person2) references people(id)
+             );
+             CREATE TABLE auth (
+                 person INTEGER NOT NULL UNIQUE,
+                 password_hash TEXT NOT NULL,
+                 FOREIGN KEY(person) REFERENCES people(id)
+             );
+             CREATE TABLE sessions (
+                 id TEXT PRIMARY KEY,
+                 person INTEGER NOT NULL UNIQUE,
+                 last_seen INTEGER NOT NULL,
+                 FOREIGN KEY(person) REFERENCES people(id)
+             );
+             INSERT INTO people (id, email, name, waiting)
+             VALUES (1, 'A@Example.com', 'a', TRUE), (2, 'b@example.com', 'b', FALSE);
+             INSERT INTO generations (id, time) VALUES (1, 1000);
+             INSERT INTO matches (generation, person1, person2) VALUES (1, '1', '2');
+             INSERT INTO edges (person1, person2, weight) VALUES (1, 2, 3);",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO auth (person, password_hash) VALUES (1, ?1)",
+            [hash_password("a long password")],
+        )
+        .unwrap();
+        conn.close().unwrap();
+
+        let db = Database::init(
+            path.to_str().unwrap(),
+            3600,
+            LoginLockout {
+                attempts: 5,
+                seconds: 900,
+            },
+            Clock::new(false),
+            Cipher::new(Some("a test key that is at least 32 bytes long")),
+        );
+        let (version, matches, weight): (usize, i64, i64) = db
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT (SELECT version FROM schema_version), (SELECT count(*) FROM matches),
+                     (SELECT weight FROM edges WHERE person1 = 1 AND person2 = 2)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
             )
             .unwrap();
-            Some(person)
+        assert_eq!((version, matches, weight), (MIGRATIONS.len(), 1, 3));
+        let a = db.get_person(PersonId(1)).unwrap().unwrap();
+        assert_eq!((a.name.as_str(), a.email.as_str()), ("a", "a@example.com"));
+        assert_eq!(db.waiters().unwrap(), vec![PersonId(1)]);
+        assert!(db
+            .sign_in_session("a@example.com", "a long password")
+            .unwrap()
+            .is_ok());
+        db.close();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn session_ids_are_stored_hashed() {
+        let db = Database::in_memory();
+        let Ok((person, session)) = db
+            .sign_up_session(
+                "a",
+                "a@example.com",
+                "a long password",
+                DEFAULT_ORGANISATION,
+            )
+            .unwrap()
+        else {
+            panic!("sign up failed");
+        };
+        let stored: String = db
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT id FROM sessions", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, hash_token(&session));
+        let now = db.clock.timestamp();
+        assert_eq!(db.get_session(&stored, now).unwrap(), None);
+        assert_eq!(db.get_session(&session, now).unwrap(), Some(person));
+    }
+
+    #[test]
+    fn people_can_have_several_sessions() {
+        let db = Database::in_memory();
+        let Ok((person, first)) = db
+            .sign_up_session(
+                "a",
+                "a@example.com",
+                "a long password",
+                DEFAULT_ORGANISATION,
+            )
+            .unwrap()
+        else {
+            panic!("sign up failed");
+        };
+        let Ok(second) = db
+            .sign_in_session("a@example.com", "a long password")
+            .unwrap()
+        else {
+            panic!("sign in failed");
+        };
+        let now = db.clock.timestamp();
+        assert_ne!(first, second);
+        assert_eq!(db.get_session(&first, now).unwrap(), Some(person));
+        assert_eq!(db.get_session(&second, now).unwrap(), Some(person));
+        db.sign_out_session(&first).unwrap();
+        assert_eq!(db.get_session(&first, now).unwrap(), None);
+        assert_eq!(db.get_session(&second, now).unwrap(), Some(person));
+    }
+
+    #[test]
+    fn cached_sessions_still_expire() {
+        let db = Database::in_memory();
+        let Ok((person, first)) = db
+            .sign_up_session(
+                "a",
+                "a@example.com",
+                "a long password",
+                DEFAULT_ORGANISATION,
+            )
+            .unwrap()
+        else {
+            panic!("sign up failed");
+        };
+        let now = db.clock.timestamp();
+        assert_eq!(db.get_session(&first, now).unwrap(), Some(person));
+        let expired = now + db.session_lifetime_seconds + 60;
+        assert_eq!(db.get_session(&first, expired).unwrap(), None);
+
+        let Ok(second) = db
+            .sign_in_session("a@example.com", "a long password")
+            .unwrap()
+        else {
+            panic!("sign in failed");
+        };
+        assert_eq!(db.get_session(&second, now).unwrap(), Some(person));
+        assert_eq!(db.purge_expired_sessions(expired).unwrap(), 1);
+        assert_eq!(db.get_session(&second, now).unwrap(), None);
+    }
+
+    #[test]
+    fn changing_passwords_signs_out_other_sessions() {
+        let db = Database::in_memory();
+        let Ok((person, first)) = db
+            .sign_up_session(
+                "a",
+                "a@example.com",
+                "a long password",
+                DEFAULT_ORGANISATION,
+            )
+            .unwrap()
+        else {
+            panic!("sign up failed");
+        };
+        let Ok(second) = db
+            .sign_in_session("a@example.com", "a long password")
+            .unwrap()
+        else {
+            panic!("sign in failed");
+        };
+        let now = db.clock.timestamp();
+        assert_eq!(db.get_session(&second, now).unwrap(), Some(person));
+        assert!(!db
+            .change_password(person, "not the password", "a new password", &first)
+            .unwrap());
+        assert!(db
+            .change_password(person, "a long password", "a new password", &first)
+            .unwrap());
+        assert_eq!(db.get_session(&first, now).unwrap(), Some(person));
+        assert_eq!(db.get_session(&second, now).unwrap(), None);
+        assert!(db
+            .sign_in_session("a@example.com", "a new password")
+            .unwrap()
+            .is_ok());
+    }
+
+    #[test]
+    fn wrong_passwords_lock_accounts_for_a_while() {
+        let db = Database::in_memory();
+        let Ok((person, _)) = db
+            .sign_up_session(
+                "a",
+                "a@example.com",
+                "a long password",
+                DEFAULT_ORGANISATION,
+            )
+            .unwrap()
+        else {
+            panic!("sign up failed");
+        };
+        for _ in 0..db.lockout.attempts {
+            assert!(matches!(
+                db.sign_in_session("a@example.com", "a wrong password")
+                    .unwrap(),
+                Err(SignInError::InvalidPassword)
+            ));
         }
+        assert!(matches!(
+            db.sign_in_session("a@example.com", "a long password")
+                .unwrap(),
+            Err(SignInError::LockedOut)
+        ));
+        assert!(matches!(
+            db.sign_up_session(
+                "a",
+                "a@example.com",
+                "a long password",
+                DEFAULT_ORGANISATION
+            )
+            .unwrap(),
+            Err(SignUpError::LockedOut)
+        ));
+
+        let conn = db.connection.lock().unwrap();
+        let now = db.clock.timestamp();
+        assert!(locked_out(&conn, person, db.lockout, now).unwrap());
+        let later = now + db.lockout.seconds + 1;
+        assert!(!locked_out(&conn, person, db.lockout, later).unwrap());
+        record_failed_login(&conn, person, later).unwrap();
+        assert!(!locked_out(&conn, person, db.lockout, later).unwrap());
+        let unlimited = LoginLockout {
+            attempts: 0,
+            seconds: 900,
+        };
+        assert!(!locked_out(&conn, person, unlimited, now).unwrap());
     }
 
-    pub fn sign_in_session(&self, email: &str, password: &str) -> Result<String, SignInError> {
-        let conn = self.connection.lock().unwrap();
-        let expected_password_hash: Result<String, _> = conn.query_row(
-            "SELECT password_hash FROM auth JOIN people ON id = person WHERE email = ?1",
-            [email],
-            |r| r.get(0),
-        );
-        match expected_password_hash {
-            Err(_) => return Err(SignInError::UnknownUser),
-            Ok(expected_password_hash) => {
-                if Argon2::default()
-                    .verify_password(
-                        password.as_bytes(),
-                        &PasswordHash::new(&expected_password_hash).unwrap(),
-                    )
-                    .is_err()
-                {
-                    return Err(SignInError::InvalidPassword);
-                }
-            }
+    #[test]
+    fn accounts_from_before_activity_tracking_can_be_flagged() {
+        let db = Database::in_memory();
+        {
+            let conn = db.connection.lock().unwrap();
+            conn.execute_batch(
+                "INSERT INTO people (id, email, name, waiting) VALUES (7, 'old@example.com', 'old', FALSE);
+                 INSERT INTO auth (person, password_hash) VALUES (7, 'hash');",
+            )
+            .unwrap();
+            backfill_account_activity(&conn);
         }
-        let session_id = uuid::Uuid::new_v4().to_string();
-        let time = chrono::offset::Utc::now().timestamp();
-        conn.execute(
-            "INSERT INTO sessions (id, person, last_seen) VALUES (?1, (SELECT id FROM people WHERE email = ?2), ?3)",
-            params![session_id, email, time],
-        ).unwrap();
-        Ok(session_id)
+        let later = db.clock.timestamp() + 3600;
+        assert_eq!(db.flag_stale_accounts(later, later).unwrap(), 1);
+        let stale = db.stale_accounts().unwrap();
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].person.id, PersonId(7));
     }
 
-    pub fn sign_out_session(&self, session_id: &str) {
-        let conn = self.connection.lock().unwrap();
-        conn.execute("DELETE FROM sessions WHERE id = ?1", [session_id])
+    #[test]
+    fn imported_accounts_are_only_claimed_from_their_email() {
+        let db = Database::in_memory();
+        db.import_slack_members(&[("Ua".to_owned(), "a@example.com".to_owned(), "a".to_owned())])
+            .unwrap();
+        let person = db.all_people().unwrap()[0].id;
+        let Err(SignUpError::Imported { token }) = db
+            .sign_up_session(
+                "Mallory",
+                "a@example.com",
+                "a long password",
+                DEFAULT_ORGANISATION,
+            )
+            .unwrap()
+        else {
+            panic!("signed up to an imported account");
+        };
+        assert!(db
+            .sign_in_session("a@example.com", "a long password")
+            .unwrap()
+            .is_err());
+        assert_eq!(db.get_person(person).unwrap().unwrap().name, "a");
+
+        let now = db.clock.timestamp();
+        assert_eq!(db.activate_account("wrong", now).unwrap(), None);
+        let (activated, session) = db.activate_account(&token, now).unwrap().unwrap();
+        assert_eq!(activated, person);
+        assert_eq!(db.get_session(&session, now).unwrap(), Some(person));
+        assert_eq!(db.get_person(person).unwrap().unwrap().name, "Mallory");
+        assert!(db
+            .sign_in_session("a@example.com", "a long password")
+            .unwrap()
+            .is_ok());
+        assert_eq!(db.activate_account(&token, now).unwrap(), None);
+    }
+
+    #[test]
+    fn pool_admins_only_run_their_pools() {
+        let db = Database::in_memory();
+        db.import_slack_members(&[
+            ("Ua".to_owned(), "a@example.com".to_owned(), "a".to_owned()),
+            ("Ub".to_owned(), "b@example.com".to_owned(), "b".to_owned()),
+        ])
+        .unwrap();
+        let a = db.person_with_email("a@example.com").unwrap().unwrap();
+        let b = db.person_with_email("B@example.com").unwrap().unwrap();
+        assert!(db.create_pool("chess", "").unwrap());
+        assert!(db.create_pool("lunch", "").unwrap());
+        db.add_admin(a).unwrap();
+
+        assert!(!db.is_pool_admin(b, "lunch").unwrap());
+        assert!(!db.add_pool_admin("nope", b).unwrap());
+        assert!(db.add_pool_admin("lunch", b).unwrap());
+        assert!(db.is_pool_admin(b, "lunch").unwrap());
+        assert!(!db.is_pool_admin(b, "chess").unwrap());
+        assert_eq!(db.administered_pools(b).unwrap(), vec!["lunch".to_owned()]);
+        assert!(db.is_pool_admin(a, "chess").unwrap());
+        assert!(db.administered_pools(a).unwrap().is_empty());
+        let pools = db.pools().unwrap();
+        let lunch = pools.iter().find(|pool| pool.name == "lunch").unwrap();
+        assert_eq!(lunch.admins.len(), 1);
+        assert_eq!(lunch.admins[0].id, b);
+
+        db.remove_pool_admin("lunch", b).unwrap();
+        assert!(!db.is_pool_admin(b, "lunch").unwrap());
+    }
+
+    #[test]
+    fn unsubscribe_tokens_are_stored_hashed() {
+        let db = Database::in_memory();
+        db.import_slack_members(&[("Ua".to_owned(), "a@example.com".to_owned(), "a".to_owned())])
+            .unwrap();
+        let person = db.all_people().unwrap()[0].id;
+        let token = db
+            .email_recipients(&[person])
+            .unwrap()
+            .remove(&person)
+            .unwrap();
+        let stored: String = db
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT token FROM unsubscribe_tokens", [], |row| row.get(0))
             .unwrap();
+        assert_eq!(stored, hash_token(&token));
+        assert!(!db.unsubscribe(&stored).unwrap());
+        assert!(db.unsubscribe(&token).unwrap());
+        assert!(!db.email_notifications(person).unwrap());
+        assert!(db.email_recipients(&[person]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn imported_pools_join_their_organisation_and_pool() {
+        let db = Database::in_memory();
+        let organisation = db.create_organisation("Moved").unwrap().id;
+        db.create_pool("lunch", "").unwrap();
+        let member = |id, name: &str| PoolMember {
+            id: PersonId(id),
+            name: name.to_owned(),
+            email: format!("{name}@example.com"),
+            team: None,
+            status: None,
+            timezone: None,
+            waiting: true,
+            interests: Vec::new(),
+        };
+        let export = PoolExport {
+            branding: Branding {
+                product_name: "Elsewhere".to_owned(),
+                ..Branding::default()
+            },
+            schedule: None,
+            members: vec![member(7, "a"), member(8, "b")],
+            generations: vec![PoolGeneration {
+                time: 1,
+                round_type: DEFAULT_ROUND_TYPE.to_owned(),
+                quality: None,
+                groups: vec![vec![PersonId(7), PersonId(8)]],
+            }],
+        };
+        let options = PoolImportOptions {
+            organisation,
+            pool: Some("lunch".to_owned()),
+            branding: false,
+            schedule: false,
+        };
+        let Ok(summary) = db.import_pool(&export, &options).unwrap() else {
+            panic!("import failed");
+        };
+        assert_eq!((summary.created, summary.generations), (2, 1));
+
+        let people = db.all_people().unwrap();
+        assert_eq!(people.len(), 2);
+        for person in &people {
+            assert_eq!(db.organisation_of(person.id).unwrap(), organisation);
+        }
+        let mut waiting = db.waiters_for("lunch").unwrap();
+        waiting.sort();
+        assert_eq!(waiting, people.iter().map(|p| p.id).collect::<Vec<_>>());
+        assert!(db.waiters().unwrap().is_empty());
+        assert_eq!(db.rounds_run("lunch").unwrap(), 1);
+        assert_eq!(db.branding().unwrap().product_name, "Matcher");
+
+        let missing = PoolImportOptions {
+            pool: Some("dinner".to_owned()),
+            ..options
+        };
+        assert!(matches!(
+            db.import_pool(&export, &missing).unwrap(),
+            Err(PoolImportError::UnknownPool(_))
+        ));
     }
 }
@@ -0,0 +1,216 @@
+use std::{fmt, str::FromStr};
+
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+
+// Declare a typed id, stored and serialized as the plain integer so it works unchanged in SQL,
+// paths, forms and templates.
+macro_rules! id {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+        #[serde(transparent)]
+        pub struct $name(pub u32);
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = std::num::ParseIntError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                s.parse().map(Self)
+            }
+        }
+
+        impl ToSql for $name {
+            fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+                self.0.to_sql()
+            }
+        }
+
+        impl FromSql for $name {
+            fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+                u32::column_result(value).map(Self)
+            }
+        }
+    };
+}
+
+id!(
+    // A row in `people`.
+    PersonId
+);
+
+id!(
+    // A round of matching, a row in `generations`.
+    GenerationId
+);
+
+id!(
+    // A company or team with its own people, a row in `organisations`.
+    OrganisationId
+);
+
+id!(
+    // People kept apart by admins, a row in `conflict_groups`.
+    ConflictGroupId
+);
+
+id!(
+    // A conversation starter given to matches, a row in `icebreakers`.
+    IcebreakerId
+);
+
+id!(
+    // An email admins sent to a segment of people, a row in `mailings`.
+    MailingId
+);
+
+// Where everyone who signed up before organisations, or without an organisation's link, belongs.
+// Its admins run the deployment.
+pub const DEFAULT_ORGANISATION: OrganisationId = OrganisationId(1);
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Person {
+    pub id: PersonId,
+    pub email: String,
+    pub name: String,
+    pub waiting: bool,
+    pub status: Option<String>,
+    pub team: Option<String>,
+}
+
+// Someone a person was matched with, and in which round.
+#[derive(Debug, Clone, Serialize)]
+pub struct PastMatch {
+    pub generation: GenerationId,
+    // When the generation was matched, in seconds since the epoch.
+    pub time: u64,
+    pub round_type: String,
+    pub partner: Person,
+    // The conversation starter the group was given, if there were any to choose from.
+    pub icebreaker: Option<String>,
+}
+
+// A group from a round, of one person when they couldn't be matched.
+#[derive(Debug)]
+pub struct Match {
+    pub members: Vec<Person>,
+    // A prompt chosen at random when the group was matched, to make the first message easier.
+    pub icebreaker: Option<String>,
+}
+
+impl Match {
+    // Everyone in the group, in the order they were matched.
+    pub fn members(&self) -> impl Iterator<Item = &Person> {
+        self.members.iter()
+    }
+}
+
+// Groups used to be at most three, so the first three members are still given as `person1` to
+// `person3` for API clients written before `members`.
+impl Serialize for Match {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct("Match", 5)?;
+        s.serialize_field("person1", &self.members.first())?;
+        s.serialize_field("person2", &self.members.get(1))?;
+        s.serialize_field("person3", &self.members.get(2))?;
+        s.serialize_field("members", &self.members)?;
+        s.serialize_field("icebreaker", &self.icebreaker)?;
+        s.end()
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchMeta {
+    pub generation: GenerationId,
+    pub time: u64,
+    // Which kind of round this was, e.g. `coffee` or `mentoring`. Each has its own latest generation.
+    pub round_type: String,
+    // Only recorded for generations matched since quality tracking was added.
+    pub quality: Option<MatchQuality>,
+    // What admins called the round when they ran it, e.g. `January 2025 round`.
+    pub title: Option<String>,
+    pub notes: Option<String>,
+}
+
+// A name and notes admins can give a round when they run it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GenerationDetails {
+    pub title: Option<String>,
+    pub notes: Option<String>,
+}
+
+// How good a round of matching was, so organisers can tell when a cheaper strategy had to be used.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchQuality {
+    // `exact` when everyone was matched together, `bucketed` when the pool was too large and was
+    // split up first.
+    pub strategy: String,
+    pub buckets: u32,
+    // Pairs who had already met before this generation.
+    pub repeat_pairs: u32,
+    // The sum of how many times each pair had met before, what the matcher minimises.
+    pub total_weight: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_plain_integers() {
+        assert_eq!(serde_json::to_string(&PersonId(7)).unwrap(), "7");
+        assert_eq!(
+            serde_json::from_str::<GenerationId>("12").unwrap(),
+            GenerationId(12)
+        );
+        assert_eq!("42".parse::<PersonId>().unwrap(), PersonId(42));
+        assert!("-1".parse::<PersonId>().is_err());
+        assert_eq!(PersonId(42).to_string(), "42");
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let id: PersonId = conn
+            .query_row("SELECT ?1", [PersonId(5)], |row| row.get(0))
+            .unwrap();
+        assert_eq!(id, PersonId(5));
+        let stored: i64 = conn
+            .query_row("SELECT ?1", [GenerationId(9)], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, 9);
+        assert!(conn
+            .query_row("SELECT -1", [], |row| row.get::<_, PersonId>(0))
+            .is_err());
+    }
+
+    #[test]
+    fn matches_keep_the_numbered_people() {
+        let person = |id, name: &str| Person {
+            id: PersonId(id),
+            email: format!("{name}@example.com"),
+            name: name.to_owned(),
+            waiting: false,
+            status: None,
+            team: None,
+        };
+        let group = Match {
+            members: vec![person(1, "a"), person(2, "b")],
+            icebreaker: Some("Tea or coffee?".to_owned()),
+        };
+        let json = serde_json::to_value(&group).unwrap();
+        assert_eq!(json["person1"]["id"], 1);
+        assert_eq!(json["person2"]["name"], "b");
+        assert!(json["person3"].is_null());
+        assert_eq!(json["members"].as_array().unwrap().len(), 2);
+        assert_eq!(json["icebreaker"], "Tea or coffee?");
+        assert_eq!(
+            group.members().map(|p| p.id).collect::<Vec<_>>(),
+            [PersonId(1), PersonId(2)]
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use axum::{
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use crate::db::DatabaseError;
+
+// Why a request couldn't be answered. Responses made from these are turned into the error page by
+// the `error_pages` middleware, which has what's needed to render it.
+#[derive(Debug, Clone, Copy)]
+pub enum AppError {
+    // The database stayed busy, trying again in a moment should work.
+    Busy,
+    // Something broke that the person can't do anything about.
+    Internal,
+    NotFound,
+    Forbidden,
+}
+
+impl AppError {
+    pub fn status(self) -> StatusCode {
+        match self {
+            AppError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::Busy => write!(f, "{}", DatabaseError::Busy),
+            AppError::Internal => write!(f, "Something went wrong, please try again later."),
+            AppError::NotFound => write!(f, "There's nothing here."),
+            AppError::Forbidden => write!(f, "You don't have access to this."),
+        }
+    }
+}
+
+// Database failures have already been logged by `Database::call`.
+impl From<DatabaseError> for AppError {
+    fn from(error: DatabaseError) -> Self {
+        match error {
+            DatabaseError::Busy => AppError::Busy,
+            DatabaseError::Sqlite(_) => AppError::Internal,
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), self.to_string()).into_response();
+        if let AppError::Busy = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from_static("5"));
+        }
+        response.extensions_mut().insert(self);
+        response
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use crate::domain::{GenerationId, Match, Person, PersonId};
+
+// Custom behaviour to run when things happen, so forks can extend matcher without patching the
+// handlers. Implementations are registered in `main.rs` and every method defaults to doing nothing.
+pub trait Hook: Send + Sync {
+    fn on_generation_created(&self, _generation: GenerationId, _matches: &[Match]) {}
+
+    fn on_person_signed_up(&self, _person: &Person) {}
+
+    // Where to report errors to an error tracker, so far only pages failing to render.
+    fn on_render_failed(&self, _template: &str, _error: &str) {}
+
+    // When someone rates or comments on a match they were in. There is nowhere to leave feedback
+    // yet, so this is never called.
+    #[allow(dead_code)]
+    fn on_match_feedback(&self, _generation: GenerationId, _person: PersonId, _feedback: &str) {}
+}
+
+// The registered hooks, each called in registration order.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    hooks: Arc<Vec<Box<dyn Hook>>>,
+}
+
+impl Hooks {
+    pub fn new(hooks: Vec<Box<dyn Hook>>) -> Self {
+        Self {
+            hooks: Arc::new(hooks),
+        }
+    }
+
+    pub fn generation_created(&self, generation: GenerationId, matches: &[Match]) {
+        for hook in self.hooks.iter() {
+            hook.on_generation_created(generation, matches);
+        }
+    }
+
+    pub fn person_signed_up(&self, person: &Person) {
+        for hook in self.hooks.iter() {
+            hook.on_person_signed_up(person);
+        }
+    }
+
+    pub fn render_failed(&self, template: &str, error: &str) {
+        for hook in self.hooks.iter() {
+            hook.on_render_failed(template, error);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::time::Instant;
+
+use axum::{extract::Request, middleware::Next, response::Response};
+use serde::Deserialize;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    // One human readable line per event.
+    #[default]
+    Text,
+    // One JSON object per line, for log collectors.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format {s:?}")),
+        }
+    }
+}
+
+// Log the method, path and status of every request, along with how long it took. Handlers add
+// fields such as `person_id` to the request's span, so events logged while handling it carry them.
+pub async fn log_requests(request: Request, next: Next) -> Response {
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        person_id = tracing::field::Empty,
+    );
+    async move {
+        let start = Instant::now();
+        let response = next.run(request).await;
+        tracing::info!(
+            status = response.status().as_u16(),
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "Handled request"
+        );
+        response
+    }
+    .instrument(span)
+    .await
+}
+
+// Write events to stdout, in `format`, along with the fields of the spans they happened in. `filter`
+// is a level, like `info`, or directives like `info,matcher::mail=debug`.
+pub fn init(filter: &str, format: LogFormat) {
+    let filter = EnvFilter::try_new(filter)
+        .expect("Invalid log level, expected off, error, warn, info, debug or trace");
+    let logs = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Text => logs.init(),
+        LogFormat::Json => logs.json().with_span_list(true).init(),
+    }
+}
@@ -0,0 +1,735 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use base64ct::{Base64, Encoding};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tera::{Context, Tera};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::Semaphore,
+    task::JoinSet,
+};
+
+use crate::{
+    db::{Branding, ContactField},
+    domain::{GenerationId, Match, Person, PersonId},
+    tls,
+};
+
+// A time as someone reads it, in their own timezone or UTC when they haven't set one.
+fn local_time(time: DateTime<Utc>, timezone: Option<&String>) -> String {
+    let timezone: chrono_tz::Tz = timezone
+        .and_then(|timezone| timezone.parse().ok())
+        .unwrap_or(chrono_tz::UTC);
+    time.with_timezone(&timezone)
+        .format("%A %-d %B %Y, %H:%M %Z")
+        .to_string()
+}
+
+// How long connecting, and then each command, can take before the server is given up on.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Email is only sent when an SMTP host has been configured.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MailConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub tls: MailTls,
+    // Defaults to `matcher@<host>`.
+    pub from: Option<String>,
+    // How many emails can be sent at once when notifying a generation.
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,
+    // The provider's sending limit, unset means no limit.
+    pub per_minute: Option<u32>,
+}
+
+fn default_port() -> u16 {
+    25
+}
+
+fn default_max_concurrent() -> usize {
+    4
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: default_port(),
+            username: None,
+            password: None,
+            tls: MailTls::default(),
+            from: None,
+            max_concurrent: default_max_concurrent(),
+            per_minute: None,
+        }
+    }
+}
+
+impl MailConfig {
+    fn sender(&self) -> String {
+        self.from
+            .clone()
+            .unwrap_or_else(|| format!("matcher@{}", self.host))
+    }
+}
+
+// How the connection to the SMTP server is secured. The username and password are only ever sent
+// over TLS.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MailTls {
+    // Upgrade with STARTTLS when the server offers it, which it must if there are credentials.
+    #[default]
+    Starttls,
+    // TLS from the start, usually on port 465.
+    Implicit,
+    // Plain text only, e.g. for a relay on the same host.
+    None,
+}
+
+impl MailTls {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MailTls::Starttls => "starttls",
+            MailTls::Implicit => "implicit",
+            MailTls::None => "none",
+        }
+    }
+}
+
+impl std::str::FromStr for MailTls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "starttls" => Ok(Self::Starttls),
+            "implicit" => Ok(Self::Implicit),
+            "none" => Ok(Self::None),
+            _ => Err(format!("unknown SMTP TLS mode {s:?}")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    Io(std::io::Error),
+    Rejected(String),
+    Render(tera::Error),
+    TimedOut,
+    // There are credentials but the connection couldn't be secured to send them.
+    Insecure,
+}
+
+impl std::fmt::Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailError::Io(error) => write!(f, "connection failed: {error}"),
+            MailError::Rejected(reply) => write!(f, "server rejected message: {reply}"),
+            MailError::Render(error) => write!(f, "failed to render email: {error}"),
+            MailError::TimedOut => write!(f, "server didn't answer in time"),
+            MailError::Insecure => write!(
+                f,
+                "server doesn't offer STARTTLS, so the credentials weren't sent"
+            ),
+        }
+    }
+}
+
+impl MailError {
+    // Whether the server refused the message outright (a 5xx reply), so trying again won't help.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, MailError::Rejected(reply) if reply.starts_with('5'))
+    }
+}
+
+impl From<std::io::Error> for MailError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<tera::Error> for MailError {
+    fn from(error: tera::Error) -> Self {
+        Self::Render(error)
+    }
+}
+
+// Someone in a match, with the contact details they share with their matches.
+#[derive(Serialize)]
+struct Partner<'a> {
+    #[serde(flatten)]
+    person: &'a Person,
+    contact_fields: &'a [ContactField],
+}
+
+// A group being introduced to each other by email.
+pub struct Introduction<'a> {
+    pub generation: GenerationId,
+    pub members: Vec<&'a Person>,
+    // People nobody in the group has met, suggested for them to meet next.
+    pub suggestions: &'a [Person],
+    pub contact_fields: &'a HashMap<PersonId, Vec<ContactField>>,
+    pub icebreaker: Option<&'a str>,
+}
+
+// How far sending a generation's match emails has got.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SendProgress {
+    pub total: usize,
+    pub sent: usize,
+    pub failed: usize,
+    pub finished: bool,
+}
+
+#[derive(Clone)]
+pub struct Mailer {
+    // Replaced when an admin saves new settings.
+    config: Arc<RwLock<Option<Arc<MailConfig>>>>,
+    templates: Arc<Tera>,
+    public_url: String,
+    // Keyed by generation, only covers generations notified since startup.
+    progress: Arc<Mutex<HashMap<GenerationId, SendProgress>>>,
+}
+
+impl Mailer {
+    pub fn new(config: Option<MailConfig>, template_dir: &str, public_url: &str) -> Self {
+        Self {
+            config: Arc::new(RwLock::new(config.map(Arc::new))),
+            templates: Arc::new(Tera::new(&format!("{template_dir}/emails/*")).unwrap()),
+            public_url: public_url.trim_end_matches('/').to_owned(),
+            progress: Arc::default(),
+        }
+    }
+
+    pub fn progress(&self, generation: GenerationId) -> Option<SendProgress> {
+        self.progress.lock().unwrap().get(&generation).cloned()
+    }
+
+    fn update_progress(&self, generation: GenerationId, update: impl FnOnce(&mut SendProgress)) {
+        update(self.progress.lock().unwrap().entry(generation).or_default());
+    }
+
+    fn config(&self) -> Option<Arc<MailConfig>> {
+        self.config.read().unwrap().clone()
+    }
+
+    // Switch to settings saved by an admin. They only cover the server, so the sending limits from
+    // the config file are kept.
+    pub fn set_config(&self, config: MailConfig) {
+        let mut current = self.config.write().unwrap();
+        let config = match current.as_deref() {
+            Some(current) => MailConfig {
+                max_concurrent: current.max_concurrent,
+                per_minute: current.per_minute,
+                ..config
+            },
+            None => config,
+        };
+        *current = Some(Arc::new(config));
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.config.read().unwrap().is_some()
+    }
+
+    // Ask someone to confirm a new email address by following a link sent to it.
+    pub async fn verify_email(
+        &self,
+        to: &str,
+        name: &str,
+        token: &str,
+        branding: &Branding,
+    ) -> Result<(), MailError> {
+        let mut context = Context::new();
+        context.insert("branding", branding);
+        context.insert("name", name);
+        context.insert(
+            "verify_url",
+            &format!("{}/verify_email/{token}", self.public_url),
+        );
+        let body = self.templates.render("verify_email.txt", &context)?;
+        self.send(to, "Confirm your new email address", &body, None)
+            .await
+    }
+
+    // Send the link an imported person follows to finish signing up, proving the address is theirs.
+    pub async fn activate_account(
+        &self,
+        to: &str,
+        name: &str,
+        token: &str,
+        branding: &Branding,
+    ) -> Result<(), MailError> {
+        let mut context = Context::new();
+        context.insert("branding", branding);
+        context.insert("name", name);
+        context.insert(
+            "activate_url",
+            &format!("{}/activate/{token}", self.public_url),
+        );
+        let body = self.templates.render("activate_account.txt", &context)?;
+        self.send(to, "Finish signing up", &body, None).await
+    }
+
+    // Let an admin know that a scheduled run was skipped because too few people were waiting.
+    pub async fn notify_run_skipped(
+        &self,
+        admin: &Person,
+        waiting: usize,
+        min_waiters: usize,
+        retry: DateTime<Utc>,
+        timezone: Option<&String>,
+        branding: &Branding,
+    ) -> Result<(), MailError> {
+        let mut context = Context::new();
+        context.insert("branding", branding);
+        context.insert("name", &admin.name);
+        context.insert("waiting", &waiting);
+        context.insert("min_waiters", &min_waiters);
+        context.insert("retry", &local_time(retry, timezone));
+        let body = self.templates.render("run_skipped.txt", &context)?;
+        self.send(&admin.email, "Scheduled matching skipped", &body, None)
+            .await
+    }
+
+    // The email telling `person` who they were matched with, and its unsubscribe URL.
+    fn match_email(
+        &self,
+        group: &Introduction,
+        person: &Person,
+        branding: &Branding,
+        unsubscribe_token: &str,
+    ) -> (Result<String, tera::Error>, String) {
+        let unsubscribe_url = format!("{}/unsubscribe/{unsubscribe_token}", self.public_url);
+        let partners: Vec<_> = group
+            .members
+            .iter()
+            .filter(|p| p.id != person.id)
+            .map(|p| Partner {
+                person: p,
+                contact_fields: group.contact_fields.get(&p.id).map_or(&[], |f| f),
+            })
+            .collect();
+        let mut context = Context::new();
+        context.insert("branding", branding);
+        context.insert("generation", &group.generation);
+        context.insert("person", person);
+        context.insert("partners", &partners);
+        context.insert("suggestions", group.suggestions);
+        context.insert("icebreaker", &group.icebreaker);
+        context.insert("unsubscribe_url", &unsubscribe_url);
+        (
+            self.templates.render("match.txt", &context),
+            unsubscribe_url,
+        )
+    }
+
+    // Send one person their match email again, e.g. after it bounced, or the one saying they weren't
+    // matched if they were on their own.
+    pub async fn resend_match(
+        &self,
+        group: &Introduction<'_>,
+        person: &Person,
+        branding: &Branding,
+        unsubscribe_token: &str,
+    ) -> Result<(), MailError> {
+        let (subject, (body, unsubscribe_url)) = if group.members.len() < 2 {
+            let email =
+                self.unmatched_email(group.generation, person, None, branding, unsubscribe_token);
+            ("You weren't matched this round", email)
+        } else {
+            let email = self.match_email(group, person, branding, unsubscribe_token);
+            ("You have a new match", email)
+        };
+        self.send(&person.email, subject, &body?, Some(&unsubscribe_url))
+            .await
+    }
+
+    // Tell someone left unmatched in a generation that they'll have priority next round, and when
+    // that is if it's scheduled.
+    fn unmatched_email(
+        &self,
+        generation: GenerationId,
+        person: &Person,
+        next_run: Option<(DateTime<Utc>, Option<&String>)>,
+        branding: &Branding,
+        unsubscribe_token: &str,
+    ) -> (Result<String, tera::Error>, String) {
+        let unsubscribe_url = format!("{}/unsubscribe/{unsubscribe_token}", self.public_url);
+        let mut context = Context::new();
+        context.insert("branding", branding);
+        context.insert("generation", &generation);
+        context.insert("person", person);
+        context.insert(
+            "next_run",
+            &next_run.map(|(run, timezone)| local_time(run, timezone)),
+        );
+        context.insert("public_url", &self.public_url);
+        context.insert("unsubscribe_url", &unsubscribe_url);
+        (
+            self.templates.render("unmatched.txt", &context),
+            unsubscribe_url,
+        )
+    }
+
+    // Tell everyone in a new generation who they have been matched with, along with the suggestions
+    // of people to meet for each match, and anyone left on their own that they'll have priority at
+    // `next_run`, in their own timezone. Only people with an unsubscribe token are emailed, anyone
+    // else has turned notifications off. Large generations are sent a few at a time and no faster
+    // than the provider allows, with progress kept for `progress`. Returns how sending to each
+    // person went.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn notify_matches(
+        &self,
+        generation: GenerationId,
+        matches: &[Match],
+        suggestions: &[Vec<Person>],
+        contact_fields: &HashMap<PersonId, Vec<ContactField>>,
+        branding: &Branding,
+        unsubscribe_tokens: &HashMap<PersonId, String>,
+        next_run: Option<DateTime<Utc>>,
+        timezones: &HashMap<PersonId, String>,
+    ) -> Vec<(PersonId, Result<(), MailError>)> {
+        let Some(config) = self.config() else {
+            return Vec::new();
+        };
+        let mut emails = Vec::new();
+        let mut unmatched = Vec::new();
+        for (m, suggestions) in matches.iter().zip(suggestions) {
+            let group = Introduction {
+                generation,
+                members: m.members().collect(),
+                suggestions,
+                contact_fields,
+                icebreaker: m.icebreaker.as_deref(),
+            };
+            if let [person] = group.members[..] {
+                if let Some(unsubscribe_token) = unsubscribe_tokens.get(&person.id) {
+                    let (body, unsubscribe_url) = self.unmatched_email(
+                        generation,
+                        person,
+                        next_run.map(|run| (run, timezones.get(&person.id))),
+                        branding,
+                        unsubscribe_token,
+                    );
+                    unmatched.push((person.id, person.email.clone(), body, unsubscribe_url));
+                }
+                continue;
+            }
+            for person in &group.members {
+                let Some(unsubscribe_token) = unsubscribe_tokens.get(&person.id) else {
+                    continue;
+                };
+                let (body, unsubscribe_url) =
+                    self.match_email(&group, person, branding, unsubscribe_token);
+                emails.push((person.id, person.email.clone(), body, unsubscribe_url));
+            }
+        }
+        self.update_progress(generation, |progress| {
+            progress.total = emails.len() + unmatched.len()
+        });
+        let mailer = self.clone();
+        let sent = move |result: &Result<(), MailError>| {
+            mailer.update_progress(generation, |progress| {
+                if result.is_ok() {
+                    progress.sent += 1;
+                } else {
+                    progress.failed += 1;
+                }
+            });
+        };
+        let mut results = self
+            .send_batch(&config, "You have a new match", emails, sent.clone())
+            .await;
+        let subject = "You weren't matched this round";
+        results.extend(self.send_batch(&config, subject, unmatched, sent).await);
+        self.update_progress(generation, |progress| progress.finished = true);
+        results
+    }
+
+    // Email everyone in a segment an admin's message. Everyone given has an unsubscribe token, the
+    // caller leaves out anyone who turned emails off. Returns how sending to each person went.
+    pub async fn send_mailing(
+        &self,
+        subject: &str,
+        message: &str,
+        recipients: &[(Person, String)],
+        branding: &Branding,
+    ) -> Vec<(PersonId, Result<(), MailError>)> {
+        let Some(config) = self.config() else {
+            return Vec::new();
+        };
+        let emails = recipients
+            .iter()
+            .map(|(person, unsubscribe_token)| {
+                let unsubscribe_url =
+                    format!("{}/unsubscribe/{unsubscribe_token}", self.public_url);
+                let mut context = Context::new();
+                context.insert("branding", branding);
+                context.insert("name", &person.name);
+                context.insert("message", message);
+                context.insert("unsubscribe_url", &unsubscribe_url);
+                let body = self.templates.render("mailing.txt", &context);
+                (person.id, person.email.clone(), body, unsubscribe_url)
+            })
+            .collect();
+        self.send_batch(&config, subject, emails, |_| {}).await
+    }
+
+    // Invite everyone given to opt in to the run at `round_at`, each with their unsubscribe token and
+    // the token for their link to opt in, telling them when it is in their own timezone. Returns how
+    // sending to each person went.
+    pub async fn send_reminders(
+        &self,
+        round_at: DateTime<Utc>,
+        recipients: &[(Person, String, String)],
+        timezones: &HashMap<PersonId, String>,
+        branding: &Branding,
+    ) -> Vec<(PersonId, Result<(), MailError>)> {
+        let Some(config) = self.config() else {
+            return Vec::new();
+        };
+        let emails = recipients
+            .iter()
+            .map(|(person, unsubscribe_token, opt_in_token)| {
+                let unsubscribe_url =
+                    format!("{}/unsubscribe/{unsubscribe_token}", self.public_url);
+                let mut context = Context::new();
+                context.insert("branding", branding);
+                context.insert("name", &person.name);
+                context.insert("round_at", &local_time(round_at, timezones.get(&person.id)));
+                context.insert(
+                    "opt_in_url",
+                    &format!("{}/opt_in/{opt_in_token}", self.public_url),
+                );
+                context.insert("unsubscribe_url", &unsubscribe_url);
+                let body = self.templates.render("round_reminder.txt", &context);
+                (person.id, person.email.clone(), body, unsubscribe_url)
+            })
+            .collect();
+        let subject = format!("Join the next {} round", branding.product_name);
+        self.send_batch(&config, &subject, emails, |_| {}).await
+    }
+
+    // Send bulk emails a few at a time and no faster than the provider allows, calling `sent` with
+    // how each one went.
+    async fn send_batch(
+        &self,
+        config: &MailConfig,
+        subject: &str,
+        emails: Vec<(PersonId, String, Result<String, tera::Error>, String)>,
+        sent: impl Fn(&Result<(), MailError>) + Clone + Send + 'static,
+    ) -> Vec<(PersonId, Result<(), MailError>)> {
+        let subject: Arc<str> = subject.into();
+        let permits = Arc::new(Semaphore::new(config.max_concurrent.max(1)));
+        let mut throttle = config
+            .per_minute
+            .map(|limit| tokio::time::interval(Duration::from_secs(60) / limit.max(1)));
+        let mut sends = JoinSet::new();
+        for (person_id, to, body, unsubscribe_url) in emails {
+            if let Some(throttle) = &mut throttle {
+                throttle.tick().await;
+            }
+            let permit = permits.clone().acquire_owned().await.unwrap();
+            let mailer = self.clone();
+            let subject = subject.clone();
+            let sent = sent.clone();
+            sends.spawn(async move {
+                let result = match body {
+                    Ok(body) => {
+                        mailer
+                            .send(&to, &subject, &body, Some(&unsubscribe_url))
+                            .await
+                    }
+                    Err(error) => Err(error.into()),
+                };
+                drop(permit);
+                if let Err(error) = &result {
+                    tracing::warn!(%to, subject = &*subject, %error, "Failed to send email");
+                }
+                sent(&result);
+                (person_id, result)
+            });
+        }
+        let mut results = Vec::new();
+        while let Some(result) = sends.join_next().await {
+            results.push(result.unwrap());
+        }
+        results
+    }
+
+    // Send a plain text email. Bulk emails should include an unsubscribe URL, which is advertised
+    // with one-click List-Unsubscribe headers (RFC 8058).
+    pub async fn send(
+        &self,
+        to: &str,
+        subject: &str,
+        body: &str,
+        unsubscribe_url: Option<&str>,
+    ) -> Result<(), MailError> {
+        let Some(config) = self.config() else {
+            return Ok(());
+        };
+        let stream = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            TcpStream::connect((config.host.as_str(), config.port)),
+        )
+        .await
+        .map_err(|_| MailError::TimedOut)??;
+
+        let mut message = format!(
+            "From: {}\r\nTo: {to}\r\nSubject: {subject}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n",
+            config.sender(),
+            chrono::offset::Utc::now().to_rfc2822(),
+        );
+        if let Some(url) = unsubscribe_url {
+            message.push_str(&format!(
+                "List-Unsubscribe: <{url}>\r\nList-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n"
+            ));
+        }
+        message.push_str("\r\n");
+        for line in body.lines() {
+            // Lines starting with a dot would otherwise end the message early.
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message.push_str(".\r\n");
+
+        if config.tls == MailTls::Implicit {
+            let mut stream = BufReader::new(tls::connect(&config.host, stream).await?);
+            expect_reply(&mut stream, "220").await?;
+            command(&mut stream, "EHLO matcher", "250").await?;
+            return deliver(&mut stream, &config, true, to, &message).await;
+        }
+        let mut stream = BufReader::new(stream);
+        expect_reply(&mut stream, "220").await?;
+        let extensions = command(&mut stream, "EHLO matcher", "250").await?;
+        let offers_starttls = extensions.iter().any(|line| {
+            line.get(4..)
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("STARTTLS"))
+        });
+        if config.tls == MailTls::Starttls && offers_starttls {
+            command(&mut stream, "STARTTLS", "220").await?;
+            let mut stream = BufReader::new(tls::connect(&config.host, stream.into_inner()).await?);
+            command(&mut stream, "EHLO matcher", "250").await?;
+            return deliver(&mut stream, &config, true, to, &message).await;
+        }
+        deliver(&mut stream, &config, false, to, &message).await
+    }
+}
+
+// Sign in if there are credentials, then send `message` to `to` on a connection that has said hello.
+async fn deliver<S>(
+    stream: &mut BufReader<S>,
+    config: &MailConfig,
+    secure: bool,
+    to: &str,
+    message: &str,
+) -> Result<(), MailError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        if !secure {
+            return Err(MailError::Insecure);
+        }
+        let token = Base64::encode_string(format!("\0{username}\0{password}").as_bytes());
+        command(stream, &format!("AUTH PLAIN {token}"), "235").await?;
+    }
+    command(stream, &format!("MAIL FROM:<{}>", config.sender()), "250").await?;
+    command(stream, &format!("RCPT TO:<{to}>"), "250").await?;
+    command(stream, "DATA", "354").await?;
+    write(stream, message).await?;
+    expect_reply(stream, "250").await?;
+    command(stream, "QUIT", "221").await?;
+    Ok(())
+}
+
+async fn write<S>(stream: &mut BufReader<S>, text: &str) -> Result<(), MailError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(COMMAND_TIMEOUT, stream.write_all(text.as_bytes()))
+        .await
+        .map_err(|_| MailError::TimedOut)??;
+    Ok(())
+}
+
+// Send a command and check the reply has the expected code, returning the reply's lines.
+async fn command<S>(
+    stream: &mut BufReader<S>,
+    line: &str,
+    code: &str,
+) -> Result<Vec<String>, MailError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write(stream, &format!("{line}\r\n")).await?;
+    expect_reply(stream, code).await
+}
+
+// Read a possibly multi-line reply from the server, checking it has the expected code.
+async fn expect_reply<R>(reader: &mut R, code: &str) -> Result<Vec<String>, MailError>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let read = tokio::time::timeout(COMMAND_TIMEOUT, reader.read_line(&mut line))
+            .await
+            .map_err(|_| MailError::TimedOut)??;
+        if read == 0 {
+            return Err(MailError::Rejected("connection closed".to_owned()));
+        }
+        if !line.starts_with(code) {
+            return Err(MailError::Rejected(line.trim_end().to_owned()));
+        }
+        let line = line.trim_end().to_owned();
+        // Continuation lines have a dash after the code.
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line);
+        if last {
+            return Ok(lines);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn times_are_shown_in_the_recipients_timezone() {
+        let time = Utc.with_ymd_and_hms(2025, 6, 2, 9, 30, 0).unwrap();
+        let london = "Europe/London".to_owned();
+        assert_eq!(
+            local_time(time, Some(&london)),
+            "Monday 2 June 2025, 10:30 BST"
+        );
+        assert_eq!(local_time(time, None), "Monday 2 June 2025, 09:30 UTC");
+        let unknown = "Nowhere/Special".to_owned();
+        assert_eq!(
+            local_time(time, Some(&unknown)),
+            "Monday 2 June 2025, 09:30 UTC"
+        );
+    }
+}
@@ -1,46 +1,646 @@
-use axum::routing::{get, post};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::routing::{get, post, put};
 use tera::Tera;
-use tokio::join;
+use tokio::{
+    join,
+    sync::{watch, Notify},
+};
 
-use crate::web::AppState;
+use crate::{
+    clock::Clock,
+    config::Config,
+    crypto::Cipher,
+    db::{Database, LoginLockout},
+    domain::GenerationDetails,
+    hooks::Hooks,
+    mail::{MailTls, Mailer},
+    matching::MatchingSettings,
+    rate_limit::RateLimiter,
+    session::SessionCookies,
+    slack::SlackNotifier,
+    snapshot::Snapshots,
+    web::AppState,
+};
 
+mod cache;
+mod clock;
+mod config;
+mod crypto;
 mod db;
+mod domain;
+mod error;
+mod hooks;
+mod logging;
+mod mail;
 mod matching;
+mod pages;
+mod privacy;
+mod rate_limit;
+mod session;
+mod shutdown;
+mod slack;
+mod snapshot;
+mod tls;
+mod validation;
 mod web;
 
 #[tokio::main]
 async fn main() {
-    let tera = Tera::new("templates/*").unwrap();
+    let config = Config::load();
+    logging::init(&config.log_level, config.log_format);
+
+    let tera = Tera::new(&format!("{}/*", config.template_dir)).unwrap();
+    assert!(
+        config.cookie_key.as_ref().is_none_or(|key| key.len() >= 32),
+        "The cookie key must be at least 32 bytes"
+    );
+    let encryption_key = config.encryption_key();
+    assert!(
+        encryption_key.as_ref().is_none_or(|key| key.len() >= 32),
+        "The encryption key must be at least 32 bytes"
+    );
+    assert!(
+        !config.round_types.is_empty(),
+        "At least one round type is needed"
+    );
+    assert!(
+        config.group_sizes.values().all(|size| *size >= 2),
+        "Groups need at least two people"
+    );
+    assert!(
+        config
+            .smtp
+            .as_ref()
+            .is_none_or(|smtp| smtp.tls != MailTls::None || smtp.username.is_none()),
+        "SMTP credentials are only sent over TLS, set smtp.tls to starttls or implicit"
+    );
+    let sessions = SessionCookies::new(
+        config.session_lifetime_seconds,
+        &config.public_url,
+        config.cookie_key.as_deref(),
+    );
+
+    let clock = Clock::new(config.test_clock);
+    if config.test_clock {
+        tracing::warn!("Using a test clock, which can be moved forward from the private port");
+    }
+    let db = db::Database::init(
+        &config.database_path,
+        config.session_lifetime_seconds,
+        LoginLockout {
+            attempts: config.lockout_attempts,
+            seconds: config.lockout_minutes * 60,
+        },
+        clock.clone(),
+        Cipher::new(encryption_key.as_deref()),
+    );
+
+    let housekeeping = tokio::spawn(housekeeping(
+        db.clone(),
+        clock.clone(),
+        config.stale_account_days,
+        config.archive_pools_after_months,
+    ));
 
-    let db = db::Database::init();
+    // Nothing is running yet, so any unfinished generation was cut short by the last process.
+    let interrupted = db
+        .interrupted_generations()
+        .expect("Failed to look for interrupted generations");
+    for generation in &interrupted {
+        tracing::warn!(
+            generation = generation.generation.0,
+            stage = ?generation.stage,
+            groups = generation.groups,
+            "Found an interrupted generation, complete or roll it back from /matches"
+        );
+    }
 
-    let state = AppState { tera, db };
+    let mailer = Mailer::new(
+        config.smtp.clone(),
+        &config.template_dir,
+        &config.public_url,
+    );
+    // Settings saved from the setup take priority over the config file.
+    if let Some(saved) = db
+        .mail_settings()
+        .expect("Failed to read the saved mail settings")
+    {
+        mailer.set_config(saved);
+    }
 
+    let state = AppState {
+        tera,
+        db,
+        sessions,
+        auth_limiter: (config.auth_requests_per_minute > 0).then(|| {
+            Arc::new(RateLimiter::new(
+                config.auth_requests_per_minute,
+                Duration::from_secs(60),
+            ))
+        }),
+        trust_forwarded_for: config.trust_forwarded_for,
+        round_types: config.round_types.clone(),
+        mentoring_round_types: config.mentoring_round_types.clone(),
+        group_sizes: config.group_sizes.clone(),
+        matching: MatchingSettings {
+            odd_person_policy: config.odd_person_policy,
+            max_exact_pool_size: config.max_exact_pool_size,
+            edge_half_life: config.edge_half_life_generations,
+            repeat_window: config.repeat_window_generations,
+            interest_bonus: config.interest_bonus,
+            same_team_policy: config.same_team_policy,
+            same_team_penalty: config.same_team_penalty,
+            mentoring: false,
+            group_size: 2,
+            strategy: config.matching_strategy,
+        },
+        suggestions: config.introduction_suggestions,
+        mailer,
+        slack: SlackNotifier::new(config.slack.clone(), &config.public_url),
+        // Register custom `hooks::Hook` implementations here.
+        hooks: Hooks::new(vec![]),
+        default_schedule: config.schedule.clone(),
+        schedule_changed: Arc::new(Notify::new()),
+        matching_lock: Arc::default(),
+        min_run_interval_seconds: config.min_run_interval_seconds,
+        notifying: Arc::default(),
+        interrupted: Arc::new(std::sync::Mutex::new(
+            interrupted.iter().map(|g| g.generation).collect(),
+        )),
+        snapshots: Snapshots::new(&config.snapshot_dir, config.max_snapshots),
+        public_stats: config.public_stats,
+        locale: config.locale.clone(),
+        clock,
+    };
+
+    let scheduler = tokio::spawn(scheduled_matching(state.clone()));
+    let reminders = config
+        .reminder_days_before
+        .map(|days| tokio::spawn(round_reminders(state.clone(), days)));
+
+    let limit_auth = axum::middleware::from_fn_with_state(state.clone(), web::limit_auth);
     let app = axum::Router::new()
         .route("/", axum::routing::get(web::root))
         .route(
             "/person/:person_id",
             get(web::view_person).post(web::toggle_waiter),
         )
+        .route("/person/:person_id/vcard", get(web::vcard))
+        .route(
+            "/person/:person_id/matches/:generation",
+            get(web::match_detail),
+        )
+        .route("/person/:person_id/weights", get(web::partner_weights_page))
+        .route("/person/:person_id/status", post(web::set_status))
+        .route("/person/:person_id/timezone", post(web::set_timezone))
+        .route(
+            "/person/:person_id/notifications",
+            post(web::set_email_notifications),
+        )
+        .route("/person/:person_id/block", post(web::block))
+        .route("/person/:person_id/max_matches", post(web::set_max_matches))
+        .route("/person/:person_id/cadence", post(web::set_match_cadence))
+        .route("/person/:person_id/mentoring", post(web::set_mentoring))
+        .route("/person/:person_id/pools/:pool", post(web::set_pool_member))
+        .route(
+            "/person/:person_id/pools/:pool/waiting",
+            post(web::toggle_pool_waiter),
+        )
+        .route("/person/:person_id/pause", post(web::pause))
+        .route("/person/:person_id/edit", get(web::edit_person))
+        .route("/person/:person_id/export", get(web::export_person))
+        .route(
+            "/person/:person_id/delete",
+            get(web::delete_account).post(web::do_delete_account),
+        )
+        .route("/person/:person_id/edit/name", post(web::edit_name))
+        .route("/person/:person_id/edit/team", post(web::edit_team))
+        .route("/person/:person_id/edit/contact", post(web::edit_contact))
+        .route(
+            "/person/:person_id/edit/interests",
+            post(web::edit_interests),
+        )
+        .route("/person/:person_id/edit/email", post(web::edit_email))
+        .route("/person/:person_id/edit/password", post(web::edit_password))
+        .route("/verify_email/:token", get(web::verify_email))
+        .route(
+            "/activate/:token",
+            get(web::activate).post(web::do_activate),
+        )
+        .route("/person/:person_id/api_token", post(web::create_api_token))
+        .route(
+            "/person/:person_id/api_token/revoke",
+            post(web::revoke_api_token),
+        )
         .route("/people", get(web::all_people))
-        .route("/matches", get(web::matches))
+        .route(
+            "/matches",
+            get(web::matches).post(web::admin_trigger_matching),
+        )
+        .route("/matches/history", get(web::matches_history))
         .route("/matches/:generation", get(web::matches_generation))
-        .route("/sign_in", get(web::sign_in).post(web::do_sign_in))
-        .route("/sign_up", get(web::sign_up).post(web::do_sign_up))
+        .route("/matches/:generation/audit", get(web::matching_audit))
+        .route(
+            "/matches/:generation/notifications",
+            get(web::generation_notifications),
+        )
+        .route(
+            "/matches/:generation/notifications/:person_id/resend",
+            post(web::resend_notification),
+        )
+        .route(
+            "/matches/:generation/complete",
+            post(web::complete_generation),
+        )
+        .route(
+            "/matches/:generation/roll_back",
+            post(web::roll_back_generation),
+        )
+        .route("/branding", get(web::branding).post(web::set_branding))
+        .route("/schedule", get(web::schedule).post(web::set_schedule))
+        .route("/pools", get(web::pools).post(web::create_pool))
+        .route(
+            "/pools/:pool/manager_pairs",
+            post(web::set_allow_manager_pairs),
+        )
+        .route("/pools/:pool/archive", post(web::archive_pool))
+        .route("/pools/:pool/reactivate", post(web::reactivate_pool))
+        .route("/pools/:pool", get(web::pool))
+        .route("/pools/:pool/members", post(web::add_pool_members))
+        .route(
+            "/pools/:pool/members/:person_id/remove",
+            post(web::remove_pool_member),
+        )
+        .route(
+            "/pools/:pool/members/:person_id/waiting",
+            post(web::toggle_pool_member_waiting),
+        )
+        .route("/pools/:pool/admins", post(web::appoint_pool_admin))
+        .route(
+            "/pools/:pool/admins/:person_id/remove",
+            post(web::dismiss_pool_admin),
+        )
+        .route(
+            "/conflicts",
+            get(web::conflicts).post(web::create_conflict_group),
+        )
+        .route("/conflicts/:group/delete", post(web::delete_conflict_group))
+        .route(
+            "/conflicts/:group/members",
+            post(web::add_conflict_group_members),
+        )
+        .route(
+            "/conflicts/:group/members/:person_id/remove",
+            post(web::remove_conflict_group_member),
+        )
+        .route(
+            "/icebreakers",
+            get(web::icebreakers).post(web::create_icebreaker),
+        )
+        .route("/icebreakers/:icebreaker", post(web::update_icebreaker))
+        .route(
+            "/icebreakers/:icebreaker/delete",
+            post(web::delete_icebreaker),
+        )
+        .route("/mailings", get(web::mailings).post(web::send_mailing))
+        .route("/snapshots", get(web::snapshots))
+        .route("/snapshots/:name/restore", post(web::restore_snapshot))
+        .route("/edges.csv", get(web::edges_csv))
+        .route("/api/v1/people", get(web::api_people))
+        .route("/api/v1/person/:person_id", get(web::api_person))
+        .route(
+            "/api/v1/matches",
+            get(web::api_matches).post(web::api_record_matches),
+        )
+        .route("/api/v1/weights", get(web::api_weights))
+        .route(
+            "/api/v1/matches/:generation",
+            get(web::api_matches_generation),
+        )
+        .route(
+            "/unsubscribe/:token",
+            get(web::unsubscribe).post(web::do_unsubscribe),
+        )
+        .route("/opt_in/:token", get(web::opt_in).post(web::do_opt_in))
+        .route("/stats", get(web::public_stats))
+        .route("/app", get(web::app))
+        .route("/app/match", get(web::app_current_match))
+        .route("/app/waiting", post(web::app_toggle_waiting))
+        .route("/manifest.webmanifest", get(web::web_manifest))
+        .route("/service-worker.js", get(web::service_worker))
+        .route(
+            "/sign_in",
+            get(web::sign_in)
+                .post(web::do_sign_in)
+                .route_layer(limit_auth.clone()),
+        )
+        .route(
+            "/sign_up",
+            get(web::sign_up)
+                .post(web::do_sign_up)
+                .route_layer(limit_auth.clone()),
+        )
+        .route(
+            "/setup",
+            get(web::setup).post(web::do_setup).route_layer(limit_auth),
+        )
+        .route(
+            "/setup/email",
+            get(web::setup_email).post(web::set_up_email),
+        )
+        .route(
+            "/setup/schedule",
+            get(web::setup_schedule).post(web::set_up_schedule),
+        )
+        .route("/join/:token", get(web::join))
         .route("/sign_out", get(web::sign_out))
         .fallback(web::fallback)
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            web::error_pages,
+        ))
+        .layer(axum::middleware::from_fn(web::form_errors))
+        .layer(axum::middleware::from_fn(logging::log_requests))
         .with_state(state.clone());
 
     let ops_app = axum::Router::new()
         .route("/matches", post(web::trigger_matching))
-        .with_state(state);
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    let ops_listener = tokio::net::TcpListener::bind("0.0.0.0:3001").await.unwrap();
-    println!("Serving public on http://0.0.0.0:3000");
-    println!("Serving private on http://0.0.0.0:3001");
-    let public = axum::serve(listener, app);
-    let private = axum::serve(ops_listener, ops_app);
+        .route("/matches/preview", post(web::preview_matching))
+        .route(
+            "/generations/:generation/waiters",
+            get(web::generation_waiters),
+        )
+        .route("/stats", get(web::stats))
+        .route("/recompute-stats", post(web::recompute_stats))
+        .route("/clock", get(web::clock))
+        .route("/clock/advance", post(web::advance_clock))
+        .route("/waiting_history", get(web::waiting_history))
+        .route("/pool/export", get(web::export_pool))
+        .route("/pool/import", post(web::import_pool))
+        .route(
+            "/generations/:generation/notifications",
+            get(web::notification_progress),
+        )
+        .route(
+            "/generations/:generation/notifications/:person_id",
+            put(web::update_notification),
+        )
+        .route(
+            "/schedule",
+            get(web::api_schedule).put(web::api_set_schedule),
+        )
+        .route(
+            "/season_plan",
+            get(web::season_plan)
+                .post(web::create_season_plan)
+                .delete(web::cancel_season_plan),
+        )
+        .route("/api/v1/generations", get(web::api_generations))
+        .route("/import/slack", post(web::import_slack))
+        .route("/import/managers", post(web::import_managers))
+        .route("/stale_accounts", get(web::stale_accounts))
+        .route(
+            "/stale_accounts/:person_id/keep",
+            post(web::keep_stale_account),
+        )
+        .route(
+            "/stale_accounts/:person_id/delete",
+            post(web::delete_stale_account),
+        )
+        .route(
+            "/admins/:person_id",
+            post(web::add_admin).delete(web::remove_admin),
+        )
+        .route(
+            "/pools/:pool/admins/:person_id",
+            post(web::add_pool_admin).delete(web::remove_pool_admin),
+        )
+        .route(
+            "/deactivated/:person_id",
+            post(web::deactivate).delete(web::reactivate),
+        )
+        .route(
+            "/floaters/:person_id",
+            post(web::add_floater).delete(web::remove_floater),
+        )
+        .route(
+            "/organisations",
+            get(web::organisations).post(web::create_organisation),
+        )
+        .route(
+            "/people/:person_id/organisation/:organisation",
+            post(web::set_organisation),
+        )
+        .route("/metrics", get(web::metrics))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            web::error_pages,
+        ))
+        .layer(axum::middleware::from_fn(logging::log_requests))
+        // Added after the logging layer so frequent probes don't fill the logs.
+        .route("/healthz", get(web::healthz))
+        .route("/readyz", get(web::readyz))
+        .with_state(state.clone());
+    let listener = tokio::net::TcpListener::bind(&config.public_address)
+        .await
+        .unwrap();
+    let ops_listener = tokio::net::TcpListener::bind(&config.private_address)
+        .await
+        .unwrap();
+    tracing::info!(address = config.public_address, "Serving public");
+    tracing::info!(address = config.private_address, "Serving private");
+
+    let (stopping, stop) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown::signal().await;
+        tracing::info!("Shutting down");
+        stopping.send_replace(true);
+    });
+    let stopped = |mut stop: watch::Receiver<bool>| async move {
+        let _ = stop.wait_for(|stop| *stop).await;
+    };
+    let public = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(stopped(stop.clone()));
+    let private = axum::serve(ops_listener, ops_app).with_graceful_shutdown(stopped(stop));
     let (a, b) = join![public, private];
     a.unwrap();
     b.unwrap();
+
+    // The servers have finished their requests, now let a round of matching and its emails finish
+    // without starting any more.
+    let _matching = state.matching_lock.clone().lock_owned().await;
+    let _notifying = state.notifying.clone().write_owned().await;
+    housekeeping.abort();
+    scheduler.abort();
+    let _ = join![housekeeping, scheduler];
+    if let Some(reminders) = reminders {
+        reminders.abort();
+        let _ = reminders.await;
+    }
+    let db = state.db.clone();
+    drop(state);
+    db.close();
+    tracing::info!("Shut down");
+}
+
+// Periodically purge expired sessions, flag accounts that haven't been used since signing up so
+// an admin can review them for deletion and archive idle pools. A test clock being moved forward
+// runs it straight away.
+async fn housekeeping(
+    db: Database,
+    clock: Clock,
+    stale_account_days: i64,
+    archive_pools_after_months: Option<u32>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = clock.advanced() => {}
+        }
+        let now = clock.timestamp();
+        // Failures are logged by `call`, and whatever was missed is done on the next tick.
+        if let Ok(purged @ 1..) = db.call(move |db| db.purge_expired_sessions(now)).await {
+            tracing::info!(purged, "Purged expired sessions");
+        }
+        let _ = db.call(move |db| db.purge_failed_logins(now)).await;
+        let created_before = now - stale_account_days * 24 * 60 * 60;
+        if let Ok(flagged @ 1..) = db
+            .call(move |db| db.flag_stale_accounts(created_before, now))
+            .await
+        {
+            tracing::info!(flagged, "Flagged stale accounts for review");
+        }
+        if let Ok(resumed @ 1..) = db.call(move |db| db.resume_paused(now)).await {
+            tracing::info!(resumed, "Resumed paused people");
+        }
+        if let Some(months) = archive_pools_after_months {
+            let idle_since = now - i64::from(months) * 30 * 24 * 60 * 60;
+            let archived = db
+                .call(move |db| {
+                    let mut archived = Vec::new();
+                    for pool in db.idle_pools(idle_since)? {
+                        if db.archive_pool(&pool)? {
+                            archived.push(pool);
+                        }
+                    }
+                    Ok(archived)
+                })
+                .await;
+            for pool in archived.unwrap_or_default() {
+                tracing::info!(pool, "Archived idle pool");
+            }
+        }
+    }
+}
+
+// Email everyone who isn't waiting once the next scheduled run is `days_before` days away or less,
+// inviting them to opt in. Checked hourly, or straight away when a test clock is moved forward, and
+// each person is only reminded once for each run.
+async fn round_reminders(state: AppState, days_before: u32) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            () = state.clock.advanced() => {}
+        }
+        // A failure has been logged, look again next time.
+        let Ok(Some(schedule)) = web::current_schedule(&state).await else {
+            continue;
+        };
+        if !schedule.enabled {
+            continue;
+        }
+        let now = state.clock.now();
+        let next = schedule.next_run(now);
+        if next - now > chrono::Duration::days(days_before.into()) {
+            continue;
+        }
+        match web::send_round_reminders(&state, next).await {
+            Ok(0) => {}
+            Ok(sent) => tracing::info!(sent, %next, "Sent reminders for the next round"),
+            Err(error) => tracing::warn!(%error, "Failed to send reminders"),
+        }
+    }
+}
+
+// Run matching automatically following the current schedule, e.g. every second Monday at 09:00
+// with a start on a Monday at 09:00 and 14 days between runs. Runs with fewer than the schedule's
+// `min_waiters` people waiting are skipped until the next one. Whenever an admin changes the
+// schedule the next run is worked out again.
+async fn scheduled_matching(state: AppState) {
+    loop {
+        let schedule = match web::current_schedule(&state).await {
+            Ok(schedule) => schedule.filter(|schedule| schedule.enabled),
+            // The failure has been logged, look again in a moment.
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                continue;
+            }
+        };
+        let Some(schedule) = schedule else {
+            state.schedule_changed.notified().await;
+            continue;
+        };
+        let next = schedule.next_run(state.clock.now());
+        tracing::info!(%next, "Next scheduled matching");
+        let changed = state.schedule_changed.notified();
+        tokio::select! {
+            () = changed => continue,
+            () = state.clock.sleep_until(next) => {}
+        }
+        let min_waiters = schedule.min_waiters;
+        let Ok(waiting) = state.db.call(|db| Ok(db.waiters()?.len())).await else {
+            continue;
+        };
+        if waiting < min_waiters {
+            tracing::info!(
+                waiting,
+                min_waiters,
+                "Scheduled matching skipped as too few people are waiting"
+            );
+            let Ok((admins, timezones, branding)) = state
+                .db
+                .call(|db| {
+                    let admins = db.admins()?;
+                    let ids: Vec<_> = admins.iter().map(|admin| admin.id).collect();
+                    Ok((admins, db.timezones(&ids)?, db.branding()?))
+                })
+                .await
+            else {
+                continue;
+            };
+            let retry = schedule.next_run(state.clock.now());
+            for admin in admins {
+                let timezone = timezones.get(&admin.id);
+                if let Err(error) = state
+                    .mailer
+                    .notify_run_skipped(&admin, waiting, min_waiters, retry, timezone, &branding)
+                    .await
+                {
+                    tracing::warn!(to = admin.email, %error, "Failed to email admin");
+                }
+            }
+            continue;
+        }
+        match web::match_and_notify(
+            &state,
+            &state.round_types[0],
+            None,
+            GenerationDetails::default(),
+        )
+        .await
+        {
+            Ok(Some(meta)) => tracing::info!(
+                generation = meta.generation.0,
+                "Scheduled matching created generation"
+            ),
+            Ok(None) => tracing::info!("Scheduled matching skipped as nobody was waiting"),
+            Err(error) => tracing::warn!(%error, "Scheduled matching skipped"),
+        }
+    }
 }
@@ -1,65 +1,1790 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::domain::{GenerationId, MatchQuality, PersonId};
+
+// What to do with the person left over when an odd number of people are waiting.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OddPersonPolicy {
+    // Leave them unmatched, they get priority next time.
+    #[default]
+    Unmatched,
+    // Add them to the pair they have met the least.
+    Trio,
+}
+
+impl std::str::FromStr for OddPersonPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unmatched" => Ok(Self::Unmatched),
+            "trio" => Ok(Self::Trio),
+            _ => Err(format!("unknown odd person policy {s:?}")),
+        }
+    }
+}
+
+// Whether people from the same team can be matched together.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SameTeamPolicy {
+    #[default]
+    Allow,
+    // Count them as having met `same_team_penalty` extra times.
+    Avoid,
+    // Never match them.
+    Forbid,
+}
+
+impl std::str::FromStr for SameTeamPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Self::Allow),
+            "avoid" => Ok(Self::Avoid),
+            "forbid" => Ok(Self::Forbid),
+            _ => Err(format!("unknown same team policy {s:?}")),
+        }
+    }
+}
+
+// Which side of a mentoring round someone is on.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MentoringRole {
+    Mentor,
+    Mentee,
+    // Happy to do either, whichever evens up the sides.
+    #[default]
+    Either,
+}
+
+impl MentoringRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Mentor => "mentor",
+            Self::Mentee => "mentee",
+            Self::Either => "either",
+        }
+    }
+}
+
+impl std::str::FromStr for MentoringRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mentor" => Ok(Self::Mentor),
+            "mentee" => Ok(Self::Mentee),
+            "either" => Ok(Self::Either),
+            _ => Err(format!("unknown mentoring role {s:?}")),
+        }
+    }
+}
+
+// Which `MatchingStrategy` pairs people up.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    Optimal,
+    Greedy,
+    Random,
+    RoundRobin,
+}
+
+impl Strategy {
+    pub fn implementation(self) -> &'static dyn MatchingStrategy {
+        match self {
+            Self::Optimal => &Optimal,
+            Self::Greedy => &Greedy,
+            Self::Random => &Random,
+            Self::RoundRobin => &RoundRobin,
+        }
+    }
+}
+
+impl std::str::FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "optimal" => Ok(Self::Optimal),
+            "greedy" => Ok(Self::Greedy),
+            "random" => Ok(Self::Random),
+            "round_robin" => Ok(Self::RoundRobin),
+            _ => Err(format!("unknown matching strategy {s:?}")),
+        }
+    }
+}
+
+// The deployment's settings that shape a round of matching.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct MatchingSettings {
+    pub odd_person_policy: OddPersonPolicy,
+    // Larger pools of waiters are split into buckets before matching.
+    pub max_exact_pool_size: usize,
+    // Past matches count for half as much after this many generations, never decaying if unset.
+    pub edge_half_life: Option<u32>,
+    // People matched within this many generations are never matched again.
+    pub repeat_window: u32,
+    // Weight taken off a pair for each interest they share.
+    #[serde(default)]
+    pub interest_bonus: u32,
+    #[serde(default)]
+    pub same_team_policy: SameTeamPolicy,
+    // Weight added to pairs from the same team when avoiding them.
+    #[serde(default)]
+    pub same_team_penalty: u32,
+    // Whether this round only pairs mentors with mentees, set for each run from its round type.
+    #[serde(default)]
+    pub mentoring: bool,
+    // How many people each group has, 2 for pairs.
+    #[serde(default = "default_group_size")]
+    pub group_size: usize,
+    // How pairs are chosen, mentoring and bigger groups always use their own algorithms.
+    #[serde(default)]
+    pub strategy: Strategy,
+}
+
+fn default_group_size() -> usize {
+    2
+}
+
+// Everything that went into a round of matching. Running `Graph::matching` with the same policy on
+// each bucket's graph reproduces its groups, the graphs hold the weights, blocked pairs and who had
+// priority to not sit out.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct MatchingAudit {
+    pub generation: GenerationId,
+    pub time: u64,
+    #[serde(flatten)]
+    pub settings: MatchingSettings,
+    pub waiters: Vec<PersonId>,
+    pub excluded: Vec<(PersonId, String)>,
+    // One for each organisation that had an odd number waiting, if there were any to bring in.
+    pub floaters: Vec<PersonId>,
+    pub quality: MatchQuality,
+    pub buckets: Vec<BucketAudit>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct BucketAudit {
+    pub graph: Graph,
+    // The groups chosen, by person id.
+    pub groups: Vec<Vec<PersonId>>,
+}
+
+// Up to this many nodes the optimal matching considers every pair and is exact, beyond it only the
+// pairs in `candidate_edges`.
+const EXACT_NODES: usize = 512;
+
+// How many people each node is offered that they have no history with, when not every pair is
+// considered.
+const FRESH_PARTNERS: usize = 16;
+
+// Most pairs in a large pool have never met, so only edges with some weight are kept, each node
+// holding its neighbours. Missing edges weigh 0.
 #[derive(Default, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Graph {
-    nodes: Vec<u32>,
-    edges: Vec<Vec<u32>>,
+    nodes: Vec<PersonId>,
+    edges: Vec<HashMap<usize, u32>>,
+    // Nodes that should not be the one left unmatched when there is an odd number of nodes.
+    priority: Vec<bool>,
+    // Pairs of nodes that must never be matched together, from each end.
+    forbidden: Vec<HashSet<usize>>,
+    // Only pair mentors with mentees, using `roles` and `capacity`.
+    #[serde(default)]
+    mentoring: bool,
+    #[serde(default)]
+    roles: Vec<MentoringRole>,
+    // How many mentees each node can take when mentoring.
+    #[serde(default)]
+    capacity: Vec<u32>,
+    // Make groups of this many instead of pairs.
+    #[serde(default)]
+    group_size: Option<usize>,
+    // How many rounds of the round type came before, for strategies that vary from round to round.
+    #[serde(default)]
+    round: u64,
 }
 
 impl Graph {
+    // A graph for a mentoring round, where everyone is someone who can take `capacity` mentees
+    // until their role is set.
+    pub fn mentoring() -> Self {
+        Self {
+            mentoring: true,
+            ..Self::default()
+        }
+    }
+
+    // A graph for making groups of `size`, e.g. lunch groups of 4, rather than pairs.
+    pub fn groups_of(size: usize) -> Self {
+        Self {
+            group_size: Some(size),
+            ..Self::default()
+        }
+    }
+
     pub fn add_edge(&mut self, id1: usize, id2: usize, weight: u32) {
         assert!(self.nodes.len() > id1);
         assert!(self.nodes.len() > id2);
-        self.edges[id1][id2] = weight;
-        self.edges[id2][id1] = weight;
+        if weight == 0 {
+            self.edges[id1].remove(&id2);
+            self.edges[id2].remove(&id1);
+        } else {
+            self.edges[id1].insert(id2, weight);
+            self.edges[id2].insert(id1, weight);
+        }
     }
 
-    pub fn add_node(&mut self, person: u32) -> usize {
+    pub fn add_node(&mut self, person: PersonId) -> usize {
         let id = self.nodes.len();
         self.nodes.push(person);
-        for edge_row in &mut self.edges {
-            edge_row.push(0);
-        }
-        self.edges.push(vec![0; self.nodes.len()]);
+        self.edges.push(HashMap::new());
+        self.priority.push(false);
+        self.forbidden.push(HashSet::new());
+        self.roles.push(MentoringRole::Either);
+        self.capacity.push(1);
         id
     }
 
-    pub fn nodes(&self) -> Vec<&u32> {
-        self.nodes.iter().collect()
+    pub fn weight(&self, id1: usize, id2: usize) -> u32 {
+        self.edges[id1].get(&id2).copied().unwrap_or(0)
+    }
+
+    pub fn max_weight(&self) -> u32 {
+        self.edges
+            .iter()
+            .flat_map(|edges| edges.values())
+            .copied()
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // The person at a node.
+    pub fn person(&self, id: usize) -> PersonId {
+        self.nodes[id]
+    }
+
+    pub fn forbid(&mut self, id1: usize, id2: usize) {
+        self.forbidden[id1].insert(id2);
+        self.forbidden[id2].insert(id1);
+    }
+
+    pub fn set_priority(&mut self, id: usize) {
+        self.priority[id] = true;
+    }
+
+    pub fn set_role(&mut self, id: usize, role: MentoringRole, capacity: u32) {
+        self.roles[id] = role;
+        self.capacity[id] = capacity;
+    }
+
+    pub fn set_round(&mut self, round: u64) {
+        self.round = round;
+    }
+
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    pub fn is_forbidden(&self, id1: usize, id2: usize) -> bool {
+        self.forbidden[id1].contains(&id2)
+    }
+
+    pub fn has_priority(&self, id: usize) -> bool {
+        self.priority[id]
     }
 
+    // The nodes with some weight to `id`, in no particular order.
     pub fn edges_for(&self, id: usize) -> impl Iterator<Item = (usize, u32)> + '_ {
-        self.edges[id].iter().enumerate().map(|(b, w)| (b, *w))
+        self.edges[id].iter().map(|(b, w)| (*b, *w))
     }
 
-    pub fn matching(&self) -> Vec<(usize, Option<usize>)> {
+    // Group everyone into pairs chosen by the strategy. When there is an odd number of nodes the
+    // leftover one is handled by the policy. Mentoring graphs are matched with `mentor_pairs`
+    // instead, which has no odd person, and bigger groups with `groups`.
+    pub fn matching(
+        &self,
+        odd_person_policy: OddPersonPolicy,
+        strategy: &dyn MatchingStrategy,
+    ) -> Vec<Vec<usize>> {
+        if self.mentoring {
+            return self.mentor_pairs();
+        }
+        if let Some(size) = self.group_size.filter(|size| *size > 2) {
+            return self.groups(size);
+        }
+        let pairs = strategy.pairs(self);
+        if odd_person_policy == OddPersonPolicy::Trio && pairs.len() > 1 {
+            if let Some(odd) = pairs.iter().position(|p| p.1.is_none()) {
+                let odd = pairs[odd].0;
+                let weight = |(a, b): &(usize, Option<usize>)| {
+                    self.weight(odd, *a) + b.map_or(0, |b| self.weight(odd, b))
+                };
+                let mut groups: Vec<Vec<usize>> = Vec::new();
+                let best = pairs
+                    .iter()
+                    .filter(|(a, b)| {
+                        b.is_some_and(|b| !self.is_forbidden(odd, *a) && !self.is_forbidden(odd, b))
+                    })
+                    .min_by_key(|p| weight(p))
+                    .copied();
+                for pair in pairs {
+                    if pair.0 == odd {
+                        continue;
+                    }
+                    let mut group: Vec<usize> = std::iter::once(pair.0).chain(pair.1).collect();
+                    if Some(pair) == best {
+                        group.push(odd);
+                    }
+                    groups.push(group);
+                }
+                if best.is_none() {
+                    // No pair can take them, so they're still on their own.
+                    groups.push(vec![odd]);
+                }
+                return groups;
+            }
+        }
+        pairs
+            .into_iter()
+            .map(|(a, b)| std::iter::once(a).chain(b).collect())
+            .collect()
+    }
+
+    // Pair the given nodes lightest edge first, leaving any that can only be paired with
+    // forbidden partners on their own.
+    fn greedy_pairs(&self, nodes: &[usize]) -> Vec<(usize, Option<usize>)> {
+        let mut edges = Vec::new();
+        for (i, a) in nodes.iter().enumerate() {
+            for b in &nodes[i + 1..] {
+                if !self.is_forbidden(*a, *b) {
+                    edges.push((self.weight(*a, *b), *a.min(b), *a.max(b)));
+                }
+            }
+        }
+        edges.sort();
+        let mut paired = vec![false; self.nodes.len()];
+        let mut pairs = Vec::new();
+        for (_, a, b) in edges {
+            if !paired[a] && !paired[b] {
+                paired[a] = true;
+                paired[b] = true;
+                pairs.push((a, Some(b)));
+            }
+        }
+        pairs.extend(nodes.iter().filter(|n| !paired[**n]).map(|n| (*n, None)));
+        pairs
+    }
+
+    // Pair up the nodes `left` over from `pairs` because their partners were forbidden, lightest
+    // edge first. Two still on their own are paired by swapping partners with an existing pair.
+    fn repair_pairs(
+        &self,
+        mut pairs: Vec<(usize, Option<usize>)>,
+        left: &[usize],
+    ) -> Vec<(usize, Option<usize>)> {
+        let mut alone = Vec::new();
+        for pair in self.greedy_pairs(left) {
+            match pair {
+                (_, Some(_)) => pairs.push(pair),
+                (id, None) => alone.push(id),
+            }
+        }
+        let mut i = 0;
+        'alone: while i < alone.len() {
+            for j in i + 1..alone.len() {
+                let (a, b) = (alone[i], alone[j]);
+                for pair in pairs.iter_mut() {
+                    let (c, Some(d)) = *pair else {
+                        continue;
+                    };
+                    let swap = if !self.is_forbidden(a, c) && !self.is_forbidden(b, d) {
+                        Some((c, d))
+                    } else if !self.is_forbidden(a, d) && !self.is_forbidden(b, c) {
+                        Some((d, c))
+                    } else {
+                        None
+                    };
+                    if let Some((c, d)) = swap {
+                        *pair = (a, Some(c));
+                        pairs.push((b, Some(d)));
+                        alone.remove(j);
+                        alone.remove(i);
+                        continue 'alone;
+                    }
+                }
+            }
+            i += 1;
+        }
+        pairs.extend(alone.into_iter().map(|id| (id, None)));
+        pairs
+    }
+
+    // Pair everyone up, minimising the total weight of the chosen edges across the whole graph.
+    // When there is an odd number of nodes one is left unmatched, avoiding priority nodes.
+    //
+    // In large graphs not every pair is considered, so the result is close to but not always the
+    // lowest total weight, see `candidate_edges`.
+    fn optimal_pairs(&self) -> Vec<(usize, Option<usize>)> {
+        let n = self.nodes.len();
+        let max_weight = self.max_weight();
+        // The blossom algorithm maximises weight so invert the weights, keeping them all positive
+        // so that every pair remains worth matching.
+        let mut edges: Vec<(usize, usize, i64)> = self
+            .candidate_edges()
+            .into_iter()
+            .map(|(i, j)| {
+                (
+                    i,
+                    j,
+                    i64::from(max_weight) + 1 - i64::from(self.weight(i, j)),
+                )
+            })
+            .collect();
+        if n % 2 == 1 {
+            // Whoever gets matched with this extra node sits out. Sitting out a priority node
+            // costs more than any difference in the real pairs could make up for.
+            let bye = n;
+            let sit_out = (i64::from(max_weight) + 1) * n as i64;
+            for i in 0..n {
+                let weight = if self.priority[i] { 1 } else { sit_out };
+                edges.push((i, bye, weight));
+            }
+        }
+        let mut mates = max_weight_matching(&edges, true);
+        mates.truncate(n);
+        for mate in &mut mates {
+            if *mate == Some(n) {
+                *mate = None;
+            }
+        }
+
         let mut matchings = Vec::new();
-        let nodes = self.nodes();
-        let mut seen = vec![false; nodes.len()];
+        let mut unmatched = Vec::new();
+        for id in 0..n {
+            match mates.get(id).copied().flatten() {
+                Some(other) if id < other => matchings.push((id, Some(other))),
+                Some(_) => {}
+                None => unmatched.push(id),
+            }
+        }
+        // Only possible when some pairs weren't considered, the leftovers might still pair up.
+        if n > EXACT_NODES && unmatched.len() > 1 {
+            let odd = sit_out(self, &unmatched);
+            unmatched.retain(|id| Some(*id) != odd);
+            let pairs = self.greedy_pairs(&unmatched);
+            unmatched.clear();
+            for pair in pairs {
+                match pair {
+                    (_, Some(_)) => matchings.push(pair),
+                    (id, None) => unmatched.push(id),
+                }
+            }
+            unmatched.extend(odd);
+        }
+        matchings.extend(unmatched.into_iter().map(|id| (id, None)));
+        matchings.sort();
+        matchings
+    }
 
-        for id in 0..nodes.len() {
-            if seen[id] {
-                continue;
+    // The pairs the optimal matching chooses from, never forbidden ones. Small graphs consider
+    // every pair. In large ones the blossom algorithm would be too slow on every pair, and most
+    // pairs have never met so weigh the same, so each node is only offered those it has some
+    // weight to and `FRESH_PARTNERS` of the next nodes it has none to. That usually finds the
+    // lowest total weight but isn't guaranteed to: when many nodes share the same few fresh
+    // partners some are left unmatched, and `optimal_pairs` pairs those greedily instead.
+    fn candidate_edges(&self) -> Vec<(usize, usize)> {
+        let n = self.nodes.len();
+        let mut edges = Vec::new();
+        if n <= EXACT_NODES {
+            for i in 0..n {
+                for j in i + 1..n {
+                    if !self.is_forbidden(i, j) {
+                        edges.push((i, j));
+                    }
+                }
+            }
+            return edges;
+        }
+        let mut seen = HashSet::new();
+        for i in 0..n {
+            for (j, _) in self.edges_for(i) {
+                if !self.is_forbidden(i, j) && seen.insert((i.min(j), i.max(j))) {
+                    edges.push((i.min(j), i.max(j)));
+                }
             }
+            let fresh = (1..n)
+                .map(|k| (i + k) % n)
+                .filter(|j| self.weight(i, *j) == 0 && !self.is_forbidden(i, *j))
+                .take(FRESH_PARTNERS);
+            for j in fresh {
+                if seen.insert((i.min(j), i.max(j))) {
+                    edges.push((i.min(j), i.max(j)));
+                }
+            }
+        }
+        edges
+    }
 
-            let other_weights: Vec<_> = self
-                .edges_for(id)
-                .filter(|e| !seen[e.0])
-                .filter(|e| id != e.0)
-                .collect();
+    // Pair mentees with mentors, each mentor taking up to their capacity of mentees, minimising the
+    // total weight of the pairs like `pairs`. A mentor with several mentees is in a pair with each.
+    // Those happy either way are mentored while the mentors so far have room and mentor otherwise.
+    // Anyone left over is in a group on their own.
+    //
+    // It's a bipartite matching between mentees and one seat for each mentee a mentor can take, so
+    // the same blossom algorithm finds it. As many mentees as possible are matched, then priority
+    // nodes are preferred, then giving every mentor someone before anyone gets a second, then the
+    // lowest weight.
+    fn mentor_pairs(&self) -> Vec<Vec<usize>> {
+        let n = self.nodes.len();
+        let mut mentors = Vec::new();
+        let mut mentees = Vec::new();
+        let mut room = 0;
+        for id in 0..n {
+            match self.roles[id] {
+                MentoringRole::Mentor => {
+                    mentors.push(id);
+                    room += self.capacity[id] as usize;
+                }
+                MentoringRole::Mentee => mentees.push(id),
+                MentoringRole::Either => {}
+            }
+        }
+        for id in (0..n).filter(|id| self.roles[*id] == MentoringRole::Either) {
+            if room > mentees.len() {
+                mentees.push(id);
+            } else {
+                mentors.push(id);
+                room += self.capacity[id] as usize;
+            }
+        }
 
-            let other_node = other_weights.iter().min_by_key(|e| e.1);
-            if let Some(other_node) = other_node {
-                if id < other_node.0 {
-                    matchings.push((id, Some(other_node.0)));
+        // Vertices are the mentees, then the mentors' seats.
+        let mut seats = Vec::new();
+        for mentor in &mentors {
+            for seat in 0..self.capacity[*mentor] {
+                seats.push((*mentor, seat));
+            }
+        }
+        let unit = i64::from(self.max_weight()) + 1;
+        let spread = unit * n as i64;
+        let urgent = spread * (n as i64 + 1);
+        let mut edges = Vec::new();
+        for (i, mentee) in mentees.iter().enumerate() {
+            for (j, (mentor, seat)) in seats.iter().enumerate() {
+                if self.is_forbidden(*mentee, *mentor) {
+                    continue;
+                }
+                let mut weight = unit - i64::from(self.weight(*mentee, *mentor));
+                if *seat == 0 {
+                    weight += spread;
+                }
+                if self.priority[*mentee] || (*seat == 0 && self.priority[*mentor]) {
+                    weight += urgent;
+                }
+                edges.push((i, mentees.len() + j, weight));
+            }
+        }
+        let mates = max_weight_matching(&edges, true);
+
+        let mut groups = Vec::new();
+        for mentor in &mentors {
+            let mut taken = false;
+            for (j, (owner, _)) in seats.iter().enumerate() {
+                if owner != mentor {
+                    continue;
+                }
+                if let Some(i) = mates.get(mentees.len() + j).copied().flatten() {
+                    groups.push(vec![*mentor, mentees[i]]);
+                    taken = true;
+                }
+            }
+            if !taken {
+                groups.push(vec![*mentor]);
+            }
+        }
+        for (i, mentee) in mentees.iter().enumerate() {
+            if mates.get(i).copied().flatten().is_none() {
+                groups.push(vec![*mentee]);
+            }
+        }
+        groups
+    }
+
+    // Splitting people into groups to minimise the weight within them is NP-hard, so groups are built
+    // greedily and then improved by swapping people between them until no swap helps. Everyone is
+    // grouped, in as few groups as keep them at most `size` and with sizes at most one apart, so
+    // nobody sits out unless they're the only one.
+    fn groups(&self, size: usize) -> Vec<Vec<usize>> {
+        let n = self.nodes.len();
+        if n == 0 {
+            return Vec::new();
+        }
+        let count = n.div_ceil(size);
+        let capacities: Vec<usize> = (0..count)
+            .map(|g| n / count + usize::from(g < n % count))
+            .collect();
+        // Worse than any group without forbidden pairs, so they're only kept when unavoidable.
+        let forbidden = (u64::from(self.max_weight()) + 1) * (n * n) as u64;
+        let cost = |a: usize, b: usize| {
+            if self.is_forbidden(a, b) {
+                forbidden
+            } else {
+                u64::from(self.weight(a, b))
+            }
+        };
+        let cost_with = |group: &[usize], node: usize, without: Option<usize>| -> u64 {
+            group
+                .iter()
+                .filter(|other| Some(**other) != without && **other != node)
+                .map(|other| cost(node, *other))
+                .sum()
+        };
+
+        // Place the people with the heaviest history first, while there's most choice for them.
+        let mut order: Vec<usize> = (0..n).collect();
+        order.sort_by_key(|a| {
+            std::cmp::Reverse(
+                self.edges_for(*a).map(|(_, w)| u64::from(w)).sum::<u64>()
+                    + self.forbidden[*a].len() as u64 * forbidden,
+            )
+        });
+        let mut groups: Vec<Vec<usize>> = vec![Vec::new(); count];
+        for node in order {
+            let best = (0..count)
+                .filter(|g| groups[*g].len() < capacities[*g])
+                .min_by_key(|g| (cost_with(&groups[*g], node, None), groups[*g].len()))
+                .expect("there is room for everyone");
+            groups[best].push(node);
+        }
+
+        // Each improving swap lowers the total, so this finishes, but give up on very long runs.
+        for _ in 0..100 {
+            let mut improved = false;
+            for g1 in 0..count {
+                for g2 in g1 + 1..count {
+                    for i in 0..groups[g1].len() {
+                        for j in 0..groups[g2].len() {
+                            let (a, b) = (groups[g1][i], groups[g2][j]);
+                            let before =
+                                cost_with(&groups[g1], a, None) + cost_with(&groups[g2], b, None);
+                            let after = cost_with(&groups[g1], b, Some(a))
+                                + cost_with(&groups[g2], a, Some(b));
+                            if after < before {
+                                groups[g1][i] = b;
+                                groups[g2][j] = a;
+                                improved = true;
+                            }
+                        }
+                    }
+                }
+            }
+            if !improved {
+                break;
+            }
+        }
+        for group in &mut groups {
+            group.sort();
+        }
+        groups
+    }
+}
+
+// Chooses the pairs in a round of matching. Strategies are picked by `Strategy` in the settings,
+// and only see the graph so they can be swapped without touching how it's built.
+pub trait MatchingStrategy: Send + Sync {
+    // Pair up every node, never choosing a forbidden pair. Nodes that can't be paired are on their
+    // own, which should avoid priority nodes where possible.
+    fn pairs(&self, graph: &Graph) -> Vec<(usize, Option<usize>)>;
+}
+
+// The pairs with the lowest total weight across the whole graph.
+pub struct Optimal;
+
+// Lightest pairs first, faster than `Optimal` but can leave heavier pairs for the end.
+pub struct Greedy;
+
+// Random pairs, ignoring the weights but still respecting forbidden pairs.
+pub struct Random;
+
+// The circle method, so in a stable pool everyone meets everyone else once every `n - 1` rounds,
+// ignoring the weights.
+pub struct RoundRobin;
+
+// Mix `state` into a well spread value, the output step of splitmix64.
+pub fn splitmix64(state: u64) -> u64 {
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Who sits out when there's an odd number: the last node without priority, or the last node when
+// everyone has it.
+fn sit_out(graph: &Graph, nodes: &[usize]) -> Option<usize> {
+    if nodes.len().is_multiple_of(2) {
+        return None;
+    }
+    nodes
+        .iter()
+        .rev()
+        .find(|n| !graph.has_priority(**n))
+        .or(nodes.last())
+        .copied()
+}
+
+impl MatchingStrategy for Optimal {
+    fn pairs(&self, graph: &Graph) -> Vec<(usize, Option<usize>)> {
+        graph.optimal_pairs()
+    }
+}
+
+impl MatchingStrategy for Greedy {
+    fn pairs(&self, graph: &Graph) -> Vec<(usize, Option<usize>)> {
+        let mut nodes: Vec<usize> = (0..graph.len()).collect();
+        let odd = sit_out(graph, &nodes);
+        nodes.retain(|n| Some(*n) != odd);
+        let mut pairs = graph.greedy_pairs(&nodes);
+        pairs.extend(odd.map(|n| (n, None)));
+        pairs
+    }
+}
+
+impl MatchingStrategy for Random {
+    fn pairs(&self, graph: &Graph) -> Vec<(usize, Option<usize>)> {
+        // Seeded from the round and who is in it, so an audit's graph gives the same pairs again.
+        let mut state = graph.round();
+        for n in 0..graph.len() {
+            state = state.rotate_left(5) ^ u64::from(graph.person(n).0);
+        }
+        let mut next = move || {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            splitmix64(state)
+        };
+        let mut nodes: Vec<usize> = (0..graph.len()).collect();
+        for i in (1..nodes.len()).rev() {
+            nodes.swap(i, (next() % (i as u64 + 1)) as usize);
+        }
+        let odd = sit_out(graph, &nodes);
+        nodes.retain(|n| Some(*n) != odd);
+        let mut pairs = Vec::new();
+        let mut left = Vec::new();
+        for pair in nodes.chunks(2) {
+            if graph.is_forbidden(pair[0], pair[1]) {
+                left.extend(pair);
+            } else {
+                pairs.push((pair[0], Some(pair[1])));
+            }
+        }
+        let mut pairs = graph.repair_pairs(pairs, &left);
+        pairs.extend(odd.map(|n| (n, None)));
+        pairs
+    }
+}
+
+impl MatchingStrategy for RoundRobin {
+    fn pairs(&self, graph: &Graph) -> Vec<(usize, Option<usize>)> {
+        // Nodes in person order so the rotation carries on from round to round.
+        let mut nodes: Vec<Option<usize>> = (0..graph.len()).map(Some).collect();
+        nodes.sort_by_key(|n| n.map(|n| graph.person(n)));
+        if nodes.len() % 2 == 1 {
+            // Whoever faces the bye sits out.
+            nodes.push(None);
+        }
+        let n = nodes.len();
+        if n < 2 {
+            return nodes.into_iter().flatten().map(|n| (n, None)).collect();
+        }
+        let mut pairs = Vec::new();
+        let mut left = Vec::new();
+        for pair in circle_round(&nodes, (graph.round() % (n as u64 - 1)) as usize) {
+            match pair {
+                (Some(a), Some(b)) if !graph.is_forbidden(a, b) => pairs.push((a, Some(b))),
+                (Some(a), Some(b)) => left.extend([a, b]),
+                (Some(a), None) | (None, Some(a)) => pairs.push((a, None)),
+                (None, None) => {}
+            }
+        }
+        graph.repair_pairs(pairs, &left)
+    }
+}
+
+// A round of the circle method: everyone but the first moves `round` places round the circle, then
+// each is paired with whoever is opposite. `None` is a bye.
+fn circle_round<T: Copy>(nodes: &[Option<T>], round: usize) -> Vec<(Option<T>, Option<T>)> {
+    let n = nodes.len();
+    let mut nodes = nodes.to_vec();
+    if n > 2 {
+        nodes[1..].rotate_right(round % (n - 1));
+    }
+    (0..n / 2).map(|i| (nodes[i], nodes[n - 1 - i])).collect()
+}
+
+// A whole round robin like a tournament schedule, where everyone is paired with everyone else
+// exactly once. That takes `n - 1` rounds, or `n` with an odd number where each sits out once.
+pub fn round_robin_schedule<T: Copy>(people: &[T]) -> Vec<Vec<(T, T)>> {
+    let mut nodes: Vec<Option<T>> = people.iter().copied().map(Some).collect();
+    if nodes.len() % 2 == 1 {
+        nodes.push(None);
+    }
+    (0..nodes.len().saturating_sub(1))
+        .map(|round| {
+            circle_round(&nodes, round)
+                .into_iter()
+                .filter_map(|(a, b)| Some((a?, b?)))
+                .collect()
+        })
+        .collect()
+}
+
+const NONE: usize = usize::MAX;
+
+// Edmonds' blossom algorithm for maximum weight matching in a general graph, following the
+// primal-dual formulation of Galil ("Efficient algorithms for finding maximum matching in graphs").
+// Vertices are numbered 0..n and edges are given as (i, j, weight). With `max_cardinality` only
+// maximum cardinality matchings are considered. Returns the mate of each vertex.
+pub fn max_weight_matching(
+    edges: &[(usize, usize, i64)],
+    max_cardinality: bool,
+) -> Vec<Option<usize>> {
+    if edges.is_empty() {
+        return Vec::new();
+    }
+    let mut state = BlossomState::new(edges);
+    state.solve(max_cardinality);
+    let nvertex = state.nvertex;
+    (0..nvertex)
+        .map(|v| {
+            let p = state.mate[v];
+            (p != NONE).then(|| state.endpoint[p])
+        })
+        .collect()
+}
+
+struct BlossomState<'a> {
+    edges: &'a [(usize, usize, i64)],
+    nvertex: usize,
+    // endpoint[p] is the vertex at endpoint p, edge k has endpoints 2k and 2k+1.
+    endpoint: Vec<usize>,
+    // neighbend[v] is the list of remote endpoints of edges attached to v.
+    neighbend: Vec<Vec<usize>>,
+    // mate[v] is the remote endpoint of the matched edge of v, or NONE.
+    mate: Vec<usize>,
+    // 0 is unlabeled, 1 is S, 2 is T, 5 is a temporary mark used by scan_blossom.
+    label: Vec<i32>,
+    labelend: Vec<usize>,
+    inblossom: Vec<usize>,
+    blossomparent: Vec<usize>,
+    blossomchilds: Vec<Vec<usize>>,
+    blossombase: Vec<usize>,
+    blossomendps: Vec<Vec<usize>>,
+    bestedge: Vec<usize>,
+    blossombestedges: Vec<Option<Vec<usize>>>,
+    unusedblossoms: Vec<usize>,
+    dualvar: Vec<i64>,
+    allowedge: Vec<bool>,
+    queue: Vec<usize>,
+}
+
+impl<'a> BlossomState<'a> {
+    fn new(edges: &'a [(usize, usize, i64)]) -> Self {
+        let nvertex = edges
+            .iter()
+            .map(|&(i, j, _)| i.max(j) + 1)
+            .max()
+            .unwrap_or(0);
+        let maxweight = edges.iter().map(|e| e.2).max().unwrap_or(0).max(0);
+        let endpoint = (0..2 * edges.len())
+            .map(|p| {
+                let (i, j, _) = edges[p / 2];
+                if p % 2 == 0 {
+                    i
                 } else {
-                    matchings.push((other_node.0, Some(id)));
+                    j
                 }
-                seen[id] = true;
-                seen[other_node.0] = true;
+            })
+            .collect();
+        let mut neighbend = vec![Vec::new(); nvertex];
+        for (k, &(i, j, _)) in edges.iter().enumerate() {
+            neighbend[i].push(2 * k + 1);
+            neighbend[j].push(2 * k);
+        }
+        let mut blossombase: Vec<usize> = (0..nvertex).collect();
+        blossombase.extend(std::iter::repeat_n(NONE, nvertex));
+        let mut dualvar = vec![maxweight; nvertex];
+        dualvar.extend(std::iter::repeat_n(0, nvertex));
+        Self {
+            edges,
+            nvertex,
+            endpoint,
+            neighbend,
+            mate: vec![NONE; nvertex],
+            label: vec![0; 2 * nvertex],
+            labelend: vec![NONE; 2 * nvertex],
+            inblossom: (0..nvertex).collect(),
+            blossomparent: vec![NONE; 2 * nvertex],
+            blossomchilds: vec![Vec::new(); 2 * nvertex],
+            blossombase,
+            blossomendps: vec![Vec::new(); 2 * nvertex],
+            bestedge: vec![NONE; 2 * nvertex],
+            blossombestedges: vec![None; 2 * nvertex],
+            unusedblossoms: (nvertex..2 * nvertex).collect(),
+            dualvar,
+            allowedge: vec![false; edges.len()],
+            queue: Vec::new(),
+        }
+    }
+
+    fn slack(&self, k: usize) -> i64 {
+        let (i, j, wt) = self.edges[k];
+        self.dualvar[i] + self.dualvar[j] - 2 * wt
+    }
+
+    fn blossom_leaves(&self, b: usize) -> Vec<usize> {
+        if b < self.nvertex {
+            return vec![b];
+        }
+        let mut leaves = Vec::new();
+        for &t in &self.blossomchilds[b] {
+            if t < self.nvertex {
+                leaves.push(t);
             } else {
-                matchings.push((id, None))
+                leaves.extend(self.blossom_leaves(t));
             }
         }
+        leaves
+    }
 
-        matchings
+    // Index into a blossom's children allowing negative indices from the end.
+    fn child_index(&self, b: usize, j: isize) -> usize {
+        let len = self.blossomchilds[b].len() as isize;
+        (((j % len) + len) % len) as usize
+    }
+
+    fn assign_label(&mut self, w: usize, t: i32, p: usize) {
+        let b = self.inblossom[w];
+        self.label[w] = t;
+        self.label[b] = t;
+        self.labelend[w] = p;
+        self.labelend[b] = p;
+        self.bestedge[w] = NONE;
+        self.bestedge[b] = NONE;
+        if t == 1 {
+            let leaves = self.blossom_leaves(b);
+            self.queue.extend(leaves);
+        } else if t == 2 {
+            let base = self.blossombase[b];
+            let mate = self.mate[base];
+            self.assign_label(self.endpoint[mate], 1, mate ^ 1);
+        }
+    }
+
+    // Trace back from v and w to discover either a new blossom (returning its base) or an
+    // augmenting path (returning NONE).
+    fn scan_blossom(&mut self, mut v: usize, mut w: usize) -> usize {
+        let mut path = Vec::new();
+        let mut base = NONE;
+        while v != NONE || w != NONE {
+            let mut b = self.inblossom[v];
+            if self.label[b] & 4 != 0 {
+                base = self.blossombase[b];
+                break;
+            }
+            path.push(b);
+            self.label[b] = 5;
+            if self.labelend[b] == NONE {
+                v = NONE;
+            } else {
+                v = self.endpoint[self.labelend[b]];
+                b = self.inblossom[v];
+                v = self.endpoint[self.labelend[b]];
+            }
+            if w != NONE {
+                std::mem::swap(&mut v, &mut w);
+            }
+        }
+        for b in path {
+            self.label[b] = 1;
+        }
+        base
+    }
+
+    fn add_blossom(&mut self, base: usize, k: usize) {
+        let (mut v, mut w, _) = self.edges[k];
+        let bb = self.inblossom[base];
+        let mut bv = self.inblossom[v];
+        let mut bw = self.inblossom[w];
+        let b = self.unusedblossoms.pop().unwrap();
+        self.blossombase[b] = base;
+        self.blossomparent[b] = NONE;
+        self.blossomparent[bb] = b;
+        let mut path = Vec::new();
+        let mut endps = Vec::new();
+        while bv != bb {
+            self.blossomparent[bv] = b;
+            path.push(bv);
+            endps.push(self.labelend[bv]);
+            v = self.endpoint[self.labelend[bv]];
+            bv = self.inblossom[v];
+        }
+        path.push(bb);
+        path.reverse();
+        endps.reverse();
+        endps.push(2 * k);
+        while bw != bb {
+            self.blossomparent[bw] = b;
+            path.push(bw);
+            endps.push(self.labelend[bw] ^ 1);
+            w = self.endpoint[self.labelend[bw]];
+            bw = self.inblossom[w];
+        }
+        self.blossomchilds[b] = path.clone();
+        self.blossomendps[b] = endps;
+        self.label[b] = 1;
+        self.labelend[b] = self.labelend[bb];
+        self.dualvar[b] = 0;
+        for v in self.blossom_leaves(b) {
+            if self.label[self.inblossom[v]] == 2 {
+                self.queue.push(v);
+            }
+            self.inblossom[v] = b;
+        }
+        let mut bestedgeto = vec![NONE; 2 * self.nvertex];
+        for bv in path {
+            let nblists: Vec<Vec<usize>> = match self.blossombestedges[bv].take() {
+                Some(list) => vec![list],
+                None => self
+                    .blossom_leaves(bv)
+                    .into_iter()
+                    .map(|v| self.neighbend[v].iter().map(|p| p / 2).collect())
+                    .collect(),
+            };
+            for nblist in nblists {
+                for k in nblist {
+                    let (mut i, mut j, _) = self.edges[k];
+                    if self.inblossom[j] == b {
+                        std::mem::swap(&mut i, &mut j);
+                    }
+                    let bj = self.inblossom[j];
+                    if bj != b
+                        && self.label[bj] == 1
+                        && (bestedgeto[bj] == NONE || self.slack(k) < self.slack(bestedgeto[bj]))
+                    {
+                        bestedgeto[bj] = k;
+                    }
+                }
+            }
+            self.bestedge[bv] = NONE;
+        }
+        let best: Vec<usize> = bestedgeto.into_iter().filter(|&k| k != NONE).collect();
+        self.bestedge[b] = NONE;
+        for &k in &best {
+            if self.bestedge[b] == NONE || self.slack(k) < self.slack(self.bestedge[b]) {
+                self.bestedge[b] = k;
+            }
+        }
+        self.blossombestedges[b] = Some(best);
+    }
+
+    fn expand_blossom(&mut self, b: usize, endstage: bool) {
+        for s in self.blossomchilds[b].clone() {
+            self.blossomparent[s] = NONE;
+            if s < self.nvertex {
+                self.inblossom[s] = s;
+            } else if endstage && self.dualvar[s] == 0 {
+                self.expand_blossom(s, endstage);
+            } else {
+                for v in self.blossom_leaves(s) {
+                    self.inblossom[v] = s;
+                }
+            }
+        }
+        if !endstage && self.label[b] == 2 {
+            let entrychild = self.inblossom[self.endpoint[self.labelend[b] ^ 1]];
+            let mut j = self.blossomchilds[b]
+                .iter()
+                .position(|&c| c == entrychild)
+                .unwrap() as isize;
+            let (jstep, endptrick): (isize, usize) = if j & 1 != 0 {
+                j -= self.blossomchilds[b].len() as isize;
+                (1, 0)
+            } else {
+                (-1, 1)
+            };
+            let mut p = self.labelend[b];
+            while j != 0 {
+                self.label[self.endpoint[p ^ 1]] = 0;
+                let endp = self.blossomendps[b][self.child_index(b, j - endptrick as isize)];
+                self.label[self.endpoint[endp ^ endptrick ^ 1]] = 0;
+                self.assign_label(self.endpoint[p ^ 1], 2, p);
+                self.allowedge[endp / 2] = true;
+                j += jstep;
+                p = self.blossomendps[b][self.child_index(b, j - endptrick as isize)] ^ endptrick;
+                self.allowedge[p / 2] = true;
+                j += jstep;
+            }
+            let bv = self.blossomchilds[b][self.child_index(b, j)];
+            self.label[self.endpoint[p ^ 1]] = 2;
+            self.label[bv] = 2;
+            self.labelend[self.endpoint[p ^ 1]] = p;
+            self.labelend[bv] = p;
+            self.bestedge[bv] = NONE;
+            j += jstep;
+            while self.blossomchilds[b][self.child_index(b, j)] != entrychild {
+                let bv = self.blossomchilds[b][self.child_index(b, j)];
+                if self.label[bv] == 1 {
+                    j += jstep;
+                    continue;
+                }
+                let labelled = self
+                    .blossom_leaves(bv)
+                    .into_iter()
+                    .find(|&v| self.label[v] != 0);
+                if let Some(v) = labelled {
+                    self.label[v] = 0;
+                    self.label[self.endpoint[self.mate[self.blossombase[bv]]]] = 0;
+                    self.assign_label(v, 2, self.labelend[v]);
+                }
+                j += jstep;
+            }
+        }
+        self.label[b] = -1;
+        self.labelend[b] = NONE;
+        self.blossomchilds[b] = Vec::new();
+        self.blossomendps[b] = Vec::new();
+        self.blossombase[b] = NONE;
+        self.blossombestedges[b] = None;
+        self.bestedge[b] = NONE;
+        self.unusedblossoms.push(b);
+    }
+
+    // Swap matched and unmatched edges along the path through blossom b from vertex v to the base.
+    fn augment_blossom(&mut self, b: usize, v: usize) {
+        let mut t = v;
+        while self.blossomparent[t] != b {
+            t = self.blossomparent[t];
+        }
+        if t >= self.nvertex {
+            self.augment_blossom(t, v);
+        }
+        let i = self.blossomchilds[b].iter().position(|&c| c == t).unwrap();
+        let mut j = i as isize;
+        let (jstep, endptrick): (isize, usize) = if i & 1 != 0 {
+            j -= self.blossomchilds[b].len() as isize;
+            (1, 0)
+        } else {
+            (-1, 1)
+        };
+        while j != 0 {
+            j += jstep;
+            let t = self.blossomchilds[b][self.child_index(b, j)];
+            let p = self.blossomendps[b][self.child_index(b, j - endptrick as isize)] ^ endptrick;
+            if t >= self.nvertex {
+                self.augment_blossom(t, self.endpoint[p]);
+            }
+            j += jstep;
+            let t = self.blossomchilds[b][self.child_index(b, j)];
+            if t >= self.nvertex {
+                self.augment_blossom(t, self.endpoint[p ^ 1]);
+            }
+            self.mate[self.endpoint[p]] = p ^ 1;
+            self.mate[self.endpoint[p ^ 1]] = p;
+        }
+        self.blossomchilds[b].rotate_left(i);
+        self.blossomendps[b].rotate_left(i);
+        self.blossombase[b] = self.blossombase[self.blossomchilds[b][0]];
+    }
+
+    fn augment_matching(&mut self, k: usize) {
+        let (v, w, _) = self.edges[k];
+        for (mut s, mut p) in [(v, 2 * k + 1), (w, 2 * k)] {
+            loop {
+                let bs = self.inblossom[s];
+                if bs >= self.nvertex {
+                    self.augment_blossom(bs, s);
+                }
+                self.mate[s] = p;
+                if self.labelend[bs] == NONE {
+                    break;
+                }
+                let t = self.endpoint[self.labelend[bs]];
+                let bt = self.inblossom[t];
+                s = self.endpoint[self.labelend[bt]];
+                let j = self.endpoint[self.labelend[bt] ^ 1];
+                if bt >= self.nvertex {
+                    self.augment_blossom(bt, j);
+                }
+                self.mate[j] = self.labelend[bt];
+                p = self.labelend[bt] ^ 1;
+            }
+        }
+    }
+
+    fn solve(&mut self, max_cardinality: bool) {
+        let nvertex = self.nvertex;
+        for _ in 0..nvertex {
+            self.label.fill(0);
+            self.bestedge.fill(NONE);
+            for b in nvertex..2 * nvertex {
+                self.blossombestedges[b] = None;
+            }
+            self.allowedge.fill(false);
+            self.queue.clear();
+            for v in 0..nvertex {
+                if self.mate[v] == NONE && self.label[self.inblossom[v]] == 0 {
+                    self.assign_label(v, 1, NONE);
+                }
+            }
+
+            let mut augmented = false;
+            loop {
+                while let Some(v) = self.queue.pop() {
+                    for p in self.neighbend[v].clone() {
+                        let k = p / 2;
+                        let w = self.endpoint[p];
+                        if self.inblossom[v] == self.inblossom[w] {
+                            continue;
+                        }
+                        let mut kslack = 0;
+                        if !self.allowedge[k] {
+                            kslack = self.slack(k);
+                            if kslack <= 0 {
+                                self.allowedge[k] = true;
+                            }
+                        }
+                        if self.allowedge[k] {
+                            if self.label[self.inblossom[w]] == 0 {
+                                self.assign_label(w, 2, p ^ 1);
+                            } else if self.label[self.inblossom[w]] == 1 {
+                                let base = self.scan_blossom(v, w);
+                                if base != NONE {
+                                    self.add_blossom(base, k);
+                                } else {
+                                    self.augment_matching(k);
+                                    augmented = true;
+                                    break;
+                                }
+                            } else if self.label[w] == 0 {
+                                self.label[w] = 2;
+                                self.labelend[w] = p ^ 1;
+                            }
+                        } else if self.label[self.inblossom[w]] == 1 {
+                            let b = self.inblossom[v];
+                            if self.bestedge[b] == NONE || kslack < self.slack(self.bestedge[b]) {
+                                self.bestedge[b] = k;
+                            }
+                        } else if self.label[w] == 0
+                            && (self.bestedge[w] == NONE || kslack < self.slack(self.bestedge[w]))
+                        {
+                            self.bestedge[w] = k;
+                        }
+                    }
+                    if augmented {
+                        break;
+                    }
+                }
+                if augmented {
+                    break;
+                }
+
+                // No augmenting path, work out how far the dual variables can be adjusted.
+                let mut deltatype = 0;
+                let mut delta = 0;
+                let mut deltaedge = NONE;
+                let mut deltablossom = NONE;
+                if !max_cardinality {
+                    deltatype = 1;
+                    delta = *self.dualvar[..nvertex].iter().min().unwrap();
+                }
+                for v in 0..nvertex {
+                    if self.label[self.inblossom[v]] == 0 && self.bestedge[v] != NONE {
+                        let d = self.slack(self.bestedge[v]);
+                        if deltatype == 0 || d < delta {
+                            delta = d;
+                            deltatype = 2;
+                            deltaedge = self.bestedge[v];
+                        }
+                    }
+                }
+                for b in 0..2 * nvertex {
+                    if self.blossomparent[b] == NONE
+                        && self.label[b] == 1
+                        && self.bestedge[b] != NONE
+                    {
+                        let d = self.slack(self.bestedge[b]) / 2;
+                        if deltatype == 0 || d < delta {
+                            delta = d;
+                            deltatype = 3;
+                            deltaedge = self.bestedge[b];
+                        }
+                    }
+                }
+                for b in nvertex..2 * nvertex {
+                    if self.blossombase[b] != NONE
+                        && self.blossomparent[b] == NONE
+                        && self.label[b] == 2
+                        && (deltatype == 0 || self.dualvar[b] < delta)
+                    {
+                        delta = self.dualvar[b];
+                        deltatype = 4;
+                        deltablossom = b;
+                    }
+                }
+                if deltatype == 0 {
+                    deltatype = 1;
+                    delta = (*self.dualvar[..nvertex].iter().min().unwrap()).max(0);
+                }
+
+                for v in 0..nvertex {
+                    match self.label[self.inblossom[v]] {
+                        1 => self.dualvar[v] -= delta,
+                        2 => self.dualvar[v] += delta,
+                        _ => {}
+                    }
+                }
+                for b in nvertex..2 * nvertex {
+                    if self.blossombase[b] != NONE && self.blossomparent[b] == NONE {
+                        match self.label[b] {
+                            1 => self.dualvar[b] += delta,
+                            2 => self.dualvar[b] -= delta,
+                            _ => {}
+                        }
+                    }
+                }
+
+                match deltatype {
+                    1 => break,
+                    2 => {
+                        self.allowedge[deltaedge] = true;
+                        let (mut i, j, _) = self.edges[deltaedge];
+                        if self.label[self.inblossom[i]] == 0 {
+                            i = j;
+                        }
+                        self.queue.push(i);
+                    }
+                    3 => {
+                        self.allowedge[deltaedge] = true;
+                        let (i, _, _) = self.edges[deltaedge];
+                        self.queue.push(i);
+                    }
+                    _ => self.expand_blossom(deltablossom, false),
+                }
+            }
+
+            if !augmented {
+                break;
+            }
+
+            for b in nvertex..2 * nvertex {
+                if self.blossomparent[b] == NONE
+                    && self.blossombase[b] != NONE
+                    && self.label[b] == 1
+                    && self.dualvar[b] == 0
+                {
+                    self.expand_blossom(b, true);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A graph of `n` people, with person ids counting up from 1 in node order.
+    fn graph(n: usize) -> Graph {
+        let mut g = Graph::default();
+        for i in 0..n {
+            g.add_node(PersonId(i as u32 + 1));
+        }
+        g
+    }
+
+    // Every node in exactly one group, and no group holding a forbidden pair.
+    fn assert_valid(g: &Graph, groups: &[Vec<usize>]) {
+        let mut seen: Vec<usize> = groups.iter().flatten().copied().collect();
+        seen.sort();
+        assert_eq!(seen, (0..g.len()).collect::<Vec<_>>(), "{groups:?}");
+        for group in groups {
+            for (i, a) in group.iter().enumerate() {
+                for b in &group[i + 1..] {
+                    assert!(!g.is_forbidden(*a, *b), "{a} and {b} in {groups:?}");
+                }
+            }
+        }
+    }
+
+    fn total_weight(g: &Graph, groups: &[Vec<usize>]) -> u32 {
+        groups
+            .iter()
+            .map(|group| {
+                let mut weight = 0;
+                for (i, a) in group.iter().enumerate() {
+                    for b in &group[i + 1..] {
+                        weight += g.weight(*a, *b);
+                    }
+                }
+                weight
+            })
+            .sum()
+    }
+
+    // The best (matched pairs, total weight) of any matching of `vertices`, trying every one.
+    fn brute_force(
+        vertices: &[usize],
+        weights: &HashMap<(usize, usize), i64>,
+        max_cardinality: bool,
+    ) -> (usize, i64) {
+        let Some((&first, rest)) = vertices.split_first() else {
+            return (0, 0);
+        };
+        let better = |a: (usize, i64), b: (usize, i64)| {
+            let better = if max_cardinality { a > b } else { a.1 > b.1 };
+            if better {
+                a
+            } else {
+                b
+            }
+        };
+        // `first` left unmatched.
+        let mut best = brute_force(rest, weights, max_cardinality);
+        for (i, other) in rest.iter().enumerate() {
+            let Some(weight) = weights.get(&(first, *other)) else {
+                continue;
+            };
+            let remaining: Vec<usize> = rest
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, v)| *v)
+                .collect();
+            let (pairs, total) = brute_force(&remaining, weights, max_cardinality);
+            best = better((pairs + 1, total + weight), best);
+        }
+        best
+    }
+
+    #[test]
+    fn max_weight_matching_is_optimal() {
+        let mut state = 1;
+        let mut next = move || {
+            state += 1;
+            splitmix64(state)
+        };
+        for case in 0..300 {
+            let n = 2 + case % 7;
+            let mut edges = Vec::new();
+            let mut weights = HashMap::new();
+            for i in 0..n {
+                for j in i + 1..n {
+                    // About two thirds of the pairs, some with negative weights.
+                    if next() % 3 == 0 {
+                        continue;
+                    }
+                    let weight = (next() % 21) as i64 - 5;
+                    edges.push((i, j, weight));
+                    weights.insert((i, j), weight);
+                }
+            }
+            let vertices: Vec<usize> = (0..n).collect();
+            for max_cardinality in [false, true] {
+                let mates = max_weight_matching(&edges, max_cardinality);
+                let mut pairs = 0;
+                let mut total = 0;
+                for (v, mate) in mates.iter().enumerate() {
+                    if let Some(mate) = *mate {
+                        assert_eq!(mates[mate], Some(v), "mates disagree in {edges:?}");
+                        if v < mate {
+                            pairs += 1;
+                            total += weights[&(v, mate)];
+                        }
+                    }
+                }
+                let (best_pairs, best_total) = brute_force(&vertices, &weights, max_cardinality);
+                assert_eq!(total, best_total, "{edges:?} {max_cardinality}");
+                if max_cardinality {
+                    assert_eq!(pairs, best_pairs, "{edges:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn optimal_pairs_have_the_lowest_weight() {
+        let mut g = graph(4);
+        // 0-1 and 2-3 have met a lot, 0-2 and 1-3 never.
+        g.add_edge(0, 1, 5);
+        g.add_edge(2, 3, 5);
+        g.add_edge(0, 3, 1);
+        g.add_edge(1, 2, 1);
+        let groups = g.matching(OddPersonPolicy::Unmatched, &Optimal);
+        assert_valid(&g, &groups);
+        assert_eq!(total_weight(&g, &groups), 0);
+    }
+
+    #[test]
+    fn odd_counts_leave_one_out_and_forbidden_pairs_are_never_matched() {
+        for strategy in [
+            &Optimal as &dyn MatchingStrategy,
+            &Greedy,
+            &Random,
+            &RoundRobin,
+        ] {
+            let mut g = graph(5);
+            g.forbid(0, 1);
+            g.forbid(2, 3);
+            g.forbid(0, 4);
+            let groups = g.matching(OddPersonPolicy::Unmatched, strategy);
+            assert_valid(&g, &groups);
+            assert_eq!(groups.iter().filter(|group| group.len() == 1).count(), 1);
+
+            let trios = g.matching(OddPersonPolicy::Trio, strategy);
+            assert_valid(&g, &trios);
+            // Whoever is left joins a pair unless every pair has someone they're forbidden from.
+            for odd in trios.iter().filter(|group| group.len() == 1) {
+                assert!(
+                    trios
+                        .iter()
+                        .filter(|group| group.len() == 2)
+                        .all(|group| group.iter().any(|n| g.is_forbidden(*n, odd[0]))),
+                    "{trios:?}"
+                );
+            }
+            assert!(trios.iter().filter(|group| group.len() == 1).count() <= 1);
+        }
+    }
+
+    #[test]
+    fn everyone_forbidden_sits_out() {
+        let mut g = graph(3);
+        g.forbid(0, 1);
+        g.forbid(0, 2);
+        g.forbid(1, 2);
+        for strategy in [
+            &Optimal as &dyn MatchingStrategy,
+            &Greedy,
+            &Random,
+            &RoundRobin,
+        ] {
+            let groups = g.matching(OddPersonPolicy::Unmatched, strategy);
+            assert_valid(&g, &groups);
+            assert!(groups.iter().all(|group| group.len() == 1));
+        }
+    }
+
+    #[test]
+    fn priority_nodes_do_not_sit_out() {
+        for strategy in [&Optimal as &dyn MatchingStrategy, &Greedy, &Random] {
+            for sits_out in 0..5 {
+                let mut g = graph(5);
+                for id in (0..5).filter(|id| *id != sits_out) {
+                    g.set_priority(id);
+                }
+                let groups = g.matching(OddPersonPolicy::Unmatched, strategy);
+                assert_valid(&g, &groups);
+                assert!(groups.contains(&vec![sits_out]), "{groups:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn priority_outweighs_history() {
+        let mut g = graph(3);
+        // Pairing 0 with 1 is far better, but 2 sat out last time.
+        g.add_edge(0, 2, 10);
+        g.add_edge(1, 2, 10);
+        g.set_priority(2);
+        let groups = g.matching(OddPersonPolicy::Unmatched, &Optimal);
+        assert!(!groups.contains(&vec![2]), "{groups:?}");
+    }
+
+    #[test]
+    fn greedy_takes_the_lightest_pairs_first() {
+        let mut g = graph(4);
+        g.add_edge(0, 2, 1);
+        g.add_edge(0, 3, 1);
+        g.add_edge(1, 2, 1);
+        g.add_edge(1, 3, 1);
+        g.add_edge(2, 3, 1);
+        // 0-1 is the only pair that has never met.
+        let groups = g.matching(OddPersonPolicy::Unmatched, &Greedy);
+        assert!(groups.contains(&vec![0, 1]), "{groups:?}");
+        assert!(groups.contains(&vec![2, 3]), "{groups:?}");
+    }
+
+    #[test]
+    fn random_pairs_follow_the_round() {
+        let mut g = graph(20);
+        g.forbid(0, 1);
+        let first = g.matching(OddPersonPolicy::Unmatched, &Random);
+        assert_valid(&g, &first);
+        assert_eq!(first, g.matching(OddPersonPolicy::Unmatched, &Random));
+        let differ = (1..10).any(|round| {
+            g.set_round(round);
+            let groups = g.matching(OddPersonPolicy::Unmatched, &Random);
+            assert_valid(&g, &groups);
+            groups != first
+        });
+        assert!(differ);
+    }
+
+    #[test]
+    fn round_robin_meets_everyone_once() {
+        for n in [2, 5, 6] {
+            let mut g = graph(n);
+            let rounds = if n % 2 == 0 { n - 1 } else { n };
+            let mut met = HashSet::new();
+            for round in 0..rounds {
+                g.set_round(round as u64);
+                let groups = g.matching(OddPersonPolicy::Unmatched, &RoundRobin);
+                assert_valid(&g, &groups);
+                for group in groups.iter().filter(|group| group.len() == 2) {
+                    assert!(met.insert((group[0].min(group[1]), group[0].max(group[1]))));
+                }
+            }
+            assert_eq!(met.len(), n * (n - 1) / 2);
+        }
+        let schedule = round_robin_schedule(&[1, 2, 3, 4]);
+        assert_eq!(schedule.len(), 3);
+        assert!(schedule.iter().all(|round| round.len() == 2));
+    }
+
+    #[test]
+    fn round_robin_repairs_forbidden_pairs() {
+        let mut g = graph(4);
+        // Round 0 pairs the first with the last.
+        g.forbid(0, 3);
+        let groups = g.matching(OddPersonPolicy::Unmatched, &RoundRobin);
+        assert_valid(&g, &groups);
+        assert!(groups.iter().all(|group| group.len() == 2), "{groups:?}");
+    }
+
+    #[test]
+    fn mentors_take_up_to_their_capacity() {
+        let mut g = Graph::mentoring();
+        for i in 0..7 {
+            g.add_node(PersonId(i + 1));
+        }
+        g.set_role(0, MentoringRole::Mentor, 2);
+        g.set_role(1, MentoringRole::Mentor, 1);
+        g.set_role(2, MentoringRole::Mentor, 3);
+        for mentee in 3..7 {
+            g.set_role(mentee, MentoringRole::Mentee, 1);
+        }
+        g.forbid(1, 3);
+        g.add_edge(2, 4, 5);
+        let groups = g.matching(OddPersonPolicy::Unmatched, &Optimal);
+        let mut mentees = HashMap::new();
+        for group in &groups {
+            assert!(!g.is_forbidden(group[0], *group.last().unwrap()));
+            if let [mentor, mentee] = group[..] {
+                assert!(mentor < 3 && mentee >= 3, "{groups:?}");
+                *mentees.entry(mentor).or_insert(0) += 1;
+            }
+        }
+        // Four mentees for six seats: everyone is mentored, every mentor gets one before anyone
+        // gets a second, and nobody is over capacity.
+        assert_eq!(mentees.values().sum::<u32>(), 4, "{groups:?}");
+        assert_eq!(mentees.len(), 3, "{groups:?}");
+        assert!(mentees[&0] <= 2 && mentees[&1] <= 1, "{groups:?}");
+        assert!(!groups.contains(&vec![2, 4]), "{groups:?}");
+    }
+
+    #[test]
+    fn mentees_left_without_a_mentor_are_on_their_own() {
+        let mut g = Graph::mentoring();
+        for i in 0..4 {
+            g.add_node(PersonId(i + 1));
+        }
+        g.set_role(0, MentoringRole::Mentor, 1);
+        for mentee in 1..4 {
+            g.set_role(mentee, MentoringRole::Mentee, 1);
+        }
+        g.set_priority(3);
+        let groups = g.matching(OddPersonPolicy::Unmatched, &Optimal);
+        assert_valid(&g, &groups);
+        assert!(groups.contains(&vec![0, 3]), "{groups:?}");
+        assert_eq!(groups.iter().filter(|group| group.len() == 1).count(), 2);
+    }
+
+    #[test]
+    fn groups_are_as_even_as_possible() {
+        let mut g = Graph::groups_of(4);
+        for i in 0..10 {
+            g.add_node(PersonId(i + 1));
+        }
+        g.forbid(0, 1);
+        g.add_edge(2, 3, 10);
+        let groups = g.matching(OddPersonPolicy::Unmatched, &Optimal);
+        assert_valid(&g, &groups);
+        let mut sizes: Vec<usize> = groups.iter().map(Vec::len).collect();
+        sizes.sort();
+        assert_eq!(sizes, vec![3, 3, 4]);
+        assert_eq!(total_weight(&g, &groups), 0);
+    }
+
+    #[test]
+    fn small_graphs_consider_every_allowed_pair() {
+        let mut g = graph(6);
+        g.forbid(0, 1);
+        let edges = g.candidate_edges();
+        assert_eq!(edges.len(), 6 * 5 / 2 - 1);
+        assert!(!edges.contains(&(0, 1)));
+    }
+
+    // Nodes 0 to 39 are all forbidden from each other, so the fresh partners of most of them are
+    // the same 16 nodes from 40 on, and the same for 300 to 339. Not everyone can be matched from
+    // the candidate edges alone, but those left over from each can be paired with the other.
+    fn crowded_graph() -> Graph {
+        let mut g = graph(EXACT_NODES + 88);
+        for start in [0, 300] {
+            for i in start..start + 40 {
+                for j in i + 1..start + 40 {
+                    g.forbid(i, j);
+                }
+            }
+        }
+        g.add_edge(100, 200, 3);
+        g
+    }
+
+    #[test]
+    fn large_graphs_only_consider_some_pairs() {
+        let g = crowded_graph();
+        let edges: HashSet<(usize, usize)> = g.candidate_edges().into_iter().collect();
+        assert!(edges.contains(&(100, 200)));
+        assert!(edges.iter().all(|(i, j)| !g.is_forbidden(*i, *j)));
+        let partners_of_30: Vec<usize> = edges
+            .iter()
+            .filter_map(|(i, j)| (*i == 30).then_some(*j).or((*j == 30).then_some(*i)))
+            .collect();
+        assert_eq!(partners_of_30.len(), FRESH_PARTNERS);
+        assert!(partners_of_30.iter().all(|j| (40..56).contains(j)));
+
+        let weighted: Vec<_> = edges.iter().map(|(i, j)| (*i, *j, 1)).collect();
+        let mates = max_weight_matching(&weighted, true);
+        assert!(mates.iter().filter(|mate| mate.is_none()).count() >= 16);
+    }
+
+    #[test]
+    fn leftovers_from_candidate_edges_are_paired_greedily() {
+        let g = crowded_graph();
+        let groups = g.matching(OddPersonPolicy::Unmatched, &Optimal);
+        assert_valid(&g, &groups);
+        assert!(
+            groups.iter().all(|group| group.len() == 2),
+            "someone sat out"
+        );
     }
 }
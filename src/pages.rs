@@ -0,0 +1,507 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::{
+    db::{
+        Branding, ConflictGroup, ContactField, ContactKind, GenerationSummary, Icebreaker,
+        InterruptedGeneration, Mailing, Pool, PoolMembership, PoolParticipant, PublicStats,
+        Segment, TimelineEvent,
+    },
+    domain::{GenerationId, PastMatch, Person, PersonId},
+    matching::{MatchingSettings, MentoringRole},
+    snapshot::Snapshot,
+    web::{AppMatch, NotificationRow, PartnerWeight, Round},
+};
+
+// What each page's template is given to render, so templates can be changed (or replaced by another
+// frontend) knowing exactly what's available without reading the handlers. Each struct's fields are
+// the template's variables.
+//
+// Every page also gets, from `AppState::render_page`:
+// - `branding`, the deployment's `Branding`
+// - `timezone`, the signed in person's timezone name, or UTC, for the `date` filter
+// - `locale`, the configured language tag, e.g. for `<html lang>` or choosing translations
+//
+// `authorized_person_id` is the signed in person, it's what the header shows links for.
+pub trait Page: Serialize {
+    // The template in `template_dir` the page is rendered with.
+    const TEMPLATE: &'static str;
+}
+
+// Shown for anything that went wrong that the person should be told about.
+#[derive(Debug, Serialize)]
+pub struct ErrorPage {
+    pub authorized_person_id: Option<PersonId>,
+    pub error: String,
+}
+
+impl Page for ErrorPage {
+    const TEMPLATE: &'static str = "error.html";
+}
+
+#[derive(Debug, Serialize)]
+pub struct IndexPage {
+    pub authorized_person_id: Option<PersonId>,
+}
+
+impl Page for IndexPage {
+    const TEMPLATE: &'static str = "index.html";
+}
+
+// The first-run setup, one `step` at a time: "account" makes the first admin and names the
+// organisation, then "email" and "schedule" can each be filled in or skipped. Fields keep what was
+// typed when a step is shown again, passwords are never sent back.
+#[derive(Debug, Default, Serialize)]
+pub struct SetupPage {
+    pub authorized_person_id: Option<PersonId>,
+    pub step: &'static str,
+    pub error: Option<String>,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub organisation: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub tls: Option<String>,
+    pub from: Option<String>,
+    // RFC 3339, or as typed when it wasn't valid.
+    pub start: Option<String>,
+    pub every_days: Option<i64>,
+    pub cron: Option<String>,
+    pub min_waiters: Option<usize>,
+}
+
+impl Page for SetupPage {
+    const TEMPLATE: &'static str = "setup.html";
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SignInPage {
+    // Filled in to save typing it again.
+    pub email: Option<String>,
+    pub error: Option<String>,
+}
+
+impl Page for SignInPage {
+    const TEMPLATE: &'static str = "sign_in.html";
+}
+
+// The fields are what was typed in, when the form is shown again.
+#[derive(Debug, Default, Serialize)]
+pub struct SignUpPage {
+    pub email: Option<String>,
+    pub name: Option<String>,
+    pub team: Option<String>,
+    pub error: Option<String>,
+    // The organisation being joined from its sign up link, and the link's token to send back.
+    pub organisation: Option<String>,
+    pub organisation_token: Option<String>,
+}
+
+impl Page for SignUpPage {
+    const TEMPLATE: &'static str = "sign_up.html";
+}
+
+// Someone's page, as seen by `authorized_person_id`, who may be them.
+#[derive(Debug, Serialize)]
+pub struct PersonPage {
+    pub authorized_person_id: PersonId,
+    pub is_admin: bool,
+    pub id: PersonId,
+    pub name: String,
+    pub email: String,
+    pub waiting: bool,
+    // Every pool and whether they're in it, only for themselves and admins.
+    pub pools: Vec<PoolMembership>,
+    pub status: Option<String>,
+    pub team: Option<String>,
+    // Who they've been matched with, grouped by round type, newest first within each.
+    pub match_rounds: Vec<(String, Vec<MatchPartner>)>,
+    // Their waiting, matches and pauses oldest first, only for themselves and admins.
+    pub timeline: Vec<TimelineEvent>,
+    pub season_matches: u32,
+    pub max_matches_per_season: Option<u32>,
+    // Matched at most once in this many rounds, missing for every round.
+    pub match_every_rounds: Option<u32>,
+    // Their mentoring role and how many mentees they take, when there are mentoring rounds.
+    pub mentoring: Option<(MentoringRole, u32)>,
+    // Whether the viewer has blocked them.
+    pub is_blocked: bool,
+    // A unix timestamp, when they're paused.
+    pub paused_until: Option<i64>,
+    pub interests: Vec<String>,
+    // Who they report to, from the imported org chart.
+    pub manager: Option<Person>,
+    // Only those the viewer is allowed to see.
+    pub contact_fields: Vec<ContactField>,
+    // Only set on their own page.
+    #[serde(flatten)]
+    pub own: Option<OwnPerson>,
+}
+
+// What only the person themselves sees on their page. Flattened, so these are top level variables
+// that are missing on anyone else's page.
+#[derive(Debug, Serialize)]
+pub struct OwnPerson {
+    // Who they've blocked.
+    pub blocked: Vec<Person>,
+    pub has_api_token: bool,
+    pub own_timezone: Option<String>,
+    pub email_notifications: bool,
+    // Every timezone name to choose from.
+    pub timezones: Vec<&'static str>,
+    // The pools they were appointed to run.
+    pub administered_pools: Vec<String>,
+}
+
+impl Page for PersonPage {
+    const TEMPLATE: &'static str = "person.html";
+}
+
+// Someone a person was matched with and how to get in touch, as far as the viewer may see.
+#[derive(Debug, Serialize)]
+pub struct MatchPartner {
+    #[serde(flatten)]
+    pub past: PastMatch,
+    pub interests: Vec<String>,
+    // Only those the viewer is allowed to see.
+    pub contact_fields: Vec<ContactField>,
+}
+
+// Who `person` was matched with in one generation, for them and admins.
+#[derive(Debug, Serialize)]
+pub struct MatchPage {
+    pub authorized_person_id: PersonId,
+    pub person: Person,
+    pub generation: GenerationId,
+    pub time: u64,
+    pub round_type: String,
+    // The conversation starter the group was given.
+    pub icebreaker: Option<String>,
+    pub partners: Vec<MatchPartner>,
+}
+
+impl Page for MatchPage {
+    const TEMPLATE: &'static str = "match.html";
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeoplePage {
+    pub authorized_person_id: PersonId,
+    pub people: Vec<Person>,
+}
+
+impl Page for PeoplePage {
+    const TEMPLATE: &'static str = "people.html";
+}
+
+// The latest generation of each round type, or one past generation.
+#[derive(Debug, Serialize)]
+pub struct MatchesPage {
+    pub authorized_person_id: PersonId,
+    pub error: Option<String>,
+    // Admins can run matching and manage the deployment from here.
+    pub is_admin: bool,
+    // Round types that matching can be run for, only for admins.
+    pub round_types: Vec<String>,
+    // Generations cut short when matcher last stopped, only for admins.
+    pub interrupted: Vec<InterruptedGeneration>,
+    pub rounds: Vec<Round>,
+    // Everyone the viewer was matched with in `rounds`, to link to their contact details.
+    pub partner_ids: Vec<PersonId>,
+}
+
+impl Page for MatchesPage {
+    const TEMPLATE: &'static str = "matches.html";
+}
+
+// Every generation that has been matched, to find one to look at.
+#[derive(Debug, Serialize)]
+pub struct MatchesHistoryPage {
+    pub authorized_person_id: PersonId,
+    pub generations: Vec<GenerationSummary>,
+}
+
+impl Page for MatchesHistoryPage {
+    const TEMPLATE: &'static str = "matches_history.html";
+}
+
+// How everyone's match email for a generation went.
+#[derive(Debug, Serialize)]
+pub struct NotificationsPage {
+    pub authorized_person_id: PersonId,
+    pub generation: GenerationId,
+    pub groups: Vec<Vec<NotificationRow>>,
+    // Whether a mail server is configured at all.
+    pub emailing: bool,
+}
+
+impl Page for NotificationsPage {
+    const TEMPLATE: &'static str = "notifications.html";
+}
+
+// Someone's potential partners in the next run of `round_type`, ranked by weight.
+#[derive(Debug, Serialize)]
+pub struct PartnerWeightsPage {
+    pub authorized_person_id: PersonId,
+    pub person: Person,
+    pub partners: Vec<PartnerWeight>,
+    pub settings: MatchingSettings,
+    // Whether past meetings count for less as they get older.
+    pub decayed: bool,
+    pub round_type: String,
+    pub round_types: Vec<String>,
+}
+
+impl Page for PartnerWeightsPage {
+    const TEMPLATE: &'static str = "partner_weights.html";
+}
+
+// A new API token, which is only ever shown this once.
+#[derive(Debug, Serialize)]
+pub struct ApiTokenPage {
+    pub authorized_person_id: PersonId,
+    pub token: String,
+}
+
+impl Page for ApiTokenPage {
+    const TEMPLATE: &'static str = "api_token.html";
+}
+
+#[derive(Debug, Serialize)]
+pub struct PublicStatsPage {
+    pub stats: PublicStats,
+}
+
+impl Page for PublicStatsPage {
+    const TEMPLATE: &'static str = "public_stats.html";
+}
+
+// Either the confirmation form for `token`, or that they've been unsubscribed.
+#[derive(Debug, Serialize)]
+pub struct UnsubscribePage {
+    pub token: Option<String>,
+    pub unsubscribed: bool,
+}
+
+impl Page for UnsubscribePage {
+    const TEMPLATE: &'static str = "unsubscribe.html";
+}
+
+// Either the confirmation form for a reminder's `token`, or that they've opted in to the run at
+// `round_at`.
+#[derive(Debug, Serialize)]
+pub struct OptInPage {
+    pub token: Option<String>,
+    pub round_at: Option<i64>,
+}
+
+impl Page for OptInPage {
+    const TEMPLATE: &'static str = "opt_in.html";
+}
+
+// Either the form to activate an imported account with `token`, or that the link has been sent.
+#[derive(Debug, Serialize)]
+pub struct ActivatePage {
+    pub token: Option<String>,
+}
+
+impl Page for ActivatePage {
+    const TEMPLATE: &'static str = "activate.html";
+}
+
+// The branding form, filled in with what's saved or what was just submitted.
+#[derive(Debug, Serialize)]
+pub struct BrandingPage {
+    pub authorized_person_id: PersonId,
+    pub error: Option<String>,
+    pub product_name: String,
+    pub logo_url: String,
+    pub accent_colour: String,
+    // One link per line, the label followed by the URL.
+    pub footer_links: String,
+}
+
+impl BrandingPage {
+    pub fn new(authorized_person_id: PersonId, branding: Branding) -> Self {
+        Self {
+            authorized_person_id,
+            error: None,
+            product_name: branding.product_name,
+            logo_url: branding.logo_url.unwrap_or_default(),
+            accent_colour: branding.accent_colour,
+            footer_links: branding
+                .footer_links
+                .iter()
+                .map(|link| format!("{} {}", link.label, link.url))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+impl Page for BrandingPage {
+    const TEMPLATE: &'static str = "branding.html";
+}
+
+// The pools run alongside the main rotation, for admins to create and run. Pool admins only see
+// the pools they were appointed to.
+#[derive(Debug, Serialize)]
+pub struct PoolsPage {
+    pub authorized_person_id: PersonId,
+    pub error: Option<String>,
+    // Whether they run the deployment, so can create, archive and appoint admins to pools.
+    pub is_admin: bool,
+    pub pools: Vec<Pool>,
+}
+
+impl Page for PoolsPage {
+    const TEMPLATE: &'static str = "pools.html";
+}
+
+// A pool's members, for its admins to add, remove and set waiting.
+#[derive(Debug, Serialize)]
+pub struct PoolPage {
+    pub authorized_person_id: PersonId,
+    pub error: Option<String>,
+    pub pool: Pool,
+    pub members: Vec<PoolParticipant>,
+}
+
+impl Page for PoolPage {
+    const TEMPLATE: &'static str = "pool.html";
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConflictsPage {
+    pub authorized_person_id: PersonId,
+    pub error: Option<String>,
+    pub groups: Vec<ConflictGroup>,
+}
+
+impl Page for ConflictsPage {
+    const TEMPLATE: &'static str = "conflicts.html";
+}
+
+// Conversation starters, one of which each new match is given at random.
+#[derive(Debug, Serialize)]
+pub struct IcebreakersPage {
+    pub authorized_person_id: PersonId,
+    pub error: Option<String>,
+    pub icebreakers: Vec<Icebreaker>,
+}
+
+impl Page for IcebreakersPage {
+    const TEMPLATE: &'static str = "icebreakers.html";
+}
+
+// How many people are in a segment right now.
+#[derive(Debug, Serialize)]
+pub struct SegmentSize {
+    pub segment: Segment,
+    pub size: usize,
+}
+
+// Emailing a segment of people, and the emails sent before.
+#[derive(Debug, Serialize)]
+pub struct MailingsPage {
+    pub authorized_person_id: PersonId,
+    pub error: Option<String>,
+    // Whether a mail server is configured at all.
+    pub emailing: bool,
+    pub segments: Vec<SegmentSize>,
+    // The segment being previewed, and who is in it.
+    pub segment: Option<Segment>,
+    pub members: Vec<Person>,
+    pub mailings: Vec<Mailing>,
+}
+
+impl Page for MailingsPage {
+    const TEMPLATE: &'static str = "mailings.html";
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotsPage {
+    pub authorized_person_id: PersonId,
+    pub error: Option<String>,
+    // Newest first.
+    pub snapshots: Vec<Snapshot>,
+}
+
+impl Page for SnapshotsPage {
+    const TEMPLATE: &'static str = "snapshots.html";
+}
+
+// The schedule form, filled in with the current schedule or what was just submitted.
+#[derive(Debug, Serialize)]
+pub struct SchedulePage {
+    pub authorized_person_id: PersonId,
+    pub error: Option<String>,
+    // RFC 3339, or as typed when it wasn't valid.
+    pub start: Option<String>,
+    pub every_days: i64,
+    // As saved or typed, empty when runs are every `every_days`.
+    pub cron: Option<String>,
+    pub min_waiters: usize,
+    pub enabled: bool,
+    // Unix timestamps of the next few runs.
+    pub next_runs: Vec<i64>,
+}
+
+impl Page for SchedulePage {
+    const TEMPLATE: &'static str = "schedule.html";
+}
+
+// The signed in person's profile form, with an error or a note about what just happened.
+#[derive(Debug, Serialize)]
+pub struct EditPersonPage {
+    pub authorized_person_id: PersonId,
+    pub error: Option<String>,
+    pub info: Option<String>,
+    pub id: PersonId,
+    pub name: String,
+    pub email: String,
+    // A new email waiting to be confirmed.
+    pub pending_email: Option<String>,
+    // Comma separated.
+    pub interests: String,
+    pub team: Option<String>,
+    // Every team anyone is in, to suggest.
+    pub teams: Vec<String>,
+    pub contact_fields: HashMap<ContactKind, ContactField>,
+}
+
+impl Page for EditPersonPage {
+    const TEMPLATE: &'static str = "edit_person.html";
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteAccountPage {
+    pub authorized_person_id: PersonId,
+    pub error: Option<String>,
+    pub id: PersonId,
+}
+
+impl Page for DeleteAccountPage {
+    const TEMPLATE: &'static str = "delete_account.html";
+}
+
+// The installable app, `me` is missing when the signed in person can't be found.
+#[derive(Debug, Serialize)]
+pub struct AppPage {
+    pub authorized_person_id: PersonId,
+    pub me: Option<AppMatch>,
+}
+
+impl Page for AppPage {
+    const TEMPLATE: &'static str = "app.html";
+}
+
+// Rendered as a template so it can use the branding, it has nothing of its own.
+#[derive(Debug, Serialize)]
+pub struct ServiceWorker {}
+
+impl Page for ServiceWorker {
+    const TEMPLATE: &'static str = "service_worker.js";
+}
@@ -0,0 +1,85 @@
+use std::collections::HashSet;
+
+use crate::{
+    db::{ContactField, ContactVisibility, Database, DatabaseError},
+    domain::{Match, MatchMeta, PastMatch, PersonId},
+};
+
+// What someone asking for people or matches may see of them. Every page, export and API showing
+// who was matched with whom goes through this rather than deciding for itself.
+//
+// Callers still limit what they load to the viewer's organisation, this only decides within it.
+pub struct Viewer {
+    person_id: PersonId,
+    // Admins of the viewer's organisation see everything in it.
+    is_admin: bool,
+    // Everyone the viewer has ever been matched with.
+    partners: HashSet<PersonId>,
+}
+
+impl Viewer {
+    pub fn load(db: &Database, person_id: PersonId) -> Result<Self, DatabaseError> {
+        Ok(Self {
+            person_id,
+            is_admin: db.is_admin(person_id)?,
+            partners: db
+                .matches_for(person_id)?
+                .into_iter()
+                .map(|m| m.partner.id)
+                .collect(),
+        })
+    }
+
+    // Whether they may see who `person_id` has been matched with, only themselves and admins.
+    pub fn sees_history_of(&self, person_id: PersonId) -> bool {
+        self.is_admin || person_id == self.person_id
+    }
+
+    // Whether they have been matched with `person_id`, so can have their contact details.
+    pub fn is_partner_of(&self, person_id: PersonId) -> bool {
+        self.partners.contains(&person_id)
+    }
+
+    // Someone's match history, empty for anyone not allowed to see it.
+    pub fn history(&self, person_id: PersonId, matches: Vec<PastMatch>) -> Vec<PastMatch> {
+        if self.sees_history_of(person_id) {
+            matches
+        } else {
+            Vec::new()
+        }
+    }
+
+    // The groups in a round they may see: all of them for admins, otherwise only their own.
+    pub fn groups(&self, mut matches: Vec<Match>) -> Vec<Match> {
+        if !self.is_admin {
+            matches.retain(|m| m.members().any(|p| p.id == self.person_id));
+        }
+        matches
+    }
+
+    pub fn rounds(&self, rounds: Vec<(MatchMeta, Vec<Match>)>) -> Vec<(MatchMeta, Vec<Match>)> {
+        rounds
+            .into_iter()
+            .map(|(meta, matches)| (meta, self.groups(matches)))
+            .collect()
+    }
+
+    // The contact fields of `person_id` they may see, going by each field's visibility.
+    pub fn contact_fields(
+        &self,
+        person_id: PersonId,
+        fields: Vec<ContactField>,
+    ) -> Vec<ContactField> {
+        if self.sees_history_of(person_id) {
+            return fields;
+        }
+        fields
+            .into_iter()
+            .filter(|field| match field.visibility {
+                ContactVisibility::Everyone => true,
+                ContactVisibility::Matches => self.is_partner_of(person_id),
+                ContactVisibility::Admins => false,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Once this many keys are tracked, finished windows are dropped before adding another.
+const PRUNE_AT: usize = 10_000;
+
+// Allow each key `limit` requests per `window`, counted in fixed windows starting from its first
+// request.
+pub struct RateLimiter<K> {
+    limit: u32,
+    window: Duration,
+    windows: Mutex<HashMap<K, (Instant, u32)>>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            windows: Mutex::default(),
+        }
+    }
+
+    // Count a request from `key`, returning how long until it may try again when it's over the limit.
+    pub fn check(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= PRUNE_AT {
+            windows.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+        }
+        let (start, count) = windows.entry(key).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.limit {
+            return Err(self.window - now.duration_since(*start));
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_limited_per_window() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        assert_eq!(limiter.check("a", start), Ok(()));
+        assert_eq!(limiter.check("a", start + Duration::from_secs(1)), Ok(()));
+        assert_eq!(
+            limiter.check("a", start + Duration::from_secs(10)),
+            Err(Duration::from_secs(50))
+        );
+        assert_eq!(limiter.check("b", start + Duration::from_secs(10)), Ok(()));
+        assert_eq!(limiter.check("a", start + Duration::from_secs(60)), Ok(()));
+    }
+
+    #[test]
+    fn finished_windows_are_pruned() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+        let start = Instant::now();
+        for key in 0..PRUNE_AT {
+            limiter.check(key, start).unwrap();
+        }
+        limiter
+            .check(PRUNE_AT, start + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(limiter.windows.lock().unwrap().len(), 1);
+    }
+}
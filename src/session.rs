@@ -0,0 +1,132 @@
+use axum::{
+    http::HeaderMap,
+    response::{IntoResponseParts, ResponseParts},
+};
+use axum_extra::extract::{
+    cookie::{Cookie, Key, SameSite, SignedCookieJar},
+    CookieJar,
+};
+
+const COOKIE_NAME: &str = "session_id";
+
+// How the session cookie is written and read back.
+#[derive(Clone)]
+pub struct SessionCookies {
+    // Browsers drop the cookie when the session would have expired anyway.
+    lifetime_seconds: i64,
+    // Only send the cookie over HTTPS, set when the site is served over it.
+    secure: bool,
+    // Signs the cookie so tampered values are rejected before reaching the database.
+    key: Option<Key>,
+}
+
+// The cookie to set on a response, signed when there's a key.
+pub enum SessionJar {
+    Signed(SignedCookieJar),
+    Plain(CookieJar),
+}
+
+impl IntoResponseParts for SessionJar {
+    type Error = std::convert::Infallible;
+
+    fn into_response_parts(self, res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        match self {
+            Self::Signed(jar) => jar.into_response_parts(res),
+            Self::Plain(jar) => jar.into_response_parts(res),
+        }
+    }
+}
+
+impl SessionCookies {
+    pub fn new(lifetime_seconds: i64, public_url: &str, key: Option<&str>) -> Self {
+        Self {
+            lifetime_seconds,
+            secure: public_url.starts_with("https://"),
+            key: key.map(|key| Key::derive_from(key.as_bytes())),
+        }
+    }
+
+    // The cookie that signs someone in with `session_id`.
+    pub fn cookie(&self, session_id: &str) -> SessionJar {
+        let cookie = self.build(session_id.to_owned(), self.lifetime_seconds);
+        match &self.key {
+            Some(key) => SessionJar::Signed(SignedCookieJar::new(key.clone()).add(cookie)),
+            None => SessionJar::Plain(CookieJar::new().add(cookie)),
+        }
+    }
+
+    // The cookie that makes the browser forget the session, it needn't be signed.
+    pub fn removal(&self) -> SessionJar {
+        SessionJar::Plain(CookieJar::new().add(self.build(String::new(), 0)))
+    }
+
+    // The session id from the request's cookie, if there is one with a valid signature.
+    pub fn session_id(&self, headers: &HeaderMap) -> Option<String> {
+        let session_id = match &self.key {
+            Some(key) => {
+                let Some(cookie) =
+                    SignedCookieJar::from_headers(headers, key.clone()).get(COOKIE_NAME)
+                else {
+                    if CookieJar::from_headers(headers).get(COOKIE_NAME).is_some() {
+                        tracing::warn!("Rejected a session cookie with an invalid signature");
+                    }
+                    return None;
+                };
+                cookie.value().to_owned()
+            }
+            None => CookieJar::from_headers(headers)
+                .get(COOKIE_NAME)?
+                .value()
+                .to_owned(),
+        };
+        (!session_id.is_empty()).then_some(session_id)
+    }
+
+    fn build(&self, value: String, max_age_seconds: i64) -> Cookie<'static> {
+        Cookie::build((COOKIE_NAME, value))
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            // Lax rather than Strict so links from match emails arrive signed in.
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(max_age_seconds))
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        http::header::{COOKIE, SET_COOKIE},
+        response::IntoResponse,
+    };
+
+    use super::*;
+
+    fn request_with(cookie: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, cookie.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn signed_cookies_are_verified() {
+        let sessions = SessionCookies::new(
+            3600,
+            "https://example.com",
+            Some("a test key that is at least 32 bytes long"),
+        );
+        let response = (sessions.cookie("abc"), ()).into_response();
+        let set_cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+        let (pair, _) = set_cookie.split_once(';').unwrap();
+        assert!(set_cookie.contains("Secure"));
+        assert_eq!(
+            sessions.session_id(&request_with(pair)),
+            Some("abc".to_owned())
+        );
+
+        let tampered = format!("{}d", pair.strip_suffix('c').unwrap());
+        assert_eq!(sessions.session_id(&request_with(&tampered)), None);
+        assert_eq!(sessions.session_id(&request_with("session_id=abc")), None);
+    }
+}
@@ -0,0 +1,12 @@
+use tokio::signal::unix::{signal as listen, SignalKind};
+
+// Wait for SIGTERM, as sent by service managers, or SIGINT from the terminal.
+pub async fn signal() {
+    let mut terminate = listen(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        interrupted = tokio::signal::ctrl_c() => {
+            interrupted.expect("Failed to listen for SIGINT");
+        }
+    }
+}
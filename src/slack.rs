@@ -0,0 +1,477 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    domain::{GenerationId, Match, PersonId},
+    tls,
+};
+
+// How long posting a message can take before giving up, so a hung webhook can't hold anything up.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long each page of the member list can take.
+const API_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Where Slack's Web API is.
+const API_URL: &str = "https://slack.com/api";
+
+// Members asked for in each page of `users.list`, Slack recommends no more than 200.
+const MEMBERS_PER_PAGE: u32 = 200;
+
+// How many times a page is asked for again after Slack's rate limit, waiting as long as it says.
+const RATE_LIMIT_RETRIES: u32 = 3;
+
+// Matches are announced in Slack when an incoming webhook has been configured, and the workspace's
+// members can be imported with a bot token.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SlackConfig {
+    // `https://` (as Slack gives them) or plain `http://`, e.g. for a proxy.
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    // A bot token with the `users:read` and `users:read.email` scopes.
+    #[serde(default)]
+    pub bot_token: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SlackError {
+    Io(std::io::Error),
+    Rejected(String),
+    TimedOut,
+    // No bot token is configured to call the Web API with.
+    NoBotToken,
+    // The Web API answered with `ok: false` and this error, e.g. `invalid_auth`.
+    Api(String),
+}
+
+impl std::fmt::Display for SlackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlackError::Io(error) => write!(f, "connection failed: {error}"),
+            SlackError::Rejected(status) => write!(f, "Slack rejected the request: {status}"),
+            SlackError::TimedOut => write!(f, "Slack didn't answer in time"),
+            SlackError::NoBotToken => write!(f, "no Slack bot token is configured"),
+            SlackError::Api(error) => write!(f, "Slack returned an error: {error}"),
+        }
+    }
+}
+
+// A member of the workspace from `users.list`.
+#[derive(Debug, Deserialize)]
+pub struct SlackMember {
+    pub id: String,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub is_bot: bool,
+    #[serde(default)]
+    pub profile: SlackProfile,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SlackProfile {
+    // Only given with the `users:read.email` scope.
+    pub email: Option<String>,
+    pub real_name: Option<String>,
+}
+
+// A page of `users.list`, the next is asked for with `next_cursor` until it's empty.
+#[derive(Debug, Deserialize)]
+struct MembersPage {
+    ok: bool,
+    error: Option<String>,
+    #[serde(default)]
+    members: Vec<SlackMember>,
+    response_metadata: Option<ResponseMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseMetadata {
+    #[serde(default)]
+    next_cursor: String,
+}
+
+impl From<std::io::Error> for SlackError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+// Talks to the workspace: announcing matches through the webhook and listing members with the bot
+// token.
+#[derive(Clone)]
+pub struct SlackNotifier {
+    config: Option<SlackConfig>,
+    public_url: String,
+    api_url: String,
+}
+
+impl SlackNotifier {
+    pub fn new(config: Option<SlackConfig>, public_url: &str) -> Self {
+        if let Some(webhook_url) = config.as_ref().and_then(|c| c.webhook_url.as_ref()) {
+            assert!(
+                parse_url(webhook_url).is_some(),
+                "Slack webhook URL must start with https:// or http://, got {webhook_url}"
+            );
+        }
+        Self {
+            config,
+            public_url: public_url.trim_end_matches('/').to_owned(),
+            api_url: API_URL.to_owned(),
+        }
+    }
+
+    // Every member of the workspace, following `users.list` page by page.
+    pub async fn list_members(&self) -> Result<Vec<SlackMember>, SlackError> {
+        let Some(token) = self.config.as_ref().and_then(|c| c.bot_token.as_ref()) else {
+            return Err(SlackError::NoBotToken);
+        };
+        let mut members = Vec::new();
+        let mut cursor = String::new();
+        let mut retries = 0;
+        loop {
+            let url = format!(
+                "{}/users.list?limit={MEMBERS_PER_PAGE}&cursor={}",
+                self.api_url,
+                encode_query_value(&cursor)
+            );
+            let authorization = format!("Authorization: Bearer {token}\r\n");
+            let response = tokio::time::timeout(API_TIMEOUT, send(&url, "GET", &authorization, ""))
+                .await
+                .unwrap_or(Err(SlackError::TimedOut))?;
+            if response.status == 429 && retries < RATE_LIMIT_RETRIES {
+                retries += 1;
+                let wait = response
+                    .header("retry-after")
+                    .and_then(|seconds| seconds.parse().ok())
+                    .unwrap_or(1);
+                tokio::time::sleep(Duration::from_secs(wait)).await;
+                continue;
+            }
+            if !(200..300).contains(&response.status) {
+                return Err(SlackError::Rejected(response.status_line));
+            }
+            let page: MembersPage = serde_json::from_str(&response.body)
+                .map_err(|error| SlackError::Api(format!("unexpected response: {error}")))?;
+            if !page.ok {
+                return Err(SlackError::Api(page.error.unwrap_or_default()));
+            }
+            members.extend(page.members);
+            cursor = page
+                .response_metadata
+                .map(|metadata| metadata.next_cursor)
+                .unwrap_or_default();
+            if cursor.is_empty() {
+                return Ok(members);
+            }
+            retries = 0;
+        }
+    }
+
+    // Post everyone's partners in a new generation to the channel, mentioning people whose Slack
+    // account is known.
+    pub async fn notify_matches(
+        &self,
+        generation: GenerationId,
+        matches: &[Match],
+        slack_ids: &HashMap<PersonId, String>,
+    ) {
+        let Some(webhook_url) = self.config.as_ref().and_then(|c| c.webhook_url.as_ref()) else {
+            return;
+        };
+        let mut text = format!(
+            "New matches are out in <{}/matches/{generation}|generation {generation}>:\n",
+            self.public_url
+        );
+        for m in matches {
+            let members: Vec<_> = m
+                .members()
+                .map(|person| match slack_ids.get(&person.id) {
+                    Some(slack_id) => format!("<@{slack_id}>"),
+                    None => slack_escape(&person.name),
+                })
+                .collect();
+            if let [rest @ .., last] = members.as_slice() {
+                if !rest.is_empty() {
+                    text.push_str(&format!("• {} and {last}\n", rest.join(", ")));
+                }
+            }
+        }
+        let body = serde_json::json!({ "text": text }).to_string();
+        let posted = tokio::time::timeout(WEBHOOK_TIMEOUT, post_json(webhook_url, &body))
+            .await
+            .unwrap_or(Err(SlackError::TimedOut));
+        if let Err(error) = posted {
+            tracing::warn!(generation = generation.0, %error, "Failed to post to Slack");
+        }
+    }
+}
+
+// Slack treats `&`, `<` and `>` as markup in message text.
+fn slack_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// A webhook URL split into whether it uses TLS, the host, port and path.
+struct Url<'a> {
+    tls: bool,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+// Split `https://host[:port]/path` or `http://host[:port]/path` into its parts.
+fn parse_url(url: &str) -> Option<Url<'_>> {
+    let (tls, rest) = match url.strip_prefix("https://") {
+        Some(rest) => (true, rest),
+        None => (false, url.strip_prefix("http://")?),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some(Url {
+        tls,
+        host,
+        port,
+        path,
+    })
+}
+
+async fn post_json(url: &str, body: &str) -> Result<(), SlackError> {
+    let response = send(url, "POST", "Content-Type: application/json\r\n", body).await?;
+    if (200..300).contains(&response.status) {
+        Ok(())
+    } else {
+        Err(SlackError::Rejected(response.status_line))
+    }
+}
+
+// An HTTP response, with the body already put back together if it was sent in chunks.
+struct Response {
+    status: u16,
+    status_line: String,
+    // Names in lower case.
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Send a request with `headers`, each ending in CRLF, and read the whole response.
+async fn send(url: &str, method: &str, headers: &str, body: &str) -> Result<Response, SlackError> {
+    let Some(url) = parse_url(url) else {
+        return Err(SlackError::Rejected(format!("invalid URL {url}")));
+    };
+    let stream = TcpStream::connect((url.host, url.port)).await?;
+    let request = format!(
+        "{method} {} HTTP/1.1\r\nHost: {}\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        url.path,
+        url.host,
+        body.len()
+    );
+    let response = if url.tls {
+        exchange(tls::connect(url.host, stream).await?, &request).await?
+    } else {
+        exchange(stream, &request).await?
+    };
+    Ok(parse_response(&response))
+}
+
+fn parse_response(response: &[u8]) -> Response {
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .unwrap_or(response.len());
+    let head = String::from_utf8_lossy(&response[..split]);
+    let body = response.get(split + 4..).unwrap_or_default();
+    let mut lines = head.lines();
+    let status_line = lines.next().unwrap_or_default().to_owned();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0);
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+        .collect();
+    let chunked = headers.iter().any(|(name, value)| {
+        name == "transfer-encoding" && value.to_ascii_lowercase().contains("chunked")
+    });
+    let body = if chunked {
+        String::from_utf8_lossy(&dechunk(body)).into_owned()
+    } else {
+        String::from_utf8_lossy(body).into_owned()
+    };
+    Response {
+        status,
+        status_line,
+        headers,
+        body,
+    }
+}
+
+// Join the chunks of a `Transfer-Encoding: chunked` body, each a hex length line then the data.
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut joined = Vec::new();
+    while let Some(end) = body.windows(2).position(|window| window == b"\r\n") {
+        let line = String::from_utf8_lossy(&body[..end]);
+        let Ok(size) = usize::from_str_radix(line.split(';').next().unwrap_or_default().trim(), 16)
+        else {
+            break;
+        };
+        let rest = &body[end + 2..];
+        if size == 0 || rest.len() < size {
+            break;
+        }
+        joined.extend_from_slice(&rest[..size]);
+        body = rest[size..].strip_prefix(b"\r\n").unwrap_or(&rest[size..]);
+    }
+    joined
+}
+
+// Percent encode everything but unreserved characters, for a value in a query string.
+fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+// Send a request and read the whole response, the server closes the connection when it's done.
+async fn exchange(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    request: &str,
+) -> std::io::Result<Vec<u8>> {
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncBufReadExt, net::TcpListener};
+
+    use super::*;
+
+    #[test]
+    fn chunked_bodies_are_joined() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nRetry-After: 3\r\n\r\n8\r\n{\"a\": \"\xc3\r\n3;x=y\r\n\xa9\"}\r\n0\r\n\r\n";
+        let response = parse_response(response);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("retry-after"), Some("3"));
+        assert_eq!(response.body, "{\"a\": \"é\"}");
+    }
+
+    // Answers each connection with the next response, returning the request lines it was sent.
+    async fn serve(listener: TcpListener, responses: Vec<String>) -> Vec<String> {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut reader = tokio::io::BufReader::new(&mut stream);
+            let mut request = String::new();
+            reader.read_line(&mut request).await.unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                request.push_str(&line);
+            }
+            requests.push(request);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+        requests
+    }
+
+    fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[test]
+    fn members_are_listed_page_by_page() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = tokio::spawn(serve(
+                listener,
+                vec![
+                    ok(r#"{"ok": true, "members": [{"id": "U1", "profile": {"email": "a@example.com", "real_name": "A"}}],
+                          "response_metadata": {"next_cursor": "dXNlcjpVMg=="}}"#),
+                    "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0\r\nContent-Length: 0\r\n\r\n".to_owned(),
+                    ok(r#"{"ok": true, "members": [{"id": "U2", "is_bot": true, "profile": {}}],
+                          "response_metadata": {"next_cursor": ""}}"#),
+                    ok(r#"{"ok": false, "error": "invalid_auth"}"#),
+                ],
+            ));
+            let mut slack = SlackNotifier::new(
+                Some(SlackConfig {
+                    webhook_url: None,
+                    bot_token: Some("xoxb-test".to_owned()),
+                }),
+                "http://localhost:3000",
+            );
+            slack.api_url = format!("http://{address}");
+            let members = slack.list_members().await.unwrap();
+            let ids: Vec<_> = members.iter().map(|m| m.id.as_str()).collect();
+            assert_eq!(ids, ["U1", "U2"]);
+            assert_eq!(members[0].profile.email.as_deref(), Some("a@example.com"));
+            assert!(members[1].is_bot);
+            assert!(matches!(
+                slack.list_members().await,
+                Err(SlackError::Api(error)) if error == "invalid_auth"
+            ));
+
+            let requests = server.await.unwrap();
+            assert!(requests[0].starts_with("GET /users.list?limit=200&cursor= HTTP/1.1"));
+            assert!(requests[0].contains("Authorization: Bearer xoxb-test\r\n"));
+            assert!(requests[1].starts_with("GET /users.list?limit=200&cursor=dXNlcjpVMg%3D%3D "));
+            assert_eq!(requests[1].lines().next(), requests[2].lines().next());
+
+            let unconfigured = SlackNotifier::new(None, "http://localhost:3000");
+            assert!(matches!(
+                unconfigured.list_members().await,
+                Err(SlackError::NoBotToken)
+            ));
+        });
+    }
+}
@@ -0,0 +1,241 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::{
+    db::{erase_person_at, Database, DatabaseError},
+    domain::PersonId,
+};
+
+// Copies of the whole database, taken before admins do something that can't be undone so it can be
+// put back if that was a mistake. Each is a SQLite file in `dir` named
+// `<unix time>.<random>-<reason>.sqlite`, and only the newest `keep` are kept, 0 keeps them all.
+// Deleted accounts are erased from every snapshot too, so restoring one never brings them back.
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    dir: PathBuf,
+    keep: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    // The file name, which is how the snapshot is picked to restore.
+    pub name: String,
+    pub created_at: i64,
+    // What it was taken before, e.g. "roll back generation 3".
+    pub reason: String,
+    pub size: u64,
+}
+
+impl Snapshots {
+    pub fn new(dir: &str, keep: usize) -> Self {
+        std::fs::create_dir_all(dir)
+            .unwrap_or_else(|error| panic!("Failed to create snapshot directory {dir}: {error}"));
+        Self {
+            dir: dir.into(),
+            keep,
+        }
+    }
+
+    // Snapshot the database now, returning the snapshot's name.
+    pub async fn create(&self, db: &Database, reason: &str) -> Result<String, DatabaseError> {
+        let name = self.take(db, reason).await?;
+        self.prune();
+        Ok(name)
+    }
+
+    async fn take(&self, db: &Database, reason: &str) -> Result<String, DatabaseError> {
+        let reason: String = reason
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' => c,
+                'A'..='Z' => c.to_ascii_lowercase(),
+                _ => '-',
+            })
+            .collect();
+        // The random part keeps snapshots taken in the same second apart.
+        let unique = &uuid::Uuid::new_v4().simple().to_string()[..8];
+        let name = format!("{}.{unique}-{reason}.sqlite", db.now().timestamp());
+        let path = self.dir.join(&name);
+        db.call(move |db| db.backup_to(&path)).await?;
+        tracing::info!(snapshot = name, "Took a database snapshot");
+        Ok(name)
+    }
+
+    // Every snapshot, newest first.
+    pub fn list(&self) -> std::io::Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+                continue;
+            };
+            let Some((created_at, reason)) = name
+                .strip_suffix(".sqlite")
+                .and_then(|stem| stem.split_once('-'))
+                .and_then(|(time, reason)| {
+                    // Snapshots from before the random part was added are just the time.
+                    let seconds = time.split_once('.').map_or(time, |(seconds, _)| seconds);
+                    Some((seconds.parse().ok()?, reason.replace('-', " ")))
+                })
+            else {
+                continue;
+            };
+            snapshots.push(Snapshot {
+                size: entry.metadata()?.len(),
+                name,
+                created_at,
+                reason,
+            });
+        }
+        snapshots.sort_by(|a, b| (b.created_at, &b.name).cmp(&(a.created_at, &a.name)));
+        Ok(snapshots)
+    }
+
+    // Replace the database with the snapshot called `name`, returning false when there isn't one.
+    // Only names of listed snapshots are used, so nothing outside `dir` can be read. A snapshot is
+    // taken first so the restore can be undone, old ones are only pruned afterwards so `name` isn't
+    // removed before it's restored.
+    pub async fn restore(&self, db: &Database, name: &str) -> Result<bool, DatabaseError> {
+        let known = match self.list() {
+            Ok(snapshots) => snapshots.iter().any(|snapshot| snapshot.name == name),
+            Err(error) => {
+                tracing::error!(%error, "Failed to list database snapshots");
+                false
+            }
+        };
+        if !known {
+            return Ok(false);
+        }
+        self.take(db, "restore").await?;
+        let path = self.dir.join(name);
+        db.call(move |db| db.restore_from(&path)).await?;
+        tracing::warn!(snapshot = name, "Restored a database snapshot");
+        self.prune();
+        Ok(true)
+    }
+
+    // Erase someone whose account was deleted from every snapshot. A snapshot that can't be changed,
+    // e.g. one from a much older schema, is removed instead.
+    pub async fn erase(&self, person_id: PersonId, now: i64) {
+        let snapshots = self.clone();
+        let erased = tokio::task::spawn_blocking(move || {
+            let list = match snapshots.list() {
+                Ok(list) => list,
+                Err(error) => {
+                    tracing::error!(%error, "Failed to list database snapshots to erase from");
+                    return;
+                }
+            };
+            for snapshot in list {
+                let path = snapshots.dir.join(&snapshot.name);
+                // Migrating an old snapshot panics if it can't be, which shouldn't stop the rest.
+                let error =
+                    match std::panic::catch_unwind(|| erase_person_at(&path, person_id, now)) {
+                        Ok(Ok(())) => continue,
+                        Ok(Err(error)) => error.to_string(),
+                        Err(_) => "migrating it failed".to_owned(),
+                    };
+                tracing::warn!(
+                    snapshot = snapshot.name,
+                    error,
+                    "Failed to erase a deleted account from a snapshot, removing it"
+                );
+                if let Err(error) = std::fs::remove_file(&path) {
+                    tracing::error!(snapshot = snapshot.name, %error, "Failed to remove snapshot");
+                }
+            }
+        })
+        .await;
+        if let Err(error) = erased {
+            tracing::error!(%error, "Erasing a deleted account from snapshots failed");
+        }
+    }
+
+    fn prune(&self) {
+        if self.keep == 0 {
+            return;
+        }
+        let snapshots = match self.list() {
+            Ok(snapshots) => snapshots,
+            Err(error) => {
+                tracing::warn!(%error, "Failed to list database snapshots to prune");
+                return;
+            }
+        };
+        for snapshot in snapshots.into_iter().skip(self.keep) {
+            if let Err(error) = std::fs::remove_file(self.dir.join(&snapshot.name)) {
+                tracing::warn!(snapshot = snapshot.name, %error, "Failed to remove old snapshot");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::Clock, crypto::Cipher, db::LoginLockout, domain::DEFAULT_ORGANISATION};
+
+    fn sign_up(db: &Database, name: &str) -> PersonId {
+        let Ok((person, _)) = db
+            .sign_up_session(
+                name,
+                &format!("{name}@example.com"),
+                "a long password",
+                DEFAULT_ORGANISATION,
+            )
+            .unwrap()
+        else {
+            panic!("sign up failed");
+        };
+        person
+    }
+
+    #[test]
+    fn restores_bring_back_everything_but_deleted_accounts() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let dir = std::env::temp_dir().join(format!(
+                "matcher-snapshots-{}",
+                uuid::Uuid::new_v4().simple()
+            ));
+            let snapshots = Snapshots::new(dir.to_str().unwrap(), 0);
+            let clock = Clock::new(true);
+            let db = Database::init(
+                ":memory:",
+                3600,
+                LoginLockout {
+                    attempts: 5,
+                    seconds: 900,
+                },
+                clock.clone(),
+                Cipher::default(),
+            );
+            let a = sign_up(&db, "a");
+            // Snapshots are named by the app's clock, which tests can move on.
+            let taken_at = clock.advance(365 * 24 * 60 * 60).unwrap().timestamp();
+            let name = snapshots.create(&db, "Before B").await.unwrap();
+            assert!(name.ends_with("-before-b.sqlite"));
+            assert!(snapshots.list().unwrap()[0].created_at >= taken_at);
+            sign_up(&db, "b");
+
+            assert!(!snapshots.restore(&db, "../matcher.sqlite").await.unwrap());
+            assert!(snapshots.restore(&db, &name).await.unwrap());
+            assert_eq!(db.person_with_email("b@example.com").unwrap(), None);
+            assert_eq!(db.person_with_email("a@example.com").unwrap(), Some(a));
+            // Restoring took its own snapshot first.
+            assert_eq!(snapshots.list().unwrap().len(), 2);
+
+            assert!(db.delete_account(a, "a long password").unwrap());
+            snapshots.erase(a, db.now().timestamp()).await;
+            assert!(snapshots.restore(&db, &name).await.unwrap());
+            assert_eq!(db.person_with_email("a@example.com").unwrap(), None);
+            assert_eq!(db.get_person(a).unwrap().unwrap().name, "Deleted account");
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        });
+    }
+}
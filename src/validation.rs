@@ -0,0 +1,356 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+pub const MAX_NAME_LENGTH: usize = 100;
+pub const MAX_EMAIL_LENGTH: usize = 254;
+pub const MAX_STATUS_LENGTH: usize = 40;
+pub const MAX_TEAM_LENGTH: usize = 100;
+pub const MAX_PHONE_LENGTH: usize = 30;
+pub const MAX_HANDLE_LENGTH: usize = 80;
+pub const MAX_URL_LENGTH: usize = 2048;
+pub const MAX_INTEREST_LENGTH: usize = 30;
+pub const MAX_INTERESTS: usize = 20;
+pub const MAX_MENTEE_CAPACITY: u32 = 10;
+pub const MIN_PASSWORD_LENGTH: usize = 10;
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+pub const MAX_POOL_NAME_LENGTH: usize = 40;
+pub const MAX_DESCRIPTION_LENGTH: usize = 200;
+pub const MAX_ICEBREAKER_LENGTH: usize = 280;
+pub const MAX_GENERATION_TITLE_LENGTH: usize = 100;
+pub const MAX_GENERATION_NOTES_LENGTH: usize = 2000;
+pub const MAX_SUBJECT_LENGTH: usize = 150;
+pub const MAX_MESSAGE_LENGTH: usize = 5000;
+pub const MAX_CRON_LENGTH: usize = 100;
+
+#[derive(Debug)]
+pub enum ValidationError {
+    Empty {
+        field: &'static str,
+    },
+    TooShort {
+        field: &'static str,
+        min: usize,
+    },
+    TooLong {
+        field: &'static str,
+        max: usize,
+    },
+    Invalid {
+        field: &'static str,
+        expected: &'static str,
+    },
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::Empty { field } => write!(f, "The {field} can't be empty."),
+            ValidationError::TooShort { field, min } => {
+                write!(f, "The {field} must be at least {min} characters.")
+            }
+            ValidationError::TooLong { field, max } => {
+                write!(f, "The {field} can't be longer than {max} characters.")
+            }
+            ValidationError::Invalid { field, expected } => {
+                write!(f, "The {field} must be {expected}.")
+            }
+        }
+    }
+}
+
+impl ValidationError {
+    pub fn field(&self) -> &'static str {
+        match self {
+            ValidationError::Empty { field }
+            | ValidationError::TooShort { field, .. }
+            | ValidationError::TooLong { field, .. }
+            | ValidationError::Invalid { field, .. } => field,
+        }
+    }
+
+    // A name for what was wrong that doesn't change with the wording of the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ValidationError::Empty { .. } => "empty",
+            ValidationError::TooShort { .. } => "too_short",
+            ValidationError::TooLong { .. } => "too_long",
+            ValidationError::Invalid { .. } => "invalid",
+        }
+    }
+}
+
+// What was wrong with a form field, for frontends that present errors themselves, e.g. next to the
+// field for screen readers, rather than scraping the message from the page. `message` is what the
+// page shows. `field` is a key like `pool_name`, made from the label the messages use.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, code: &'static str, message: &str) -> Self {
+        Self {
+            field: field.to_owned(),
+            code,
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl From<ValidationError> for FieldError {
+    fn from(error: ValidationError) -> Self {
+        Self {
+            field: error.field().to_lowercase().replace(' ', "_"),
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+}
+
+// Clean up a user supplied string before it is stored: control characters are stripped,
+// surrounding whitespace is trimmed and the result must be non-empty and at most `max` characters.
+pub fn clean_text(field: &'static str, input: &str, max: usize) -> Result<String, ValidationError> {
+    let cleaned: String = input.chars().filter(|c| !c.is_control()).collect();
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() {
+        return Err(ValidationError::Empty { field });
+    }
+    if cleaned.chars().count() > max {
+        return Err(ValidationError::TooLong { field, max });
+    }
+    Ok(cleaned.to_owned())
+}
+
+// Interests are given as a comma separated list. Each is cleaned like any other text and lower
+// cased so that the same interest typed differently still matches, duplicates are dropped.
+pub fn clean_interests(input: &str) -> Result<Vec<String>, ValidationError> {
+    let mut interests = Vec::new();
+    for tag in input.split(',').filter(|t| !t.trim().is_empty()) {
+        let tag = clean_text("interest", tag, MAX_INTEREST_LENGTH)?.to_lowercase();
+        if !interests.contains(&tag) {
+            interests.push(tag);
+        }
+    }
+    if interests.len() > MAX_INTERESTS {
+        return Err(ValidationError::Invalid {
+            field: "interests",
+            expected: "at most 20 tags",
+        });
+    }
+    Ok(interests)
+}
+
+// Teams are optional, an empty one means no team.
+pub fn clean_team(input: &str) -> Result<Option<String>, ValidationError> {
+    match clean_text("team", input, MAX_TEAM_LENGTH) {
+        Ok(team) => Ok(Some(team)),
+        Err(ValidationError::Empty { .. }) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+// Like `clean_text` but keeping line breaks, for text such as an email body.
+pub fn clean_multiline_text(
+    field: &'static str,
+    input: &str,
+    max: usize,
+) -> Result<String, ValidationError> {
+    let cleaned: String = input
+        .chars()
+        .filter(|c| *c == '\n' || !c.is_control())
+        .collect();
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() {
+        return Err(ValidationError::Empty { field });
+    }
+    if cleaned.chars().count() > max {
+        return Err(ValidationError::TooLong { field, max });
+    }
+    Ok(cleaned.to_owned())
+}
+
+// Like `clean_text` for fields that can be left out, an empty one is `None`.
+pub fn clean_optional_text(
+    field: &'static str,
+    input: &str,
+    max: usize,
+) -> Result<Option<String>, ValidationError> {
+    match clean_text(field, input, max) {
+        Ok(text) => Ok(Some(text)),
+        Err(ValidationError::Empty { .. }) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+// Pool names are used in URLs and as the round type of the pool's generations, so they're kept to
+// lower case letters, digits and dashes.
+pub fn clean_pool_name(input: &str) -> Result<String, ValidationError> {
+    let cleaned = clean_text("pool name", input, MAX_POOL_NAME_LENGTH)?.to_lowercase();
+    if cleaned
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        Ok(cleaned)
+    } else {
+        Err(ValidationError::Invalid {
+            field: "pool name",
+            expected: "letters, digits and dashes",
+        })
+    }
+}
+
+// Phone numbers are kept as typed, as long as they look like one.
+pub fn clean_phone(input: &str) -> Result<String, ValidationError> {
+    let cleaned = clean_text("phone number", input, MAX_PHONE_LENGTH)?;
+    let valid = cleaned.chars().any(|c| c.is_ascii_digit())
+        && cleaned
+            .chars()
+            .all(|c| c.is_ascii_digit() || " +-().".contains(c));
+    if !valid {
+        return Err(ValidationError::Invalid {
+            field: "phone number",
+            expected: "digits, spaces and + - ( ) only",
+        });
+    }
+    Ok(cleaned)
+}
+
+// Only the shape of an address is checked, sending to it is the real test.
+pub fn clean_email(field: &'static str, input: &str) -> Result<String, ValidationError> {
+    let cleaned = clean_text(field, input, MAX_EMAIL_LENGTH)?;
+    let valid = match cleaned.rsplit_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !cleaned.chars().any(char::is_whitespace)
+        }
+        None => false,
+    };
+    if !valid {
+        return Err(ValidationError::Invalid {
+            field,
+            expected: "an email address",
+        });
+    }
+    Ok(cleaned)
+}
+
+// Passwords are hashed as typed, so nothing is cleaned, but they have to be long enough and not
+// easily guessed from a few repeated characters or from `personal` details like the person's name.
+pub fn check_password(
+    field: &'static str,
+    input: &str,
+    personal: &[&str],
+) -> Result<(), ValidationError> {
+    let length = input.chars().count();
+    if length == 0 {
+        return Err(ValidationError::Empty { field });
+    }
+    if length < MIN_PASSWORD_LENGTH {
+        return Err(ValidationError::TooShort {
+            field,
+            min: MIN_PASSWORD_LENGTH,
+        });
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return Err(ValidationError::TooLong {
+            field,
+            max: MAX_PASSWORD_LENGTH,
+        });
+    }
+    let lower = input.to_lowercase();
+    // Very short details would rule out too many passwords that merely contain them.
+    let personal = personal
+        .iter()
+        .map(|detail| detail.trim().to_lowercase())
+        .any(|detail| detail.chars().count() >= 4 && lower.contains(&detail));
+    let distinct = input.chars().collect::<HashSet<_>>().len();
+    if personal || distinct < 4 {
+        return Err(ValidationError::Invalid {
+            field,
+            expected: "harder to guess than a few repeated characters or your name or email",
+        });
+    }
+    Ok(())
+}
+
+pub fn clean_timestamp(field: &'static str, input: &str) -> Result<DateTime<Utc>, ValidationError> {
+    let cleaned = clean_text(field, input, 40)?;
+    cleaned.parse().map_err(|_| ValidationError::Invalid {
+        field,
+        expected: "an RFC 3339 timestamp such as 2024-01-01T09:00:00Z",
+    })
+}
+
+// Matching more than once a day or less than once a year is almost certainly a typo.
+pub fn clean_every_days(days: i64) -> Result<i64, ValidationError> {
+    if (1..=365).contains(&days) {
+        Ok(days)
+    } else {
+        Err(ValidationError::Invalid {
+            field: "days between runs",
+            expected: "between 1 and 365",
+        })
+    }
+}
+
+// A schedule's cron expression, if there is one. Ones that never match, such as the 30th of
+// February, are refused as the schedule would never run.
+pub fn clean_cron(input: &str) -> Result<Option<String>, ValidationError> {
+    let Some(cleaned) = clean_optional_text("cron expression", input, MAX_CRON_LENGTH)? else {
+        return Ok(None);
+    };
+    let now = Utc::now();
+    match crate::config::next_cron_run(&cleaned, now, now) {
+        Some(_) => Ok(Some(cleaned)),
+        None => Err(ValidationError::Invalid {
+            field: "cron expression",
+            expected: "five fields such as 0 9 * * MON that match at least once",
+        }),
+    }
+}
+
+// Colours are placed directly into the stylesheet so only accept `#rrggbb`.
+pub fn clean_colour(field: &'static str, input: &str) -> Result<String, ValidationError> {
+    let cleaned = clean_text(field, input, 7)?;
+    let valid = cleaned.len() == 7
+        && cleaned.starts_with('#')
+        && cleaned[1..].chars().all(|c| c.is_ascii_hexdigit());
+    if !valid {
+        return Err(ValidationError::Invalid {
+            field,
+            expected: "a colour like #0085f2",
+        });
+    }
+    Ok(cleaned)
+}
+
+// Links are placed in `href`s and `src`s so only accept web URLs, never `javascript:` and friends.
+pub fn clean_url(field: &'static str, input: &str) -> Result<String, ValidationError> {
+    let cleaned = clean_text(field, input, MAX_URL_LENGTH)?;
+    if !(cleaned.starts_with("https://") || cleaned.starts_with("http://")) {
+        return Err(ValidationError::Invalid {
+            field,
+            expected: "a web address starting with https://",
+        });
+    }
+    Ok(cleaned)
+}
+
+// Timezones must be IANA names like `Europe/London` so they can be used to display times.
+pub fn clean_timezone(input: &str) -> Result<String, ValidationError> {
+    let cleaned = clean_text("timezone", input, MAX_NAME_LENGTH)?;
+    match cleaned.parse::<chrono_tz::Tz>() {
+        Ok(timezone) => Ok(timezone.name().to_owned()),
+        Err(_) => Err(ValidationError::Invalid {
+            field: "timezone",
+            expected: "a timezone like Europe/London",
+        }),
+    }
+}
@@ -1,28 +1,193 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
 
 use axum::{
-    extract::{FromRef, FromRequestParts, Path, Query, State},
-    http::{header::SET_COOKIE, request::Parts},
-    response::{AppendHeaders, Html, IntoResponse, Redirect, Response},
-    Form,
+    extract::{ConnectInfo, FromRef, FromRequestParts, Path, Query, Request, State},
+    http::{
+        header::{
+            ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH,
+            CONTENT_TYPE, RETRY_AFTER,
+        },
+        request::Parts,
+        StatusCode,
+    },
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+    Form, Json,
 };
-use axum_extra::extract::{cookie::Cookie, CookieJar};
-use serde::Deserialize;
+use chrono::{DateTime, Datelike, NaiveTime, Offset, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
 use tera::{Context, Tera};
+use tokio::sync::{Mutex, Notify};
+use tracing::Instrument;
 
 use crate::{
-    db::{Database, SignInError},
-    matching::Graph,
+    clock::{AdvanceError, Clock},
+    config::ScheduleConfig,
+    db::{
+        Branding, ContactField, ContactKind, ContactVisibility, Database, DatabaseError,
+        EdgesRebuild, FooterLink, GenerationExport, GenerationSummary, InterruptedStage,
+        ManagersImport, Notification, NotificationStatus, Organisation, PoolExport, PoolImport,
+        PoolImportError, PoolImportOptions, SeasonPlan, Segment, SignInError, SignUpError,
+        StaleAccount, Stats, WaiterSnapshot, WaitingDay, EDGE_WEIGHT_SCALE,
+    },
+    domain::{
+        ConflictGroupId, GenerationDetails, GenerationId, IcebreakerId, Match, MatchMeta,
+        MatchQuality, OrganisationId, PastMatch, Person, PersonId, DEFAULT_ORGANISATION,
+    },
+    error::AppError,
+    hooks::Hooks,
+    mail::{Introduction, MailConfig, MailError, MailTls, Mailer, SendProgress},
+    matching::{
+        round_robin_schedule, splitmix64, BucketAudit, Graph, MatchingAudit, MatchingSettings,
+        MentoringRole, SameTeamPolicy, Strategy,
+    },
+    pages::{
+        ActivatePage, ApiTokenPage, AppPage, BrandingPage, ConflictsPage, DeleteAccountPage,
+        EditPersonPage, ErrorPage, IcebreakersPage, IndexPage, MailingsPage, MatchPage,
+        MatchPartner, MatchesHistoryPage, MatchesPage, NotificationsPage, OptInPage, OwnPerson,
+        Page, PartnerWeightsPage, PeoplePage, PersonPage, PoolPage, PoolsPage, PublicStatsPage,
+        SchedulePage, SegmentSize, ServiceWorker, SetupPage, SignInPage, SignUpPage, SnapshotsPage,
+        UnsubscribePage,
+    },
+    privacy::Viewer,
+    rate_limit::RateLimiter,
+    session::SessionCookies,
+    slack::{SlackError, SlackNotifier},
+    snapshot::Snapshots,
+    validation::{
+        check_password, clean_colour, clean_cron, clean_email, clean_every_days, clean_interests,
+        clean_multiline_text, clean_optional_text, clean_phone, clean_pool_name, clean_team,
+        clean_text, clean_timestamp, clean_timezone, clean_url, FieldError, ValidationError,
+        MAX_DESCRIPTION_LENGTH, MAX_EMAIL_LENGTH, MAX_GENERATION_NOTES_LENGTH,
+        MAX_GENERATION_TITLE_LENGTH, MAX_HANDLE_LENGTH, MAX_ICEBREAKER_LENGTH, MAX_MENTEE_CAPACITY,
+        MAX_MESSAGE_LENGTH, MAX_NAME_LENGTH, MAX_STATUS_LENGTH, MAX_SUBJECT_LENGTH, MAX_URL_LENGTH,
+    },
 };
 
-pub fn session_id_cookie(session_id: &str) -> String {
-    Cookie::new("session_id", session_id).to_string()
+// Answer requests that failed with an `AppError` with the error page, keeping the error's status
+// and headers. The request runs as its own task so a panic is answered the same way rather than
+// dropping the connection.
+pub async fn error_pages(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let handled = tokio::spawn(next.run(request).in_current_span()).await;
+    let response = match handled {
+        Ok(response) => response,
+        Err(error) => {
+            tracing::error!(%error, "Request handler panicked");
+            AppError::Internal.into_response()
+        }
+    };
+    let Some(error) = response.extensions().get::<AppError>().copied() else {
+        // Handlers can give a page their own status, e.g. a 404, but one that failed to render is
+        // always an error.
+        let mut response = response;
+        if response.extensions().get::<RenderFailed>().is_some() {
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+        return response;
+    };
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(CONTENT_TYPE);
+    parts.headers.remove(CONTENT_LENGTH);
+    let page = ErrorPage {
+        authorized_person_id: None,
+        error: error.to_string(),
+    };
+    (parts, state.render_page(&page).await).into_response()
+}
+
+// The field errors a form page was rendered with, for `form_errors`.
+#[derive(Debug, Clone)]
+struct FormErrors(Vec<FieldError>);
+
+// Render a form again with what was wrong with it. People see the page with its message, requests
+// asking for JSON get the error from `form_errors` instead.
+fn invalid_form(page: impl IntoResponse, error: impl Into<FieldError>) -> Response {
+    let mut response = page.into_response();
+    response
+        .extensions_mut()
+        .insert(FormErrors(vec![error.into()]));
+    response
+}
+
+fn accepts_json(headers: &axum::http::HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("application/json"))
+}
+
+#[derive(Debug, Serialize)]
+struct ApiFieldErrors {
+    errors: Vec<FieldError>,
+}
+
+// Form posts that accept JSON, e.g. from the app or an assistive frontend, get their errors as
+// `{"errors": [{"field", "code", "message"}]}` with a 422 rather than the page with the message in.
+pub async fn form_errors(request: Request, next: Next) -> Response {
+    let wants_json = accepts_json(request.headers());
+    let response = next.run(request).await;
+    if !wants_json {
+        return response;
+    }
+    match response.extensions().get::<FormErrors>() {
+        Some(FormErrors(errors)) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ApiFieldErrors {
+                errors: errors.clone(),
+            }),
+        )
+            .into_response(),
+        None => response,
+    }
+}
+
+// Limit how often each address can try to sign in or up, to slow down password guessing.
+pub async fn limit_auth(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = &state.auth_limiter else {
+        return next.run(request).await;
+    };
+    if request.method() != axum::http::Method::POST {
+        return next.run(request).await;
+    }
+    // The last entry is the one added by our own proxy, earlier ones can be made up by the client.
+    let forwarded = state
+        .trust_forwarded_for
+        .then(|| request.headers().get("x-forwarded-for"))
+        .flatten()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|address| address.trim().parse::<IpAddr>().ok());
+    let address = forwarded.unwrap_or(peer.ip());
+    if let Err(retry_after) = limiter.check(address, Instant::now()) {
+        tracing::warn!(%address, path = request.uri().path(), "Rate limited authentication");
+        let page = ErrorPage {
+            authorized_person_id: None,
+            error: "Too many attempts to sign in from your network, please wait a minute and try again.".to_owned(),
+        };
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, (retry_after.as_secs() + 1).to_string())],
+            state.render_page(&page).await,
+        )
+            .into_response();
+    }
+    next.run(request).await
 }
 
 // An extractor that performs authorization.
 pub struct Authorized {
     session_id: String,
-    person_id: u32,
+    person_id: PersonId,
 }
 
 #[async_trait::async_trait]
@@ -34,100 +199,661 @@ where
     type Rejection = Response;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let cookies = CookieJar::from_request_parts(parts, state).await.unwrap();
-
         let state = AppState::from_ref(state);
 
-        let Some(session_id) = cookies.get("session_id").and_then(|c| {
-            let v = c.value();
-            if v.is_empty() {
-                None
-            } else {
-                Some(v.to_owned())
-            }
-        }) else {
-            let mut context = Context::default();
-            context.insert("error", "It seems like you aren't signed in. Please either sign up to this Matcher, or sign in.");
-            let error_page = Html(state.tera.render("error.html", &context).unwrap());
-            return Err(error_page.into_response());
+        let Some(session_id) = state.sessions.session_id(&parts.headers) else {
+            let page = ErrorPage {
+                authorized_person_id: None,
+                error: "It seems like you aren't signed in. Please either sign up to this Matcher, or sign in.".to_owned(),
+            };
+            return Err(state.render_page(&page).await.into_response());
         };
 
-        let now = chrono::offset::Utc::now().timestamp();
-        match state.db.get_session(&session_id, now) {
-            Some(person_id) => Ok(Self {
-                session_id,
-                person_id,
-            }),
+        let now = state.clock.timestamp();
+        let session = session_id.clone();
+        let person_id = state
+            .db
+            .call(move |db| db.get_session(&session, now))
+            .await
+            .map_err(|error| AppError::from(error).into_response())?;
+        match person_id {
+            Some(person_id) => {
+                tracing::Span::current().record("person_id", person_id.0);
+                Ok(Self {
+                    session_id,
+                    person_id,
+                })
+            }
             _ => {
-                let mut context = Context::default();
-                context.insert(
-                    "error",
-                    "Failed to find your session, please try logging in again.",
+                let page = ErrorPage {
+                    authorized_person_id: None,
+                    error: "Failed to find your session, please try logging in again.".to_owned(),
+                };
+                return Err(
+                    (state.sessions.removal(), state.render_page(&page).await).into_response()
                 );
-                let error_page = Html(state.tera.render("error.html", &context).unwrap());
-                return Err((
-                    AppendHeaders([(SET_COOKIE, session_id_cookie(""))]),
-                    error_page.into_response(),
-                )
-                    .into_response());
             }
         }
     }
 }
 
+// An extractor that only lets through people who have been appointed as admins of the default
+// organisation, who run the deployment.
+pub struct Admin {
+    person_id: PersonId,
+}
+
+#[async_trait::async_trait]
+impl<S> FromRequestParts<S> for Admin
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let authorized = Authorized::from_request_parts(parts, state).await?;
+        let state = AppState::from_ref(state);
+        let person_id = authorized.person_id;
+        // Admins of other organisations only manage their own people.
+        let is_admin = state
+            .db
+            .call(move |db| db.is_deployment_admin(person_id))
+            .await
+            .map_err(|error| AppError::from(error).into_response())?;
+        if is_admin {
+            Ok(Self {
+                person_id: authorized.person_id,
+            })
+        } else {
+            let page = ErrorPage {
+                authorized_person_id: Some(authorized.person_id),
+                error: "Only admins can do that.".to_owned(),
+            };
+            Err((StatusCode::FORBIDDEN, state.render_page(&page).await).into_response())
+        }
+    }
+}
+
+// An extractor for people who run at least one pool, either appointed to it or as a deployment
+// admin who runs them all. Handlers still check the pool with `manages`.
+pub struct PoolAdmin {
+    person_id: PersonId,
+    // The pools they were appointed to, unset for deployment admins.
+    pools: Option<Vec<String>>,
+}
+
+impl PoolAdmin {
+    // Whether they can run the round type, pool admins only run their own pools.
+    fn manages(&self, round_type: &str) -> bool {
+        match &self.pools {
+            Some(pools) => pools.iter().any(|pool| pool == round_type),
+            None => true,
+        }
+    }
+
+    fn is_admin(&self) -> bool {
+        self.pools.is_none()
+    }
+}
+
+// The pools someone runs, unset when they run the deployment and so every pool.
+async fn administered_pools(
+    state: &AppState,
+    person_id: PersonId,
+) -> Result<Option<Vec<String>>, AppError> {
+    Ok(state
+        .db
+        .call(move |db| {
+            if db.is_deployment_admin(person_id)? {
+                return Ok(None);
+            }
+            Ok(Some(db.administered_pools(person_id)?))
+        })
+        .await?)
+}
+
+#[async_trait::async_trait]
+impl<S> FromRequestParts<S> for PoolAdmin
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let authorized = Authorized::from_request_parts(parts, state).await?;
+        let state = AppState::from_ref(state);
+        let pools = administered_pools(&state, authorized.person_id)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        if pools.as_ref().is_some_and(Vec::is_empty) {
+            let page = ErrorPage {
+                authorized_person_id: Some(authorized.person_id),
+                error: "Only admins can do that.".to_owned(),
+            };
+            return Err((StatusCode::FORBIDDEN, state.render_page(&page).await).into_response());
+        }
+        Ok(Self {
+            person_id: authorized.person_id,
+            pools,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
     pub tera: Tera,
     pub db: Database,
+    pub sessions: SessionCookies,
+    // Limits sign in and up attempts per address, unset when there's no limit.
+    pub auth_limiter: Option<Arc<RateLimiter<IpAddr>>>,
+    // Take the client's address from `X-Forwarded-For`, when behind a proxy.
+    pub trust_forwarded_for: bool,
+    pub matching: MatchingSettings,
+    // The kinds of round that can be run, the first is the one run on schedule. Never empty.
+    pub round_types: Vec<String>,
+    pub mentoring_round_types: Vec<String>,
+    pub group_sizes: HashMap<String, usize>,
+    // How many people nobody in a group has met to suggest in their introduction.
+    pub suggestions: u32,
+    pub mailer: Mailer,
+    pub slack: SlackNotifier,
+    pub hooks: Hooks,
+    // The schedule from the config, used until an admin saves one.
+    pub default_schedule: Option<ScheduleConfig>,
+    // Wakes the scheduler when the schedule is changed.
+    pub schedule_changed: Arc<Notify>,
+    // Held while a round of matching runs.
+    pub matching_lock: Arc<Mutex<()>>,
+    // Matching is refused this soon after the last generation, to catch duplicate triggers.
+    pub min_run_interval_seconds: i64,
+    // Held for reading by each generation's notifications while they send, so shutdown and
+    // snapshot restores can wait for them.
+    pub notifying: Arc<tokio::sync::RwLock<()>>,
+    // Generations found unfinished at startup that an admin hasn't yet completed or rolled back.
+    pub interrupted: Arc<std::sync::Mutex<HashSet<GenerationId>>>,
+    // Taken before destructive admin actions.
+    pub snapshots: Snapshots,
+    // Whether `/stats` is shown on the public port.
+    pub public_stats: bool,
+    // The language pages are written in, as a BCP 47 tag.
+    pub locale: String,
+    pub clock: Clock,
+}
+
+impl AppState {
+    // The settings for a run of the round type, which may be mentoring or in bigger groups.
+    pub fn matching_settings(&self, round_type: &str) -> MatchingSettings {
+        MatchingSettings {
+            mentoring: self.mentoring_round_types.iter().any(|r| r == round_type),
+            group_size: self.group_sizes.get(round_type).copied().unwrap_or(2),
+            ..self.matching
+        }
+    }
+    pub async fn render_page<P: Page>(&self, page: &P) -> RenderedPage {
+        // Pages are structs, which serialise to maps.
+        let context = Context::from_serialize(page).expect("Failed to serialise page");
+        self.render(P::TEMPLATE, &context).await
+    }
+
+    // Render a page with the deployment's branding and locale available to every template, and
+    // times shown in the signed in person's timezone.
+    async fn render(&self, template: &str, context: &Context) -> RenderedPage {
+        let mut context = context.clone();
+        let person_id = context
+            .get("authorized_person_id")
+            .and_then(|id| id.as_u64())
+            .map(|id| PersonId(id as u32));
+        // Pages still render without these, e.g. the error page for a busy database.
+        let (branding, timezone) = self
+            .db
+            .call(move |db| {
+                let timezone = match person_id {
+                    Some(id) => db.timezone(id)?,
+                    None => None,
+                };
+                Ok((db.branding()?, timezone))
+            })
+            .await
+            .unwrap_or_default();
+        context.insert("branding", &branding);
+        context.insert("timezone", &timezone.unwrap_or_else(|| "UTC".to_owned()));
+        context.insert("locale", &self.locale);
+        match self.tera.render(template, &context) {
+            Ok(html) => RenderedPage {
+                html,
+                failed: false,
+            },
+            Err(error) => {
+                // Tera's own message only names the template, the causes say what went wrong.
+                let mut message = error.to_string();
+                let mut source = std::error::Error::source(&error);
+                while let Some(cause) = source {
+                    message.push_str(&format!(": {cause}"));
+                    source = cause.source();
+                }
+                tracing::error!(template, error = %message, "Failed to render page");
+                self.hooks.render_failed(template, &message);
+                let is_admin = match person_id {
+                    Some(id) => self
+                        .db
+                        .call(move |db| db.is_admin(id))
+                        .await
+                        .unwrap_or(false),
+                    None => false,
+                };
+                RenderedPage {
+                    html: render_failed_page(template, &message, is_admin),
+                    failed: true,
+                }
+            }
+        }
+    }
+}
+
+// A rendered page, or the fallback from `render_failed_page` if rendering it failed, which is
+// answered with a 500 whatever status the handler gave it.
+pub struct RenderedPage {
+    html: String,
+    failed: bool,
 }
 
-pub async fn root(State(state): State<AppState>, authorized: Option<Authorized>) -> Html<String> {
-    let mut context = Context::default();
-    if let Some(authorized) = authorized {
-        context.insert("authorized_person_id", &authorized.person_id);
+// Marks responses whose page failed to render, for `error_pages`.
+#[derive(Debug, Clone, Copy)]
+struct RenderFailed;
+
+impl IntoResponse for RenderedPage {
+    fn into_response(self) -> Response {
+        let mut response = Html(self.html).into_response();
+        if self.failed {
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response.extensions_mut().insert(RenderFailed);
+        }
+        response
     }
-    Html(state.tera.render("index.html", &context).unwrap())
 }
 
-pub async fn sign_in(State(state): State<AppState>) -> Html<String> {
-    Html(
-        state
-            .tera
-            .render("sign_in.html", &Context::default())
-            .unwrap(),
+// A page that doesn't depend on any templates, for when rendering one failed. Only admins are shown
+// the details as they can include data from the page.
+fn render_failed_page(template: &str, message: &str, is_admin: bool) -> String {
+    let details = if is_admin {
+        format!(
+            "<p>Rendering <code>{}</code> failed:</p><pre>{}</pre>",
+            tera::escape_html(template),
+            tera::escape_html(message)
+        )
+    } else {
+        String::new()
+    };
+    format!(
+        "<!doctype html><html><head><title>Something went wrong</title></head><body>\
+         <h1>Something went wrong</h1><p>This page couldn't be shown, please try again later.</p>\
+         {details}<a href=\"/\">Home</a></body></html>"
+    )
+}
+
+// A new install has nothing to show yet, so starts with the setup instead.
+pub async fn root(
+    State(state): State<AppState>,
+    authorized: Option<Authorized>,
+) -> Result<Response, AppError> {
+    if state.db.call(|db| db.needs_setup()).await? {
+        return Ok(Redirect::to("/setup").into_response());
+    }
+    let page = IndexPage {
+        authorized_person_id: authorized.map(|authorized| authorized.person_id),
+    };
+    Ok(state.render_page(&page).await.into_response())
+}
+
+// The first step of the setup, only shown until the first account exists.
+pub async fn setup(State(state): State<AppState>) -> Result<Response, AppError> {
+    if !state.db.call(|db| db.needs_setup()).await? {
+        return Ok(Redirect::to("/").into_response());
+    }
+    let page = SetupPage {
+        step: "account",
+        ..Default::default()
+    };
+    Ok(state.render_page(&page).await.into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetupAccountForm {
+    name: String,
+    email: String,
+    password: String,
+    organisation: String,
+}
+
+pub async fn do_setup(
+    State(state): State<AppState>,
+    Form(form): Form<SetupAccountForm>,
+) -> Result<Response, AppError> {
+    let cleaned = clean_email("email", &form.email).and_then(|email| {
+        let name = clean_text("name", &form.name, MAX_NAME_LENGTH)?;
+        let organisation = clean_text("organisation name", &form.organisation, MAX_NAME_LENGTH)?;
+        let (local, _) = email.rsplit_once('@').unwrap_or_default();
+        check_password("password", &form.password, &[&name, &email, local])?;
+        Ok((name, email, organisation))
+    });
+    let (name, email, organisation) = match cleaned {
+        Ok(cleaned) => cleaned,
+        Err(error) => {
+            let page = SetupPage {
+                step: "account",
+                error: Some(error.to_string()),
+                name: Some(form.name),
+                email: Some(form.email),
+                organisation: Some(form.organisation),
+                ..Default::default()
+            };
+            return Ok(invalid_form(state.render_page(&page).await, error));
+        }
+    };
+    let password = form.password;
+    let set_up = state
+        .db
+        .call(move |db| db.set_up(&name, &email, &password, &organisation))
+        .await?;
+    // Someone else finished the first step first, they are the admin.
+    let Some((person_id, session_id)) = set_up else {
+        return Ok(Redirect::to("/sign_in").into_response());
+    };
+    tracing::info!(admin = person_id.0, "First admin set up");
+    Ok((
+        state.sessions.cookie(&session_id),
+        Redirect::to("/setup/email"),
     )
+        .into_response())
+}
+
+pub async fn setup_email(
+    State(state): State<AppState>,
+    admin: Admin,
+) -> Result<RenderedPage, AppError> {
+    let saved = state.db.call(|db| db.mail_settings()).await?;
+    let page = SetupPage {
+        authorized_person_id: Some(admin.person_id),
+        step: "email",
+        host: saved.as_ref().map(|saved| saved.host.clone()),
+        port: saved.as_ref().map(|saved| saved.port),
+        username: saved.as_ref().and_then(|saved| saved.username.clone()),
+        tls: saved.as_ref().map(|saved| saved.tls.as_str().to_owned()),
+        from: saved.and_then(|saved| saved.from),
+        ..Default::default()
+    };
+    Ok(state.render_page(&page).await)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetupEmailForm {
+    host: String,
+    port: u16,
+    #[serde(default)]
+    username: String,
+    // Left empty to keep the saved password.
+    #[serde(default)]
+    password: String,
+    tls: String,
+    #[serde(default)]
+    from: String,
+}
+
+pub async fn set_up_email(
+    State(state): State<AppState>,
+    admin: Admin,
+    Form(form): Form<SetupEmailForm>,
+) -> Result<Response, AppError> {
+    let cleaned = clean_text("SMTP host", &form.host, MAX_URL_LENGTH).and_then(|host| {
+        let from = match form.from.trim() {
+            "" => None,
+            from => Some(clean_email("from address", from)?),
+        };
+        let username = clean_optional_text("SMTP username", &form.username, MAX_EMAIL_LENGTH)?;
+        let tls = form.tls.parse().map_err(|_| ValidationError::Invalid {
+            field: "TLS",
+            expected: "STARTTLS, implicit TLS or none",
+        })?;
+        // Credentials are only ever sent over TLS.
+        if tls == MailTls::None && username.is_some() {
+            return Err(ValidationError::Invalid {
+                field: "TLS",
+                expected: "STARTTLS or implicit TLS to sign in with a username",
+            });
+        }
+        Ok(MailConfig {
+            host,
+            port: form.port,
+            username,
+            password: (!form.password.is_empty()).then(|| form.password.clone()),
+            tls,
+            from,
+            ..MailConfig::default()
+        })
+    });
+    let mut settings = match cleaned {
+        Ok(settings) => settings,
+        Err(error) => {
+            let page = SetupPage {
+                authorized_person_id: Some(admin.person_id),
+                step: "email",
+                error: Some(error.to_string()),
+                host: Some(form.host),
+                port: Some(form.port),
+                username: Some(form.username),
+                tls: Some(form.tls),
+                from: Some(form.from),
+                ..Default::default()
+            };
+            return Ok(invalid_form(state.render_page(&page).await, error));
+        }
+    };
+    let settings = state
+        .db
+        .call(move |db| {
+            if settings.password.is_none() {
+                settings.password = db.mail_settings()?.and_then(|saved| saved.password);
+            }
+            db.set_mail_settings(&settings)?;
+            Ok(settings)
+        })
+        .await?;
+    tracing::info!(
+        admin = admin.person_id.0,
+        host = settings.host,
+        "Mail settings updated"
+    );
+    state.mailer.set_config(settings);
+    Ok(Redirect::to("/setup/schedule").into_response())
+}
+
+pub async fn setup_schedule(
+    State(state): State<AppState>,
+    admin: Admin,
+) -> Result<RenderedPage, AppError> {
+    let schedule = current_schedule(&state).await?;
+    let page = SetupPage {
+        authorized_person_id: Some(admin.person_id),
+        step: "schedule",
+        start: schedule
+            .as_ref()
+            .map(|schedule| schedule.start.to_rfc3339()),
+        every_days: Some(schedule.as_ref().map_or(7, |schedule| schedule.every_days)),
+        cron: schedule.as_ref().and_then(|schedule| schedule.cron.clone()),
+        min_waiters: Some(schedule.map_or(0, |schedule| schedule.min_waiters)),
+        ..Default::default()
+    };
+    Ok(state.render_page(&page).await)
+}
+
+// Choosing a schedule during setup turns it on, it can be paused later from /schedule.
+pub async fn set_up_schedule(
+    State(state): State<AppState>,
+    admin: Admin,
+    Form(form): Form<ScheduleForm>,
+) -> Result<Response, AppError> {
+    let schedule = clean_timestamp("start", &form.start).and_then(|start| {
+        Ok(ScheduleConfig {
+            start,
+            every_days: clean_every_days(form.every_days)?,
+            cron: clean_cron(&form.cron)?,
+            min_waiters: form.min_waiters,
+            enabled: true,
+        })
+    });
+    match schedule {
+        Ok(schedule) => {
+            tracing::info!(admin = admin.person_id.0, "Schedule updated");
+            save_schedule(&state, schedule).await?;
+            Ok(Redirect::to("/matches").into_response())
+        }
+        Err(error) => {
+            let page = SetupPage {
+                authorized_person_id: Some(admin.person_id),
+                step: "schedule",
+                error: Some(error.to_string()),
+                start: Some(form.start),
+                every_days: Some(form.every_days),
+                cron: Some(form.cron),
+                min_waiters: Some(form.min_waiters),
+                ..Default::default()
+            };
+            Ok(invalid_form(state.render_page(&page).await, error))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignInQuery {
+    email: Option<String>,
+    // Set when sent here from signing up with an email that already has an account.
+    #[serde(default)]
+    existing: bool,
+}
+
+pub async fn sign_in(
+    State(state): State<AppState>,
+    Query(sign_in_query): Query<SignInQuery>,
+) -> RenderedPage {
+    let page = SignInPage {
+        email: sign_in_query.email,
+        error: sign_in_query
+            .existing
+            .then(|| "That email is already signed up, please sign in instead.".to_owned()),
+    };
+    state.render_page(&page).await
+}
+
+// Percent-encode a value for a query string, everything but unreserved characters.
+fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
 }
 
 #[derive(Clone, Deserialize)]
 pub struct SignIn {
     email: String,
     password: String,
+    // Filled in by the browser, see `timezone_field.html`.
+    #[serde(default)]
+    timezone: String,
 }
 
 pub async fn do_sign_in(State(state): State<AppState>, Form(user): Form<SignIn>) -> Response {
-    match state.db.sign_in_session(&user.email, &user.password) {
+    let (email, password) = (user.email.clone(), user.password.clone());
+    let timezone = clean_timezone(&user.timezone).ok();
+    let signed_in = state
+        .db
+        .call_retrying(move |db| {
+            let session_id = match db.sign_in_session(&email, &password)? {
+                Ok(session_id) => session_id,
+                Err(error) => return Ok(Err(error)),
+            };
+            let now = db.now().timestamp();
+            if let (Some(person_id), Some(timezone)) =
+                (db.get_session(&session_id, now)?, &timezone)
+            {
+                db.set_default_timezone(person_id, timezone)?;
+            }
+            Ok(Ok(session_id))
+        })
+        .await;
+    // Showing the form again keeps what was typed in, ready to try again.
+    let signed_in = match signed_in {
+        Ok(signed_in) => signed_in,
+        Err(DatabaseError::Busy) => {
+            let page = SignInPage {
+                error: Some(DatabaseError::Busy.to_string()),
+                ..Default::default()
+            };
+            let page = state.render_page(&page).await;
+            return (StatusCode::SERVICE_UNAVAILABLE, page).into_response();
+        }
+        Err(error) => return AppError::from(error).into_response(),
+    };
+    match signed_in {
         Ok(session_id) => {
-            let headers = AppendHeaders([(SET_COOKIE, session_id_cookie(&session_id))]);
+            let headers = state.sessions.cookie(&session_id);
             (headers, Redirect::to("/")).into_response()
         }
-        Err(SignInError::UnknownUser) => {
-            Redirect::to(&format!("/sign_up?email={}", user.email)).into_response()
-        }
+        Err(SignInError::UnknownUser) => Redirect::to(&format!(
+            "/sign_up?email={}",
+            encode_query_value(&user.email)
+        ))
+        .into_response(),
         Err(SignInError::InvalidPassword) => {
-            let mut context = Context::default();
-            context.insert(
-                "error",
-                "Failed to sign you in, please check your password.",
-            );
-            let error_page = Html(state.tera.render("sign_in.html", &context).unwrap());
-            error_page.into_response()
+            let page = SignInPage {
+                error: Some("Failed to sign you in, please check your password.".to_owned()),
+                ..Default::default()
+            };
+            state.render_page(&page).await.into_response()
+        }
+        Err(SignInError::LockedOut) => {
+            tracing::warn!(email = user.email, "Sign in refused for a locked account");
+            let page = SignInPage {
+                error: Some(
+                    "Too many wrong passwords for this account, please try again later.".to_owned(),
+                ),
+                ..Default::default()
+            };
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                state.render_page(&page).await,
+            )
+                .into_response()
+        }
+        Err(SignInError::Deactivated) => {
+            let page = SignInPage {
+                error: Some(
+                    "This account has been deactivated, ask an admin to reactivate it.".to_owned(),
+                ),
+                ..Default::default()
+            };
+            state.render_page(&page).await.into_response()
         }
     }
 }
 
-pub async fn sign_out(State(state): State<AppState>, authorized: Authorized) -> Redirect {
-    state.db.sign_out_session(&authorized.session_id);
-    Redirect::to("/")
+pub async fn sign_out(
+    State(state): State<AppState>,
+    authorized: Authorized,
+) -> Result<Response, AppError> {
+    state
+        .db
+        .call(move |db| db.sign_out_session(&authorized.session_id))
+        .await?;
+    let headers = state.sessions.removal();
+    Ok((headers, Redirect::to("/")).into_response())
 }
 
 #[derive(Debug, Deserialize)]
@@ -135,18 +861,202 @@ pub struct SignUp {
     email: String,
     password: String,
     name: String,
+    #[serde(default)]
+    team: String,
+    #[serde(default)]
+    timezone: String,
+    // From an organisation's sign up link, otherwise people join the default organisation.
+    #[serde(default)]
+    organisation_token: String,
 }
 
-pub async fn do_sign_up(State(state): State<AppState>, Form(sign_up): Form<SignUp>) -> Response {
-    let (user_id, session_id) =
-        state
-            .db
-            .sign_up_session(&sign_up.name, &sign_up.email, &sign_up.password);
-    (
-        AppendHeaders([(SET_COOKIE, session_id_cookie(&session_id))]),
+impl SignUp {
+    // The form again, keeping what was typed in, ready to try again.
+    fn page(&self, error: String, organisation: Option<&Organisation>) -> SignUpPage {
+        SignUpPage {
+            email: Some(self.email.clone()),
+            name: Some(self.name.clone()),
+            team: Some(self.team.clone()),
+            error: Some(error),
+            organisation: organisation.map(|o| o.name.clone()),
+            organisation_token: organisation.map(|o| o.sign_up_token.clone()),
+        }
+    }
+}
+
+pub async fn do_sign_up(
+    State(state): State<AppState>,
+    Form(sign_up): Form<SignUp>,
+) -> Result<Response, AppError> {
+    let cleaned = clean_email("email", &sign_up.email).and_then(|email| {
+        let name = clean_text("name", &sign_up.name, MAX_NAME_LENGTH)?;
+        let (local, _) = email.rsplit_once('@').unwrap_or_default();
+        check_password("password", &sign_up.password, &[&name, &email, local])?;
+        Ok((name, email, clean_team(&sign_up.team)?))
+    });
+    let organisation = match sign_up.organisation_token.trim() {
+        "" => None,
+        token => {
+            let token = token.to_owned();
+            let found = state
+                .db
+                .call(move |db| db.organisation_by_token(&token))
+                .await?;
+            Some(found.ok_or(AppError::NotFound)?)
+        }
+    };
+    let (name, email, team) = match cleaned {
+        Ok(cleaned) => cleaned,
+        Err(error) => {
+            let page = sign_up.page(error.to_string(), organisation.as_ref());
+            return Ok(invalid_form(state.render_page(&page).await, error));
+        }
+    };
+    let organisation_id = organisation
+        .as_ref()
+        .map_or(DEFAULT_ORGANISATION, |organisation| organisation.id);
+    let password = sign_up.password.clone();
+    let timezone = clean_timezone(&sign_up.timezone).ok();
+    let (to, to_name) = (email.clone(), name.clone());
+    // Signing up again signs in to the account made the first time, so it's safe to retry.
+    let signed_up = state
+        .db
+        .call_retrying(move |db| {
+            let (user_id, session_id) =
+                match db.sign_up_session(&name, &email, &password, organisation_id)? {
+                    Ok(signed_up) => signed_up,
+                    Err(error) => return Ok(Err(error)),
+                };
+            if let Some(timezone) = &timezone {
+                db.set_default_timezone(user_id, timezone)?;
+            }
+            if team.is_some() {
+                db.set_team(user_id, team.as_deref())?;
+            }
+            Ok(Ok((user_id, session_id, db.get_person(user_id)?)))
+        })
+        .await;
+    let (user_id, session_id, person) = match signed_up {
+        Ok(Ok(signed_up)) => signed_up,
+        Err(DatabaseError::Busy) => {
+            let page = state
+                .render_page(&sign_up.page(DatabaseError::Busy.to_string(), organisation.as_ref()))
+                .await;
+            return Ok((StatusCode::SERVICE_UNAVAILABLE, page).into_response());
+        }
+        Err(error) => return Err(error.into()),
+        Ok(Err(SignUpError::EmailTaken)) => {
+            return Ok(Redirect::to(&format!(
+                "/sign_in?existing=true&email={}",
+                encode_query_value(sign_up.email.trim())
+            ))
+            .into_response());
+        }
+        Ok(Err(SignUpError::Imported { token })) => {
+            return send_activation(
+                &state,
+                &sign_up,
+                organisation.as_ref(),
+                &to,
+                &to_name,
+                &token,
+            )
+            .await;
+        }
+        Ok(Err(SignUpError::LockedOut)) => {
+            tracing::warn!(
+                email = sign_up.email,
+                "Sign up refused for a locked account"
+            );
+            let page = sign_up.page(
+                "Too many wrong passwords for this account, please try again later.".to_owned(),
+                organisation.as_ref(),
+            );
+            let page = state.render_page(&page).await;
+            return Ok((StatusCode::TOO_MANY_REQUESTS, page).into_response());
+        }
+    };
+    if let Some(person) = person {
+        state.hooks.person_signed_up(&person);
+    }
+    Ok((
+        state.sessions.cookie(&session_id),
         Redirect::to(&format!("/person/{}", user_id)),
     )
-        .into_response()
+        .into_response())
+}
+
+// Email an imported person the link to finish signing up with, as nobody can take over the account
+// without showing they can read its email.
+async fn send_activation(
+    state: &AppState,
+    sign_up: &SignUp,
+    organisation: Option<&Organisation>,
+    to: &str,
+    name: &str,
+    token: &str,
+) -> Result<Response, AppError> {
+    let error = if state.mailer.is_configured() {
+        let branding = state.db.call(|db| db.branding()).await?;
+        match state
+            .mailer
+            .activate_account(to, name, token, &branding)
+            .await
+        {
+            Ok(()) => {
+                let page = ActivatePage { token: None };
+                return Ok(state.render_page(&page).await.into_response());
+            }
+            Err(error) => {
+                tracing::warn!(%to, %error, "Failed to send account activation email");
+                "We couldn't send an email to that address, please try again later."
+            }
+        }
+    } else {
+        "An admin has already added this email, ask them to set up email so you can finish signing up."
+    };
+    let page = state
+        .render_page(&sign_up.page(error.to_owned(), organisation))
+        .await;
+    Ok((StatusCode::SERVICE_UNAVAILABLE, page).into_response())
+}
+
+pub async fn activate(State(state): State<AppState>, Path(token): Path<String>) -> RenderedPage {
+    let page = ActivatePage { token: Some(token) };
+    state.render_page(&page).await
+}
+
+// Give an imported account the name and password it was signed up with, and sign in to it.
+pub async fn do_activate(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Response, AppError> {
+    let now = state.clock.timestamp();
+    let activated = state
+        .db
+        .call(move |db| {
+            let Some((person_id, session_id)) = db.activate_account(&token, now)? else {
+                return Ok(None);
+            };
+            Ok(Some((person_id, session_id, db.get_person(person_id)?)))
+        })
+        .await?;
+    let Some((person_id, session_id, person)) = activated else {
+        let page = ErrorPage {
+            authorized_person_id: None,
+            error: "That link has expired or has already been used, please sign up again."
+                .to_owned(),
+        };
+        return Ok((StatusCode::NOT_FOUND, state.render_page(&page).await).into_response());
+    };
+    if let Some(person) = person {
+        state.hooks.person_signed_up(&person);
+    }
+    Ok((
+        state.sessions.cookie(&session_id),
+        Redirect::to(&format!("/person/{}", person_id)),
+    )
+        .into_response())
 }
 
 #[derive(Debug, Deserialize)]
@@ -157,116 +1067,5075 @@ pub struct SignUpQuery {
 pub async fn sign_up(
     State(state): State<AppState>,
     Query(sign_up_query): Query<SignUpQuery>,
-) -> Html<String> {
-    let mut context = Context::default();
-    context.insert("email", &sign_up_query.email);
-    Html(state.tera.render("sign_up.html", &context).unwrap())
+) -> RenderedPage {
+    let page = SignUpPage {
+        email: sign_up_query.email,
+        ..Default::default()
+    };
+    state.render_page(&page).await
+}
+
+// An organisation's sign up link, new people signing up from it join the organisation.
+pub async fn join(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<RenderedPage, AppError> {
+    let organisation = state
+        .db
+        .call(move |db| db.organisation_by_token(&token))
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let page = SignUpPage {
+        organisation: Some(organisation.name),
+        organisation_token: Some(organisation.sign_up_token),
+        ..Default::default()
+    };
+    Ok(state.render_page(&page).await)
 }
 
 pub async fn view_person(
     State(state): State<AppState>,
     authorized: Authorized,
-    Path(person_id): Path<u32>,
-) -> Response {
-    if let Some((user, mut matches)) = state.db.get_person_and_matches(person_id) {
-        matches.sort_by_key(|m| m.0);
-        matches.reverse();
-        let mut context = Context::new();
-        context.insert("id", &user.id);
-        context.insert("name", &user.name);
-        context.insert("email", &user.email);
-        context.insert("waiting", &user.waiting);
-        context.insert("matches", &matches);
-        context.insert("authorized_person_id", &authorized.person_id);
-        Html(state.tera.render("person.html", &context).unwrap()).into_response()
+    Path(person_id): Path<PersonId>,
+) -> Result<Response, AppError> {
+    let viewer = authorized.person_id;
+    let mentoring_rounds = !state.mentoring_round_types.is_empty();
+    let page = state
+        .db
+        .call(move |db| {
+            // People in other organisations can't be seen at all.
+            if !db.same_organisation(viewer, person_id)? {
+                return Ok(None);
+            }
+            let Some((user, matches)) = db.get_person_and_matches(person_id)? else {
+                return Ok(None);
+            };
+            let policy = Viewer::load(db, viewer)?;
+            let matches = policy.history(user.id, matches);
+            let (season_matches, max_matches_per_season) =
+                db.season_matches(user.id, season_start(db.now()))?;
+            let blocked = db.blocked_by(viewer)?;
+            let is_blocked = blocked.iter().any(|p| p.id == user.id);
+            let is_admin = db.is_admin_of(viewer, user.id)?;
+            let pools = if viewer == user.id || is_admin {
+                let mut pools = db.pool_memberships(user.id)?;
+                pools.retain(|membership| !membership.archived);
+                pools
+            } else {
+                Vec::new()
+            };
+            let own = if viewer == user.id {
+                Some(OwnPerson {
+                    blocked,
+                    has_api_token: db.has_api_token(user.id)?,
+                    own_timezone: db.timezone(user.id)?,
+                    email_notifications: db.email_notifications(user.id)?,
+                    timezones: chrono_tz::TZ_VARIANTS.iter().map(|tz| tz.name()).collect(),
+                    administered_pools: db.administered_pools(user.id)?,
+                })
+            } else {
+                None
+            };
+            Ok(Some(PersonPage {
+                authorized_person_id: viewer,
+                is_admin,
+                pools,
+                match_rounds: rounds_by_type(match_partners(db, &policy, matches)?),
+                timeline: if policy.sees_history_of(user.id) {
+                    db.timeline(user.id)?
+                } else {
+                    Vec::new()
+                },
+                season_matches,
+                max_matches_per_season,
+                match_every_rounds: db.match_every_rounds(user.id)?,
+                mentoring: if mentoring_rounds {
+                    Some(db.mentoring(user.id)?)
+                } else {
+                    None
+                },
+                is_blocked,
+                paused_until: db.paused_until(user.id)?,
+                interests: db.interests(user.id)?,
+                manager: db.manager(user.id)?,
+                contact_fields: policy.contact_fields(user.id, db.contact_fields(user.id)?),
+                own,
+                id: user.id,
+                name: user.name,
+                email: user.email,
+                waiting: user.waiting,
+                status: user.status,
+                team: user.team,
+            }))
+        })
+        .await?;
+    if let Some(page) = page {
+        Ok(state.render_page(&page).await.into_response())
     } else {
-        Redirect::to("/person").into_response()
+        Ok(Redirect::to("/person").into_response())
     }
 }
 
-pub async fn all_people(State(state): State<AppState>, authorized: Authorized) -> Html<String> {
-    let mut context = Context::new();
-    context.insert("authorized_person_id", &authorized.person_id);
-    let people = state.db.all_people();
-    context.insert("people", &people);
-    Html(state.tera.render("people.html", &context).unwrap())
+pub async fn all_people(
+    State(state): State<AppState>,
+    authorized: Authorized,
+) -> Result<RenderedPage, AppError> {
+    let viewer = authorized.person_id;
+    let people = state
+        .db
+        .call(move |db| db.people_in(db.organisation_of(viewer)?))
+        .await?;
+    let page = PeoplePage {
+        authorized_person_id: authorized.person_id,
+        people,
+    };
+    Ok(state.render_page(&page).await)
+}
+
+pub async fn matches(
+    State(state): State<AppState>,
+    authorized: Authorized,
+) -> Result<RenderedPage, AppError> {
+    matches_page(&state, authorized.person_id, None).await
 }
 
-pub async fn matches(State(state): State<AppState>, authorized: Authorized) -> Html<String> {
-    let mut context = Context::new();
-    context.insert("authorized_person_id", &authorized.person_id);
-    if let Some((match_meta, matches)) = state.db.latest_matches() {
-        context.insert("match_meta", &match_meta);
-        context.insert("matches", &matches);
+async fn matches_page(
+    state: &AppState,
+    person_id: PersonId,
+    error: Option<String>,
+) -> Result<RenderedPage, AppError> {
+    let (is_admin, latest, generations) = state
+        .db
+        .call(move |db| {
+            let latest = db.latest_matches()?;
+            Ok((
+                db.is_deployment_admin(person_id)?,
+                visible_rounds(db, person_id, latest)?,
+                db.list_generations(db.organisation_of(person_id)?)?,
+            ))
+        })
+        .await?;
+    let mut interrupted_generations = Vec::new();
+    if is_admin {
+        let interrupted = state.interrupted.lock().unwrap().clone();
+        if !interrupted.is_empty() {
+            interrupted_generations = state.db.call(|db| db.interrupted_generations()).await?;
+            interrupted_generations.retain(|g| interrupted.contains(&g.generation));
+        }
     }
-    Html(state.tera.render("matches.html", &context).unwrap())
+    let (rounds, partner_ids) = rounds_with_partners(latest, person_id, &generations);
+    let page = MatchesPage {
+        authorized_person_id: person_id,
+        error,
+        is_admin,
+        round_types: state.round_types.clone(),
+        interrupted: interrupted_generations,
+        rounds,
+        partner_ids,
+    };
+    Ok(state.render_page(&page).await)
+}
+
+#[derive(Debug, Serialize)]
+pub struct Round {
+    match_meta: MatchMeta,
+    matches: Vec<Match>,
+    // The generations of the same round type either side of this one.
+    previous: Option<GenerationId>,
+    next: Option<GenerationId>,
+}
+
+// Each round's matches, and whoever `person_id` was matched with in any of them to link to their
+// contact details. `generations` is every generation, oldest first, to link each round to the ones
+// either side of it.
+fn rounds_with_partners(
+    rounds: Vec<(MatchMeta, Vec<Match>)>,
+    person_id: PersonId,
+    generations: &[GenerationSummary],
+) -> (Vec<Round>, Vec<PersonId>) {
+    let partners: Vec<PersonId> = rounds
+        .iter()
+        .flat_map(|(_, matches)| partner_ids(matches, person_id))
+        .collect();
+    let rounds: Vec<Round> = rounds
+        .into_iter()
+        .map(|(match_meta, matches)| {
+            let same_type = || {
+                generations
+                    .iter()
+                    .filter(|g| g.round_type == match_meta.round_type)
+                    .map(|g| g.generation)
+            };
+            let previous = same_type()
+                .take_while(|g| *g != match_meta.generation)
+                .last();
+            let next = same_type()
+                .skip_while(|g| *g != match_meta.generation)
+                .nth(1);
+            Round {
+                match_meta,
+                matches,
+                previous,
+                next,
+            }
+        })
+        .collect();
+    (rounds, partners)
 }
 
 pub async fn matches_generation(
     State(state): State<AppState>,
     authorized: Authorized,
-    Path(generation): Path<u32>,
-) -> Html<String> {
-    let mut context = Context::new();
-    context.insert("authorized_person_id", &authorized.person_id);
-    if let Some((match_meta, matches)) = state.db.matches_at(generation) {
-        context.insert("match_meta", &match_meta);
-        context.insert("matches", &matches);
-    }
-    Html(state.tera.render("matches.html", &context).unwrap())
+    Path(generation): Path<GenerationId>,
+) -> Result<RenderedPage, AppError> {
+    let viewer = authorized.person_id;
+    let (rounds, generations) = state
+        .db
+        .call(move |db| {
+            let rounds = db.matches_at(generation)?.into_iter().collect();
+            Ok((
+                visible_rounds(db, viewer, rounds)?,
+                db.list_generations(db.organisation_of(viewer)?)?,
+            ))
+        })
+        .await?;
+    let (rounds, partner_ids) = rounds_with_partners(rounds, viewer, &generations);
+    let page = MatchesPage {
+        authorized_person_id: authorized.person_id,
+        error: None,
+        is_admin: false,
+        round_types: Vec::new(),
+        interrupted: Vec::new(),
+        rounds,
+        partner_ids,
+    };
+    Ok(state.render_page(&page).await)
 }
 
-pub async fn trigger_matching(State(state): State<AppState>) -> Redirect {
-    let mut g = Graph::default();
-
-    let mut waiter_index_mapping = HashMap::new();
-    let mut index_waiter_mapping = HashMap::new();
-    let waiters = state.db.waiters();
-    if waiters.is_empty() {
-        return Redirect::to("/matches");
-    }
+// Every generation, newest first, with how many groups each made in the viewer's organisation.
+pub async fn matches_history(
+    State(state): State<AppState>,
+    authorized: Authorized,
+) -> Result<RenderedPage, AppError> {
+    let viewer = authorized.person_id;
+    let mut generations = state
+        .db
+        .call(move |db| db.list_generations(db.organisation_of(viewer)?))
+        .await?;
+    generations.reverse();
+    let page = MatchesHistoryPage {
+        authorized_person_id: viewer,
+        generations,
+    };
+    Ok(state.render_page(&page).await)
+}
 
-    for waiter in &waiters {
-        let index = g.add_node(*waiter);
-        waiter_index_mapping.insert(*waiter, index);
-        index_waiter_mapping.insert(index, *waiter);
+// Only the groups from `viewer`'s organisation that they may see, everyone in a group is from the
+// same one.
+fn visible_rounds(
+    db: &Database,
+    viewer: PersonId,
+    mut rounds: Vec<(MatchMeta, Vec<Match>)>,
+) -> Result<Vec<(MatchMeta, Vec<Match>)>, DatabaseError> {
+    let members = db.organisation_members(db.organisation_of(viewer)?)?;
+    for (_, matches) in &mut rounds {
+        matches.retain(|m| m.members().any(|p| members.contains(&p.id)));
     }
+    Ok(Viewer::load(db, viewer)?.rounds(rounds))
+}
 
-    let edges = state.db.edges_for(waiters);
-    for (id1, id2, weight) in edges {
-        g.add_edge(
-            waiter_index_mapping[&id1],
-            waiter_index_mapping[&id2],
-            weight,
-        )
+// Someone's matches grouped by round type, newest first within each.
+fn rounds_by_type(matches: Vec<MatchPartner>) -> Vec<(String, Vec<MatchPartner>)> {
+    let mut rounds: BTreeMap<String, Vec<MatchPartner>> = BTreeMap::new();
+    for m in matches {
+        rounds.entry(m.past.round_type.clone()).or_default().push(m);
     }
+    rounds.into_iter().collect()
+}
 
-    let matching = g.matching();
+// What the viewer needs to get in touch with each partner in the matches.
+fn match_partners(
+    db: &Database,
+    policy: &Viewer,
+    matches: Vec<PastMatch>,
+) -> Result<Vec<MatchPartner>, DatabaseError> {
+    matches
+        .into_iter()
+        .map(|past| {
+            let partner = past.partner.id;
+            Ok(MatchPartner {
+                interests: db.interests(partner)?,
+                contact_fields: policy.contact_fields(partner, db.contact_fields(partner)?),
+                past,
+            })
+        })
+        .collect()
+}
 
-    let generation = state.db.add_matching_generation();
+// Who someone was matched with in a generation and how to reach them, for them and admins.
+pub async fn match_detail(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path((person_id, generation)): Path<(PersonId, GenerationId)>,
+) -> Result<RenderedPage, AppError> {
+    let viewer = authorized.person_id;
+    let page = state
+        .db
+        .call(move |db| {
+            if !db.same_organisation(viewer, person_id)? {
+                return Ok(None);
+            }
+            let policy = Viewer::load(db, viewer)?;
+            if !policy.sees_history_of(person_id) {
+                return Ok(None);
+            }
+            let Some((person, matches)) = db.get_person_and_matches(person_id)? else {
+                return Ok(None);
+            };
+            let matches: Vec<PastMatch> = matches
+                .into_iter()
+                .filter(|m| m.generation == generation)
+                .collect();
+            let Some(first) = matches.first() else {
+                return Ok(None);
+            };
+            Ok(Some(MatchPage {
+                authorized_person_id: viewer,
+                generation,
+                time: first.time,
+                round_type: first.round_type.clone(),
+                icebreaker: first.icebreaker.clone(),
+                partners: match_partners(db, &policy, matches)?,
+                person,
+            }))
+        })
+        .await?
+        .ok_or(AppError::NotFound)?;
+    Ok(state.render_page(&page).await)
+}
 
-    for (p1, p2) in matching {
-        state.db.add_matching(
-            index_waiter_mapping[&p1],
-            p2.map(|p2| index_waiter_mapping[&p2]),
-            generation,
-        );
-    }
+// The people matched with `person_id` in these matches.
+fn partner_ids(matches: &[Match], person_id: PersonId) -> Vec<PersonId> {
+    matches
+        .iter()
+        .map(|m| m.members().map(|p| p.id).collect::<Vec<_>>())
+        .filter(|ids| ids.contains(&person_id))
+        .flatten()
+        .filter(|id| *id != person_id)
+        .collect()
+}
 
-    Redirect::to("/matches")
+// Escape a value for use in a vCard property.
+fn vcard_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+        .replace('\n', "\\n")
 }
 
-pub async fn toggle_waiter(
+// Download someone's contact details as a vCard, only for people you've been matched with.
+pub async fn vcard(
     State(state): State<AppState>,
     authorized: Authorized,
-    Path(person_id): Path<u32>,
-) -> Redirect {
-    if authorized.person_id == person_id {
-        state.db.toggle_waiter(person_id);
+    Path(person_id): Path<PersonId>,
+) -> Result<Response, AppError> {
+    let viewer = authorized.person_id;
+    let (matched, person) = state
+        .db
+        .call(move |db| {
+            let matched = Viewer::load(db, viewer)?.is_partner_of(person_id);
+            Ok((matched, db.get_person(person_id)?))
+        })
+        .await?;
+    let person = match person {
+        Some(person) if matched || person_id == authorized.person_id => person,
+        _ => {
+            let page = ErrorPage {
+                authorized_person_id: Some(authorized.person_id),
+                error: "You can only download contact details for people you've been matched with."
+                    .to_owned(),
+            };
+            return Ok((StatusCode::FORBIDDEN, state.render_page(&page).await).into_response());
+        }
+    };
+    let mut card = String::new();
+    card.push_str("BEGIN:VCARD\r\nVERSION:3.0\r\n");
+    card.push_str(&format!("FN:{}\r\n", vcard_escape(&person.name)));
+    card.push_str(&format!("N:{};;;;\r\n", vcard_escape(&person.name)));
+    card.push_str(&format!(
+        "EMAIL;TYPE=INTERNET:{}\r\n",
+        vcard_escape(&person.email)
+    ));
+    if let Some(status) = &person.status {
+        card.push_str(&format!("NOTE:{}\r\n", vcard_escape(status)));
     }
-    Redirect::to(&format!("/person/{}", person_id))
+    card.push_str("END:VCARD\r\n");
+    Ok((
+        [
+            (CONTENT_TYPE, "text/vcard; charset=utf-8".to_owned()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"person-{}.vcf\"", person.id),
+            ),
+        ],
+        card,
+    )
+        .into_response())
 }
 
-pub async fn fallback() -> Redirect {
-    Redirect::to("/")
+#[derive(Debug, Deserialize)]
+pub struct RoundTypeQuery {
+    round_type: Option<String>,
+    // A pool's rounds are its own round type.
+    pool: Option<String>,
+}
+
+impl RoundTypeQuery {
+    // The requested pool or round type, or the deployment's first round type.
+    fn round_type(self, state: &AppState) -> String {
+        self.pool
+            .or(self.round_type)
+            .filter(|round_type| !round_type.is_empty())
+            .unwrap_or_else(|| state.round_types[0].clone())
+    }
+}
+
+// Try out another way of choosing pairs for one run, e.g. `?strategy=greedy`.
+#[derive(Debug, Deserialize)]
+pub struct StrategyQuery {
+    strategy: Option<Strategy>,
+}
+
+// A name and notes for the round being run, e.g. `?title=January 2025 round`.
+#[derive(Debug, Deserialize)]
+pub struct GenerationDetailsQuery {
+    title: Option<String>,
+    notes: Option<String>,
+}
+
+impl GenerationDetailsQuery {
+    fn clean(&self) -> Result<GenerationDetails, ValidationError> {
+        let title = self.title.as_deref().unwrap_or_default();
+        let notes = self.notes.as_deref().unwrap_or_default();
+        Ok(GenerationDetails {
+            title: clean_optional_text("title", title, MAX_GENERATION_TITLE_LENGTH)?,
+            notes: clean_optional_text("notes", notes, MAX_GENERATION_NOTES_LENGTH)?,
+        })
+    }
+}
+
+pub async fn trigger_matching(
+    State(state): State<AppState>,
+    Query(query): Query<RoundTypeQuery>,
+    Query(strategy): Query<StrategyQuery>,
+    Query(details): Query<GenerationDetailsQuery>,
+) -> Response {
+    let round_type = query.round_type(&state);
+    let details = match details.clean() {
+        Ok(details) => details,
+        Err(error) => return (StatusCode::BAD_REQUEST, error.to_string()).into_response(),
+    };
+    match match_and_notify(&state, &round_type, strategy.strategy, details).await {
+        Ok(_) => Redirect::to("/matches").into_response(),
+        Err(MatchingError::Database(error)) => {
+            let error = AppError::from(error);
+            (error.status(), error.to_string()).into_response()
+        }
+        Err(error) => (StatusCode::CONFLICT, error.to_string()).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TriggerMatchingForm {
+    #[serde(flatten)]
+    round_type: RoundTypeQuery,
+    #[serde(flatten)]
+    details: GenerationDetailsQuery,
+}
+
+// Where a pool admin goes back to with an error, pool admins can't see the matches page.
+async fn admin_page(
+    state: &AppState,
+    admin: &PoolAdmin,
+    error: String,
+) -> Result<RenderedPage, AppError> {
+    if admin.is_admin() {
+        matches_page(state, admin.person_id, Some(error)).await
+    } else {
+        pools_page(state, admin.person_id, Some(error)).await
+    }
+}
+
+// Admins can trigger matching from the web as well as from the private port, pool admins only for
+// their own pools.
+pub async fn admin_trigger_matching(
+    State(state): State<AppState>,
+    admin: PoolAdmin,
+    Form(form): Form<TriggerMatchingForm>,
+) -> Response {
+    let round_type = form.round_type.round_type(&state);
+    if !admin.manages(&round_type) {
+        return AppError::Forbidden.into_response();
+    }
+    let details = match form.details.clean() {
+        Ok(details) => details,
+        Err(error) => {
+            return match admin_page(&state, &admin, error.to_string()).await {
+                Ok(page) => invalid_form(page, error),
+                Err(error) => error.into_response(),
+            }
+        }
+    };
+    tracing::info!(
+        admin = admin.person_id.0,
+        round_type,
+        title = details.title,
+        "Matching triggered"
+    );
+    match match_and_notify(&state, &round_type, None, details).await {
+        Ok(_) if admin.is_admin() => Redirect::to("/matches").into_response(),
+        Ok(_) => Redirect::to("/pools").into_response(),
+        Err(error) => admin_page(&state, &admin, error.to_string())
+            .await
+            .into_response(),
+    }
+}
+
+// Finish a generation interrupted at startup, keeping the groups that were saved and telling
+// everyone in them. Anyone who was notified before the interruption is told again.
+pub async fn complete_generation(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(generation): Path<GenerationId>,
+) -> Result<Response, AppError> {
+    let Ok(_running) = state.matching_lock.try_lock() else {
+        return Ok(matches_page(
+            &state,
+            admin.person_id,
+            Some(MatchingError::AlreadyRunning.to_string()),
+        )
+        .await
+        .into_response());
+    };
+    if !state.interrupted.lock().unwrap().remove(&generation) {
+        return Ok(Redirect::to("/matches").into_response());
+    }
+    tracing::info!(
+        admin = admin.person_id.0,
+        generation = generation.0,
+        "Completing interrupted generation"
+    );
+    let (stage, matches) = state
+        .db
+        .call(move |db| {
+            let stage = db
+                .interrupted_generations()?
+                .into_iter()
+                .find(|g| g.generation == generation)
+                .map(|g| g.stage);
+            db.finish_matching(generation)?;
+            Ok((stage, db.matches_in(generation)?))
+        })
+        .await?;
+    if stage == Some(InterruptedStage::Matching) {
+        state.hooks.generation_created(generation, &matches);
+    }
+    if let Err(error) = notify_generation(&state, generation, matches).await {
+        state.interrupted.lock().unwrap().insert(generation);
+        return Err(error.into());
+    }
+    Ok(Redirect::to("/matches").into_response())
+}
+
+// Undo a generation interrupted at startup, putting everyone back in the waiting pool.
+pub async fn roll_back_generation(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(generation): Path<GenerationId>,
+) -> Result<Response, AppError> {
+    let Ok(_running) = state.matching_lock.try_lock() else {
+        return Ok(matches_page(
+            &state,
+            admin.person_id,
+            Some(MatchingError::AlreadyRunning.to_string()),
+        )
+        .await
+        .into_response());
+    };
+    if !state.interrupted.lock().unwrap().remove(&generation) {
+        return Ok(Redirect::to("/matches").into_response());
+    }
+    let reason = format!("roll back generation {}", generation.0);
+    if let Err(error) = state.snapshots.create(&state.db, &reason).await {
+        state.interrupted.lock().unwrap().insert(generation);
+        return Err(error.into());
+    }
+    tracing::info!(
+        admin = admin.person_id.0,
+        generation = generation.0,
+        "Rolling back interrupted generation"
+    );
+    state
+        .db
+        .call(move |db| db.roll_back_generation(generation))
+        .await?;
+    Ok(Redirect::to("/matches").into_response())
+}
+
+// Why a round of matching wasn't run.
+#[derive(Debug)]
+pub enum MatchingError {
+    AlreadyRunning,
+    // Anything saved before the database failed is left as an interrupted generation.
+    Database(DatabaseError),
+    // Not one of the deployment's `round_types`.
+    UnknownRoundType(String),
+    // Most likely a duplicate trigger for the generation that was just created.
+    TooSoon {
+        generation: GenerationId,
+        seconds_ago: i64,
+    },
+    // Groups chosen outside the matcher that it wouldn't have made, saying why.
+    InvalidGroups(String),
+}
+
+impl std::fmt::Display for MatchingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MatchingError::AlreadyRunning => write!(f, "Matching is already running."),
+            MatchingError::Database(DatabaseError::Busy) => write!(f, "{}", DatabaseError::Busy),
+            MatchingError::Database(DatabaseError::Sqlite(_)) => write!(f, "{}", AppError::Internal),
+            MatchingError::UnknownRoundType(round_type) => {
+                write!(f, "There is no round type called {round_type}.")
+            }
+            MatchingError::TooSoon {
+                generation,
+                seconds_ago,
+            } => write!(
+                f,
+                "Generation {generation} was created {seconds_ago} seconds ago, wait a little before matching again."
+            ),
+            MatchingError::InvalidGroups(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+// Run a round of matching and let everyone matched know, returns the new generation if anyone was
+// waiting. Only one round runs at a time, and not within `min_run_interval_seconds` of the last of
+// the same round type.
+// Run matching for the round type and tell everyone, with the configured strategy unless another is
+// given.
+pub async fn match_and_notify(
+    state: &AppState,
+    round_type: &str,
+    strategy: Option<Strategy>,
+    details: GenerationDetails,
+) -> Result<Option<MatchMeta>, MatchingError> {
+    save_and_notify(state, round_type, strategy, None, details).await
+}
+
+// Save groups chosen outside the matcher as the next generation of the round type and tell
+// everyone, as long as matching could have made them.
+pub async fn record_external_matching(
+    state: &AppState,
+    round_type: &str,
+    groups: Vec<Vec<PersonId>>,
+) -> Result<Option<MatchMeta>, MatchingError> {
+    save_and_notify(
+        state,
+        round_type,
+        None,
+        Some(groups),
+        GenerationDetails::default(),
+    )
+    .await
+}
+
+// Whether it's one of the deployment's round types or a pool that isn't archived.
+async fn is_round_type(state: &AppState, round_type: &str) -> Result<bool, DatabaseError> {
+    if state.round_types.iter().any(|r| r == round_type) {
+        return Ok(true);
+    }
+    let pool = round_type.to_owned();
+    state.db.call(move |db| db.is_active_pool(&pool)).await
+}
+
+// Run matching, or take the `groups` given, and tell everyone.
+async fn save_and_notify(
+    state: &AppState,
+    round_type: &str,
+    strategy: Option<Strategy>,
+    groups: Option<Vec<Vec<PersonId>>>,
+    details: GenerationDetails,
+) -> Result<Option<MatchMeta>, MatchingError> {
+    if !is_round_type(state, round_type)
+        .await
+        .map_err(MatchingError::Database)?
+    {
+        return Err(MatchingError::UnknownRoundType(round_type.to_owned()));
+    }
+    let Ok(_running) = state.matching_lock.try_lock() else {
+        tracing::warn!("Matching refused as it is already running");
+        return Err(MatchingError::AlreadyRunning);
+    };
+    // Trying another strategy for a run leaves any season plan for the next one.
+    let follow_season = groups.is_none() && strategy.is_none();
+    let mut settings = state.matching_settings(round_type);
+    settings.strategy = strategy.unwrap_or(settings.strategy);
+    let min_interval = state.min_run_interval_seconds;
+    let round_type = round_type.to_owned();
+    // Not retried, as a run cut off part way has already saved some of its groups.
+    let ran = state
+        .db
+        .call(move |db| {
+            if let Some(latest) = db.latest_match_meta(&round_type)? {
+                let seconds_ago = db.now().timestamp() - latest.time as i64;
+                if seconds_ago < min_interval {
+                    return Ok(Err(MatchingError::TooSoon {
+                        generation: latest.generation,
+                        seconds_ago,
+                    }));
+                }
+            }
+            let season = if follow_season {
+                db.season_plan(&round_type)?
+                    .and_then(|season| Some((season.next_round?, season)))
+            } else {
+                None
+            };
+            let plan = match (&groups, &season) {
+                (None, None) => plan_matching(db, &settings, &round_type)?,
+                (None, Some((round, season))) => {
+                    season_round_plan(db, &settings, &round_type, season, *round)?
+                }
+                (Some(groups), _) => match external_plan(db, &settings, &round_type, groups)? {
+                    Ok(plan) => plan,
+                    Err(error) => return Ok(Err(MatchingError::InvalidGroups(error))),
+                },
+            };
+            let Some(meta) = save_plan(db, &settings, &round_type, &details, plan)? else {
+                return Ok(Ok(None));
+            };
+            if let Some((round, season)) = season {
+                db.record_season_round(season.id, round, meta.generation)?;
+            }
+            let matches = db.matches_in(meta.generation)?;
+            Ok(Ok(Some((meta, matches))))
+        })
+        .await;
+    let ran = match ran {
+        Ok(ran) => ran?,
+        Err(error) => {
+            // Matching holds the lock so any unfinished generation is this run's, for an admin to
+            // complete or roll back.
+            if let Ok(interrupted) = state.db.call(|db| db.interrupted_generations()).await {
+                let mut known = state.interrupted.lock().unwrap();
+                known.extend(interrupted.iter().map(|g| g.generation));
+            }
+            return Err(MatchingError::Database(error));
+        }
+    };
+    let Some((meta, matches)) = ran else {
+        tracing::info!("Matching skipped as nobody was waiting");
+        return Ok(None);
+    };
+    tracing::info!(
+        generation = meta.generation.0,
+        round_type = meta.round_type,
+        groups = matches.len(),
+        "Created generation"
+    );
+    state.hooks.generation_created(meta.generation, &matches);
+    if let Err(error) = notify_generation(state, meta.generation, matches).await {
+        // Left for an admin to complete, which tries telling everyone again.
+        state.interrupted.lock().unwrap().insert(meta.generation);
+        return Err(MatchingError::Database(error));
+    }
+    Ok(Some(meta))
+}
+
+// Email everyone in the generation in the background so the request doesn't wait on the mail
+// server.
+async fn notify_generation(
+    state: &AppState,
+    generation: GenerationId,
+    matches: Vec<Match>,
+) -> Result<(), DatabaseError> {
+    let people: Vec<PersonId> = matches
+        .iter()
+        .flat_map(|m| m.members())
+        .map(|p| p.id)
+        .collect();
+    let suggestions = state.suggestions;
+    let emailing = state.mailer.is_configured();
+    // Only the first round type is run by the schedule, so only its unmatched are told when the
+    // next run is.
+    let schedule = current_schedule(state)
+        .await?
+        .filter(|schedule| schedule.enabled);
+    let scheduled_round_type = state.round_types[0].clone();
+    let now = state.clock.now();
+    let groups: Vec<Vec<PersonId>> = matches
+        .iter()
+        .map(|m| m.members().map(|p| p.id).collect())
+        .collect();
+    let (branding, unsubscribe_tokens, slack_ids, suggestions, contact_fields, next_run, timezones) =
+        state
+            .db
+            .call(move |db| {
+                let unsubscribe_tokens = db.email_recipients(&people)?;
+                if emailing {
+                    for person in groups.iter().flatten() {
+                        let status = if unsubscribe_tokens.contains_key(person) {
+                            NotificationStatus::Pending
+                        } else {
+                            NotificationStatus::Suppressed
+                        };
+                        db.set_notification(generation, *person, status, None)?;
+                    }
+                }
+                let suggestions: Vec<Vec<Person>> = groups
+                    .iter()
+                    .map(|group| group_suggestions(db, group, suggestions))
+                    .collect::<Result<_, _>>()?;
+                let round_type = db.match_meta_at(generation)?.map(|meta| meta.round_type);
+                let next_run = schedule
+                    .filter(|_| round_type.as_ref() == Some(&scheduled_round_type))
+                    .map(|schedule| schedule.next_run(now));
+                Ok((
+                    db.branding()?,
+                    unsubscribe_tokens,
+                    db.slack_ids(&people)?,
+                    suggestions,
+                    db.contact_fields_for_matches(&people)?,
+                    next_run,
+                    db.timezones(&people)?,
+                ))
+            })
+            .await?;
+    let span = tracing::info_span!("notify", generation = generation.0);
+    // Slack is only a courtesy, so it's posted on its own and nothing waits for it.
+    let slack = state.slack.clone();
+    let matches = Arc::new(matches);
+    let slack_matches = matches.clone();
+    tokio::spawn(
+        async move {
+            slack
+                .notify_matches(generation, &slack_matches, &slack_ids)
+                .await;
+        }
+        .instrument(span.clone()),
+    );
+    let mailer = state.mailer.clone();
+    let db = state.db.clone();
+    let notifying = state.notifying.clone().read_owned().await;
+    tokio::spawn(
+        async move {
+            let results: Vec<_> = mailer
+                .notify_matches(
+                    generation,
+                    &matches,
+                    &suggestions,
+                    &contact_fields,
+                    &branding,
+                    &unsubscribe_tokens,
+                    next_run,
+                    &timezones,
+                )
+                .await
+                .into_iter()
+                .map(|(person_id, result)| (person_id, sent_status(&result)))
+                .collect();
+            // A failure has been logged, and leaves the generation unfinished.
+            let _ = db
+                .call(move |db| {
+                    for (person_id, (status, error)) in results {
+                        db.set_notification(generation, person_id, status, error.as_deref())?;
+                    }
+                    db.finish_notifying(generation)
+                })
+                .await;
+            drop(notifying);
+        }
+        .instrument(span),
+    );
+    Ok(())
+}
+
+// People nobody in the group has met, to suggest in their introduction.
+fn group_suggestions(
+    db: &Database,
+    group: &[PersonId],
+    count: u32,
+) -> Result<Vec<Person>, DatabaseError> {
+    if count > 0 && group.len() > 1 {
+        db.not_yet_met(group, count)
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+// The status to record for an attempt to send someone their match email.
+fn sent_status(result: &Result<(), MailError>) -> (NotificationStatus, Option<String>) {
+    match result {
+        Ok(()) => (NotificationStatus::Sent, None),
+        Err(error) if error.is_permanent() => {
+            (NotificationStatus::Bounced, Some(error.to_string()))
+        }
+        Err(error) => (NotificationStatus::Failed, Some(error.to_string())),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct NotificationRow {
+    person: Person,
+    // None for people who weren't emailed, e.g. because email isn't set up.
+    notification: Option<Notification>,
+}
+
+// How each person's match email for a generation went, grouped by match.
+pub async fn generation_notifications(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(generation): Path<GenerationId>,
+) -> Result<RenderedPage, AppError> {
+    let person_id = admin.person_id;
+    let (matches, mut notifications) = state
+        .db
+        .call(move |db| Ok((db.matches_in(generation)?, db.notifications(generation)?)))
+        .await?;
+    let groups: Vec<Vec<NotificationRow>> = matches
+        .iter()
+        .map(|m| {
+            m.members()
+                .map(|person| NotificationRow {
+                    person: person.clone(),
+                    notification: notifications.remove(&person.id),
+                })
+                .collect()
+        })
+        .collect();
+    let page = NotificationsPage {
+        authorized_person_id: person_id,
+        generation,
+        groups,
+        emailing: state.mailer.is_configured(),
+    };
+    Ok(state.render_page(&page).await)
+}
+
+// Send someone their match email for a generation again, e.g. after fixing a bounced address.
+pub async fn resend_notification(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path((generation, person_id)): Path<(GenerationId, PersonId)>,
+) -> Result<Redirect, AppError> {
+    let redirect = Redirect::to(&format!("/matches/{generation}/notifications"));
+    if !state.mailer.is_configured() {
+        return Ok(redirect);
+    }
+    let suggestions = state.suggestions;
+    let found = state
+        .db
+        .call(move |db| {
+            let Some(m) = db
+                .matches_in(generation)?
+                .into_iter()
+                .find(|m| m.members().any(|p| p.id == person_id))
+            else {
+                return Ok(None);
+            };
+            let group: Vec<PersonId> = m.members().map(|p| p.id).collect();
+            Ok(Some((
+                group_suggestions(db, &group, suggestions)?,
+                db.contact_fields_for_matches(&group)?,
+                db.branding()?,
+                db.email_recipients(&[person_id])?.remove(&person_id),
+                m,
+            )))
+        })
+        .await?;
+    let Some((suggestions, contact_fields, branding, unsubscribe_token, m)) = found else {
+        return Ok(redirect);
+    };
+    let (status, error) = match unsubscribe_token {
+        Some(unsubscribe_token) => {
+            let group = Introduction {
+                generation,
+                members: m.members().collect(),
+                suggestions: &suggestions,
+                contact_fields: &contact_fields,
+                icebreaker: m.icebreaker.as_deref(),
+            };
+            let person = group.members.iter().find(|p| p.id == person_id).unwrap();
+            let result = state
+                .mailer
+                .resend_match(&group, person, &branding, &unsubscribe_token)
+                .await;
+            sent_status(&result)
+        }
+        None => (NotificationStatus::Suppressed, None),
+    };
+    tracing::info!(
+        admin = admin.person_id.0,
+        generation = generation.0,
+        person_id = person_id.0,
+        ?status,
+        "Resent match email"
+    );
+    state
+        .db
+        .call(move |db| db.set_notification(generation, person_id, status, error.as_deref()))
+        .await?;
+    Ok(redirect)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationUpdate {
+    status: NotificationStatus,
+    error: Option<String>,
+}
+
+// For a mail provider's webhooks to report what happened to a match email after it was sent.
+pub async fn update_notification(
+    State(state): State<AppState>,
+    Path((generation, person_id)): Path<(GenerationId, PersonId)>,
+    Json(update): Json<NotificationUpdate>,
+) -> Result<StatusCode, AppError> {
+    let updated = state
+        .db
+        .call(move |db| {
+            db.update_notification(
+                generation,
+                person_id,
+                update.status,
+                update.error.as_deref(),
+            )
+        })
+        .await?;
+    if updated {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+// Seasons run for a calendar quarter, returns the timestamp the current one started at.
+pub fn season_start(now: DateTime<Utc>) -> i64 {
+    let month = (now.month0() / 3) * 3 + 1;
+    Utc.with_ymd_and_hms(now.year(), month, 1, 0, 0, 0)
+        .unwrap()
+        .timestamp()
+}
+
+// A round of matching worked out from the current waiters but not saved.
+#[derive(Debug, Serialize)]
+pub struct MatchingPlan {
+    waiters: Vec<PersonId>,
+    excluded: Vec<(PersonId, String)>,
+    // Those excluded for the rest of the season, who stop waiting. Anyone only sitting out this
+    // round keeps waiting.
+    #[serde(skip)]
+    at_season_limit: Vec<PersonId>,
+    floaters: Vec<PersonId>,
+    groups: Vec<Vec<PersonId>>,
+    quality: MatchQuality,
+    #[serde(skip)]
+    buckets: Vec<BucketAudit>,
+}
+
+// Save a round of matching as a new generation, unless nobody was waiting.
+fn save_plan(
+    db: &Database,
+    settings: &MatchingSettings,
+    round_type: &str,
+    details: &GenerationDetails,
+    plan: MatchingPlan,
+) -> Result<Option<MatchMeta>, DatabaseError> {
+    db.stop_waiting(&plan.at_season_limit, round_type)?;
+    if plan.waiters.is_empty() {
+        return Ok(None);
+    }
+
+    let meta = db.add_matching_generation(&plan.quality, round_type, details)?;
+    db.snapshot_waiters(
+        meta.generation,
+        &plan.waiters,
+        &plan.excluded,
+        &plan.floaters,
+    )?;
+
+    for members in &plan.groups {
+        db.add_matching(members, meta.generation)?;
+    }
+
+    db.save_audit(&MatchingAudit {
+        generation: meta.generation,
+        time: meta.time,
+        settings: *settings,
+        waiters: plan.waiters,
+        excluded: plan.excluded,
+        floaters: plan.floaters,
+        quality: plan.quality,
+        buckets: plan.buckets,
+    })?;
+    db.finish_matching(meta.generation)?;
+
+    Ok(Some(meta))
+}
+
+// Work out a round of matching without changing anything.
+fn plan_matching(
+    db: &Database,
+    settings: &MatchingSettings,
+    round_type: &str,
+) -> Result<MatchingPlan, DatabaseError> {
+    let mut waiters = db.waiters_for(round_type)?;
+    let mut excluded = Vec::new();
+
+    // Both cover everyone, not just waiters, so floaters are held to the same limits.
+    let season_limited = db.waiters_at_season_limit(season_start(db.now()))?;
+    let all_resting = db.waiters_resting(round_type)?;
+
+    let mut at_season_limit = season_limited.clone();
+    at_season_limit.retain(|w| waiters.contains(w));
+    waiters.retain(|w| !at_season_limit.contains(w));
+    excluded.extend(at_season_limit.iter().map(|w| {
+        (
+            *w,
+            "Reached their limit of matches for the season".to_owned(),
+        )
+    }));
+
+    let mut resting = all_resting.clone();
+    resting.retain(|(w, _)| waiters.contains(w));
+    waiters.retain(|w| !resting.iter().any(|(r, _)| r == w));
+    excluded.extend(
+        resting
+            .into_iter()
+            .map(|(w, every)| (w, format!("Only matched every {every} rounds"))),
+    );
+
+    if waiters.is_empty() {
+        return Ok(MatchingPlan {
+            waiters,
+            excluded,
+            at_season_limit,
+            floaters: Vec::new(),
+            groups: Vec::new(),
+            quality: MatchQuality {
+                strategy: "exact".to_owned(),
+                buckets: 0,
+                repeat_pairs: 0,
+                total_weight: 0,
+            },
+            buckets: Vec::new(),
+        });
+    }
+
+    // Rather than leave someone out, bring in a floater to even the numbers. Organisations are
+    // matched separately so each needs its own.
+    let organisations = db.organisations_of(&waiters)?;
+    let mut waiting_in: BTreeMap<OrganisationId, usize> = BTreeMap::new();
+    for waiter in &waiters {
+        *waiting_in
+            .entry(
+                organisations
+                    .get(waiter)
+                    .copied()
+                    .unwrap_or(DEFAULT_ORGANISATION),
+            )
+            .or_default() += 1;
+    }
+    // Anyone already taking part or left out for a reason can't float.
+    let mut unavailable: Vec<PersonId> = waiters.clone();
+    unavailable.extend(excluded.iter().map(|(w, _)| *w));
+    unavailable.extend(&season_limited);
+    unavailable.extend(all_resting.iter().map(|(w, _)| *w));
+    let mut floaters = Vec::new();
+    for (organisation, waiting) in waiting_in {
+        // Mentoring and bigger groups take everyone in, so there's nobody odd to pair with.
+        if waiting % 2 == 1 && !settings.mentoring && settings.group_size <= 2 {
+            floaters.extend(db.pick_floater(&unavailable, organisation)?);
+        }
+    }
+    waiters.extend(&floaters);
+
+    let (groups, audit_buckets) = match_buckets(db, &waiters, settings, round_type)?;
+    let strategy = if audit_buckets.len() > 1 {
+        "bucketed"
+    } else {
+        "exact"
+    };
+    let quality = plan_quality(db, &waiters, &groups, strategy, audit_buckets.len())?;
+
+    Ok(MatchingPlan {
+        waiters,
+        excluded,
+        at_season_limit,
+        floaters,
+        groups,
+        quality,
+        buckets: audit_buckets,
+    })
+}
+
+// Split the waiters into buckets and match each, returning everyone's groups and the buckets.
+fn match_buckets(
+    db: &Database,
+    waiters: &[PersonId],
+    settings: &MatchingSettings,
+    round_type: &str,
+) -> Result<(Vec<Vec<PersonId>>, Vec<BucketAudit>), DatabaseError> {
+    let buckets = partition_waiters(db, waiters, settings.max_exact_pool_size, round_type)?;
+    // Whoever sat out last time shouldn't have to again.
+    let priority = db.unmatched_in_latest_generation(round_type)?;
+
+    let mut groups = Vec::new();
+    let mut audit_buckets = Vec::new();
+    for bucket in &buckets {
+        let graph = bucket_graph(db, bucket, &priority, settings, round_type)?;
+        let bucket_groups: Vec<Vec<PersonId>> = graph
+            .matching(
+                settings.odd_person_policy,
+                settings.strategy.implementation(),
+            )
+            .into_iter()
+            .map(|group| group.iter().map(|i| graph.person(*i)).collect())
+            .collect();
+        groups.extend(bucket_groups.iter().cloned());
+        audit_buckets.push(BucketAudit {
+            graph,
+            groups: bucket_groups,
+        });
+    }
+    Ok((groups, audit_buckets))
+}
+
+// A plan for groups chosen outside the matcher. They can only be ones matching could have made:
+// of people it would match, each in one group no bigger than the round's groups plus an odd one
+// out, and never with someone from another organisation or someone it wouldn't pair them with.
+// Anyone waiting who isn't in a group sits out, and has priority next time.
+fn external_plan(
+    db: &Database,
+    settings: &MatchingSettings,
+    round_type: &str,
+    groups: &[Vec<PersonId>],
+) -> Result<Result<MatchingPlan, String>, DatabaseError> {
+    if settings.mentoring {
+        return Ok(Err(
+            "Mentoring rounds can't be matched externally.".to_owned()
+        ));
+    }
+    let mut plan = plan_matching(db, settings, round_type)?;
+    let waiting: HashSet<PersonId> = plan.waiters.iter().copied().collect();
+    let largest = settings.group_size.max(2) + 1;
+    let mut grouped = HashSet::new();
+    for group in groups {
+        if group.is_empty() || group.len() > largest {
+            return Ok(Err(format!(
+                "Groups must have between 1 and {largest} people."
+            )));
+        }
+        for person in group {
+            if !waiting.contains(person) {
+                return Ok(Err(format!("Person {person} isn't waiting.")));
+            }
+            if !grouped.insert(*person) {
+                return Ok(Err(format!("Person {person} is in more than one group.")));
+            }
+        }
+    }
+    // Floaters are only brought in when they're used.
+    let unused: Vec<PersonId> = plan
+        .floaters
+        .iter()
+        .filter(|floater| !grouped.contains(floater))
+        .copied()
+        .collect();
+    plan.floaters.retain(|floater| grouped.contains(floater));
+    plan.waiters.retain(|waiter| !unused.contains(waiter));
+
+    let graph = bucket_graph(db, &plan.waiters, &[], settings, round_type)?;
+    let index: HashMap<PersonId, usize> = (0..graph.len()).map(|i| (graph.person(i), i)).collect();
+    let organisations = db.organisations_of(&plan.waiters)?;
+    let organisation = |person: &PersonId| {
+        organisations
+            .get(person)
+            .copied()
+            .unwrap_or(DEFAULT_ORGANISATION)
+    };
+    for group in groups {
+        for (i, a) in group.iter().enumerate() {
+            for b in &group[i + 1..] {
+                if organisation(a) != organisation(b) {
+                    return Ok(Err(format!(
+                        "People {a} and {b} are in different organisations."
+                    )));
+                }
+                if graph.is_forbidden(index[a], index[b]) {
+                    return Ok(Err(format!(
+                        "People {a} and {b} can't be matched together."
+                    )));
+                }
+            }
+        }
+    }
+
+    plan.groups = groups.to_vec();
+    plan.groups.extend(
+        plan.waiters
+            .iter()
+            .filter(|waiter| !grouped.contains(waiter))
+            .map(|waiter| vec![*waiter]),
+    );
+    plan.quality = plan_quality(db, &plan.waiters, &plan.groups, "external", 1)?;
+    plan.buckets = vec![BucketAudit {
+        graph,
+        groups: plan.groups.clone(),
+    }];
+    Ok(Ok(plan))
+}
+
+// The next round of a season plan: its pairs where both are waiting and can still be matched, with
+// everyone else, e.g. those who joined since it was planned or whose partner isn't waiting, matched
+// as usual.
+fn season_round_plan(
+    db: &Database,
+    settings: &MatchingSettings,
+    round_type: &str,
+    season: &SeasonPlan,
+    round: u32,
+) -> Result<MatchingPlan, DatabaseError> {
+    let mut plan = plan_matching(db, settings, round_type)?;
+    if plan.waiters.is_empty() {
+        return Ok(plan);
+    }
+    let graph = bucket_graph(db, &plan.waiters, &[], settings, round_type)?;
+    let index: HashMap<PersonId, usize> = (0..graph.len()).map(|i| (graph.person(i), i)).collect();
+    let mut planned = Vec::new();
+    for (a, b) in db.season_plan_pairs(season.id, round)? {
+        if let (Some(i), Some(j)) = (index.get(&a), index.get(&b)) {
+            if !graph.is_forbidden(*i, *j) {
+                planned.push(vec![a, b]);
+            }
+        }
+    }
+    let rest: Vec<PersonId> = plan
+        .waiters
+        .iter()
+        .filter(|waiter| !planned.iter().flatten().any(|p| p == *waiter))
+        .copied()
+        .collect();
+    let (rest_groups, rest_buckets) = match_buckets(db, &rest, settings, round_type)?;
+    plan.groups = planned.clone();
+    plan.groups.extend(rest_groups);
+    plan.quality = plan_quality(
+        db,
+        &plan.waiters,
+        &plan.groups,
+        "season_plan",
+        rest_buckets.len() + 1,
+    )?;
+    plan.buckets = vec![BucketAudit {
+        graph,
+        groups: planned,
+    }];
+    plan.buckets.extend(rest_buckets);
+    Ok(plan)
+}
+
+// How many of the pairs in the groups had met before, and how often.
+fn plan_quality(
+    db: &Database,
+    waiters: &[PersonId],
+    groups: &[Vec<PersonId>],
+    strategy: &str,
+    buckets: usize,
+) -> Result<MatchQuality, DatabaseError> {
+    // Weights are how many times each pair had met before this generation.
+    let weights: HashMap<(PersonId, PersonId), u32> = db
+        .edges_for(waiters.to_vec(), None)?
+        .into_iter()
+        .map(|(id1, id2, weight)| ((id1.min(id2), id1.max(id2)), weight))
+        .collect();
+    let mut quality = MatchQuality {
+        strategy: strategy.to_owned(),
+        buckets: buckets as u32,
+        repeat_pairs: 0,
+        total_weight: 0,
+    };
+    for group in groups {
+        for (i, a) in group.iter().enumerate() {
+            for b in &group[i + 1..] {
+                let weight = weights.get(&(*a.min(b), *a.max(b))).copied().unwrap_or(0);
+                if weight > 0 {
+                    quality.repeat_pairs += 1;
+                    quality.total_weight += weight;
+                }
+            }
+        }
+    }
+    Ok(quality)
+}
+
+// Nobody is matched with someone from another organisation, so each is matched separately.
+// Exact matching gets slow for very large pools so each organisation's waiters are split into
+// buckets of people in similar timezones, each small enough to match exactly. Buckets are kept even
+// so only the last one of an organisation can have someone left over.
+//
+// Waiters are ordered by their timezone's UTC offset now, then team, before being cut into buckets.
+// Each offset is first nudged by a random number of half hours, up to `BUCKET_JITTER_HOURS` either
+// way, so the cuts move from round to round: people near the edge of a bucket are sometimes matched
+// with the next one over, more often the closer their timezones are.
+fn partition_waiters(
+    db: &Database,
+    waiters: &[PersonId],
+    max_exact_pool_size: usize,
+    round_type: &str,
+) -> Result<Vec<Vec<PersonId>>, DatabaseError> {
+    let organisations = db.organisations_of(waiters)?;
+    let mut by_organisation: BTreeMap<OrganisationId, Vec<PersonId>> = BTreeMap::new();
+    for waiter in waiters {
+        by_organisation
+            .entry(
+                organisations
+                    .get(waiter)
+                    .copied()
+                    .unwrap_or(DEFAULT_ORGANISATION),
+            )
+            .or_default()
+            .push(*waiter);
+    }
+    let mut buckets = Vec::new();
+    for waiters in by_organisation.into_values() {
+        if waiters.len() <= max_exact_pool_size {
+            buckets.push(waiters);
+            continue;
+        }
+        let now = db.now();
+        let round = db.rounds_run(round_type)?;
+        let timezones = db.timezones(&waiters)?;
+        let teams = db.teams(&waiters)?;
+        let mut sorted = waiters;
+        sorted.sort_by_cached_key(|w| {
+            let offset = timezones
+                .get(w)
+                .and_then(|timezone| timezone.parse::<chrono_tz::Tz>().ok())
+                .map_or(0, |timezone| {
+                    i64::from(
+                        now.with_timezone(&timezone)
+                            .offset()
+                            .fix()
+                            .local_minus_utc(),
+                    )
+                });
+            (offset + bucket_jitter(round, *w), teams.get(w).cloned(), *w)
+        });
+        let bucket_size = (max_exact_pool_size / 2 * 2).max(2);
+        buckets.extend(sorted.chunks(bucket_size).map(|c| c.to_vec()));
+    }
+    Ok(buckets)
+}
+
+// How far either way a waiter's UTC offset can be moved when they are put into a bucket.
+const BUCKET_JITTER_HOURS: i64 = 3;
+
+// The seconds to move a waiter's UTC offset by for the round, a whole number of half hours. It
+// depends only on the round and who they are so previews and the run itself agree.
+fn bucket_jitter(round: u64, person: PersonId) -> i64 {
+    let steps = BUCKET_JITTER_HOURS * 2;
+    let random = splitmix64(round.rotate_left(32) ^ u64::from(person.0));
+    ((random % (2 * steps as u64 + 1)) as i64 - steps) * 30 * 60
+}
+
+// The graph for matching a bucket of waiters, weighted by how much each pair has met and with the
+// constraints on who can be paired.
+fn bucket_graph(
+    db: &Database,
+    waiters: &[PersonId],
+    priority: &[PersonId],
+    settings: &MatchingSettings,
+    round_type: &str,
+) -> Result<Graph, DatabaseError> {
+    let mut g = if settings.mentoring {
+        Graph::mentoring()
+    } else if settings.group_size > 2 {
+        Graph::groups_of(settings.group_size)
+    } else {
+        Graph::default()
+    };
+
+    g.set_round(db.rounds_run(round_type)?);
+
+    let mut waiter_index_mapping = HashMap::new();
+
+    for waiter in waiters {
+        let index = g.add_node(*waiter);
+        waiter_index_mapping.insert(*waiter, index);
+    }
+
+    if settings.mentoring {
+        for (person, (role, capacity)) in db.mentoring_roles(waiters)? {
+            g.set_role(waiter_index_mapping[&person], role, capacity);
+        }
+    }
+
+    let edges = db.edges_for(waiters.to_vec(), settings.edge_half_life)?;
+    for (id1, id2, weight) in edges {
+        g.add_edge(
+            waiter_index_mapping[&id1],
+            waiter_index_mapping[&id2],
+            weight,
+        )
+    }
+
+    // Settings are in past meetings, which are scaled when they decay.
+    let scale = if settings.edge_half_life.is_some() {
+        EDGE_WEIGHT_SCALE as u32
+    } else {
+        1
+    };
+
+    // Weights can't go below zero, so rather than taking the bonus off for each shared interest every
+    // pair pays it for each interest they have fewer than the pair sharing the most.
+    if settings.interest_bonus > 0 {
+        let bonus = settings.interest_bonus * scale;
+        let shared = db.shared_interests(waiters)?;
+        let most = shared.values().copied().max().unwrap_or(0);
+        for i in 0..g.len() {
+            for j in i + 1..g.len() {
+                let (a, b) = (g.person(i), g.person(j));
+                let key = if a < b { (a, b) } else { (b, a) };
+                let missing = most - shared.get(&key).copied().unwrap_or(0);
+                g.add_edge(i, j, g.weight(i, j) + bonus * missing);
+            }
+        }
+    }
+
+    if settings.same_team_policy != SameTeamPolicy::Allow {
+        let mut teams: HashMap<String, Vec<usize>> = HashMap::new();
+        for (person, team) in db.teams(waiters)? {
+            teams
+                .entry(team)
+                .or_default()
+                .push(waiter_index_mapping[&person]);
+        }
+        for members in teams.values() {
+            for (k, i) in members.iter().copied().enumerate() {
+                for j in members[k + 1..].iter().copied() {
+                    if settings.same_team_policy == SameTeamPolicy::Forbid {
+                        g.forbid(i, j);
+                    } else {
+                        g.add_edge(i, j, g.weight(i, j) + settings.same_team_penalty * scale);
+                    }
+                }
+            }
+        }
+    }
+
+    for (id1, id2) in db.forbidden_pairs(waiters)? {
+        g.forbid(waiter_index_mapping[&id1], waiter_index_mapping[&id2]);
+    }
+
+    for (id1, id2) in db.conflict_pairs(waiters)? {
+        g.forbid(waiter_index_mapping[&id1], waiter_index_mapping[&id2]);
+    }
+
+    // Managers already meet their direct reports in 1:1s.
+    if !db.allows_manager_pairs(round_type)? {
+        for (id1, id2) in db.manager_pairs(waiters)? {
+            g.forbid(waiter_index_mapping[&id1], waiter_index_mapping[&id2]);
+        }
+    }
+
+    // With small pools every weight ends up equal, so repeats need ruling out entirely.
+    if settings.repeat_window > 0 {
+        for (id1, id2) in db.recent_pairs(waiters, settings.repeat_window, round_type)? {
+            g.forbid(waiter_index_mapping[&id1], waiter_index_mapping[&id2]);
+        }
+    }
+
+    for person in priority {
+        if let Some(index) = waiter_index_mapping.get(person) {
+            g.set_priority(*index);
+        }
+    }
+
+    Ok(g)
+}
+
+// How matching would weigh pairing someone with one of the other waiters in the next run.
+#[derive(Debug, Serialize)]
+pub struct PartnerWeight {
+    person: Person,
+    // Times they've been matched before, ignoring decay.
+    meetings: u32,
+    // The parts of `total`, in the units the graph uses.
+    history: u32,
+    interests: u32,
+    team: u32,
+    total: u32,
+    // Why the pair can't be matched next run at all.
+    blocked: Option<String>,
+}
+
+// Everyone `person_id` could be matched with in the next run of the round type, most likely first,
+// with each part of the weight `bucket_graph` would give the pair. They are treated as waiting even
+// if they aren't.
+fn partner_weights(
+    db: &Database,
+    person_id: PersonId,
+    settings: &MatchingSettings,
+    round_type: &str,
+) -> Result<Vec<PartnerWeight>, DatabaseError> {
+    let mut pool = db.waiters_for(round_type)?;
+    let at_season_limit = db.waiters_at_season_limit(season_start(db.now()))?;
+    pool.retain(|w| *w != person_id && !at_season_limit.contains(w));
+    pool.push(person_id);
+
+    let bucket: HashSet<PersonId> =
+        partition_waiters(db, &pool, settings.max_exact_pool_size, round_type)?
+            .into_iter()
+            .find(|bucket| bucket.contains(&person_id))
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+    let scale = if settings.edge_half_life.is_some() {
+        EDGE_WEIGHT_SCALE as u32
+    } else {
+        1
+    };
+    let with = |edges: Vec<(PersonId, PersonId, u32)>| -> HashMap<PersonId, u32> {
+        edges
+            .into_iter()
+            .filter_map(|(a, b, weight)| match (a == person_id, b == person_id) {
+                (true, _) => Some((b, weight)),
+                (_, true) => Some((a, weight)),
+                _ => None,
+            })
+            .collect()
+    };
+    let meetings = with(db.edges_for(pool.clone(), None)?);
+    let history = with(db.edges_for(pool.clone(), settings.edge_half_life)?);
+    let key = |other: PersonId| (person_id.min(other), person_id.max(other));
+
+    // The interest bonus is relative to the pair sharing the most within the bucket.
+    let shared = if settings.interest_bonus > 0 {
+        db.shared_interests(&pool)?
+    } else {
+        HashMap::new()
+    };
+    let most = shared
+        .iter()
+        .filter(|((a, b), _)| bucket.contains(a) && bucket.contains(b))
+        .map(|(_, count)| *count)
+        .max()
+        .unwrap_or(0);
+    let teams = db.teams(&pool)?;
+    let roles = if settings.mentoring {
+        db.mentoring_roles(&pool)?
+    } else {
+        HashMap::new()
+    };
+    let role = |person: &PersonId| roles.get(person).map(|(role, _)| *role);
+    let forbidden: HashSet<(PersonId, PersonId)> = db
+        .forbidden_pairs(&pool)?
+        .into_iter()
+        .map(|(a, b)| (a.min(b), a.max(b)))
+        .collect();
+    let conflicts: HashSet<(PersonId, PersonId)> = db.conflict_pairs(&pool)?.into_iter().collect();
+    let managers: HashSet<(PersonId, PersonId)> = if db.allows_manager_pairs(round_type)? {
+        HashSet::new()
+    } else {
+        db.manager_pairs(&pool)?
+            .into_iter()
+            .map(|(a, b)| (a.min(b), a.max(b)))
+            .collect()
+    };
+    let recent: HashSet<(PersonId, PersonId)> = if settings.repeat_window > 0 {
+        db.recent_pairs(&pool, settings.repeat_window, round_type)?
+            .into_iter()
+            .map(|(a, b)| (a.min(b), a.max(b)))
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    let mut partners: Vec<PartnerWeight> = pool
+        .iter()
+        .filter(|other| **other != person_id)
+        .filter_map(|other| {
+            let person = match db.get_person(*other) {
+                Ok(person) => person?,
+                Err(error) => return Some(Err(error)),
+            };
+            let history = history.get(other).copied().unwrap_or(0);
+            let interests = if settings.interest_bonus > 0 {
+                let missing = most.saturating_sub(shared.get(&key(*other)).copied().unwrap_or(0));
+                settings.interest_bonus * scale * missing
+            } else {
+                0
+            };
+            let same_team = teams
+                .get(&person_id)
+                .is_some_and(|team| teams.get(other) == Some(team));
+            let team = if same_team && settings.same_team_policy == SameTeamPolicy::Avoid {
+                settings.same_team_penalty * scale
+            } else {
+                0
+            };
+            let blocked = if !bucket.contains(other) {
+                Some("In a different bucket".to_owned())
+            } else if role(&person_id)
+                .is_some_and(|mine| mine != MentoringRole::Either && role(other) == Some(mine))
+            {
+                Some("Both want to be a mentor, or both a mentee".to_owned())
+            } else if same_team && settings.same_team_policy == SameTeamPolicy::Forbid {
+                Some("On the same team".to_owned())
+            } else if forbidden.contains(&key(*other)) {
+                Some("One of them asked never to be matched with the other".to_owned())
+            } else if conflicts.contains(&key(*other)) {
+                Some("In the same conflict of interest group".to_owned())
+            } else if managers.contains(&key(*other)) {
+                Some("One of them manages the other".to_owned())
+            } else if recent.contains(&key(*other)) {
+                Some(format!(
+                    "Matched within the last {} generations",
+                    settings.repeat_window
+                ))
+            } else {
+                None
+            };
+            Some(Ok(PartnerWeight {
+                person,
+                meetings: meetings.get(other).copied().unwrap_or(0),
+                history,
+                interests,
+                team,
+                total: history + interests + team,
+                blocked,
+            }))
+        })
+        .collect::<Result<_, _>>()?;
+    partners.sort_by(|a, b| {
+        (a.blocked.is_some(), a.total, &a.person.name).cmp(&(
+            b.blocked.is_some(),
+            b.total,
+            &b.person.name,
+        ))
+    });
+    Ok(partners)
+}
+
+// Why someone keeps getting matched with the same people: their potential partners for the next run,
+// ranked by the weights matching would use.
+// For admins of the person's organisation.
+pub async fn partner_weights_page(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Query(query): Query<RoundTypeQuery>,
+) -> Result<RenderedPage, AppError> {
+    let viewer = authorized.person_id;
+    if !state
+        .db
+        .call(move |db| db.is_admin_of(viewer, person_id))
+        .await?
+    {
+        return Err(AppError::Forbidden);
+    }
+    let round_type = query.round_type(&state);
+    let settings = state.matching_settings(&round_type);
+    let for_round = round_type.clone();
+    let (person, partners) = state
+        .db
+        .call(move |db| {
+            let Some(person) = db.get_person(person_id)? else {
+                return Ok(None);
+            };
+            Ok(Some((
+                person,
+                partner_weights(db, person_id, &settings, &for_round)?,
+            )))
+        })
+        .await?
+        .ok_or(AppError::NotFound)?;
+    let page = PartnerWeightsPage {
+        authorized_person_id: viewer,
+        person,
+        partners,
+        decayed: settings.edge_half_life.is_some(),
+        settings,
+        round_type,
+        round_types: state.round_types.clone(),
+    };
+    Ok(state.render_page(&page).await)
+}
+
+pub async fn toggle_waiter(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+) -> Result<Redirect, AppError> {
+    let viewer = authorized.person_id;
+    state
+        .db
+        .call(move |db| {
+            if db.can_manage(viewer, person_id)? {
+                db.toggle_waiter(person_id)?;
+            }
+            Ok(())
+        })
+        .await?;
+    Ok(Redirect::to(&format!("/person/{}", person_id)))
+}
+
+pub async fn generation_waiters(
+    State(state): State<AppState>,
+    Path(generation): Path<GenerationId>,
+) -> Result<Json<Vec<WaiterSnapshot>>, AppError> {
+    Ok(Json(
+        state
+            .db
+            .call(move |db| db.waiters_snapshot(generation))
+            .await?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GenerationsQuery {
+    since: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GenerationsPage {
+    generations: Vec<GenerationExport>,
+    // Pass back as `since` on the next sync to only fetch what changed.
+    cursor: i64,
+}
+
+pub async fn api_generations(
+    State(state): State<AppState>,
+    Query(query): Query<GenerationsQuery>,
+) -> Result<Json<GenerationsPage>, AppError> {
+    let since = query.since.unwrap_or(0);
+    let generations = state.db.call(move |db| db.generations_since(since)).await?;
+    let cursor = generations.last().map_or(since, |g| g.updated_at);
+    Ok(Json(GenerationsPage {
+        generations,
+        cursor,
+    }))
+}
+
+// An extractor for the JSON API, authorized by a bearer token from the person's page.
+pub struct ApiAuthorized {
+    // Whose token it is, they only see their own organisation.
+    person_id: PersonId,
+}
+
+#[async_trait::async_trait]
+impl<S> FromRequestParts<S> for ApiAuthorized
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let state = AppState::from_ref(state);
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let token = token.map(|token| token.trim().to_owned());
+        let person = match token {
+            Some(token) => state
+                .db
+                .call(move |db| db.api_token_person(&token))
+                .await
+                .map_err(|error| AppError::from(error).into_response())?,
+            None => None,
+        };
+        match person {
+            Some(person_id) => Ok(Self { person_id }),
+            None => Err((
+                StatusCode::UNAUTHORIZED,
+                Json(ApiError {
+                    error: "Missing or invalid API token.".to_owned(),
+                }),
+            )
+                .into_response()),
+        }
+    }
+}
+
+// An API caller who runs at least one pool, like `PoolAdmin`. Deployment admins run every pool and
+// the main rotation.
+pub struct ApiPoolAdmin {
+    person_id: PersonId,
+    pools: Option<Vec<String>>,
+}
+
+impl ApiPoolAdmin {
+    fn manages(&self, round_type: &str) -> bool {
+        match &self.pools {
+            Some(pools) => pools.iter().any(|pool| pool == round_type),
+            None => true,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> FromRequestParts<S> for ApiPoolAdmin
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let authorized = ApiAuthorized::from_request_parts(parts, state).await?;
+        let state = AppState::from_ref(state);
+        let person_id = authorized.person_id;
+        let pools = administered_pools(&state, person_id)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        if pools.as_ref().is_some_and(Vec::is_empty) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                Json(ApiError {
+                    error: "Only admins can do that.".to_owned(),
+                }),
+            )
+                .into_response());
+        }
+        Ok(Self { person_id, pools })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    error: String,
+}
+
+fn api_not_found() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ApiError {
+            error: "Not found.".to_owned(),
+        }),
+    )
+        .into_response()
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiMatches {
+    match_meta: MatchMeta,
+    matches: Vec<Match>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiPersonMatch {
+    generation: GenerationId,
+    time: u64,
+    round_type: String,
+    person: Person,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiPerson {
+    person: Person,
+    matches: Vec<ApiPersonMatch>,
+}
+
+pub async fn api_people(
+    State(state): State<AppState>,
+    authorized: ApiAuthorized,
+) -> Result<Json<Vec<Person>>, AppError> {
+    let viewer = authorized.person_id;
+    Ok(Json(
+        state
+            .db
+            .call(move |db| db.people_in(db.organisation_of(viewer)?))
+            .await?,
+    ))
+}
+
+pub async fn api_person(
+    State(state): State<AppState>,
+    authorized: ApiAuthorized,
+    Path(person_id): Path<PersonId>,
+) -> Result<Response, AppError> {
+    let viewer = authorized.person_id;
+    let person_and_matches = state
+        .db
+        .call(move |db| {
+            if !db.same_organisation(viewer, person_id)? {
+                return Ok(None);
+            }
+            let Some((person, matches)) = db.get_person_and_matches(person_id)? else {
+                return Ok(None);
+            };
+            let matches = Viewer::load(db, viewer)?.history(person_id, matches);
+            Ok(Some((person, matches)))
+        })
+        .await?;
+    let Some((person, matches)) = person_and_matches else {
+        return Ok(api_not_found());
+    };
+    let matches = matches
+        .into_iter()
+        .map(|m| ApiPersonMatch {
+            generation: m.generation,
+            time: m.time,
+            round_type: m.round_type,
+            person: m.partner,
+        })
+        .collect();
+    Ok(Json(ApiPerson { person, matches }).into_response())
+}
+
+pub async fn api_matches(
+    State(state): State<AppState>,
+    authorized: ApiAuthorized,
+    Query(query): Query<RoundTypeQuery>,
+) -> Result<Response, AppError> {
+    let viewer = authorized.person_id;
+    let round_type = query.round_type(&state);
+    let latest = state
+        .db
+        .call(move |db| {
+            let Some(meta) = db.latest_match_meta(&round_type)? else {
+                return Ok(None);
+            };
+            let rounds = db.matches_at(meta.generation)?.into_iter().collect();
+            Ok(visible_rounds(db, viewer, rounds)?.pop())
+        })
+        .await?;
+    match latest {
+        Some((match_meta, matches)) => Ok(Json(ApiMatches {
+            match_meta,
+            matches,
+        })
+        .into_response()),
+        None => Ok(api_not_found()),
+    }
+}
+
+pub async fn api_matches_generation(
+    State(state): State<AppState>,
+    authorized: ApiAuthorized,
+    Path(generation): Path<GenerationId>,
+) -> Result<Response, AppError> {
+    let viewer = authorized.person_id;
+    let found = state
+        .db
+        .call(move |db| {
+            let rounds = db.matches_at(generation)?.into_iter().collect();
+            Ok(visible_rounds(db, viewer, rounds)?.pop())
+        })
+        .await?;
+    match found {
+        Some((match_meta, matches)) => Ok(Json(ApiMatches {
+            match_meta,
+            matches,
+        })
+        .into_response()),
+        None => Ok(api_not_found()),
+    }
+}
+
+// The next round's waiters and the weights matching would use, for trying out other algorithms.
+// Waiters are matched in buckets, each on its own, so the weights are per bucket.
+#[derive(Debug, Serialize)]
+pub struct ApiWeights {
+    round_type: String,
+    settings: MatchingSettings,
+    // Waiters left out of this round, and why.
+    excluded: Vec<(PersonId, String)>,
+    // People brought in to even up an organisation's numbers, already in a bucket.
+    floaters: Vec<PersonId>,
+    buckets: Vec<ApiWeightsBucket>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiWeightsBucket {
+    people: Vec<PersonId>,
+    // The weight of pairing each of `people` with each other, lower is better.
+    weights: Vec<Vec<u32>>,
+    // Pairs that can never be matched.
+    forbidden: Vec<(PersonId, PersonId)>,
+    // Who sat out last time so shouldn't again.
+    priority: Vec<PersonId>,
+}
+
+pub async fn api_weights(
+    State(state): State<AppState>,
+    admin: ApiPoolAdmin,
+    Query(query): Query<RoundTypeQuery>,
+) -> Result<Response, AppError> {
+    let round_type = query.round_type(&state);
+    if !admin.manages(&round_type) {
+        return Ok((
+            StatusCode::FORBIDDEN,
+            Json(ApiError {
+                error: "Only admins can do that.".to_owned(),
+            }),
+        )
+            .into_response());
+    }
+    if !is_round_type(&state, &round_type).await? {
+        return Ok(api_not_found());
+    }
+    let settings = state.matching_settings(&round_type);
+    let planned = round_type.clone();
+    let plan = state
+        .db
+        .call(move |db| plan_matching(db, &settings, &planned))
+        .await?;
+    let buckets = plan
+        .buckets
+        .iter()
+        .map(|bucket| {
+            let graph = &bucket.graph;
+            let nodes = 0..graph.len();
+            let mut forbidden = Vec::new();
+            for i in nodes.clone() {
+                for j in i + 1..graph.len() {
+                    if graph.is_forbidden(i, j) {
+                        forbidden.push((graph.person(i), graph.person(j)));
+                    }
+                }
+            }
+            ApiWeightsBucket {
+                people: nodes.clone().map(|i| graph.person(i)).collect(),
+                weights: nodes
+                    .clone()
+                    .map(|i| nodes.clone().map(|j| graph.weight(i, j)).collect())
+                    .collect(),
+                forbidden,
+                priority: nodes
+                    .filter(|i| graph.has_priority(*i))
+                    .map(|i| graph.person(i))
+                    .collect(),
+            }
+        })
+        .collect();
+    Ok(Json(ApiWeights {
+        round_type,
+        settings,
+        excluded: plan.excluded,
+        floaters: plan.floaters,
+        buckets,
+    })
+    .into_response())
+}
+
+// Groups chosen by another algorithm, by person id.
+#[derive(Debug, Deserialize)]
+pub struct ApiExternalMatching {
+    groups: Vec<Vec<PersonId>>,
+}
+
+// Save an externally chosen matching as the next generation, telling everyone as usual. Pool admins
+// can only save their own pools' matchings.
+pub async fn api_record_matches(
+    State(state): State<AppState>,
+    admin: ApiPoolAdmin,
+    Query(query): Query<RoundTypeQuery>,
+    Json(matching): Json<ApiExternalMatching>,
+) -> Result<Response, AppError> {
+    let round_type = query.round_type(&state);
+    let api_error =
+        |status: StatusCode, error: String| Ok((status, Json(ApiError { error })).into_response());
+    if !admin.manages(&round_type) {
+        return api_error(StatusCode::FORBIDDEN, "Only admins can do that.".to_owned());
+    }
+    tracing::info!(
+        admin = admin.person_id.0,
+        round_type,
+        "External matching submitted"
+    );
+    let meta = match record_external_matching(&state, &round_type, matching.groups).await {
+        Ok(Some(meta)) => meta,
+        Ok(None) => return api_error(StatusCode::CONFLICT, "Nobody is waiting.".to_owned()),
+        Err(MatchingError::Database(error)) => return Err(error.into()),
+        Err(MatchingError::UnknownRoundType(_)) => return Ok(api_not_found()),
+        Err(error @ MatchingError::InvalidGroups(_)) => {
+            return api_error(StatusCode::BAD_REQUEST, error.to_string())
+        }
+        Err(error) => return api_error(StatusCode::CONFLICT, error.to_string()),
+    };
+    let viewer = admin.person_id;
+    let saved = state
+        .db
+        .call(move |db| {
+            let rounds = db.matches_at(meta.generation)?.into_iter().collect();
+            Ok(visible_rounds(db, viewer, rounds)?.pop())
+        })
+        .await?;
+    match saved {
+        Some((match_meta, matches)) => Ok((
+            StatusCode::CREATED,
+            Json(ApiMatches {
+                match_meta,
+                matches,
+            }),
+        )
+            .into_response()),
+        None => Ok(api_not_found()),
+    }
+}
+
+// Generate a new API token, it is only shown this once.
+pub async fn create_api_token(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+) -> Result<Response, AppError> {
+    if authorized.person_id != person_id {
+        return Ok(Redirect::to(&format!("/person/{}", person_id)).into_response());
+    }
+    let token = state
+        .db
+        .call(move |db| db.create_api_token(person_id))
+        .await?;
+    let page = ApiTokenPage {
+        authorized_person_id: authorized.person_id,
+        token,
+    };
+    Ok(state.render_page(&page).await.into_response())
+}
+
+pub async fn revoke_api_token(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+) -> Result<Redirect, AppError> {
+    if authorized.person_id == person_id {
+        state
+            .db
+            .call(move |db| db.revoke_api_token(person_id))
+            .await?;
+    }
+    Ok(Redirect::to(&format!("/person/{}", person_id)))
+}
+
+// Liveness, answering at all means the process is up.
+pub async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    database: bool,
+    templates: bool,
+}
+
+// Readiness, whether requests can be served: the database answers and the templates loaded.
+pub async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let readiness = Readiness {
+        database: state.db.call(|db| Ok(db.ping())).await.unwrap_or(false),
+        templates: state.tera.get_template("error.html").is_ok(),
+    };
+    let status = if readiness.database && readiness.templates {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsQuery {
+    // Defaults to the default organisation.
+    organisation: Option<OrganisationId>,
+}
+
+pub async fn stats(
+    State(state): State<AppState>,
+    Query(query): Query<StatsQuery>,
+) -> Result<Json<Stats>, AppError> {
+    let organisation = query.organisation.unwrap_or(DEFAULT_ORGANISATION);
+    Ok(Json(state.db.call(move |db| db.stats(organisation)).await?))
+}
+
+// Totals anyone can see, when enabled with `public_stats`. People who are signed in see their own
+// organisation's, anyone else the default organisation's.
+pub async fn public_stats(
+    State(state): State<AppState>,
+    authorized: Option<Authorized>,
+) -> Result<RenderedPage, AppError> {
+    if !state.public_stats {
+        return Err(AppError::NotFound);
+    }
+    let stats = state
+        .db
+        .call(move |db| {
+            let organisation = match authorized {
+                Some(authorized) => db.organisation_of(authorized.person_id)?,
+                None => DEFAULT_ORGANISATION,
+            };
+            db.public_stats(organisation)
+        })
+        .await?;
+    Ok(state.render_page(&PublicStatsPage { stats }).await)
+}
+
+// Counters in Prometheus' text format.
+pub async fn metrics(State(state): State<AppState>) -> Response {
+    let busy = &state.db.busy;
+    let counters = [
+        (
+            "matcher_database_busy_retries_total",
+            "Database work run again after finding the database busy.",
+            busy.retries.load(std::sync::atomic::Ordering::Relaxed),
+        ),
+        (
+            "matcher_database_busy_failures_total",
+            "Database work given up on because the database stayed busy.",
+            busy.failures.load(std::sync::atomic::Ordering::Relaxed),
+        ),
+    ];
+    let mut body = String::new();
+    for (name, help, value) in counters {
+        body.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n"
+        ));
+    }
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+pub async fn waiting_history(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<WaitingDay>>, AppError> {
+    Ok(Json(state.db.call(|db| db.waiting_history()).await?))
+}
+
+// A row of an org chart, `manager` being the email of who they report to.
+#[derive(Debug, Deserialize)]
+pub struct ManagerImport {
+    email: String,
+    manager: Option<String>,
+}
+
+pub async fn import_managers(
+    State(state): State<AppState>,
+    Json(people): Json<Vec<ManagerImport>>,
+) -> Result<Json<ManagersImport>, AppError> {
+    let people: Vec<_> = people
+        .into_iter()
+        .map(|p| (p.email, p.manager.filter(|m| !m.trim().is_empty())))
+        .collect();
+    Ok(Json(
+        state.db.call(move |db| db.import_managers(&people)).await?,
+    ))
+}
+
+// The response from Slack's `users.list` API method.
+// Import every member of the Slack workspace with an email as an inactive account, fetched with
+// the bot token. Names are cleaned like any other, falling back to the start of the email.
+pub async fn import_slack(State(state): State<AppState>) -> Result<Response, AppError> {
+    let listed = match state.slack.list_members().await {
+        Ok(listed) => listed,
+        Err(SlackError::NoBotToken) => {
+            return Ok((
+                StatusCode::CONFLICT,
+                "Set MATCHER_SLACK_BOT_TOKEN to import members from Slack.",
+            )
+                .into_response())
+        }
+        Err(error) => {
+            tracing::warn!(%error, "Failed to list Slack members");
+            return Ok((StatusCode::BAD_GATEWAY, error.to_string()).into_response());
+        }
+    };
+    let mut skipped = 0;
+    let members: Vec<_> = listed
+        .into_iter()
+        .filter(|m| !m.deleted && !m.is_bot)
+        .filter_map(|m| {
+            let cleaned = m.profile.email.as_deref().and_then(|email| {
+                let email = clean_email("email", email).ok()?;
+                let (local, _) = email.rsplit_once('@').unwrap_or_default();
+                let name = m
+                    .profile
+                    .real_name
+                    .as_deref()
+                    .and_then(|name| clean_text("name", name, MAX_NAME_LENGTH).ok())
+                    .or_else(|| clean_text("name", local, MAX_NAME_LENGTH).ok())?;
+                Some((m.id, email, name))
+            });
+            if cleaned.is_none() {
+                skipped += 1;
+            }
+            cleaned
+        })
+        .collect();
+    let count = members.len();
+    let created = state
+        .db
+        .call(move |db| db.import_slack_members(&members))
+        .await?;
+    Ok(
+        Json(serde_json::json!({ "members": count, "created": created, "skipped": skipped }))
+            .into_response(),
+    )
+}
+
+// Show the groups matching would make right now without saving anything.
+pub async fn preview_matching(
+    State(state): State<AppState>,
+    Query(query): Query<RoundTypeQuery>,
+    Query(strategy): Query<StrategyQuery>,
+) -> Result<Json<MatchingPlan>, AppError> {
+    let round_type = query.round_type(&state);
+    let mut settings = state.matching_settings(&round_type);
+    settings.strategy = strategy.strategy.unwrap_or(settings.strategy);
+    Ok(Json(
+        state
+            .db
+            .call(move |db| plan_matching(db, &settings, &round_type))
+            .await?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PoolExportQuery {
+    team: Option<String>,
+}
+
+pub async fn export_pool(
+    State(state): State<AppState>,
+    Query(query): Query<PoolExportQuery>,
+) -> Result<Json<PoolExport>, AppError> {
+    Ok(Json(
+        state
+            .db
+            .call(move |db| db.export_pool(query.team.as_deref()))
+            .await?,
+    ))
+}
+
+// Where to import a pool to, e.g. `?organisation=2&pool=lunch`, and `&branding=true` or
+// `&schedule=true` to take those from the export as well.
+#[derive(Debug, Deserialize)]
+pub struct PoolImportQuery {
+    organisation: Option<OrganisationId>,
+    pool: Option<String>,
+    #[serde(default)]
+    branding: bool,
+    #[serde(default)]
+    schedule: bool,
+}
+
+pub async fn import_pool(
+    State(state): State<AppState>,
+    Query(query): Query<PoolImportQuery>,
+    Json(pool): Json<PoolExport>,
+) -> Result<Response, AppError> {
+    let options = PoolImportOptions {
+        organisation: query.organisation.unwrap_or(DEFAULT_ORGANISATION),
+        pool: query.pool.filter(|pool| !pool.is_empty()),
+        branding: query.branding,
+        schedule: query.schedule,
+    };
+    state.snapshots.create(&state.db, "import pool").await?;
+    let imported = state
+        .db
+        .call(move |db| db.import_pool(&pool, &options))
+        .await?;
+    let response = match imported {
+        Ok(summary) => {
+            if query.schedule {
+                state.schedule_changed.notify_one();
+            }
+            Json::<PoolImport>(summary).into_response()
+        }
+        Err(PoolImportError::HasGenerations) => (
+            StatusCode::CONFLICT,
+            "This deployment already has generations, history can only be imported into one without any.",
+        )
+            .into_response(),
+        Err(PoolImportError::UnknownMember(id)) => (
+            StatusCode::BAD_REQUEST,
+            format!("Generations refer to member {id}, who isn't in the export."),
+        )
+            .into_response(),
+        Err(PoolImportError::UnknownOrganisation(id)) => (
+            StatusCode::NOT_FOUND,
+            format!("There is no organisation {}.", id.0),
+        )
+            .into_response(),
+        Err(PoolImportError::UnknownPool(pool)) => (
+            StatusCode::NOT_FOUND,
+            format!("There is no active pool called {pool}, create it first."),
+        )
+            .into_response(),
+    };
+    Ok(response)
+}
+
+pub async fn notification_progress(
+    State(state): State<AppState>,
+    Path(generation): Path<GenerationId>,
+) -> Result<Json<SendProgress>, StatusCode> {
+    state
+        .mailer
+        .progress(generation)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClockView {
+    now: DateTime<Utc>,
+    offset_seconds: i64,
+}
+
+pub async fn clock(State(state): State<AppState>) -> Json<ClockView> {
+    Json(ClockView {
+        now: state.clock.now(),
+        offset_seconds: state.clock.offset_seconds(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdvanceClock {
+    seconds: i64,
+}
+
+// Move a test clock forward, e.g. a week to see sessions expire and pauses end. Only available
+// with `test_clock`, and it can't go back.
+pub async fn advance_clock(
+    State(state): State<AppState>,
+    Json(advance): Json<AdvanceClock>,
+) -> Result<Response, AppError> {
+    let now = match state.clock.advance(advance.seconds) {
+        Ok(now) => now,
+        Err(AdvanceError::RealClock) => return Err(AppError::NotFound),
+        Err(AdvanceError::OutOfRange) => {
+            let message = "The clock can only move forward, and at most 100 years ahead";
+            return Ok((StatusCode::BAD_REQUEST, message).into_response());
+        }
+    };
+    tracing::info!(%now, seconds = advance.seconds, "Advanced the test clock");
+    Ok(Json(ClockView {
+        now,
+        offset_seconds: state.clock.offset_seconds(),
+    })
+    .into_response())
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatsRecompute {
+    #[serde(flatten)]
+    edges: EdgesRebuild,
+    duration_ms: u128,
+}
+
+// Rebuild the meeting counts matching weighs from the match history, e.g. after importing or
+// fixing matches by hand.
+pub async fn recompute_stats(
+    State(state): State<AppState>,
+) -> Result<Json<StatsRecompute>, AppError> {
+    let start = Instant::now();
+    let edges = state.db.call(|db| db.rebuild_edges()).await?;
+    let duration_ms = start.elapsed().as_millis();
+    tracing::info!(
+        matches = edges.matches,
+        edges = edges.edges,
+        changed = edges.changed,
+        duration_ms,
+        "Recomputed stats"
+    );
+    Ok(Json(StatsRecompute { edges, duration_ms }))
+}
+
+pub async fn stale_accounts(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<StaleAccount>>, AppError> {
+    Ok(Json(state.db.call(|db| db.stale_accounts()).await?))
+}
+
+pub async fn keep_stale_account(
+    State(state): State<AppState>,
+    Path(person_id): Path<PersonId>,
+) -> Result<StatusCode, AppError> {
+    let now = state.clock.timestamp();
+    state
+        .db
+        .call(move |db| db.keep_stale_account(person_id, now))
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_stale_account(
+    State(state): State<AppState>,
+    Path(person_id): Path<PersonId>,
+) -> Result<StatusCode, AppError> {
+    // No snapshot is taken first, deleting an account is meant to be for good.
+    if state
+        .db
+        .call(move |db| db.delete_stale_account(person_id))
+        .await?
+    {
+        state
+            .snapshots
+            .erase(person_id, state.clock.timestamp())
+            .await;
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+pub async fn add_admin(
+    State(state): State<AppState>,
+    Path(person_id): Path<PersonId>,
+) -> Result<StatusCode, AppError> {
+    let added = state
+        .db
+        .call(move |db| {
+            if db.get_person(person_id)?.is_none() {
+                return Ok(false);
+            }
+            db.add_admin(person_id)?;
+            Ok(true)
+        })
+        .await?;
+    if added {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+pub async fn remove_admin(
+    State(state): State<AppState>,
+    Path(person_id): Path<PersonId>,
+) -> Result<StatusCode, AppError> {
+    state.db.call(move |db| db.remove_admin(person_id)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Appoint a pool admin from the private port, before anyone can do it from the web.
+pub async fn add_pool_admin(
+    State(state): State<AppState>,
+    Path((pool, person_id)): Path<(String, PersonId)>,
+) -> Result<StatusCode, AppError> {
+    if state
+        .db
+        .call(move |db| db.add_pool_admin(&pool, person_id))
+        .await?
+    {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+pub async fn remove_pool_admin(
+    State(state): State<AppState>,
+    Path((pool, person_id)): Path<(String, PersonId)>,
+) -> Result<StatusCode, AppError> {
+    state
+        .db
+        .call(move |db| db.remove_pool_admin(&pool, person_id))
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn deactivate(
+    State(state): State<AppState>,
+    Path(person_id): Path<PersonId>,
+) -> Result<StatusCode, AppError> {
+    let now = state.clock.timestamp();
+    if state
+        .db
+        .call(move |db| db.deactivate(person_id, now))
+        .await?
+    {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+pub async fn reactivate(
+    State(state): State<AppState>,
+    Path(person_id): Path<PersonId>,
+) -> Result<StatusCode, AppError> {
+    state.db.call(move |db| db.reactivate(person_id)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn organisations(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Organisation>>, AppError> {
+    Ok(Json(state.db.call(|db| db.organisations()).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewOrganisation {
+    name: String,
+}
+
+// People join it by signing up from `/join/<sign_up_token>`.
+pub async fn create_organisation(
+    State(state): State<AppState>,
+    Json(organisation): Json<NewOrganisation>,
+) -> Result<Response, AppError> {
+    let name = match clean_text("name", &organisation.name, MAX_NAME_LENGTH) {
+        Ok(name) => name,
+        Err(error) => return Ok((StatusCode::BAD_REQUEST, error.to_string()).into_response()),
+    };
+    let organisation = state
+        .db
+        .call(move |db| db.create_organisation(&name))
+        .await?;
+    tracing::info!(
+        organisation = organisation.id.0,
+        name = organisation.name,
+        "Organisation created"
+    );
+    Ok((StatusCode::CREATED, Json(organisation)).into_response())
+}
+
+// Move someone to another organisation, e.g. someone who signed up without its link.
+pub async fn set_organisation(
+    State(state): State<AppState>,
+    Path((person_id, organisation)): Path<(PersonId, OrganisationId)>,
+) -> Result<StatusCode, AppError> {
+    let moved = state
+        .db
+        .call(move |db| db.set_organisation(person_id, organisation))
+        .await?;
+    if moved {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+pub async fn add_floater(
+    State(state): State<AppState>,
+    Path(person_id): Path<PersonId>,
+) -> Result<StatusCode, AppError> {
+    let added = state
+        .db
+        .call(move |db| {
+            if db.get_person(person_id)?.is_none() {
+                return Ok(false);
+            }
+            db.add_floater(person_id)?;
+            Ok(true)
+        })
+        .await?;
+    if added {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+pub async fn remove_floater(
+    State(state): State<AppState>,
+    Path(person_id): Path<PersonId>,
+) -> Result<StatusCode, AppError> {
+    state
+        .db
+        .call(move |db| db.remove_floater(person_id))
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Status {
+    status: String,
+}
+
+pub async fn set_status(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(status): Form<Status>,
+) -> Result<Response, AppError> {
+    if authorized.person_id != person_id {
+        return Ok(Redirect::to(&format!("/person/{}", person_id)).into_response());
+    }
+    let status = match clean_text("status", &status.status, MAX_STATUS_LENGTH) {
+        Ok(status) => Some(status),
+        Err(ValidationError::Empty { .. }) => None,
+        Err(error) => {
+            let page = ErrorPage {
+                authorized_person_id: Some(authorized.person_id),
+                error: error.to_string(),
+            };
+            return Ok(invalid_form(state.render_page(&page).await, error));
+        }
+    };
+    state
+        .db
+        .call(move |db| db.set_status(person_id, status.as_deref()))
+        .await?;
+    Ok(Redirect::to(&format!("/person/{}", person_id)).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MaxMatches {
+    max_matches_per_season: String,
+}
+
+pub async fn set_max_matches(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(max_matches): Form<MaxMatches>,
+) -> Result<Redirect, AppError> {
+    if authorized.person_id == person_id {
+        let max_matches = max_matches.max_matches_per_season.trim().parse().ok();
+        state
+            .db
+            .call(move |db| db.set_max_matches_per_season(person_id, max_matches))
+            .await?;
+    }
+    Ok(Redirect::to(&format!("/person/{}", person_id)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MatchCadence {
+    match_every_rounds: String,
+}
+
+// How often the person wants to be matched, in rounds. Anything but a number over 1 is every round.
+pub async fn set_match_cadence(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(cadence): Form<MatchCadence>,
+) -> Result<Redirect, AppError> {
+    if authorized.person_id == person_id {
+        let every_rounds = cadence
+            .match_every_rounds
+            .trim()
+            .parse()
+            .ok()
+            .filter(|n| *n > 1);
+        state
+            .db
+            .call(move |db| db.set_match_every_rounds(person_id, every_rounds))
+            .await?;
+    }
+    Ok(Redirect::to(&format!("/person/{}", person_id)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Mentoring {
+    role: String,
+    mentee_capacity: String,
+}
+
+// Which side of mentoring rounds the person is on, and how many mentees they take as a mentor.
+pub async fn set_mentoring(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(mentoring): Form<Mentoring>,
+) -> Result<Redirect, AppError> {
+    let viewer = authorized.person_id;
+    let role = mentoring.role.parse::<MentoringRole>().ok();
+    let capacity = mentoring
+        .mentee_capacity
+        .trim()
+        .parse()
+        .ok()
+        .filter(|n| (1..=MAX_MENTEE_CAPACITY).contains(n));
+    if let (Some(role), Some(capacity)) = (role, capacity) {
+        state
+            .db
+            .call(move |db| {
+                if db.can_manage(viewer, person_id)? {
+                    db.set_mentoring(person_id, role, capacity)?;
+                }
+                Ok(())
+            })
+            .await?;
+    }
+    Ok(Redirect::to(&format!("/person/{}", person_id)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Pause {
+    // From a date input, `YYYY-MM-DD`.
+    until: String,
+}
+
+// Stop waiting until a date, e.g. for a holiday. Matching resumes at the start of that day in the
+// person's timezone.
+pub async fn pause(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(pause): Form<Pause>,
+) -> Result<Response, AppError> {
+    let viewer = authorized.person_id;
+    let (allowed, timezone) = state
+        .db
+        .call(move |db| Ok((db.can_manage(viewer, person_id)?, db.timezone(person_id)?)))
+        .await?;
+    if !allowed {
+        return Ok(Redirect::to(&format!("/person/{}", person_id)).into_response());
+    }
+    let timezone: chrono_tz::Tz = timezone
+        .and_then(|tz| tz.parse().ok())
+        .unwrap_or(chrono_tz::UTC);
+    let until = chrono::NaiveDate::parse_from_str(pause.until.trim(), "%Y-%m-%d")
+        .ok()
+        .and_then(|date| {
+            timezone
+                .from_local_datetime(&date.and_time(NaiveTime::MIN))
+                .earliest()
+        })
+        .map(|until| until.timestamp())
+        .filter(|until| *until > state.clock.timestamp());
+    let Some(until) = until else {
+        let error = ValidationError::Invalid {
+            field: "date",
+            expected: "a day in the future",
+        };
+        let page = ErrorPage {
+            authorized_person_id: Some(authorized.person_id),
+            error: error.to_string(),
+        };
+        return Ok(invalid_form(state.render_page(&page).await, error));
+    };
+    state.db.call(move |db| db.pause(person_id, until)).await?;
+    Ok(Redirect::to(&format!("/person/{}", person_id)).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Timezone {
+    timezone: String,
+}
+
+pub async fn set_timezone(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(timezone): Form<Timezone>,
+) -> Result<Response, AppError> {
+    if authorized.person_id != person_id {
+        return Ok(Redirect::to(&format!("/person/{}", person_id)).into_response());
+    }
+    let timezone = match clean_timezone(&timezone.timezone) {
+        Ok(timezone) => Some(timezone),
+        Err(ValidationError::Empty { .. }) => None,
+        Err(error) => {
+            let page = ErrorPage {
+                authorized_person_id: Some(authorized.person_id),
+                error: error.to_string(),
+            };
+            return Ok(invalid_form(state.render_page(&page).await, error));
+        }
+    };
+    state
+        .db
+        .call(move |db| db.set_timezone(person_id, timezone.as_deref()))
+        .await?;
+    Ok(Redirect::to(&format!("/person/{}", person_id)).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailNotifications {
+    enabled: bool,
+}
+
+pub async fn set_email_notifications(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(notifications): Form<EmailNotifications>,
+) -> Result<Redirect, AppError> {
+    if authorized.person_id == person_id {
+        state
+            .db
+            .call(move |db| db.set_email_notifications(person_id, notifications.enabled))
+            .await?;
+    }
+    Ok(Redirect::to(&format!("/person/{}", person_id)))
+}
+
+// Links in emails land here, they only show a confirmation as link scanners follow them.
+pub async fn unsubscribe(State(state): State<AppState>, Path(token): Path<String>) -> RenderedPage {
+    let page = UnsubscribePage {
+        token: Some(token),
+        unsubscribed: false,
+    };
+    state.render_page(&page).await
+}
+
+// Both the confirmation form and one-click unsubscribes from mail clients (RFC 8058) post here.
+pub async fn do_unsubscribe(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Response, AppError> {
+    if state.db.call(move |db| db.unsubscribe(&token)).await? {
+        let page = UnsubscribePage {
+            token: None,
+            unsubscribed: true,
+        };
+        Ok(state.render_page(&page).await.into_response())
+    } else {
+        let page = ErrorPage {
+            authorized_person_id: None,
+            error: "That unsubscribe link isn't valid.".to_owned(),
+        };
+        Ok((StatusCode::NOT_FOUND, state.render_page(&page).await).into_response())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Block {
+    block: bool,
+}
+
+// Stop (or allow again) the signed in person being matched with this person.
+pub async fn block(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(block): Form<Block>,
+) -> Result<Redirect, AppError> {
+    let viewer = authorized.person_id;
+    if viewer != person_id {
+        state
+            .db
+            .call(move |db| {
+                if block.block {
+                    db.block(viewer, person_id)
+                } else {
+                    db.unblock(viewer, person_id)
+                }
+            })
+            .await?;
+    }
+    Ok(Redirect::to(&format!("/person/{}", person_id)))
+}
+
+pub async fn branding(
+    State(state): State<AppState>,
+    admin: Admin,
+) -> Result<RenderedPage, AppError> {
+    let branding = state.db.call(|db| db.branding()).await?;
+    let page = BrandingPage::new(admin.person_id, branding);
+    Ok(state.render_page(&page).await)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BrandingForm {
+    product_name: String,
+    logo_url: String,
+    accent_colour: String,
+    // One link per line, the label followed by the URL.
+    footer_links: String,
+}
+
+fn parse_branding(form: &BrandingForm) -> Result<Branding, ValidationError> {
+    let logo_url = match clean_url("logo URL", &form.logo_url) {
+        Ok(url) => Some(url),
+        Err(ValidationError::Empty { .. }) => None,
+        Err(error) => return Err(error),
+    };
+    let mut footer_links = Vec::new();
+    for line in form.footer_links.lines().filter(|l| !l.trim().is_empty()) {
+        let (label, url) = line
+            .trim()
+            .rsplit_once(char::is_whitespace)
+            .unwrap_or(("", line));
+        footer_links.push(FooterLink {
+            label: clean_text("footer link label", label, MAX_NAME_LENGTH)?,
+            url: clean_url("footer link URL", url)?,
+        });
+    }
+    Ok(Branding {
+        product_name: clean_text("product name", &form.product_name, MAX_NAME_LENGTH)?,
+        logo_url,
+        accent_colour: clean_colour("accent colour", &form.accent_colour)?,
+        footer_links,
+    })
+}
+
+pub async fn set_branding(
+    State(state): State<AppState>,
+    admin: Admin,
+    Form(form): Form<BrandingForm>,
+) -> Result<Response, AppError> {
+    match parse_branding(&form) {
+        Ok(branding) => {
+            tracing::info!(admin = admin.person_id.0, "Branding updated");
+            state.db.call(move |db| db.set_branding(&branding)).await?;
+            Ok(Redirect::to("/branding").into_response())
+        }
+        Err(error) => {
+            let page = BrandingPage {
+                authorized_person_id: admin.person_id,
+                error: Some(error.to_string()),
+                product_name: form.product_name,
+                logo_url: form.logo_url,
+                accent_colour: form.accent_colour,
+                footer_links: form.footer_links,
+            };
+            Ok(invalid_form(state.render_page(&page).await, error))
+        }
+    }
+}
+
+async fn snapshots_page(
+    state: &AppState,
+    admin: PersonId,
+    error: Option<String>,
+) -> Result<RenderedPage, AppError> {
+    let snapshots = state.snapshots.list().map_err(|error| {
+        tracing::error!(%error, "Failed to list database snapshots");
+        AppError::Internal
+    })?;
+    let page = SnapshotsPage {
+        authorized_person_id: admin,
+        error,
+        snapshots,
+    };
+    Ok(state.render_page(&page).await)
+}
+
+async fn pools_page(
+    state: &AppState,
+    admin: PersonId,
+    error: Option<String>,
+) -> Result<RenderedPage, AppError> {
+    let administered = administered_pools(state, admin).await?;
+    let mut pools = state.db.call(|db| db.pools()).await?;
+    if let Some(administered) = &administered {
+        pools.retain(|pool| administered.contains(&pool.name));
+    }
+    let page = PoolsPage {
+        authorized_person_id: admin,
+        error,
+        is_admin: administered.is_none(),
+        pools,
+    };
+    Ok(state.render_page(&page).await)
+}
+
+pub async fn pools(
+    State(state): State<AppState>,
+    admin: PoolAdmin,
+) -> Result<RenderedPage, AppError> {
+    pools_page(&state, admin.person_id, None).await
+}
+
+async fn pool_page(
+    state: &AppState,
+    admin: PersonId,
+    pool: String,
+    error: Option<String>,
+) -> Result<RenderedPage, AppError> {
+    let found = state
+        .db
+        .call(move |db| {
+            let Some(found) = db.pools()?.into_iter().find(|p| p.name == pool) else {
+                return Ok(None);
+            };
+            Ok(Some((db.pool_members(&pool)?, found)))
+        })
+        .await?;
+    let Some((members, pool)) = found else {
+        return Err(AppError::NotFound);
+    };
+    let page = PoolPage {
+        authorized_person_id: admin,
+        error,
+        pool,
+        members,
+    };
+    Ok(state.render_page(&page).await)
+}
+
+pub async fn pool(
+    State(state): State<AppState>,
+    admin: PoolAdmin,
+    Path(pool): Path<String>,
+) -> Result<RenderedPage, AppError> {
+    if !admin.manages(&pool) {
+        return Err(AppError::Forbidden);
+    }
+    pool_page(&state, admin.person_id, pool, None).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PoolMembersForm {
+    // Separated by commas, spaces or new lines.
+    emails: String,
+}
+
+// Add people to a pool by their email addresses, they start waiting for its next round.
+pub async fn add_pool_members(
+    State(state): State<AppState>,
+    admin: PoolAdmin,
+    Path(pool): Path<String>,
+    Form(form): Form<PoolMembersForm>,
+) -> Result<Response, AppError> {
+    if !admin.manages(&pool) {
+        return Err(AppError::Forbidden);
+    }
+    let emails: Vec<String> = form
+        .emails
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|email| !email.is_empty())
+        .map(str::to_owned)
+        .collect();
+    let name = pool.clone();
+    let unknown = state
+        .db
+        .call(move |db| {
+            let mut unknown = Vec::new();
+            for email in emails {
+                match db.person_with_email(&email)? {
+                    Some(person_id) => db.set_pool_member(&name, person_id, true)?,
+                    None => unknown.push(email),
+                }
+            }
+            Ok(unknown)
+        })
+        .await?;
+    tracing::info!(admin = admin.person_id.0, pool, "Pool members added");
+    if unknown.is_empty() {
+        return Ok(Redirect::to(&format!("/pools/{pool}")).into_response());
+    }
+    let error = format!("Nobody has signed up as {}.", unknown.join(", "));
+    let page = pool_page(&state, admin.person_id, pool, Some(error.clone())).await?;
+    Ok(invalid_form(
+        page,
+        FieldError::new("emails", "unknown", &error),
+    ))
+}
+
+pub async fn remove_pool_member(
+    State(state): State<AppState>,
+    admin: PoolAdmin,
+    Path((pool, person_id)): Path<(String, PersonId)>,
+) -> Result<Redirect, AppError> {
+    if !admin.manages(&pool) {
+        return Err(AppError::Forbidden);
+    }
+    let name = pool.clone();
+    state
+        .db
+        .call(move |db| db.set_pool_member(&name, person_id, false))
+        .await?;
+    tracing::info!(
+        admin = admin.person_id.0,
+        pool,
+        person = person_id.0,
+        "Pool member removed"
+    );
+    Ok(Redirect::to(&format!("/pools/{pool}")))
+}
+
+pub async fn toggle_pool_member_waiting(
+    State(state): State<AppState>,
+    admin: PoolAdmin,
+    Path((pool, person_id)): Path<(String, PersonId)>,
+) -> Result<Redirect, AppError> {
+    if !admin.manages(&pool) {
+        return Err(AppError::Forbidden);
+    }
+    let name = pool.clone();
+    state
+        .db
+        .call(move |db| db.toggle_pool_waiter(&name, person_id))
+        .await?;
+    Ok(Redirect::to(&format!("/pools/{pool}")))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PoolAdminForm {
+    email: String,
+}
+
+// Appoint someone to run a pool, they can then manage its members and trigger its rounds.
+pub async fn appoint_pool_admin(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(pool): Path<String>,
+    Form(form): Form<PoolAdminForm>,
+) -> Result<Response, AppError> {
+    let email = form.email.trim().to_owned();
+    let name = pool.clone();
+    let appointed = state
+        .db
+        .call(move |db| {
+            let Some(person_id) = db.person_with_email(&email)? else {
+                return Ok(None);
+            };
+            Ok(Some((person_id, db.add_pool_admin(&name, person_id)?)))
+        })
+        .await?;
+    match appointed {
+        Some((person_id, true)) => {
+            tracing::info!(
+                admin = admin.person_id.0,
+                pool,
+                person = person_id.0,
+                "Pool admin appointed"
+            );
+            Ok(Redirect::to("/pools").into_response())
+        }
+        Some((_, false)) => Err(AppError::NotFound),
+        None => {
+            let error = format!("Nobody has signed up as {}.", form.email.trim());
+            let page = pools_page(&state, admin.person_id, Some(error.clone())).await?;
+            Ok(invalid_form(
+                page,
+                FieldError::new("email", "unknown", &error),
+            ))
+        }
+    }
+}
+
+pub async fn dismiss_pool_admin(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path((pool, person_id)): Path<(String, PersonId)>,
+) -> Result<Redirect, AppError> {
+    let name = pool.clone();
+    state
+        .db
+        .call(move |db| db.remove_pool_admin(&name, person_id))
+        .await?;
+    tracing::info!(
+        admin = admin.person_id.0,
+        pool,
+        person = person_id.0,
+        "Pool admin removed"
+    );
+    Ok(Redirect::to("/pools"))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PoolForm {
+    name: String,
+    #[serde(default)]
+    description: String,
+}
+
+pub async fn create_pool(
+    State(state): State<AppState>,
+    admin: Admin,
+    Form(form): Form<PoolForm>,
+) -> Result<Response, AppError> {
+    let cleaned = clean_pool_name(&form.name).and_then(|name| {
+        let description = match clean_text("description", &form.description, MAX_DESCRIPTION_LENGTH)
+        {
+            Err(ValidationError::Empty { .. }) => String::new(),
+            description => description?,
+        };
+        Ok((name, description))
+    });
+    let (name, description) = match cleaned {
+        Ok(cleaned) => cleaned,
+        Err(error) => {
+            let page = pools_page(&state, admin.person_id, Some(error.to_string())).await?;
+            return Ok(invalid_form(page, error));
+        }
+    };
+    // Their generations would be mixed up with the round type's.
+    let created = !state.round_types.contains(&name) && {
+        let name = name.clone();
+        state
+            .db
+            .call(move |db| db.create_pool(&name, &description))
+            .await?
+    };
+    if !created {
+        let error = format!("There is already a pool or round type called {name}.");
+        let page = pools_page(&state, admin.person_id, Some(error.clone())).await?;
+        return Ok(invalid_form(page, FieldError::new("name", "taken", &error)));
+    }
+    tracing::info!(admin = admin.person_id.0, pool = name, "Pool created");
+    Ok(Redirect::to("/pools").into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ManagerPairsForm {
+    allowed: bool,
+}
+
+// Let managers be matched with their direct reports in a pool, or keep them apart again.
+pub async fn set_allow_manager_pairs(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(pool): Path<String>,
+    Form(form): Form<ManagerPairsForm>,
+) -> Result<Redirect, AppError> {
+    let name = pool.clone();
+    let updated = state
+        .db
+        .call(move |db| db.set_allow_manager_pairs(&name, form.allowed))
+        .await?;
+    if !updated {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!(
+        admin = admin.person_id.0,
+        pool,
+        allowed = form.allowed,
+        "Manager pairs changed"
+    );
+    Ok(Redirect::to("/pools"))
+}
+
+// Archive a pool straight away rather than waiting for it to be idle.
+pub async fn archive_pool(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(pool): Path<String>,
+) -> Result<Redirect, AppError> {
+    let reason = format!("archive pool {pool}");
+    state.snapshots.create(&state.db, &reason).await?;
+    let name = pool.clone();
+    if !state.db.call(move |db| db.archive_pool(&name)).await? {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!(admin = admin.person_id.0, pool, "Pool archived");
+    Ok(Redirect::to("/pools"))
+}
+
+// Bring an archived pool's matches back so it can be joined and run again.
+pub async fn reactivate_pool(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(pool): Path<String>,
+) -> Result<Redirect, AppError> {
+    let name = pool.clone();
+    if !state.db.call(move |db| db.reactivate_pool(&name)).await? {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!(admin = admin.person_id.0, pool, "Pool reactivated");
+    Ok(Redirect::to("/pools"))
+}
+
+async fn conflicts_page(
+    state: &AppState,
+    admin: PersonId,
+    error: Option<String>,
+) -> Result<RenderedPage, AppError> {
+    let groups = state.db.call(|db| db.conflict_groups()).await?;
+    let page = ConflictsPage {
+        authorized_person_id: admin,
+        error,
+        groups,
+    };
+    Ok(state.render_page(&page).await)
+}
+
+pub async fn conflicts(
+    State(state): State<AppState>,
+    admin: Admin,
+) -> Result<RenderedPage, AppError> {
+    conflicts_page(&state, admin.person_id, None).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConflictGroupForm {
+    name: String,
+}
+
+pub async fn create_conflict_group(
+    State(state): State<AppState>,
+    admin: Admin,
+    Form(form): Form<ConflictGroupForm>,
+) -> Result<Response, AppError> {
+    let name = match clean_text("name", &form.name, MAX_NAME_LENGTH) {
+        Ok(name) => name,
+        Err(error) => {
+            let page = conflicts_page(&state, admin.person_id, Some(error.to_string())).await?;
+            return Ok(invalid_form(page, error));
+        }
+    };
+    let created = name.clone();
+    let group = state
+        .db
+        .call(move |db| db.create_conflict_group(&created))
+        .await?;
+    tracing::info!(
+        admin = admin.person_id.0,
+        group = group.0,
+        name,
+        "Conflict group created"
+    );
+    Ok(Redirect::to("/conflicts").into_response())
+}
+
+pub async fn delete_conflict_group(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(group): Path<ConflictGroupId>,
+) -> Result<Redirect, AppError> {
+    if !state
+        .db
+        .call(move |db| db.delete_conflict_group(group))
+        .await?
+    {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!(
+        admin = admin.person_id.0,
+        group = group.0,
+        "Conflict group deleted"
+    );
+    Ok(Redirect::to("/conflicts"))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConflictMembersForm {
+    // Separated by commas, spaces or new lines.
+    emails: String,
+}
+
+// Add several people to a conflict group at once, e.g. a whole interview panel.
+pub async fn add_conflict_group_members(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(group): Path<ConflictGroupId>,
+    Form(form): Form<ConflictMembersForm>,
+) -> Result<Response, AppError> {
+    let emails: Vec<String> = form
+        .emails
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|email| !email.is_empty())
+        .map(str::to_owned)
+        .collect();
+    let Some(unknown) = state
+        .db
+        .call(move |db| db.add_conflict_group_members(group, &emails))
+        .await?
+    else {
+        return Err(AppError::NotFound);
+    };
+    tracing::info!(
+        admin = admin.person_id.0,
+        group = group.0,
+        "Conflict group members added"
+    );
+    if unknown.is_empty() {
+        return Ok(Redirect::to("/conflicts").into_response());
+    }
+    let error = format!("Nobody has signed up as {}.", unknown.join(", "));
+    let page = conflicts_page(&state, admin.person_id, Some(error.clone())).await?;
+    Ok(invalid_form(
+        page,
+        FieldError::new("emails", "unknown", &error),
+    ))
+}
+
+pub async fn remove_conflict_group_member(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path((group, person_id)): Path<(ConflictGroupId, PersonId)>,
+) -> Result<Redirect, AppError> {
+    state
+        .db
+        .call(move |db| db.remove_conflict_group_member(group, person_id))
+        .await?;
+    tracing::info!(
+        admin = admin.person_id.0,
+        group = group.0,
+        person = person_id.0,
+        "Conflict group member removed"
+    );
+    Ok(Redirect::to("/conflicts"))
+}
+
+async fn icebreakers_page(
+    state: &AppState,
+    admin: PersonId,
+    error: Option<String>,
+) -> Result<RenderedPage, AppError> {
+    let icebreakers = state.db.call(|db| db.icebreakers()).await?;
+    let page = IcebreakersPage {
+        authorized_person_id: admin,
+        error,
+        icebreakers,
+    };
+    Ok(state.render_page(&page).await)
+}
+
+pub async fn icebreakers(
+    State(state): State<AppState>,
+    admin: Admin,
+) -> Result<RenderedPage, AppError> {
+    icebreakers_page(&state, admin.person_id, None).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IcebreakerForm {
+    prompt: String,
+}
+
+pub async fn create_icebreaker(
+    State(state): State<AppState>,
+    admin: Admin,
+    Form(form): Form<IcebreakerForm>,
+) -> Result<Response, AppError> {
+    let prompt = match clean_text("prompt", &form.prompt, MAX_ICEBREAKER_LENGTH) {
+        Ok(prompt) => prompt,
+        Err(error) => {
+            let page = icebreakers_page(&state, admin.person_id, Some(error.to_string())).await?;
+            return Ok(invalid_form(page, error));
+        }
+    };
+    let icebreaker = state
+        .db
+        .call(move |db| db.create_icebreaker(&prompt))
+        .await?;
+    tracing::info!(
+        admin = admin.person_id.0,
+        icebreaker = icebreaker.0,
+        "Icebreaker created"
+    );
+    Ok(Redirect::to("/icebreakers").into_response())
+}
+
+// Changing a prompt only affects matches made from now on.
+pub async fn update_icebreaker(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(icebreaker): Path<IcebreakerId>,
+    Form(form): Form<IcebreakerForm>,
+) -> Result<Response, AppError> {
+    let prompt = match clean_text("prompt", &form.prompt, MAX_ICEBREAKER_LENGTH) {
+        Ok(prompt) => prompt,
+        Err(error) => {
+            let page = icebreakers_page(&state, admin.person_id, Some(error.to_string())).await?;
+            return Ok(invalid_form(page, error));
+        }
+    };
+    if !state
+        .db
+        .call(move |db| db.update_icebreaker(icebreaker, &prompt))
+        .await?
+    {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!(
+        admin = admin.person_id.0,
+        icebreaker = icebreaker.0,
+        "Icebreaker updated"
+    );
+    Ok(Redirect::to("/icebreakers").into_response())
+}
+
+pub async fn delete_icebreaker(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(icebreaker): Path<IcebreakerId>,
+) -> Result<Redirect, AppError> {
+    if !state
+        .db
+        .call(move |db| db.delete_icebreaker(icebreaker))
+        .await?
+    {
+        return Err(AppError::NotFound);
+    }
+    tracing::info!(
+        admin = admin.person_id.0,
+        icebreaker = icebreaker.0,
+        "Icebreaker deleted"
+    );
+    Ok(Redirect::to("/icebreakers"))
+}
+
+async fn mailings_page(
+    state: &AppState,
+    admin: PersonId,
+    segment: Option<Segment>,
+    error: Option<String>,
+) -> Result<RenderedPage, AppError> {
+    let (segments, members, mailings) = state
+        .db
+        .call(move |db| {
+            let organisation = db.organisation_of(admin)?;
+            let segments = Segment::ALL
+                .into_iter()
+                .map(|segment| {
+                    Ok(SegmentSize {
+                        segment,
+                        size: db.segment_members(organisation, segment)?.len(),
+                    })
+                })
+                .collect::<Result<_, DatabaseError>>()?;
+            let members = match segment {
+                Some(segment) => db.segment_members(organisation, segment)?,
+                None => Vec::new(),
+            };
+            Ok((segments, members, db.mailings(organisation)?))
+        })
+        .await?;
+    let page = MailingsPage {
+        authorized_person_id: admin,
+        error,
+        emailing: state.mailer.is_configured(),
+        segments,
+        segment,
+        members,
+        mailings,
+    };
+    Ok(state.render_page(&page).await)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SegmentQuery {
+    segment: Option<Segment>,
+}
+
+// Pick a segment to see who is in it before emailing them.
+pub async fn mailings(
+    State(state): State<AppState>,
+    admin: Admin,
+    Query(query): Query<SegmentQuery>,
+) -> Result<RenderedPage, AppError> {
+    mailings_page(&state, admin.person_id, query.segment, None).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MailingForm {
+    segment: Segment,
+    subject: String,
+    message: String,
+}
+
+// Email everyone in the segment, in the background like match emails. Anyone who turned emails off
+// is recorded as suppressed rather than emailed.
+pub async fn send_mailing(
+    State(state): State<AppState>,
+    admin: Admin,
+    Form(form): Form<MailingForm>,
+) -> Result<Response, AppError> {
+    let segment = form.segment;
+    let cleaned = clean_text("subject", &form.subject, MAX_SUBJECT_LENGTH).and_then(|subject| {
+        let message = clean_multiline_text("message", &form.message, MAX_MESSAGE_LENGTH)?;
+        Ok((subject, message))
+    });
+    let (subject, message) = match cleaned {
+        Ok(cleaned) => cleaned,
+        Err(error) => {
+            let page = mailings_page(
+                &state,
+                admin.person_id,
+                Some(segment),
+                Some(error.to_string()),
+            )
+            .await?;
+            return Ok(invalid_form(page, error));
+        }
+    };
+    if !state.mailer.is_configured() {
+        let error = "No mail server is configured, so nothing can be sent.".to_owned();
+        let page = mailings_page(&state, admin.person_id, Some(segment), Some(error)).await?;
+        return Ok(page.into_response());
+    }
+    let sender = admin.person_id;
+    let (stored_subject, stored_message) = (subject.clone(), message.clone());
+    let created = state
+        .db
+        .call(move |db| {
+            let organisation = db.organisation_of(sender)?;
+            let members = db.segment_members(organisation, segment)?;
+            if members.is_empty() {
+                return Ok(None);
+            }
+            let people: Vec<PersonId> = members.iter().map(|p| p.id).collect();
+            let tokens = db.email_recipients(&people)?;
+            let mailing = db.create_mailing(
+                segment,
+                &stored_subject,
+                &stored_message,
+                sender,
+                &people,
+                &tokens,
+            )?;
+            let recipients: Vec<(Person, String)> = members
+                .into_iter()
+                .filter_map(|person| {
+                    let token = tokens.get(&person.id)?.clone();
+                    Some((person, token))
+                })
+                .collect();
+            Ok(Some((mailing, recipients, db.branding()?)))
+        })
+        .await?;
+    let Some((mailing, recipients, branding)) = created else {
+        let error = "Nobody is in that segment.".to_owned();
+        let page = mailings_page(&state, admin.person_id, Some(segment), Some(error)).await?;
+        return Ok(page.into_response());
+    };
+    tracing::info!(
+        admin = admin.person_id.0,
+        mailing = mailing.0,
+        ?segment,
+        recipients = recipients.len(),
+        "Mailing sent"
+    );
+    let mailer = state.mailer.clone();
+    let db = state.db.clone();
+    let notifying = state.notifying.clone().read_owned().await;
+    let span = tracing::info_span!("mailing", mailing = mailing.0);
+    tokio::spawn(
+        async move {
+            let results: Vec<_> = mailer
+                .send_mailing(&subject, &message, &recipients, &branding)
+                .await
+                .into_iter()
+                .map(|(person_id, result)| (person_id, sent_status(&result)))
+                .collect();
+            // A failure has been logged, and leaves the rest pending.
+            let _ = db
+                .call(move |db| {
+                    for (person_id, (status, error)) in results {
+                        db.set_mailing_recipient(mailing, person_id, status, error.as_deref())?;
+                    }
+                    Ok(())
+                })
+                .await;
+            drop(notifying);
+        }
+        .instrument(span),
+    );
+    Ok(Redirect::to("/mailings").into_response())
+}
+
+// Remind everyone who isn't waiting about the run at `round_at`, each with a link to opt in,
+// returning how many were emailed. Only those emailed stay recorded as reminded, so anyone whose
+// email failed, or who turns emails back on before the run, is still reminded.
+pub async fn send_round_reminders(
+    state: &AppState,
+    round_at: DateTime<Utc>,
+) -> Result<usize, DatabaseError> {
+    if !state.mailer.is_configured() {
+        return Ok(0);
+    }
+    let at = round_at.timestamp();
+    let (recipients, timezones, branding) = state
+        .db
+        .call(move |db| {
+            let people = db.reminder_recipients(at)?;
+            let ids: Vec<PersonId> = people.iter().map(|p| p.id).collect();
+            let unsubscribe_tokens = db.email_recipients(&ids)?;
+            let mut recipients = Vec::new();
+            for person in people {
+                let Some(unsubscribe_token) = unsubscribe_tokens.get(&person.id).cloned() else {
+                    continue;
+                };
+                let opt_in_token = db.create_reminder(at, person.id)?;
+                recipients.push((person, unsubscribe_token, opt_in_token));
+            }
+            Ok((recipients, db.timezones(&ids)?, db.branding()?))
+        })
+        .await?;
+    if recipients.is_empty() {
+        return Ok(0);
+    }
+    let notifying = state.notifying.read().await;
+    let results = state
+        .mailer
+        .send_reminders(round_at, &recipients, &timezones, &branding)
+        .await;
+    drop(notifying);
+    let failed: Vec<PersonId> = results
+        .iter()
+        .filter(|(_, result)| result.is_err())
+        .map(|(person_id, _)| *person_id)
+        .collect();
+    let emailed = results.len() - failed.len();
+    if !failed.is_empty() {
+        state
+            .db
+            .call(move |db| db.forget_reminders(at, &failed))
+            .await?;
+    }
+    Ok(emailed)
+}
+
+// The link in a reminder, which asks them to confirm so mail scanners fetching it don't opt anyone
+// in.
+pub async fn opt_in(State(state): State<AppState>, Path(token): Path<String>) -> RenderedPage {
+    let page = OptInPage {
+        token: Some(token),
+        round_at: None,
+    };
+    state.render_page(&page).await
+}
+
+// Start whoever the reminder was for waiting without signing in. Only starting to wait, so
+// confirming again changes nothing more.
+pub async fn do_opt_in(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Response, AppError> {
+    match state.db.call(move |db| db.opt_in(&token)).await? {
+        Some((person_id, round_at)) => {
+            tracing::info!(
+                person_id = person_id.0,
+                round_at,
+                "Opted in from a reminder"
+            );
+            let page = OptInPage {
+                token: None,
+                round_at: Some(round_at),
+            };
+            Ok(state.render_page(&page).await.into_response())
+        }
+        None => {
+            let page = ErrorPage {
+                authorized_person_id: None,
+                error: "That link has expired or isn't valid.".to_owned(),
+            };
+            Ok((StatusCode::NOT_FOUND, state.render_page(&page).await).into_response())
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PoolMemberForm {
+    member: bool,
+}
+
+// Join or leave a pool, for the person themselves, an admin or the pool's admins.
+pub async fn set_pool_member(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path((person_id, pool)): Path<(PersonId, String)>,
+    Form(form): Form<PoolMemberForm>,
+) -> Result<Redirect, AppError> {
+    let viewer = authorized.person_id;
+    state
+        .db
+        .call(move |db| {
+            if db.can_manage(viewer, person_id)? || db.is_pool_admin(viewer, &pool)? {
+                db.set_pool_member(&pool, person_id, form.member)?;
+            }
+            Ok(())
+        })
+        .await?;
+    Ok(Redirect::to(&format!("/person/{}", person_id)))
+}
+
+pub async fn toggle_pool_waiter(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path((person_id, pool)): Path<(PersonId, String)>,
+) -> Result<Redirect, AppError> {
+    let viewer = authorized.person_id;
+    state
+        .db
+        .call(move |db| {
+            if db.can_manage(viewer, person_id)? || db.is_pool_admin(viewer, &pool)? {
+                db.toggle_pool_waiter(&pool, person_id)?;
+            }
+            Ok(())
+        })
+        .await?;
+    Ok(Redirect::to(&format!("/person/{}", person_id)))
+}
+
+pub async fn snapshots(
+    State(state): State<AppState>,
+    admin: Admin,
+) -> Result<RenderedPage, AppError> {
+    snapshots_page(&state, admin.person_id, None).await
+}
+
+// Put the whole database back to how it was when the snapshot was taken. Anyone who signed in
+// since is signed out, including possibly the admin.
+pub async fn restore_snapshot(
+    State(state): State<AppState>,
+    admin: Admin,
+    Path(name): Path<String>,
+) -> Result<Response, AppError> {
+    let Ok(_running) = state.matching_lock.try_lock() else {
+        let error = MatchingError::AlreadyRunning.to_string();
+        return Ok(snapshots_page(&state, admin.person_id, Some(error))
+            .await?
+            .into_response());
+    };
+    // Notifications still sending would record what they sent over the restored database.
+    let _notifying = state.notifying.write().await;
+    tracing::info!(
+        admin = admin.person_id.0,
+        snapshot = name,
+        "Restoring snapshot"
+    );
+    if !state.snapshots.restore(&state.db, &name).await? {
+        return Err(AppError::NotFound);
+    }
+    // Generations unfinished in the snapshot were cut short when it was taken, or are still being
+    // emailed about.
+    let interrupted = state.db.call(|db| db.interrupted_generations()).await?;
+    *state.interrupted.lock().unwrap() = interrupted.iter().map(|g| g.generation).collect();
+    state.schedule_changed.notify_one();
+    Ok(Redirect::to("/snapshots").into_response())
+}
+
+// How many upcoming runs to show when looking at the schedule.
+const SCHEDULE_PREVIEW_RUNS: usize = 5;
+
+pub async fn current_schedule(state: &AppState) -> Result<Option<ScheduleConfig>, DatabaseError> {
+    let default_schedule = state.default_schedule.clone();
+    Ok(state
+        .db
+        .call(|db| db.schedule())
+        .await?
+        .or(default_schedule))
+}
+
+async fn save_schedule(state: &AppState, schedule: ScheduleConfig) -> Result<(), AppError> {
+    state.db.call(move |db| db.set_schedule(&schedule)).await?;
+    state.schedule_changed.notify_one();
+    Ok(())
+}
+
+pub async fn schedule(
+    State(state): State<AppState>,
+    admin: Admin,
+) -> Result<RenderedPage, AppError> {
+    let page = match current_schedule(&state).await? {
+        Some(schedule) => SchedulePage {
+            authorized_person_id: admin.person_id,
+            error: None,
+            start: Some(schedule.start.to_rfc3339()),
+            every_days: schedule.every_days,
+            cron: schedule.cron.clone(),
+            min_waiters: schedule.min_waiters,
+            enabled: schedule.enabled,
+            next_runs: schedule
+                .next_runs(state.clock.now(), SCHEDULE_PREVIEW_RUNS)
+                .iter()
+                .map(|run| run.timestamp())
+                .collect(),
+        },
+        None => SchedulePage {
+            authorized_person_id: admin.person_id,
+            error: None,
+            start: None,
+            every_days: 7,
+            cron: None,
+            min_waiters: 0,
+            enabled: false,
+            next_runs: Vec::new(),
+        },
+    };
+    Ok(state.render_page(&page).await)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduleForm {
+    start: String,
+    every_days: i64,
+    // Left empty to run every `every_days`.
+    #[serde(default)]
+    cron: String,
+    min_waiters: usize,
+    // Checkboxes are only sent when ticked.
+    enabled: Option<String>,
+}
+
+pub async fn set_schedule(
+    State(state): State<AppState>,
+    admin: Admin,
+    Form(form): Form<ScheduleForm>,
+) -> Result<Response, AppError> {
+    let schedule = clean_timestamp("start", &form.start).and_then(|start| {
+        Ok(ScheduleConfig {
+            start,
+            every_days: clean_every_days(form.every_days)?,
+            cron: clean_cron(&form.cron)?,
+            min_waiters: form.min_waiters,
+            enabled: form.enabled.is_some(),
+        })
+    });
+    match schedule {
+        Ok(schedule) => {
+            tracing::info!(admin = admin.person_id.0, "Schedule updated");
+            save_schedule(&state, schedule).await?;
+            Ok(Redirect::to("/schedule").into_response())
+        }
+        Err(error) => {
+            let page = SchedulePage {
+                authorized_person_id: admin.person_id,
+                error: Some(error.to_string()),
+                start: Some(form.start),
+                every_days: form.every_days,
+                cron: Some(form.cron),
+                min_waiters: form.min_waiters,
+                enabled: form.enabled.is_some(),
+                next_runs: Vec::new(),
+            };
+            Ok(invalid_form(state.render_page(&page).await, error))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScheduleView {
+    schedule: Option<ScheduleConfig>,
+    next_runs: Vec<DateTime<Utc>>,
+}
+
+async fn schedule_view(state: &AppState) -> Result<ScheduleView, AppError> {
+    let schedule = current_schedule(state).await?;
+    let next_runs = schedule
+        .as_ref()
+        .filter(|schedule| schedule.enabled)
+        .map(|schedule| schedule.next_runs(state.clock.now(), SCHEDULE_PREVIEW_RUNS))
+        .unwrap_or_default();
+    Ok(ScheduleView {
+        schedule,
+        next_runs,
+    })
+}
+
+pub async fn api_schedule(State(state): State<AppState>) -> Result<Json<ScheduleView>, AppError> {
+    Ok(Json(schedule_view(&state).await?))
+}
+
+pub async fn api_set_schedule(
+    State(state): State<AppState>,
+    Json(mut schedule): Json<ScheduleConfig>,
+) -> Result<Response, AppError> {
+    let cleaned = clean_every_days(schedule.every_days)
+        .and_then(|_| clean_cron(schedule.cron.as_deref().unwrap_or_default()));
+    match cleaned {
+        Ok(cron) => schedule.cron = cron,
+        Err(error) => return Ok((StatusCode::BAD_REQUEST, error.to_string()).into_response()),
+    }
+    save_schedule(&state, schedule).await?;
+    Ok(Json(schedule_view(&state).await?).into_response())
+}
+
+pub async fn season_plan(
+    State(state): State<AppState>,
+    Query(query): Query<RoundTypeQuery>,
+) -> Result<Response, AppError> {
+    let round_type = query.round_type(&state);
+    let season = state.db.call(move |db| db.season_plan(&round_type)).await?;
+    Ok(match season {
+        Some(season) => Json(season).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+// Plan a round robin over everyone waiting for the round type, so each run after pairs them as
+// one round of it until everyone has met everyone else.
+pub async fn create_season_plan(
+    State(state): State<AppState>,
+    Query(query): Query<RoundTypeQuery>,
+) -> Result<Response, AppError> {
+    let round_type = query.round_type(&state);
+    if !is_round_type(&state, &round_type).await? {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let season = state
+        .db
+        .call(move |db| {
+            let waiters = db.waiters_for(&round_type)?;
+            db.create_season_plan(&round_type, &round_robin_schedule(&waiters))
+        })
+        .await?;
+    tracing::info!(
+        round_type = season.round_type,
+        rounds = season.rounds,
+        "Planned season"
+    );
+    Ok(Json(season).into_response())
+}
+
+// Go back to matching each round as it comes.
+pub async fn cancel_season_plan(
+    State(state): State<AppState>,
+    Query(query): Query<RoundTypeQuery>,
+) -> Result<StatusCode, AppError> {
+    let round_type = query.round_type(&state);
+    let cancelled = state
+        .db
+        .call(move |db| db.cancel_season_plan(&round_type))
+        .await?;
+    Ok(if cancelled {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+pub async fn fallback() -> Redirect {
+    Redirect::to("/")
+}
+
+// Quote a CSV field if it contains anything that would break the row. Fields that a spreadsheet
+// would run as a formula get a leading `'` so they are shown as text instead.
+fn csv_escape(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{field}")
+    } else {
+        field.to_owned()
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+// Every pair who have met with their raw count and the decayed weight matching uses, so organisers
+// can audit why people were or weren't paired.
+pub async fn edges_csv(State(state): State<AppState>, _admin: Admin) -> Result<Response, AppError> {
+    let edge_half_life = state.matching.edge_half_life;
+    let (edges, people) = state
+        .db
+        .call(move |db| Ok((db.edge_history(edge_half_life)?, db.all_people()?)))
+        .await?;
+    let names: HashMap<PersonId, String> = people.into_iter().map(|p| (p.id, p.name)).collect();
+    let mut csv =
+        "person1,person1_name,person2,person2_name,count,last_generation,effective_weight\r\n"
+            .to_owned();
+    for edge in edges {
+        // Deleted accounts can still appear in old matches.
+        let (Some(name1), Some(name2)) = (names.get(&edge.person1), names.get(&edge.person2))
+        else {
+            continue;
+        };
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{:.3}\r\n",
+            edge.person1,
+            csv_escape(name1),
+            edge.person2,
+            csv_escape(name2),
+            edge.count,
+            edge.last_generation,
+            edge.effective_weight,
+        ));
+    }
+    Ok((
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (CONTENT_DISPOSITION, "attachment; filename=\"edges.csv\""),
+        ],
+        csv,
+    )
+        .into_response())
+}
+
+// Everything that went into matching a generation, for investigating disputed pairings offline.
+pub async fn matching_audit(
+    State(state): State<AppState>,
+    _admin: Admin,
+    Path(generation): Path<GenerationId>,
+) -> Result<Response, AppError> {
+    match state.db.call(move |db| db.audit(generation)).await? {
+        Some(audit) => Ok((
+            [
+                (CONTENT_TYPE, "application/json".to_owned()),
+                (
+                    CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"generation-{generation}-audit.json\""),
+                ),
+            ],
+            audit,
+        )
+            .into_response()),
+        None => Err(AppError::NotFound),
+    }
+}
+
+// The profile editing page, with an error or a note about what just happened.
+async fn edit_person_page(
+    state: &AppState,
+    person_id: PersonId,
+    error: Option<FieldError>,
+    info: Option<&str>,
+) -> Result<Response, AppError> {
+    let now = state.clock.timestamp();
+    let page = state
+        .db
+        .call(move |db| {
+            let Some(person) = db.get_person(person_id)? else {
+                return Ok(None);
+            };
+            Ok(Some((
+                person,
+                db.pending_email_change(person_id, now)?,
+                db.interests(person_id)?,
+                db.team_names(db.organisation_of(person_id)?)?,
+                db.contact_fields(person_id)?,
+            )))
+        })
+        .await?;
+    let Some((person, pending_email, interests, teams, contact_fields)) = page else {
+        return Ok(Redirect::to("/").into_response());
+    };
+    let page = EditPersonPage {
+        authorized_person_id: person_id,
+        error: error.as_ref().map(|error| error.message.clone()),
+        info: info.map(str::to_owned),
+        id: person.id,
+        name: person.name,
+        email: person.email,
+        pending_email,
+        interests: interests.join(", "),
+        team: person.team,
+        teams,
+        contact_fields: contact_fields
+            .into_iter()
+            .map(|field| (field.kind, field))
+            .collect(),
+    };
+    let page = state.render_page(&page).await;
+    Ok(match error {
+        Some(error) => invalid_form(page, error),
+        None => page.into_response(),
+    })
+}
+
+pub async fn edit_person(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+) -> Result<Response, AppError> {
+    if authorized.person_id != person_id {
+        return Ok(Redirect::to(&format!("/person/{}", person_id)).into_response());
+    }
+    edit_person_page(&state, person_id, None, None).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditName {
+    name: String,
+}
+
+pub async fn edit_name(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(edit): Form<EditName>,
+) -> Result<Response, AppError> {
+    if authorized.person_id != person_id {
+        return Ok(Redirect::to(&format!("/person/{}", person_id)).into_response());
+    }
+    let name = match clean_text("name", &edit.name, MAX_NAME_LENGTH) {
+        Ok(name) => name,
+        Err(error) => return edit_person_page(&state, person_id, Some(error.into()), None).await,
+    };
+    state
+        .db
+        .call(move |db| db.set_name(person_id, &name))
+        .await?;
+    edit_person_page(&state, person_id, None, Some("Your name has been updated.")).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditTeam {
+    team: String,
+}
+
+pub async fn edit_team(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(edit): Form<EditTeam>,
+) -> Result<Response, AppError> {
+    if authorized.person_id != person_id {
+        return Ok(Redirect::to(&format!("/person/{}", person_id)).into_response());
+    }
+    let team = match clean_team(&edit.team) {
+        Ok(team) => team,
+        Err(error) => return edit_person_page(&state, person_id, Some(error.into()), None).await,
+    };
+    state
+        .db
+        .call(move |db| db.set_team(person_id, team.as_deref()))
+        .await?;
+    edit_person_page(&state, person_id, None, Some("Your team has been updated.")).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditContact {
+    phone: String,
+    phone_visibility: String,
+    slack: String,
+    slack_visibility: String,
+    teams: String,
+    teams_visibility: String,
+}
+
+fn parse_contact_fields(edit: &EditContact) -> Result<Vec<ContactField>, ValidationError> {
+    let mut fields = Vec::new();
+    for (kind, value, visibility) in [
+        (ContactKind::Phone, &edit.phone, &edit.phone_visibility),
+        (ContactKind::Slack, &edit.slack, &edit.slack_visibility),
+        (ContactKind::Teams, &edit.teams, &edit.teams_visibility),
+    ] {
+        if value.trim().is_empty() {
+            continue;
+        }
+        let value = match kind {
+            ContactKind::Phone => clean_phone(value)?,
+            ContactKind::Slack => clean_text("Slack handle", value, MAX_HANDLE_LENGTH)?,
+            ContactKind::Teams => clean_email("Teams email", value)?,
+        };
+        let visibility = ContactVisibility::parse(visibility).ok_or(ValidationError::Invalid {
+            field: "visibility",
+            expected: "matches, everyone or admins",
+        })?;
+        fields.push(ContactField {
+            kind,
+            value,
+            visibility,
+        });
+    }
+    Ok(fields)
+}
+
+pub async fn edit_contact(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(edit): Form<EditContact>,
+) -> Result<Response, AppError> {
+    if authorized.person_id != person_id {
+        return Ok(Redirect::to(&format!("/person/{}", person_id)).into_response());
+    }
+    let fields = match parse_contact_fields(&edit) {
+        Ok(fields) => fields,
+        Err(error) => return edit_person_page(&state, person_id, Some(error.into()), None).await,
+    };
+    state
+        .db
+        .call(move |db| db.set_contact_fields(person_id, &fields))
+        .await?;
+    edit_person_page(
+        &state,
+        person_id,
+        None,
+        Some("Your contact details have been updated."),
+    )
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditInterests {
+    interests: String,
+}
+
+pub async fn edit_interests(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(edit): Form<EditInterests>,
+) -> Result<Response, AppError> {
+    if authorized.person_id != person_id {
+        return Ok(Redirect::to(&format!("/person/{}", person_id)).into_response());
+    }
+    let interests = match clean_interests(&edit.interests) {
+        Ok(interests) => interests,
+        Err(error) => return edit_person_page(&state, person_id, Some(error.into()), None).await,
+    };
+    state
+        .db
+        .call(move |db| db.set_interests(person_id, &interests))
+        .await?;
+    edit_person_page(
+        &state,
+        person_id,
+        None,
+        Some("Your interests have been updated."),
+    )
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditEmail {
+    email: String,
+}
+
+// New addresses have to be confirmed from a link sent to them, unless there is no mail server to
+// send it with.
+pub async fn edit_email(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(edit): Form<EditEmail>,
+) -> Result<Response, AppError> {
+    if authorized.person_id != person_id {
+        return Ok(Redirect::to(&format!("/person/{}", person_id)).into_response());
+    }
+    let email = match clean_email("email", &edit.email) {
+        Ok(email) => email,
+        Err(error) => return edit_person_page(&state, person_id, Some(error.into()), None).await,
+    };
+    let now = state.clock.timestamp();
+    let verify = state.mailer.is_configured();
+    let to = email.clone();
+    let requested = state
+        .db
+        .call(move |db| {
+            let Some(token) = db.request_email_change(person_id, &email, now)? else {
+                return Ok(None);
+            };
+            if !verify && db.confirm_email_change(&token, now)?.is_none() {
+                return Ok(None);
+            }
+            let Some(person) = db.get_person(person_id)? else {
+                return Ok(None);
+            };
+            Ok(Some((token, person.name, db.branding()?)))
+        })
+        .await?;
+    let Some((token, name, branding)) = requested else {
+        let error = FieldError::new("email", "taken", "That email is already in use.");
+        return edit_person_page(&state, person_id, Some(error), None).await;
+    };
+    if !verify {
+        return edit_person_page(
+            &state,
+            person_id,
+            None,
+            Some("Your email has been updated."),
+        )
+        .await;
+    }
+    if let Err(error) = state
+        .mailer
+        .verify_email(&to, &name, &token, &branding)
+        .await
+    {
+        tracing::warn!(%to, %error, "Failed to send verification email");
+        let error = FieldError::new(
+            "email",
+            "not_sent",
+            "We couldn't send an email to that address, please try again later.",
+        );
+        return edit_person_page(&state, person_id, Some(error), None).await;
+    }
+    edit_person_page(
+        &state,
+        person_id,
+        None,
+        Some("Follow the link we've emailed to your new address to finish changing it."),
+    )
+    .await
+}
+
+pub async fn verify_email(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Response, AppError> {
+    let now = state.clock.timestamp();
+    match state
+        .db
+        .call(move |db| db.confirm_email_change(&token, now))
+        .await?
+    {
+        Some(person_id) => Ok(Redirect::to(&format!("/person/{}", person_id)).into_response()),
+        None => {
+            let page = ErrorPage {
+                authorized_person_id: None,
+                error: "That link has expired or the email is already in use, please try changing your email again.".to_owned(),
+            };
+            Ok((StatusCode::NOT_FOUND, state.render_page(&page).await).into_response())
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditPassword {
+    current_password: String,
+    new_password: String,
+}
+
+pub async fn edit_password(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(edit): Form<EditPassword>,
+) -> Result<Response, AppError> {
+    if authorized.person_id != person_id {
+        return Ok(Redirect::to(&format!("/person/{}", person_id)).into_response());
+    }
+    if let Err(error) = check_password("new password", &edit.new_password, &[]) {
+        return edit_person_page(&state, person_id, Some(error.into()), None).await;
+    }
+    let session_id = authorized.session_id;
+    let changed = state
+        .db
+        .call(move |db| {
+            db.change_password(
+                person_id,
+                &edit.current_password,
+                &edit.new_password,
+                &session_id,
+            )
+        })
+        .await?;
+    if changed {
+        edit_person_page(
+            &state,
+            person_id,
+            None,
+            Some("Your password has been changed."),
+        )
+        .await
+    } else {
+        let error = FieldError::new(
+            "current_password",
+            "incorrect",
+            "Your current password isn't right.",
+        );
+        edit_person_page(&state, person_id, Some(error), None).await
+    }
+}
+
+// Everything stored about the signed in person, as a JSON download.
+pub async fn export_person(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+) -> Result<Response, AppError> {
+    if authorized.person_id != person_id {
+        return Err(AppError::Forbidden);
+    }
+    match state.db.call(move |db| db.export_person(person_id)).await? {
+        Some(export) => Ok((
+            [(
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"person-{person_id}.json\""),
+            )],
+            Json(export),
+        )
+            .into_response()),
+        None => Err(AppError::NotFound),
+    }
+}
+
+pub async fn delete_account(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+) -> Response {
+    if authorized.person_id != person_id {
+        return Redirect::to(&format!("/person/{}", person_id)).into_response();
+    }
+    let page = DeleteAccountPage {
+        authorized_person_id: person_id,
+        error: None,
+        id: person_id,
+    };
+    state.render_page(&page).await.into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAccount {
+    password: String,
+}
+
+pub async fn do_delete_account(
+    State(state): State<AppState>,
+    authorized: Authorized,
+    Path(person_id): Path<PersonId>,
+    Form(delete): Form<DeleteAccount>,
+) -> Result<Response, AppError> {
+    if authorized.person_id != person_id {
+        return Ok(Redirect::to(&format!("/person/{}", person_id)).into_response());
+    }
+    let deleted = state
+        .db
+        .call(move |db| db.delete_account(person_id, &delete.password))
+        .await?;
+    if !deleted {
+        let page = DeleteAccountPage {
+            authorized_person_id: person_id,
+            error: Some("That password isn't right.".to_owned()),
+            id: person_id,
+        };
+        return Ok(state.render_page(&page).await.into_response());
+    }
+    state
+        .snapshots
+        .erase(person_id, state.clock.timestamp())
+        .await;
+    let headers = state.sessions.removal();
+    Ok((headers, Redirect::to("/")).into_response())
+}
+
+// Session authorization for the app's JSON endpoints, rejecting with JSON rather than a page.
+pub struct AppAuthorized {
+    person_id: PersonId,
+}
+
+#[async_trait::async_trait]
+impl<S> FromRequestParts<S> for AppAuthorized
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        match Authorized::from_request_parts(parts, state).await {
+            Ok(authorized) => Ok(Self {
+                person_id: authorized.person_id,
+            }),
+            Err(_) => Err((
+                StatusCode::UNAUTHORIZED,
+                Json(ApiError {
+                    error: "Not signed in.".to_owned(),
+                }),
+            )
+                .into_response()),
+        }
+    }
+}
+
+// Someone's current match, compact enough for a phone.
+#[derive(Debug, Serialize)]
+pub struct AppMatch {
+    person_id: PersonId,
+    name: String,
+    waiting: bool,
+    // Their latest generation still current for its round type, when they were matched in one.
+    generation: Option<GenerationId>,
+    icebreaker: Option<String>,
+    partners: Vec<AppPartner>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppPartner {
+    #[serde(flatten)]
+    person: Person,
+    contact_fields: Vec<ContactField>,
+}
+
+async fn app_match(
+    state: &AppState,
+    person_id: PersonId,
+) -> Result<Option<AppMatch>, DatabaseError> {
+    state
+        .db
+        .call(move |db| {
+            let Some(person) = db.get_person(person_id)? else {
+                return Ok(None);
+            };
+            let current: HashSet<GenerationId> = db
+                .latest_match_metas()?
+                .into_iter()
+                .map(|meta| meta.generation)
+                .collect();
+            let matches: Vec<PastMatch> = db
+                .matches_for(person_id)?
+                .into_iter()
+                .filter(|m| current.contains(&m.generation))
+                .collect();
+            let latest = matches.first().map(|m| m.generation);
+            let icebreaker = matches.first().and_then(|m| m.icebreaker.clone());
+            let policy = Viewer::load(db, person_id)?;
+            let partners: Vec<AppPartner> = matches
+                .into_iter()
+                .filter(|m| Some(m.generation) == latest)
+                .map(|PastMatch { partner, .. }| {
+                    Ok(AppPartner {
+                        contact_fields: policy
+                            .contact_fields(partner.id, db.contact_fields(partner.id)?),
+                        person: partner,
+                    })
+                })
+                .collect::<Result<_, DatabaseError>>()?;
+            Ok(Some(AppMatch {
+                person_id,
+                name: person.name,
+                waiting: person.waiting,
+                generation: latest.filter(|_| !partners.is_empty()),
+                icebreaker,
+                partners,
+            }))
+        })
+        .await
+}
+
+// The installable app's page, which works without scripts and refreshes itself from the JSON
+// endpoints when they're available.
+pub async fn app(
+    State(state): State<AppState>,
+    authorized: Authorized,
+) -> Result<RenderedPage, AppError> {
+    let page = AppPage {
+        authorized_person_id: authorized.person_id,
+        me: app_match(&state, authorized.person_id).await?,
+    };
+    Ok(state.render_page(&page).await)
+}
+
+pub async fn app_current_match(
+    State(state): State<AppState>,
+    authorized: AppAuthorized,
+) -> Result<Response, AppError> {
+    Ok(match app_match(&state, authorized.person_id).await? {
+        Some(me) => Json(me).into_response(),
+        None => api_not_found(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppWaiting {
+    waiting: bool,
+}
+
+// Join or leave the waiting pool. Scripts asking for JSON get the new state back, the page's plain
+// form is sent back to the page.
+pub async fn app_toggle_waiting(
+    State(state): State<AppState>,
+    authorized: AppAuthorized,
+    headers: axum::http::HeaderMap,
+) -> Result<Response, AppError> {
+    let person_id = authorized.person_id;
+    let waiting = state
+        .db
+        .call(move |db| {
+            db.toggle_waiter(person_id)?;
+            Ok(db.get_person(person_id)?.is_some_and(|p| p.waiting))
+        })
+        .await?;
+    if accepts_json(&headers) {
+        Ok(Json(AppWaiting { waiting }).into_response())
+    } else {
+        Ok(Redirect::to("/app").into_response())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebManifestIcon {
+    src: String,
+    sizes: &'static str,
+}
+
+// https://developer.mozilla.org/en-US/docs/Web/Manifest
+#[derive(Debug, Serialize)]
+pub struct WebManifest {
+    name: String,
+    short_name: String,
+    start_url: &'static str,
+    scope: &'static str,
+    display: &'static str,
+    theme_color: String,
+    background_color: &'static str,
+    icons: Vec<WebManifestIcon>,
+}
+
+pub async fn web_manifest(State(state): State<AppState>) -> Result<Response, AppError> {
+    let branding = state.db.call(|db| db.branding()).await?;
+    let manifest = WebManifest {
+        short_name: branding.product_name.clone(),
+        name: branding.product_name,
+        start_url: "/app",
+        scope: "/",
+        display: "standalone",
+        theme_color: branding.accent_colour,
+        background_color: "#ffffff",
+        icons: branding
+            .logo_url
+            .into_iter()
+            .map(|src| WebManifestIcon { src, sizes: "any" })
+            .collect(),
+    };
+    Ok((
+        [(CONTENT_TYPE, "application/manifest+json")],
+        serde_json::to_string(&manifest).unwrap(),
+    )
+        .into_response())
+}
+
+// Served from the root so it can control every page, and never cached so updates reach phones.
+pub async fn service_worker(State(state): State<AppState>) -> Response {
+    (
+        [
+            (CONTENT_TYPE, "text/javascript; charset=utf-8"),
+            (CACHE_CONTROL, "no-cache"),
+        ],
+        state.render_page(&ServiceWorker {}).await,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::DEFAULT_ROUND_TYPE, matching::OddPersonPolicy};
+
+    fn settings() -> MatchingSettings {
+        MatchingSettings {
+            odd_person_policy: OddPersonPolicy::Unmatched,
+            max_exact_pool_size: 100,
+            edge_half_life: None,
+            repeat_window: 0,
+            interest_bonus: 0,
+            same_team_policy: SameTeamPolicy::Allow,
+            same_team_penalty: 0,
+            mentoring: false,
+            group_size: 2,
+            strategy: Strategy::Optimal,
+        }
+    }
+
+    // People named `a`, `b`, ... with ids in that order.
+    fn people(db: &Database, names: &[&str]) -> Vec<PersonId> {
+        let members: Vec<_> = names
+            .iter()
+            .map(|name| {
+                (
+                    format!("U{name}"),
+                    format!("{name}@example.com"),
+                    name.to_string(),
+                )
+            })
+            .collect();
+        db.import_slack_members(&members).unwrap();
+        let mut ids: Vec<_> = db.all_people().unwrap().iter().map(|p| p.id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn floaters_left_out_of_the_round_are_not_picked() {
+        let db = Database::in_memory();
+        let ids = people(&db, &["capped", "resting", "x", "w1", "w2", "w3"]);
+        let (capped, resting) = (ids[0], ids[1]);
+
+        let quality = MatchQuality {
+            strategy: "exact".to_owned(),
+            buckets: 1,
+            repeat_pairs: 0,
+            total_weight: 0,
+        };
+        let meta = db
+            .add_matching_generation(&quality, DEFAULT_ROUND_TYPE, &GenerationDetails::default())
+            .unwrap();
+        db.add_matching(&[capped, ids[2]], meta.generation).unwrap();
+        db.add_matching(&[resting, ids[2]], meta.generation)
+            .unwrap();
+        db.finish_matching(meta.generation).unwrap();
+
+        db.set_max_matches_per_season(capped, Some(1)).unwrap();
+        db.set_match_every_rounds(resting, Some(3)).unwrap();
+        for floater in [capped, resting] {
+            db.add_floater(floater).unwrap();
+        }
+        // The capped floater is also waiting, so is excluded from the round.
+        for waiter in [capped, ids[3], ids[4], ids[5]] {
+            db.toggle_waiter(waiter).unwrap();
+        }
+
+        let plan = plan_matching(&db, &settings(), DEFAULT_ROUND_TYPE).unwrap();
+        assert!(plan.excluded.iter().any(|(w, _)| *w == capped));
+        assert!(plan.floaters.is_empty(), "picked {:?}", plan.floaters);
+        assert_eq!(plan.waiters.len(), 3);
+    }
+
+    #[test]
+    fn csv_fields_are_not_formulas() {
+        assert_eq!(csv_escape("Ada"), "Ada");
+        assert_eq!(csv_escape("=1+2"), "'=1+2");
+        assert_eq!(csv_escape("+44"), "'+44");
+        assert_eq!(csv_escape("-2"), "'-2");
+        assert_eq!(csv_escape("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_escape("=A1,\"x\""), "\"'=A1,\"\"x\"\"\"");
+    }
+
+    #[test]
+    fn failed_pages_are_server_errors() {
+        let failed = RenderedPage {
+            html: render_failed_page("page.html", "broken", false),
+            failed: true,
+        };
+        let response = (StatusCode::NOT_FOUND, failed).into_response();
+        assert!(response.extensions().get::<RenderFailed>().is_some());
+        let rendered = RenderedPage {
+            html: String::new(),
+            failed: false,
+        };
+        assert_eq!(rendered.into_response().status(), StatusCode::OK);
+    }
+
+    // User supplied strings end up in every page so templates must never opt out of Tera's
+    // autoescaping.
+    #[test]
+    fn templates_keep_autoescaping() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/templates");
+        let tera = Tera::new(&format!("{dir}/*")).unwrap();
+        let unescaped: Vec<_> = tera
+            .get_template_names()
+            .filter(|name| {
+                let source = std::fs::read_to_string(std::path::Path::new(dir).join(name)).unwrap();
+                let source: String = source.split_whitespace().collect();
+                source.contains("|safe") || source.contains("autoescapefalse")
+            })
+            .collect();
+        assert!(
+            unescaped.is_empty(),
+            "Templates must not disable autoescaping: {unescaped:?}"
+        );
+    }
+
+    #[test]
+    fn buckets_follow_utc_offsets() {
+        let db = Database::in_memory();
+        let ids = people(&db, &["h1", "s1", "t1", "h2", "s2", "t2"]);
+        let zones = [
+            "Pacific/Honolulu",
+            "America/Sao_Paulo",
+            "Asia/Tokyo",
+            "Pacific/Honolulu",
+            "America/Sao_Paulo",
+            "Asia/Tokyo",
+        ];
+        for (id, zone) in ids.iter().zip(zones) {
+            db.set_timezone(*id, Some(zone)).unwrap();
+        }
+        // Far enough apart that no nudge moves anyone into another bucket, and in a different order
+        // to their names.
+        let buckets = partition_waiters(&db, &ids, 2, DEFAULT_ROUND_TYPE).unwrap();
+        let sorted = |mut bucket: Vec<PersonId>| {
+            bucket.sort();
+            bucket
+        };
+        let buckets: Vec<_> = buckets.into_iter().map(sorted).collect();
+        assert_eq!(
+            buckets,
+            vec![
+                vec![ids[0], ids[3]],
+                vec![ids[1], ids[4]],
+                vec![ids[2], ids[5]]
+            ]
+        );
+    }
+
+    #[test]
+    fn bucket_jitter_is_bounded_and_varies() {
+        let limit = BUCKET_JITTER_HOURS * 60 * 60;
+        let jitters: HashSet<i64> = (0..50)
+            .map(|round| bucket_jitter(round, PersonId(1)))
+            .collect();
+        assert!(jitters.iter().all(|jitter| jitter.abs() <= limit));
+        assert!(jitters.len() > 1);
+    }
 }